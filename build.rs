@@ -1,7 +1,8 @@
 use std::fs;
 
 fn main() {
-    // 初始化 ESP-IDF 构建环境变量
+    // 初始化 ESP-IDF 构建环境变量（主机构建不启用 esp 特性时跳过）
+    #[cfg(feature = "esp")]
     embuild::espidf::sysenv::output();
     // 从 .env 读取编译期配置
     load_dotenv();
@@ -34,7 +35,7 @@ fn load_dotenv() {
         // 仅允许白名单字段进入编译期环境
         if matches!(
             key,
-            "WIFI_SSID" | "WIFI_PASS" | "BACKEND_BASE_URL" | "DEFAULT_ROUTE_ID"
+            "WIFI_SSID" | "WIFI_PASS" | "BACKEND_BASE_URL" | "DEFAULT_ROUTE_ID" | "ADMIN_PIN"
         ) {
             println!("cargo:rustc-env={}={}", key, value);
         }
@@ -1,3 +1,69 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::model::{
+    CardTypeStyle, ConfigUpdateScope, FareRule, FareType, MessageTtlOverride, PassengerTone,
+    RouteConfig, SettlementMode, StationConfig, TapMode,
+};
+
+/// 通用 API 响应格式（与后端保持一致）。
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: Option<T>,
+    pub message: Option<String>,
+}
+
+// 无法识别的响应在错误信息中保留的最大字符数。
+const RESPONSE_PREVIEW_CHARS: usize = 64;
+
+/// 解析后端响应：兼容 {success,data,message} 封装（缺少 success 视为成功）与裸数据两种形式。
+/// 无法识别时返回响应开头片段。
+pub fn parse_api_response<T: DeserializeOwned>(body: &[u8]) -> Result<ApiResponse<T>, String> {
+    let unexpected = || {
+        let text = String::from_utf8_lossy(body);
+        text.chars()
+            .take(RESPONSE_PREVIEW_CHARS)
+            .collect::<String>()
+    };
+    let value: serde_json::Value = serde_json::from_slice(body).map_err(|_| unexpected())?;
+    if let serde_json::Value::Object(mut obj) = value {
+        if obj.contains_key("success") || obj.contains_key("data") {
+            let success = obj
+                .get("success")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(true);
+            let message = obj
+                .get("message")
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_string);
+            let data = match obj.remove("data") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(data) => Some(serde_json::from_value(data).map_err(|_| unexpected())?),
+            };
+            return Ok(ApiResponse {
+                success,
+                data,
+                message,
+            });
+        }
+        return serde_json::from_value(serde_json::Value::Object(obj))
+            .map(bare_response)
+            .map_err(|_| unexpected());
+    }
+    serde_json::from_value(value)
+        .map(bare_response)
+        .map_err(|_| unexpected())
+}
+
+/// 裸数据视为成功响应。
+fn bare_response<T>(data: T) -> ApiResponse<T> {
+    ApiResponse {
+        success: true,
+        data: Some(data),
+        message: None,
+    }
+}
+
 /// 后端 API 配置。
 #[derive(Clone, Debug)]
 pub struct ApiConfig {
@@ -10,6 +76,10 @@ pub const BATCH_RECORDS_PATH: &str = "/api/v1/bus/batchRecords";
 pub const CARDS_PATH: &str = "/api/v1/cards";
 pub const CARD_STATE_BATCH_PATH: &str = "/api/v1/cards/state/batch";
 pub const CARD_REGISTER_PATH: &str = "/api/v1/cards/register";
+pub const HEARTBEAT_PATH: &str = "/api/v1/bus/heartbeat";
+/// 对端网关（同车另一网关）提供的配置与黑名单路径。
+pub const PEER_CONFIG_PATH: &str = "/peer/config";
+pub const PEER_BLACKLIST_PATH: &str = "/peer/blacklist";
 
 impl ApiConfig {
     /// 线路配置接口 URL。
@@ -22,3 +92,418 @@ impl ApiConfig {
         format!("{}{}", self.base_url, BATCH_RECORDS_PATH)
     }
 }
+
+// 后端下发的单项金额上限（元），超出视为数据异常。
+const MAX_AMOUNT_YUAN: f32 = 10_000.0;
+
+/// 后端返回的线路配置（网关侧解析用）。
+#[derive(Deserialize)]
+pub struct RouteConfigResponse {
+    pub route_id: u16,
+    pub route_name: String,
+    #[serde(default)]
+    pub fare_type: Option<String>,
+    #[serde(default)]
+    pub tap_mode: Option<String>,
+    #[serde(default)]
+    pub settlement_mode: Option<String>,
+    pub max_fare: Option<f32>,
+    #[serde(default)]
+    pub trip_timeout_secs: Option<u32>,
+    #[serde(default)]
+    pub message_ttl_ok_ms: Option<u32>,
+    #[serde(default)]
+    pub message_ttl_action_ms: Option<u32>,
+    #[serde(default)]
+    pub message_ttl_error_ms: Option<u32>,
+    #[serde(default)]
+    pub stations: Vec<StationResponse>,
+    #[serde(default)]
+    pub fares: Vec<FareRuleResponse>,
+    #[serde(default)]
+    pub card_types: Vec<CardTypeStyleResponse>,
+    // 本次变化的配置段（如 ["fares"]），缺省表示整体更新。
+    #[serde(default)]
+    pub changed: Option<Vec<String>>,
+}
+
+/// 以后端配置接口的响应格式输出线路配置（供对端网关拉取，经 `From<RouteConfigResponse>` 还原）。
+pub fn peer_config_payload(route: &RouteConfig) -> serde_json::Value {
+    let mut data = serde_json::to_value(route).unwrap_or(serde_json::Value::Null);
+    if let Some(obj) = data.as_object_mut() {
+        // 字段名与后端不一致的部分按后端格式改写
+        if let Some(styles) = obj.remove("card_type_styles") {
+            obj.insert("card_types".to_string(), styles);
+        }
+        obj.remove("message_ttl");
+        obj.insert(
+            "message_ttl_ok_ms".to_string(),
+            route.message_ttl.ok_ms.into(),
+        );
+        obj.insert(
+            "message_ttl_action_ms".to_string(),
+            route.message_ttl.action_ms.into(),
+        );
+        obj.insert(
+            "message_ttl_error_ms".to_string(),
+            route.message_ttl.error_ms.into(),
+        );
+    }
+    serde_json::json!({ "success": true, "data": data })
+}
+
+/// 以后端卡片列表格式输出黑名单（供对端网关拉取）。
+pub fn peer_blacklist_payload(cards: &[String]) -> serde_json::Value {
+    let data: Vec<serde_json::Value> = cards
+        .iter()
+        .map(|card_id| serde_json::json!({ "card_id": card_id, "status": "blocked" }))
+        .collect();
+    serde_json::json!({ "success": true, "data": data })
+}
+
+impl RouteConfigResponse {
+    /// 根据 changed 字段确定更新范围（无法识别时按整体更新）。
+    pub fn update_scope(&self) -> ConfigUpdateScope {
+        let Some(changed) = self.changed.as_ref() else {
+            return ConfigUpdateScope::Full;
+        };
+        let stations = changed.iter().any(|section| section == "stations");
+        let fares = changed.iter().any(|section| section == "fares");
+        match (stations, fares) {
+            (true, false) => ConfigUpdateScope::Stations,
+            (false, true) => ConfigUpdateScope::Fares,
+            _ => ConfigUpdateScope::Full,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CardTypeStyleResponse {
+    pub card_type: String,
+    #[serde(default)]
+    pub tone: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct StationResponse {
+    #[serde(default)]
+    pub id: Option<u16>,
+    pub name: String,
+    pub sequence: u16,
+    pub zone_id: Option<u16>,
+    #[serde(default)]
+    pub is_transfer: Option<bool>,
+    #[serde(default)]
+    pub distance_m: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct FareRuleResponse {
+    #[serde(default)]
+    pub base_price: Option<f32>,
+    #[serde(default)]
+    pub fare_type: Option<String>,
+    #[serde(default)]
+    pub segment_count: Option<u16>,
+    #[serde(default)]
+    pub extra_price: Option<f32>,
+    #[serde(default)]
+    pub start_station: Option<u16>,
+    #[serde(default)]
+    pub end_station: Option<u16>,
+    #[serde(default)]
+    pub per_km_price: Option<f32>,
+    #[serde(default)]
+    pub effective_from: Option<u64>,
+    #[serde(default)]
+    pub effective_to: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct CardResponse {
+    #[serde(default)]
+    pub card_id: Option<String>,
+    #[serde(default)]
+    pub card_type: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub balance: Option<f64>,
+    #[serde(default)]
+    pub discount_rate: Option<f32>,
+    #[serde(default)]
+    pub discount_amount: Option<f32>,
+}
+
+/// 卡片画像（用于状态与优惠更新）。
+pub struct CardProfile {
+    pub card_type: Option<String>,
+    pub status: Option<String>,
+    pub balance_cents: Option<u32>,
+    pub discount_rate: Option<f32>,
+    pub discount_amount: Option<f32>,
+}
+
+impl From<CardResponse> for CardProfile {
+    fn from(card: CardResponse) -> Self {
+        Self {
+            card_type: card.card_type,
+            status: card.status,
+            balance_cents: card
+                .balance
+                .filter(|value| value.is_finite())
+                .map(|value| (value.max(0.0) * 100.0).round() as u32),
+            discount_rate: card.discount_rate.and_then(sanitize_rate),
+            discount_amount: card.discount_amount.and_then(sanitize_amount),
+        }
+    }
+}
+
+/// 金额清洗（元）：NaN/Inf、负数或超出上限视为无效，避免异常值进入扣费计算。
+pub fn sanitize_amount(value: f32) -> Option<f32> {
+    if value.is_finite() && (0.0..=MAX_AMOUNT_YUAN).contains(&value) {
+        Some(value)
+    } else {
+        log::warn!("Ignoring invalid amount from backend: {}", value);
+        None
+    }
+}
+
+/// 折扣率清洗：NaN/Inf 或负数视为无效，大于 1 按 1（全免）处理。
+pub fn sanitize_rate(value: f32) -> Option<f32> {
+    if value.is_finite() && value >= 0.0 {
+        Some(value.min(1.0))
+    } else {
+        log::warn!("Ignoring invalid discount rate from backend: {}", value);
+        None
+    }
+}
+
+/// 将后端响应转换为网关内部模型。
+impl From<RouteConfigResponse> for RouteConfig {
+    fn from(value: RouteConfigResponse) -> Self {
+        let fare_type = match value.fare_type.as_deref() {
+            Some("segment") => FareType::Segment,
+            Some("distance") => FareType::Distance,
+            _ => FareType::Uniform,
+        };
+        let tap_mode = match value.tap_mode.as_deref() {
+            Some("tap_in_out") => TapMode::TapInOut,
+            _ => TapMode::SingleTap,
+        };
+        let settlement_mode = match value.settlement_mode.as_deref() {
+            Some("deposit") => SettlementMode::Deposit,
+            _ => SettlementMode::PayOnExit,
+        };
+        let fares = value
+            .fares
+            .into_iter()
+            .map(|fare| FareRule {
+                base_price: fare.base_price.and_then(sanitize_amount).unwrap_or(0.0),
+                fare_type: fare.fare_type,
+                segment_count: fare.segment_count,
+                extra_price: fare.extra_price.and_then(sanitize_amount),
+                start_station: fare.start_station,
+                end_station: fare.end_station,
+                per_km_price: fare.per_km_price.and_then(sanitize_amount),
+                effective_from: fare.effective_from,
+                effective_to: fare.effective_to,
+            })
+            .collect();
+        let stations = value
+            .stations
+            .into_iter()
+            .map(|station| StationConfig {
+                id: station.id.unwrap_or(0),
+                name: station.name,
+                sequence: station.sequence,
+                zone_id: station.zone_id,
+                is_transfer: station.is_transfer.unwrap_or(false),
+                distance_m: station.distance_m,
+            })
+            .collect();
+        // 未识别的提示色按普通票处理
+        let card_type_styles = value
+            .card_types
+            .into_iter()
+            .filter(|style| !style.card_type.trim().is_empty())
+            .map(|style| CardTypeStyle {
+                card_type: style.card_type.trim().to_string(),
+                tone: style
+                    .tone
+                    .as_deref()
+                    .and_then(PassengerTone::from_name)
+                    .unwrap_or(PassengerTone::Normal),
+                label: style.label.filter(|label| !label.trim().is_empty()),
+            })
+            .collect();
+        RouteConfig {
+            route_id: value.route_id,
+            route_name: value.route_name,
+            fare_type,
+            tap_mode,
+            settlement_mode,
+            max_fare: value.max_fare.and_then(sanitize_amount),
+            trip_timeout_secs: value.trip_timeout_secs.filter(|secs| *secs > 0),
+            message_ttl: MessageTtlOverride {
+                ok_ms: value.message_ttl_ok_ms.filter(|ms| *ms > 0),
+                action_ms: value.message_ttl_action_ms.filter(|ms| *ms > 0),
+                error_ms: value.message_ttl_error_ms.filter(|ms| *ms > 0),
+            },
+            stations,
+            fares,
+            card_type_styles,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_response(fares: serde_json::Value, max_fare: serde_json::Value) -> RouteConfig {
+        let response: RouteConfigResponse = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "max_fare": max_fare,
+            "stations": [],
+            "fares": fares
+        }))
+        .unwrap();
+        RouteConfig::from(response)
+    }
+
+    #[test]
+    fn non_finite_and_negative_amounts_are_rejected() {
+        assert_eq!(sanitize_amount(f32::NAN), None);
+        assert_eq!(sanitize_amount(f32::INFINITY), None);
+        assert_eq!(sanitize_amount(-0.5), None);
+        assert_eq!(sanitize_amount(MAX_AMOUNT_YUAN + 1.0), None);
+        assert_eq!(sanitize_amount(2.5), Some(2.5));
+        assert_eq!(sanitize_rate(f32::NAN), None);
+        assert_eq!(sanitize_rate(f32::NEG_INFINITY), None);
+        assert_eq!(sanitize_rate(-0.1), None);
+        assert_eq!(sanitize_rate(1.5), Some(1.0));
+        assert_eq!(sanitize_rate(0.8), Some(0.8));
+    }
+
+    #[test]
+    fn route_config_drops_invalid_fares() {
+        let config = route_response(
+            serde_json::json!([
+                {"base_price": -2.0, "extra_price": 1e30, "per_km_price": 0.3},
+                {"base_price": 2.0}
+            ]),
+            serde_json::json!(-1.0),
+        );
+        assert_eq!(config.fares[0].base_price, 0.0);
+        assert_eq!(config.fares[0].extra_price, None);
+        assert_eq!(config.fares[0].per_km_price, Some(0.3));
+        assert_eq!(config.fares[1].base_price, 2.0);
+        assert_eq!(config.max_fare, None);
+    }
+
+    #[test]
+    fn card_profile_sanitizes_balance_and_discounts() {
+        let response: CardResponse = serde_json::from_value(serde_json::json!({
+            "card_id": "A1B2C3D4",
+            "card_type": "student",
+            "balance": -3.0,
+            "discount_rate": 2.0,
+            "discount_amount": -1.0
+        }))
+        .unwrap();
+        let profile = CardProfile::from(response);
+        assert_eq!(profile.balance_cents, Some(0));
+        assert_eq!(profile.discount_rate, Some(1.0));
+        assert_eq!(profile.discount_amount, None);
+    }
+
+    #[test]
+    fn enveloped_response_is_parsed() {
+        let parsed: ApiResponse<Vec<u32>> =
+            parse_api_response(br#"{"success":false,"data":[1,2],"message":"busy"}"#).unwrap();
+        assert!(!parsed.success);
+        assert_eq!(parsed.data, Some(vec![1, 2]));
+        assert_eq!(parsed.message.as_deref(), Some("busy"));
+        let implicit: ApiResponse<Vec<u32>> = parse_api_response(br#"{"data":[3]}"#).unwrap();
+        assert!(implicit.success);
+        assert_eq!(implicit.data, Some(vec![3]));
+    }
+
+    #[test]
+    fn bare_response_is_treated_as_success() {
+        let parsed: ApiResponse<Vec<u32>> = parse_api_response(b"[4,5]").unwrap();
+        assert!(parsed.success);
+        assert_eq!(parsed.data, Some(vec![4, 5]));
+        assert!(parsed.message.is_none());
+    }
+
+    #[test]
+    fn garbage_response_reports_preview() {
+        let body = format!("<html>{}</html>", "x".repeat(200));
+        let err = parse_api_response::<Vec<u32>>(body.as_bytes())
+            .err()
+            .unwrap();
+        assert!(err.starts_with("<html>"));
+        assert_eq!(err.chars().count(), RESPONSE_PREVIEW_CHARS);
+        assert!(parse_api_response::<Vec<u32>>(br#"{"data":"oops"}"#).is_err());
+    }
+
+    #[test]
+    fn route_message_ttl_ignores_zero() {
+        let response: RouteConfigResponse = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "stations": [],
+            "fares": [],
+            "message_ttl_ok_ms": 800,
+            "message_ttl_error_ms": 0
+        }))
+        .unwrap();
+        let route = RouteConfig::from(response);
+        assert_eq!(route.message_ttl.ok_ms, Some(800));
+        assert_eq!(route.message_ttl.action_ms, None);
+        assert_eq!(route.message_ttl.error_ms, None);
+    }
+
+    #[test]
+    fn peer_config_round_trips() {
+        let route: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "segment",
+            "tap_mode": "tap_in_out",
+            "max_fare": 4.0,
+            "trip_timeout_secs": 5400,
+            "message_ttl": {"ok_ms": 800},
+            "stations": [
+                {"id": 1, "name": "一站", "sequence": 1},
+                {"id": 2, "name": "二站", "sequence": 2}
+            ],
+            "fares": [{"base_price": 2.0, "extra_price": 0.5}],
+            "card_type_styles": [{"card_type": "student", "tone": "student", "label": "学生卡"}]
+        }))
+        .unwrap();
+        let body = serde_json::to_vec(&peer_config_payload(&route)).unwrap();
+        let parsed: ApiResponse<RouteConfigResponse> = parse_api_response(&body).unwrap();
+        assert!(parsed.success);
+        let restored = RouteConfig::from(parsed.data.unwrap());
+        assert_eq!(
+            serde_json::to_value(&restored).unwrap(),
+            serde_json::to_value(&route).unwrap()
+        );
+    }
+
+    #[test]
+    fn peer_blacklist_uses_card_list_shape() {
+        let body = serde_json::to_vec(&peer_blacklist_payload(&["A1B2C3D4".to_string()])).unwrap();
+        let parsed: ApiResponse<Vec<CardResponse>> = parse_api_response(&body).unwrap();
+        let cards = parsed.data.unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].card_id.as_deref(), Some("A1B2C3D4"));
+        assert_eq!(cards[0].status.as_deref(), Some("blocked"));
+    }
+}
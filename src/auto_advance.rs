@@ -0,0 +1,23 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::lock::LockRecover;
+use crate::net::NetCommand;
+use crate::state::GatewayState;
+
+/// 启动自动报站任务：按固定间隔前进一站，终点站自动掉头。
+pub fn spawn_auto_advance_task(
+    state: Arc<Mutex<GatewayState>>,
+    net_cmd_tx: Sender<NetCommand>,
+    interval_secs: u32,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval_secs.max(1) as u64));
+        if state.lock_recover().auto_advance_station() {
+            // 与手动切站一致：换站时立即上报缓冲记录
+            let _ = net_cmd_tx.send(NetCommand::UploadNow);
+        }
+    })
+}
@@ -0,0 +1,84 @@
+#[cfg(feature = "esp")]
+use esp_idf_hal::sys::EspError;
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use crate::card_data::normalize_uid;
+
+// NVS 命名空间与键名（本地黑名单以换行分隔的文本保存）。
+#[cfg(feature = "esp")]
+const NVS_NAMESPACE: &str = "blacklist";
+#[cfg(feature = "esp")]
+const NVS_KEY_LOCAL: &str = "local";
+// 本地黑名单最大字节数（受 NVS blob 与内存限制）。
+pub const LOCAL_BLACKLIST_MAX_BYTES: usize = 8192;
+
+/// 本地黑名单持久化（离线站点导入，断电保留）。
+#[cfg(feature = "esp")]
+pub struct BlacklistStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+#[cfg(feature = "esp")]
+impl BlacklistStore {
+    /// 打开 NVS 命名空间。
+    pub fn open(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+
+    /// 读取已保存的本地黑名单（不存在或读取失败时为空）。
+    pub fn load(&self) -> Vec<String> {
+        let len = match self.nvs.blob_len(NVS_KEY_LOCAL) {
+            Ok(Some(len)) if len > 0 => len.min(LOCAL_BLACKLIST_MAX_BYTES),
+            _ => return Vec::new(),
+        };
+        let mut buf = vec![0u8; len];
+        match self.nvs.get_blob(NVS_KEY_LOCAL, &mut buf) {
+            Ok(Some(bytes)) => parse_blacklist_text(&String::from_utf8_lossy(bytes)),
+            Ok(None) => Vec::new(),
+            Err(err) => {
+                log::warn!("Local blacklist load failed: {:?}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// 保存本地黑名单（空列表时删除记录）。
+    pub fn save(&mut self, cards: &[String]) -> Result<(), EspError> {
+        if cards.is_empty() {
+            self.nvs.remove(NVS_KEY_LOCAL)?;
+            return Ok(());
+        }
+        let text = cards.join("\n");
+        self.nvs.set_blob(NVS_KEY_LOCAL, text.as_bytes())
+    }
+}
+
+/// 解析换行分隔的卡号列表（忽略空行与 # 注释，规范化后去重）。
+pub fn parse_blacklist_text(text: &str) -> Vec<String> {
+    let mut cards: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let card_id = normalize_uid(line);
+        if !cards.contains(&card_id) {
+            cards.push(card_id);
+        }
+    }
+    cards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_comments_blank_lines_and_duplicates() {
+        let text = "# 离线黑名单\na1:b2:c3:d4\n\n  A1B2C3D4  \n01-02-03-04 # 挂失\n";
+        assert_eq!(parse_blacklist_text(text), ["A1B2C3D4", "01020304"]);
+        assert!(parse_blacklist_text("").is_empty());
+    }
+}
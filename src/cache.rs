@@ -1,4 +1,6 @@
-use crate::model::{CardStateSnapshot, RouteConfig, TapEvent};
+use std::collections::HashMap;
+
+use crate::model::{CardStateSnapshot, FareRule, RouteConfig, TapEvent};
 
 /// 刷卡事件缓存（用于批量上报或 UI 显示）。
 pub struct TapEventCache {
@@ -25,10 +27,15 @@ impl CardStateSnapshotCache {
         self.entries.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
     pub fn is_full(&self) -> bool {
         self.entries.len() >= self.max_len
     }
 
+    #[allow(clippy::result_large_err)]
     pub fn push(&mut self, snapshot: CardStateSnapshot) -> Result<(), CardStateSnapshot> {
         if self.is_full() {
             return Err(snapshot);
@@ -41,6 +48,11 @@ impl CardStateSnapshotCache {
         let take = core::cmp::min(limit, self.entries.len());
         self.entries.drain(0..take).collect()
     }
+
+    /// 取出全部快照（交班对账时一次性推送）。
+    pub fn drain_all(&mut self) -> Vec<CardStateSnapshot> {
+        std::mem::take(&mut self.entries)
+    }
 }
 
 impl TapEventCache {
@@ -56,6 +68,10 @@ impl TapEventCache {
         self.events.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
     /// 是否已达到容量上限。
     pub fn is_full(&self) -> bool {
         self.events.len() >= self.max_len
@@ -87,6 +103,98 @@ pub struct ConfigCache {
     pub route: Option<RouteConfig>,
     pub fetched_at: u64,
     pub ttl_secs: u32,
+    pub fare_index: FareIndex,
+}
+
+/// 票价计算索引（配置更新时预计算，避免每次刷卡线性扫描）。
+#[derive(Clone, Debug, Default)]
+pub struct FareIndex {
+    station_sequence: HashMap<u16, u16>,
+    // 站点 ID -> 自首站起的累计里程（米），仅当全部站点给出距离时构建。
+    cumulative_m: HashMap<u16, u64>,
+    // 同键按下发顺序保留全部规则，查询时取第一条当前生效的规则。
+    pair_rules: HashMap<(u16, u16), Vec<FareRule>>,
+    base_rules: Vec<FareRule>,
+}
+
+impl FareIndex {
+    /// 根据线路配置构建索引（同键取首条生效规则，与线性查找语义一致）。
+    pub fn build(route: &RouteConfig) -> Self {
+        let mut station_sequence = HashMap::with_capacity(route.stations.len());
+        for station in &route.stations {
+            station_sequence.entry(station.id).or_insert(station.sequence);
+        }
+        let cumulative_m = build_cumulative_distance(route);
+        let mut pair_rules: HashMap<(u16, u16), Vec<FareRule>> = HashMap::new();
+        for fare in &route.fares {
+            if let (Some(start), Some(end)) = (fare.start_station, fare.end_station) {
+                pair_rules.entry((start, end)).or_default().push(fare.clone());
+            }
+        }
+        let base_rules = route
+            .fares
+            .iter()
+            .filter(|fare| {
+                fare.start_station.unwrap_or(0) == 0 && fare.end_station.unwrap_or(0) == 0
+            })
+            .cloned()
+            .collect();
+        Self {
+            station_sequence,
+            cumulative_m,
+            pair_rules,
+            base_rules,
+        }
+    }
+
+    /// 站点 ID 对应的序号。
+    pub fn sequence(&self, station_id: u16) -> Option<u16> {
+        self.station_sequence.get(&station_id).copied()
+    }
+
+    /// 两站之间沿线路的里程（米），缺少距离数据时返回 None。
+    pub fn distance_m(&self, start_station_id: u16, end_station_id: u16) -> Option<u64> {
+        let start = *self.cumulative_m.get(&start_station_id)?;
+        let end = *self.cumulative_m.get(&end_station_id)?;
+        Some(start.abs_diff(end))
+    }
+
+    /// 起终点专属票价（如有）；`now` 为 None 表示未校时。
+    pub fn pair_price(
+        &self,
+        start_station_id: u16,
+        end_station_id: u16,
+        now: Option<u64>,
+    ) -> Option<f32> {
+        self.pair_rules
+            .get(&(start_station_id, end_station_id))?
+            .iter()
+            .find(|fare| fare.is_effective(now))
+            .map(|fare| fare.base_price)
+    }
+
+    /// 分段/距离计价的基础规则（起终点均未指定）。
+    pub fn base_rule(&self, now: Option<u64>) -> Option<&FareRule> {
+        self.base_rules.iter().find(|fare| fare.is_effective(now))
+    }
+}
+
+/// 按序号累加站间距离；除首站外任一站缺少距离则放弃（回退按站数计价）。
+fn build_cumulative_distance(route: &RouteConfig) -> HashMap<u16, u64> {
+    let mut stations: Vec<_> = route.stations.iter().collect();
+    stations.sort_by_key(|station| station.sequence);
+    let mut cumulative = HashMap::with_capacity(stations.len());
+    let mut total = 0u64;
+    for (idx, station) in stations.iter().enumerate() {
+        if idx > 0 {
+            match station.distance_m {
+                Some(distance) => total += distance as u64,
+                None => return HashMap::new(),
+            }
+        }
+        cumulative.entry(station.id).or_insert(total);
+    }
+    cumulative
 }
 
 impl ConfigCache {
@@ -96,6 +204,7 @@ impl ConfigCache {
             route: None,
             fetched_at: 0,
             ttl_secs,
+            fare_index: FareIndex::default(),
         }
     }
 
@@ -105,14 +214,27 @@ impl ConfigCache {
 
     /// 更新缓存内容与时间戳。
     pub fn update(&mut self, route: RouteConfig, now: u64) {
+        self.fare_index = FareIndex::build(&route);
         self.route = Some(route);
         self.fetched_at = now;
     }
+
+    /// 诊断用 JSON：内存中的线路配置原样输出，附缓存时间与是否过期。
+    pub fn raw_json(&self, now: u64) -> serde_json::Value {
+        serde_json::json!({
+            "route": self.route,
+            "fetched_at": self.fetched_at,
+            "ttl_secs": self.ttl_secs,
+            "is_expired": self.is_expired(now),
+        })
+    }
 }
 
 /// 黑名单缓存（用于快速拒绝刷卡）。
 pub struct BlacklistCache {
     pub cards: Vec<String>,
+    // 本地导入的黑名单（离线站点），与后端下发的名单合并判断。
+    pub local_cards: Vec<String>,
     pub fetched_at: u64,
     pub ttl_secs: u32,
 }
@@ -122,6 +244,7 @@ impl BlacklistCache {
     pub fn new(ttl_secs: u32) -> Self {
         Self {
             cards: Vec::new(),
+            local_cards: Vec::new(),
             fetched_at: 0,
             ttl_secs,
         }
@@ -137,9 +260,17 @@ impl BlacklistCache {
         self.fetched_at = now;
     }
 
-    /// 判断卡号是否被拉黑。
+    /// 替换本地导入的黑名单（不影响后端名单）。
+    pub fn replace_local(&mut self, cards: Vec<String>) {
+        self.local_cards = cards;
+    }
+
+    /// 判断卡号是否被拉黑（后端名单或本地名单任一命中）。
     pub fn is_blocked(&self, card_id: &str) -> bool {
-        self.cards.iter().any(|id| id == card_id)
+        self.cards
+            .iter()
+            .chain(self.local_cards.iter())
+            .any(|id| id == card_id)
     }
 }
 
@@ -206,6 +337,70 @@ impl TapDebounce {
     }
 }
 
+/// 读卡器重复上报去重：同一卡号且卡内数据相同的检测在毫秒级窗口内只处理一次。
+/// 不区分模式（单次有效的充值/注册模式在首次检测后即退出），也不受员工卡免防抖影响；
+/// 写卡后卡内数据变化，真实的再次刷卡不会被误判。
+pub struct TapDedup {
+    window_ms: u64,
+    entries: Vec<DetectionSeen>,
+}
+
+struct DetectionSeen {
+    card_id: String,
+    card_data: Vec<u8>,
+    at_ms: u64,
+}
+
+// 去重窗口内最多记录的检测数。
+const TAP_DEDUP_MAX: usize = 16;
+
+impl TapDedup {
+    /// 创建去重缓存，窗口为 0 时不去重。
+    pub fn new(window_ms: u32) -> Self {
+        Self {
+            window_ms: window_ms as u64,
+            entries: Vec::new(),
+        }
+    }
+
+    /// 记录一次检测；窗口内已有相同检测时返回 false。
+    pub fn allow(&mut self, card_id: &str, card_data: &[u8], now_ms: u64) -> bool {
+        if self.window_ms == 0 {
+            return true;
+        }
+        let window = self.window_ms;
+        self.entries.retain(|e| now_ms.saturating_sub(e.at_ms) <= window);
+        let duplicate = self
+            .entries
+            .iter()
+            .any(|e| e.card_id == card_id && e.card_data == card_data);
+        if duplicate {
+            return false;
+        }
+        if self.entries.len() >= TAP_DEDUP_MAX {
+            self.entries.remove(0);
+        }
+        self.entries.push(DetectionSeen {
+            card_id: card_id.to_string(),
+            card_data: card_data.to_vec(),
+            at_ms: now_ms,
+        });
+        true
+    }
+}
+
+/// 未完成行程概要（供 /trips 接口列出）。
+pub struct ActiveTripSummary {
+    pub card_id: String,
+    // 上车记录号，用于精确指定要清除的行程。
+    pub record_id: String,
+    pub route_id: u16,
+    pub station_id: u16,
+    pub station_name: String,
+    // 上车至今的秒数。
+    pub age_secs: u64,
+}
+
 /// 进出站模式下的未完成行程缓存。
 pub struct ActiveTripCache {
     ttl_secs: u32,
@@ -216,6 +411,7 @@ struct ActiveTrip {
     card_id: String,
     event: TapEvent,
     last_seen: u64,
+    ttl_secs: u32,
 }
 
 impl ActiveTripCache {
@@ -227,7 +423,8 @@ impl ActiveTripCache {
         }
     }
 
-    pub fn insert(&mut self, event: TapEvent, now: u64) {
+    /// 记录未完成行程；`ttl_secs` 为线路级超时，None 时使用默认值。
+    pub fn insert(&mut self, event: TapEvent, now: u64, ttl_secs: Option<u32>) {
         // 插入前清理过期记录
         self.purge_expired(now);
         self.entries.retain(|e| e.card_id != event.card_id);
@@ -235,9 +432,52 @@ impl ActiveTripCache {
             card_id: event.card_id.clone(),
             event,
             last_seen: now,
+            ttl_secs: ttl_secs.unwrap_or(self.ttl_secs),
         });
     }
 
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 列出全部未完成行程（按上车先后排序）。
+    pub fn list(&mut self, now: u64) -> Vec<ActiveTripSummary> {
+        self.purge_expired(now);
+        let mut trips: Vec<ActiveTripSummary> = self
+            .entries
+            .iter()
+            .map(|e| ActiveTripSummary {
+                card_id: e.card_id.clone(),
+                record_id: e.event.record_id.clone(),
+                route_id: e.event.route_id,
+                station_id: e.event.station_id,
+                station_name: e.event.station_name.clone(),
+                age_secs: now.saturating_sub(e.last_seen),
+            })
+            .collect();
+        trips.sort_by_key(|trip| std::cmp::Reverse(trip.age_secs));
+        trips
+    }
+
+    /// 查看指定卡号的未完成行程（不移除）。
+    pub fn get(&mut self, card_id: &str, now: u64) -> Option<&TapEvent> {
+        self.purge_expired(now);
+        self.entries
+            .iter()
+            .find(|e| e.card_id == card_id)
+            .map(|e| &e.event)
+    }
+
+    /// 是否存在指定卡号的未完成行程。
+    pub fn contains(&mut self, card_id: &str, now: u64) -> bool {
+        self.purge_expired(now);
+        self.entries.iter().any(|e| e.card_id == card_id)
+    }
+
     /// 取出并移除指定卡号的未完成行程。
     pub fn take(&mut self, card_id: &str, now: u64) -> Option<TapEvent> {
         self.purge_expired(now);
@@ -247,9 +487,141 @@ impl ActiveTripCache {
         None
     }
 
-    /// 清理过期行程。
+    /// 清理过期行程（各行程按自身超时判断）。
     fn purge_expired(&mut self, now: u64) {
-        let ttl = self.ttl_secs as u64;
-        self.entries.retain(|e| now.saturating_sub(e.last_seen) <= ttl);
+        self.entries
+            .retain(|e| now.saturating_sub(e.last_seen) <= e.ttl_secs as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TapType;
+
+    fn fare_route() -> RouteConfig {
+        serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "segment",
+            "tap_mode": "tap_in_out",
+            "stations": [
+                {"id": 10, "name": "一站", "sequence": 1},
+                {"id": 20, "name": "二站", "sequence": 2},
+                {"id": 30, "name": "三站", "sequence": 3}
+            ],
+            "fares": [
+                {"base_price": 2.0, "segment_count": 2, "extra_price": 1.0},
+                {"base_price": 3.5, "start_station": 10, "end_station": 30},
+                {"base_price": 9.0, "start_station": 10, "end_station": 30}
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fare_index_matches_linear_lookup() {
+        let index = FareIndex::build(&fare_route());
+        assert_eq!(index.sequence(20), Some(2));
+        assert_eq!(index.sequence(99), None);
+        // 同一起终点取首条规则
+        assert_eq!(index.pair_price(10, 30, None), Some(3.5));
+        assert_eq!(index.pair_price(30, 10, None), None);
+        assert_eq!(index.base_rule(None).map(|rule| rule.base_price), Some(2.0));
+    }
+
+    #[test]
+    fn config_update_rebuilds_fare_index() {
+        let mut cache = ConfigCache::new(60);
+        assert!(cache.is_expired(0));
+        cache.update(fare_route(), 100);
+        assert_eq!(cache.fare_index.sequence(30), Some(3));
+        assert!(!cache.is_expired(160));
+        assert!(cache.is_expired(161));
+    }
+
+    fn board_event(card_id: &str) -> TapEvent {
+        TapEvent::new(
+            format!("rec-{}", card_id),
+            card_id.to_string(),
+            7,
+            10,
+            "一站".to_string(),
+            TapType::TapIn,
+            1000,
+            "gw-test".to_string(),
+        )
+    }
+
+    #[test]
+    fn active_trips_expire_by_their_own_timeout() {
+        let mut trips = ActiveTripCache::new(3600);
+        trips.insert(board_event("A"), 1000, Some(600));
+        trips.insert(board_event("B"), 1000, None);
+        assert!(trips.contains("A", 1600));
+        assert!(!trips.contains("A", 1601));
+        assert!(trips.contains("B", 4600));
+        assert!(trips.take("B", 4601).is_none());
+        assert!(trips.is_empty());
+    }
+
+    #[test]
+    fn local_blacklist_merges_with_backend_list() {
+        let mut blacklist = BlacklistCache::new(300);
+        blacklist.replace(vec!["AAAA0001".to_string()], 100);
+        blacklist.replace_local(vec!["BBBB0002".to_string()]);
+        assert!(blacklist.is_blocked("AAAA0001"));
+        assert!(blacklist.is_blocked("BBBB0002"));
+        // 后端名单刷新不影响本地导入的名单
+        blacklist.replace(Vec::new(), 200);
+        assert!(!blacklist.is_blocked("AAAA0001"));
+        assert!(blacklist.is_blocked("BBBB0002"));
+        assert!(!blacklist.is_expired(500));
+        assert!(blacklist.is_expired(501));
+    }
+
+    #[test]
+    fn raw_config_json_includes_fare_rules() {
+        let mut cache = ConfigCache::new(600);
+        assert_eq!(cache.raw_json(0)["route"], serde_json::Value::Null);
+        assert_eq!(cache.raw_json(0)["is_expired"], true);
+
+        cache.update(fare_route(), 1000);
+        let raw = cache.raw_json(1600);
+        assert_eq!(raw["fetched_at"], 1000);
+        assert_eq!(raw["is_expired"], false);
+        assert_eq!(raw["route"]["fare_type"], "segment");
+        assert_eq!(raw["route"]["stations"].as_array().unwrap().len(), 3);
+        let fares = raw["route"]["fares"].as_array().unwrap();
+        assert_eq!(fares.len(), 3);
+        assert_eq!(fares[0]["segment_count"], 2);
+        assert_eq!(fares[1]["start_station"], 10);
+        assert_eq!(cache.raw_json(1601)["is_expired"], true);
+    }
+
+    #[test]
+    fn dedup_collapses_same_detection_within_window() {
+        let mut dedup = TapDedup::new(300);
+        assert!(dedup.allow("A1B2C3D4", &[1, 2], 1_000));
+        assert!(!dedup.allow("A1B2C3D4", &[1, 2], 1_300));
+        // 卡内数据变化（已写卡）或其他卡不视为重复
+        assert!(dedup.allow("A1B2C3D4", &[1, 3], 1_300));
+        assert!(dedup.allow("11223344", &[1, 2], 1_300));
+        // 窗口过后再次检测正常处理
+        assert!(dedup.allow("A1B2C3D4", &[1, 2], 1_301));
+    }
+
+    #[test]
+    fn dedup_disabled_with_zero_window_and_bounded() {
+        let mut dedup = TapDedup::new(0);
+        assert!(dedup.allow("A1B2C3D4", &[1], 0));
+        assert!(dedup.allow("A1B2C3D4", &[1], 0));
+        let mut dedup = TapDedup::new(300);
+        for i in 0..=TAP_DEDUP_MAX as u8 {
+            assert!(dedup.allow("A1B2C3D4", &[i], 0));
+        }
+        assert_eq!(dedup.entries.len(), TAP_DEDUP_MAX);
+        // 最早的记录被淘汰
+        assert!(dedup.allow("A1B2C3D4", &[0], 0));
     }
 }
@@ -3,14 +3,19 @@ use crate::model::Direction;
 pub const CARD_DATA_LEN: usize = 32;
 pub const CARD_DATA_BLOCK_START: u8 = 8;
 pub const CARD_DATA_BLOCK_COUNT: u8 = 2;
+pub const CARD_DATA_BLOCK_SIZE: usize = 16;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CardDataParseError {
     BadLength,
     BadMagic,
     BadVersion,
+    /// 版本受支持但低于网关要求的最低版本，需重新注册。
+    OutdatedVersion,
     BadUidLen,
     BadCrc,
+    /// v2 分块布局中某个数据块 CRC 校验失败（块序号从 0 开始）。
+    BadBlockCrc(u8),
     UnknownStatus,
 }
 
@@ -20,15 +25,25 @@ impl CardDataParseError {
             CardDataParseError::BadLength => "bad_length",
             CardDataParseError::BadMagic => "bad_magic",
             CardDataParseError::BadVersion => "bad_version",
+            CardDataParseError::OutdatedVersion => "outdated_version",
             CardDataParseError::BadUidLen => "bad_uid_len",
             CardDataParseError::BadCrc => "bad_crc",
+            CardDataParseError::BadBlockCrc(0) => "bad_block_crc_0",
+            CardDataParseError::BadBlockCrc(1) => "bad_block_crc_1",
+            CardDataParseError::BadBlockCrc(_) => "bad_block_crc",
             CardDataParseError::UnknownStatus => "unknown_status",
         }
     }
 }
 const MAGIC: [u8; 2] = [0x54, 0x54];
-const VERSION: u8 = 0x01;
+// v1：整卡 32B 一个 CRC；v2：每个 16B 块末尾各带 CRC，可单独恢复余额块。
+const VERSION_V1: u8 = 0x01;
+const VERSION: u8 = 0x02;
 const EMPTY_ID: u16 = 0xFFFF;
+// 块 0 标志字节（13）：押金标志置位时，字节 22..24 保存押金而非上次上车站。
+const FLAG_DEPOSIT: u8 = 0x01;
+/// 卡内可记录的最大押金（分，2 字节）。
+pub const MAX_DEPOSIT_CENTS: u32 = u16::MAX as u32;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CardStatus {
@@ -64,9 +79,45 @@ impl CardStatus {
     }
 }
 
+/// 卡片 UID（4 字节单倍长或 7 字节双倍长，如 ISO14443-4 卡）。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CardUid {
+    bytes: [u8; 7],
+    len: u8,
+}
+
+impl CardUid {
+    /// 仅接受 4 或 7 字节。
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 4 && bytes.len() != 7 {
+            return None;
+        }
+        let mut out = [0u8; 7];
+        out[..bytes.len()].copy_from_slice(bytes);
+        Some(Self {
+            bytes: out,
+            len: bytes.len() as u8,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// 卡内 UID 字段（4 字节）：7 字节 UID 空间不足，保存末 4 字节（序列号部分）。
+    /// 限制：前 3 字节（含厂商码）不落卡，末 4 字节相同的两张 7 字节卡无法据此区分。
+    fn stored_bytes(&self) -> [u8; 4] {
+        let bytes = self.as_bytes();
+        let tail = &bytes[bytes.len() - 4..];
+        [tail[0], tail[1], tail[2], tail[3]]
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CardData {
+    // 卡内保存的 UID 字段与原始 UID 长度（4 或 7）。
     pub uid: [u8; 4],
+    pub uid_len: u8,
     pub balance_cents: u32,
     pub status: CardStatus,
     pub entry_station_id: Option<u16>,
@@ -74,12 +125,20 @@ pub struct CardData {
     pub last_direction: Option<Direction>,
     pub last_board_station_id: Option<u16>,
     pub last_alight_station_id: Option<u16>,
+    // 最近一次刷卡的网关标记（网关 ID 摘要）与时间（秒），用于识别复制卡/重放。
+    pub last_tap_gateway: Option<u8>,
+    pub last_tap_time: Option<u32>,
+    // 进站时间（秒）：行程中时与上车写入的上次刷卡时间共用卡内字段。
+    pub entry_time: Option<u32>,
+    // 押金模式上车预扣金额（分）：仅行程中保存，与上次上车站共用卡内字段。
+    pub deposit_cents: Option<u32>,
 }
 
 impl CardData {
-    pub fn new(uid: [u8; 4]) -> Self {
+    pub fn new(uid: CardUid) -> Self {
         Self {
-            uid,
+            uid: uid.stored_bytes(),
+            uid_len: uid.len,
             balance_cents: 0,
             status: CardStatus::Idle,
             entry_station_id: None,
@@ -87,6 +146,10 @@ impl CardData {
             last_direction: None,
             last_board_station_id: None,
             last_alight_station_id: None,
+            last_tap_gateway: None,
+            last_tap_time: None,
+            entry_time: None,
+            deposit_cents: None,
         }
     }
 
@@ -95,18 +158,49 @@ impl CardData {
     }
 
     pub fn from_bytes_verbose(data: &[u8]) -> Result<Self, CardDataParseError> {
+        match Self::from_bytes_recover(data)? {
+            (card, None) => Ok(card),
+            (_, Some(err)) => Err(err),
+        }
+    }
+
+    /// 卡内数据版本低于要求（0 表示不限制）时返回 `OutdatedVersion`；
+    /// 长度/魔数不对的数据交由完整解析报错。要求高于当前写卡版本时按当前版本处理，
+    /// 否则重新注册写入的新卡也会被拒绝。
+    pub fn check_min_version(data: &[u8], min_version: u8) -> Result<(), CardDataParseError> {
+        let min_version = min_version.min(VERSION);
+        if data.len() >= CARD_DATA_LEN
+            && data[0..2] == MAGIC
+            && matches!(data[2], VERSION_V1 | VERSION)
+            && data[2] < min_version
+        {
+            return Err(CardDataParseError::OutdatedVersion);
+        }
+        Ok(())
+    }
+
+    /// 解析卡内数据；v2 布局下若仅行程块损坏，仍返回余额块数据并附带损坏块错误。
+    pub fn from_bytes_recover(
+        data: &[u8],
+    ) -> Result<(Self, Option<CardDataParseError>), CardDataParseError> {
         if data.len() < CARD_DATA_LEN {
             return Err(CardDataParseError::BadLength);
         }
         if data[0..2] != MAGIC {
             return Err(CardDataParseError::BadMagic);
         }
-        if data[2] != VERSION {
-            return Err(CardDataParseError::BadVersion);
-        }
-        if data[3] != 4 {
+        if data[3] != 4 && data[3] != 7 {
             return Err(CardDataParseError::BadUidLen);
         }
+        match data[2] {
+            VERSION_V1 => Self::decode_v1(data).map(|card| (card, None)),
+            VERSION => Self::decode_v2(data),
+            _ => Err(CardDataParseError::BadVersion),
+        }
+    }
+
+    /// v1 布局：bytes 0..30 数据 + 30..32 CRC。
+    fn decode_v1(data: &[u8]) -> Result<Self, CardDataParseError> {
         let stored_crc = u16::from_le_bytes([data[30], data[31]]);
         let computed_crc = crc16(&data[..30]);
         if stored_crc != computed_crc {
@@ -123,6 +217,7 @@ impl CardData {
 
         Ok(Self {
             uid,
+            uid_len: data[3],
             balance_cents,
             status,
             entry_station_id,
@@ -130,40 +225,146 @@ impl CardData {
             last_direction,
             last_board_station_id,
             last_alight_station_id,
+            last_tap_gateway: None,
+            last_tap_time: None,
+            entry_time: None,
+            deposit_cents: None,
         })
     }
 
+    /// v2 布局：
+    /// - block 0：magic/version/uid_len/uid/balance/status/标志(13) + CRC(14..16)
+    /// - block 1：进站/上次行程字段 + 上次刷卡网关(21)/时间(26..30) + CRC(30..32)
+    ///
+    /// 行程中的卡，26..30 为上车时写入的进站时间；押金标志置位时 22..24 为押金。
+    fn decode_v2(data: &[u8]) -> Result<(Self, Option<CardDataParseError>), CardDataParseError> {
+        if !block_crc_ok(data, 0) {
+            return Err(CardDataParseError::BadBlockCrc(0));
+        }
+        let balance_cents = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        let status = CardStatus::from_u8(data[12]).ok_or(CardDataParseError::UnknownStatus)?;
+        let uid = CardUid::from_slice(&data[4..8]).ok_or(CardDataParseError::BadUidLen)?;
+        let mut card = Self::new(uid);
+        card.uid_len = data[3];
+        card.balance_cents = balance_cents;
+        card.status = status;
+        if !block_crc_ok(data, 1) {
+            return Ok((card, Some(CardDataParseError::BadBlockCrc(1))));
+        }
+        card.entry_station_id = decode_optional_u16(&data[16..18]);
+        card.last_route_id = decode_optional_u16(&data[18..20]);
+        card.last_direction = decode_direction(data[20]);
+        if data[13] & FLAG_DEPOSIT != 0 && status == CardStatus::InTrip {
+            card.deposit_cents = Some(u16::from_le_bytes([data[22], data[23]]) as u32);
+        } else {
+            card.last_board_station_id = decode_optional_u16(&data[22..24]);
+        }
+        card.last_alight_station_id = decode_optional_u16(&data[24..26]);
+        card.last_tap_gateway = Some(data[21]).filter(|tag| *tag != 0);
+        card.last_tap_time =
+            Some(u32::from_le_bytes([data[26], data[27], data[28], data[29]])).filter(|t| *t != 0);
+        card.entry_time = card.last_tap_time.filter(|_| status == CardStatus::InTrip);
+        Ok((card, None))
+    }
+
+    /// 编码为 v2 分块布局。
     pub fn to_bytes(&self) -> [u8; CARD_DATA_LEN] {
         let mut out = [0u8; CARD_DATA_LEN];
         out[0..2].copy_from_slice(&MAGIC);
         out[2] = VERSION;
-        out[3] = 4;
+        out[3] = self.uid_len;
         out[4..8].copy_from_slice(&self.uid);
-        out[12..16].copy_from_slice(&self.balance_cents.to_le_bytes());
-        out[16] = self.status.as_u8();
-        write_optional_u16(&mut out[18..20], self.entry_station_id);
-        write_optional_u16(&mut out[20..22], self.last_route_id);
-        out[22] = encode_direction(self.last_direction);
-        write_optional_u16(&mut out[24..26], self.last_board_station_id);
-        write_optional_u16(&mut out[26..28], self.last_alight_station_id);
-        let crc = crc16(&out[..30]);
-        out[30..32].copy_from_slice(&crc.to_le_bytes());
+        out[8..12].copy_from_slice(&self.balance_cents.to_le_bytes());
+        out[12] = self.status.as_u8();
+        write_optional_u16(&mut out[16..18], self.entry_station_id);
+        write_optional_u16(&mut out[18..20], self.last_route_id);
+        out[20] = encode_direction(self.last_direction);
+        match self.deposit_cents.filter(|_| self.status == CardStatus::InTrip) {
+            Some(deposit) => {
+                out[13] |= FLAG_DEPOSIT;
+                let deposit = deposit.min(MAX_DEPOSIT_CENTS) as u16;
+                out[22..24].copy_from_slice(&deposit.to_le_bytes());
+            }
+            None => write_optional_u16(&mut out[22..24], self.last_board_station_id),
+        }
+        write_optional_u16(&mut out[24..26], self.last_alight_station_id);
+        out[21] = self.last_tap_gateway.unwrap_or(0);
+        let tap_time = match self.status {
+            CardStatus::InTrip => self.entry_time,
+            _ => self.last_tap_time,
+        };
+        out[26..30].copy_from_slice(&tap_time.unwrap_or(0).to_le_bytes());
+        for block in 0..CARD_DATA_BLOCK_COUNT as usize {
+            let start = block * CARD_DATA_BLOCK_SIZE;
+            let end = start + CARD_DATA_BLOCK_SIZE;
+            let crc = crc16(&out[start..end - 2]);
+            out[end - 2..end].copy_from_slice(&crc.to_le_bytes());
+        }
         out
     }
+
+    /// 卡内 UID 是否与读卡器上报的 UID 一致（长度与卡内保存的 UID 字段均需匹配）。
+    /// 7 字节 UID 仅比对末 4 字节，见 `CardUid::stored_bytes`。
+    pub fn matches_uid(&self, uid: &CardUid) -> bool {
+        self.uid_len == uid.len && self.uid == uid.stored_bytes()
+    }
+
+    /// 是否为“不可能的行程”：上次在另一网关刷卡，且间隔短于最短通行时间。
+    pub fn impossible_travel(&self, gateway_tag: u8, now_secs: u64, min_travel_secs: u32) -> bool {
+        if min_travel_secs == 0 {
+            return false;
+        }
+        let (Some(last_gateway), Some(last_time)) = (self.last_tap_gateway, self.last_tap_time)
+        else {
+            return false;
+        };
+        if last_gateway == gateway_tag {
+            return false;
+        }
+        now_secs.saturating_sub(last_time as u64) < min_travel_secs as u64
+    }
 }
 
-pub fn decode_uid_hex(input: &str) -> Option<[u8; 4]> {
-    if input.len() != 8 {
+/// 网关 ID 摘要（1 字节，写入卡内；0 保留表示未记录）。
+pub fn gateway_tag(gateway_id: &str) -> u8 {
+    let crc = crc16(gateway_id.as_bytes());
+    match (crc as u8) ^ ((crc >> 8) as u8) {
+        0 => 1,
+        tag => tag,
+    }
+}
+
+/// 规范化卡号：去掉分隔符（冒号、短横线、空白）并转为大写，
+/// 使不同读卡器上报的 `a1:b2:c3:d4`、`A1B2C3D4` 等格式一致。
+pub fn normalize_uid(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-') && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub fn decode_uid_hex(input: &str) -> Option<CardUid> {
+    let input = normalize_uid(input);
+    if input.len() != 8 && input.len() != 14 {
         return None;
     }
     let bytes = input.as_bytes();
-    let mut out = [0u8; 4];
+    let mut out = [0u8; 7];
     for (i, chunk) in bytes.chunks(2).enumerate() {
         let hi = hex_val(chunk[0])?;
         let lo = hex_val(chunk[1])?;
         out[i] = (hi << 4) | lo;
     }
-    Some(out)
+    CardUid::from_slice(&out[..input.len() / 2])
+}
+
+/// 校验单个 16B 数据块（末尾 2 字节为块内 CRC）。
+fn block_crc_ok(data: &[u8], block: usize) -> bool {
+    let start = block * CARD_DATA_BLOCK_SIZE;
+    let end = start + CARD_DATA_BLOCK_SIZE;
+    let stored = u16::from_le_bytes([data[end - 2], data[end - 1]]);
+    stored == crc16(&data[start..end - 2])
 }
 
 fn decode_optional_u16(bytes: &[u8]) -> Option<u16> {
@@ -219,3 +420,204 @@ fn hex_val(byte: u8) -> Option<u8> {
         _ => None,
     }
 }
+
+/// 按 v1 布局编码的空闲卡（测试旧卡兼容用）。
+#[cfg(test)]
+pub(crate) fn v1_bytes(uid: &[u8; 4], balance_cents: u32) -> [u8; CARD_DATA_LEN] {
+    let mut data = [0u8; CARD_DATA_LEN];
+    data[0..2].copy_from_slice(&MAGIC);
+    data[2] = VERSION_V1;
+    data[3] = 4;
+    data[4..8].copy_from_slice(uid);
+    data[12..16].copy_from_slice(&balance_cents.to_le_bytes());
+    data[18..28].fill(0xFF);
+    let crc = crc16(&data[..30]);
+    data[30..32].copy_from_slice(&crc.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_card() -> CardData {
+        let mut card = CardData::new(CardUid::from_slice(&[0xA1, 0xB2, 0xC3, 0xD4]).unwrap());
+        card.balance_cents = 1234;
+        card.status = CardStatus::InTrip;
+        card.entry_station_id = Some(3);
+        card.last_route_id = Some(12);
+        card.last_direction = Some(Direction::Down);
+        card.last_board_station_id = Some(3);
+        card.last_tap_gateway = Some(0x42);
+        card.entry_time = Some(1_700_000_000);
+        card
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let bytes = sample_card().to_bytes();
+        let card = CardData::from_bytes_verbose(&bytes).unwrap();
+        assert_eq!(card.uid, [0xA1, 0xB2, 0xC3, 0xD4]);
+        assert_eq!(card.uid_len, 4);
+        assert_eq!(card.balance_cents, 1234);
+        assert_eq!(card.status, CardStatus::InTrip);
+        assert_eq!(card.entry_station_id, Some(3));
+        assert_eq!(card.last_route_id, Some(12));
+        assert_eq!(card.last_direction, Some(Direction::Down));
+        assert_eq!(card.last_board_station_id, Some(3));
+        assert_eq!(card.last_alight_station_id, None);
+        assert_eq!(card.last_tap_gateway, Some(0x42));
+        assert_eq!(card.entry_time, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn v1_layout_still_decodes() {
+        let data = v1_bytes(&[1, 2, 3, 4], 500);
+        let card = CardData::from_bytes_verbose(&data).unwrap();
+        assert_eq!(card.balance_cents, 500);
+        assert_eq!(card.status, CardStatus::Idle);
+        assert_eq!(card.entry_station_id, None);
+        assert_eq!(
+            CardData::check_min_version(&data, VERSION),
+            Err(CardDataParseError::OutdatedVersion)
+        );
+        assert_eq!(CardData::check_min_version(&data, 0), Ok(()));
+        // 要求高于当前版本时按当前版本处理，v2 卡仍可用
+        let v2 = sample_card().to_bytes();
+        assert_eq!(CardData::check_min_version(&v2, VERSION + 1), Ok(()));
+        assert_eq!(
+            CardData::check_min_version(&data, u8::MAX),
+            Err(CardDataParseError::OutdatedVersion)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let bytes = sample_card().to_bytes();
+        assert_eq!(
+            CardData::from_bytes_verbose(&bytes[..31]).unwrap_err(),
+            CardDataParseError::BadLength
+        );
+        let mut bad = bytes;
+        bad[0] = 0;
+        assert_eq!(
+            CardData::from_bytes_verbose(&bad).unwrap_err(),
+            CardDataParseError::BadMagic
+        );
+        let mut bad = bytes;
+        bad[2] = 9;
+        assert_eq!(
+            CardData::from_bytes_verbose(&bad).unwrap_err(),
+            CardDataParseError::BadVersion
+        );
+        let mut bad = bytes;
+        bad[3] = 5;
+        assert_eq!(
+            CardData::from_bytes_verbose(&bad).unwrap_err(),
+            CardDataParseError::BadUidLen
+        );
+    }
+
+    #[test]
+    fn corrupt_trip_block_keeps_balance() {
+        let mut bytes = sample_card().to_bytes();
+        bytes[17] ^= 0xFF;
+        let (card, err) = CardData::from_bytes_recover(&bytes).unwrap();
+        assert_eq!(err, Some(CardDataParseError::BadBlockCrc(1)));
+        assert_eq!(card.balance_cents, 1234);
+        assert_eq!(card.entry_station_id, None);
+        let mut bytes = sample_card().to_bytes();
+        bytes[9] ^= 0xFF;
+        assert_eq!(
+            CardData::from_bytes_recover(&bytes).unwrap_err(),
+            CardDataParseError::BadBlockCrc(0)
+        );
+    }
+
+    #[test]
+    fn uid_hex_is_normalized() {
+        assert_eq!(normalize_uid("a1:b2-c3 d4"), "A1B2C3D4");
+        let uid = decode_uid_hex("a1:b2:c3:d4").unwrap();
+        assert_eq!(uid.as_bytes(), &[0xA1, 0xB2, 0xC3, 0xD4]);
+        assert!(decode_uid_hex("A1B2C3").is_none());
+        assert!(decode_uid_hex("A1B2C3ZZ").is_none());
+    }
+
+    #[test]
+    fn gateway_tag_is_never_zero() {
+        assert_ne!(gateway_tag(""), 0);
+        assert_eq!(gateway_tag("gw-01"), gateway_tag("gw-01"));
+    }
+
+    #[test]
+    fn impossible_travel_needs_other_gateway_within_window() {
+        let mut card = CardData::new(CardUid::from_slice(&[1, 2, 3, 4]).unwrap());
+        assert!(!card.impossible_travel(7, 1_000, 60));
+        card.last_tap_gateway = Some(9);
+        card.last_tap_time = Some(1_000);
+        assert!(card.impossible_travel(7, 1_059, 60));
+        assert!(!card.impossible_travel(7, 1_060, 60));
+        // 同一网关或功能关闭时不判断
+        assert!(!card.impossible_travel(9, 1_010, 60));
+        assert!(!card.impossible_travel(7, 1_010, 0));
+    }
+
+    #[test]
+    fn uid_formats_decode_to_same_bytes() {
+        let expected = decode_uid_hex("A1B2C3D4").unwrap();
+        for input in ["a1b2c3d4", "A1:B2:C3:D4", "a1-B2-c3-D4", "a1 b2 C3 d4"] {
+            assert_eq!(normalize_uid(input), "A1B2C3D4");
+            assert_eq!(decode_uid_hex(input).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn deposit_round_trips_while_in_trip() {
+        let mut card = sample_card();
+        card.deposit_cents = Some(450);
+        let bytes = card.to_bytes();
+        assert_eq!(bytes[13] & FLAG_DEPOSIT, FLAG_DEPOSIT);
+        let decoded = CardData::from_bytes_verbose(&bytes).unwrap();
+        assert_eq!(decoded.deposit_cents, Some(450));
+        // 押金占用上次上车站字段
+        assert_eq!(decoded.last_board_station_id, None);
+        assert_eq!(decoded.entry_station_id, Some(3));
+    }
+
+    #[test]
+    fn deposit_is_dropped_once_trip_ends() {
+        let mut card = sample_card();
+        card.deposit_cents = Some(450);
+        card.status = CardStatus::Idle;
+        let bytes = card.to_bytes();
+        assert_eq!(bytes[13], 0);
+        let decoded = CardData::from_bytes_verbose(&bytes).unwrap();
+        assert_eq!(decoded.deposit_cents, None);
+        assert_eq!(decoded.last_board_station_id, Some(3));
+    }
+
+    #[test]
+    fn deposit_is_capped_to_field_width() {
+        let mut card = sample_card();
+        card.deposit_cents = Some(MAX_DEPOSIT_CENTS + 1);
+        let decoded = CardData::from_bytes_verbose(&card.to_bytes()).unwrap();
+        assert_eq!(decoded.deposit_cents, Some(MAX_DEPOSIT_CENTS));
+    }
+
+    #[test]
+    fn seven_byte_uid_stores_last_four_bytes() {
+        let uid = CardUid::from_slice(&[0x04, 0x11, 0x22, 0xA1, 0xB2, 0xC3, 0xD4]).unwrap();
+        let mut card = CardData::new(uid);
+        assert_eq!(card.uid, [0xA1, 0xB2, 0xC3, 0xD4]);
+        assert_eq!(card.uid_len, 7);
+        card.balance_cents = 500;
+        let decoded = CardData::from_bytes_verbose(&card.to_bytes()).unwrap();
+        assert_eq!(decoded.uid_len, 7);
+        assert!(decoded.matches_uid(&uid));
+        // 同尾 4 字节的 4 字节 UID 因长度不同不匹配
+        assert!(!decoded.matches_uid(&CardUid::from_slice(&[0xA1, 0xB2, 0xC3, 0xD4]).unwrap()));
+        // 已知限制：仅前 3 字节不同的 7 字节 UID 无法区分
+        let twin = CardUid::from_slice(&[0x05, 0x99, 0x88, 0xA1, 0xB2, 0xC3, 0xD4]).unwrap();
+        assert!(decoded.matches_uid(&twin));
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 时间来源（便于替换为可控时钟，验证 TTL/防抖/提示时长等逻辑）。
+pub trait Clock: Send + Sync {
+    /// 当前时间戳（毫秒）。
+    fn now_millis(&self) -> u64;
+
+    /// 当前时间戳（秒）。
+    fn now_secs(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+}
+
+/// 系统时钟（设备实际运行使用）。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+// 一天的分钟数。
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// UTC 时间戳按时区偏移换算为本地当天分钟数（0..1440，跨零点回绕）。
+pub fn local_minute_of_day(epoch_secs: u64, tz_offset_minutes: i16) -> u16 {
+    let utc_minute = (epoch_secs / 60 % MINUTES_PER_DAY as u64) as i64;
+    (utc_minute + tz_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY) as u16
+}
+
+/// 格式化本地时间为 HH:MM。
+pub fn format_local_time(epoch_secs: u64, tz_offset_minutes: i16) -> String {
+    let minute = local_minute_of_day(epoch_secs, tz_offset_minutes);
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// 手动推进的时钟（仅测试构建可用）。
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockClock {
+    millis: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// 以指定毫秒时间戳创建。
+    pub fn new(millis: u64) -> Self {
+        Self {
+            millis: std::sync::atomic::AtomicU64::new(millis),
+        }
+    }
+
+    /// 设置当前时间。
+    pub fn set(&self, millis: u64) {
+        self.millis
+            .store(millis, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// 时间前进指定毫秒。
+    pub fn advance(&self, millis: u64) {
+        self.millis
+            .fetch_add(millis, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 00:00:00 UTC。
+    const MIDNIGHT_UTC: u64 = 1_704_067_200;
+
+    #[test]
+    fn minute_of_day_applies_offset() {
+        assert_eq!(local_minute_of_day(MIDNIGHT_UTC, 0), 0);
+        assert_eq!(local_minute_of_day(MIDNIGHT_UTC + 90 * 60, 480), 570);
+        assert_eq!(format_local_time(MIDNIGHT_UTC + 90 * 60, 480), "09:30");
+    }
+
+    #[test]
+    fn minute_of_day_wraps_midnight() {
+        // UTC 23:30 + 8 小时 = 次日 07:30。
+        assert_eq!(
+            format_local_time(MIDNIGHT_UTC + 23 * 3600 + 1800, 480),
+            "07:30"
+        );
+        // UTC 00:15 - 1 小时 = 前一日 23:15。
+        assert_eq!(format_local_time(MIDNIGHT_UTC + 15 * 60, -60), "23:15");
+    }
+}
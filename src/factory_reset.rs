@@ -0,0 +1,68 @@
+#[cfg(feature = "esp")]
+use std::thread;
+#[cfg(feature = "esp")]
+use std::time::Duration;
+
+use crate::model::GatewaySettings;
+use crate::state::GatewayState;
+
+// 恢复出厂需显式确认的口令（防止误触发）。
+pub const FACTORY_RESET_CONFIRM: &str = "RESET";
+// 应答发出后延迟重启，保证 HTTP 响应送达。
+#[cfg(feature = "esp")]
+const RESTART_DELAY_MS: u64 = 500;
+
+/// 内存中的设置与本地名单恢复为默认值（NVS 擦除后重启前调用）。
+pub fn reset_in_memory(state: &mut GatewayState) {
+    state.settings = GatewaySettings::default();
+    state.update_local_blacklist(Vec::new());
+    state.clear_card_cache();
+}
+
+/// 擦除默认 NVS 分区（Wi-Fi、本地黑名单等持久化数据），随后延迟重启。
+#[cfg(feature = "esp")]
+pub fn erase_nvs_and_restart() -> bool {
+    let err = unsafe { esp_idf_hal::sys::nvs_flash_erase() };
+    if err != 0 {
+        log::error!("Factory reset: NVS erase failed ({})", err);
+        return false;
+    }
+    log::warn!("Factory reset: NVS erased, restarting");
+    thread::spawn(|| {
+        thread::sleep(Duration::from_millis(RESTART_DELAY_MS));
+        unsafe { esp_idf_hal::sys::esp_restart() };
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_restores_defaults_and_clears_local_data() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.lookup_wait_ms = 0;
+        settings.anti_passback = true;
+        let mut state = GatewayState::bootstrap(settings);
+        state.update_local_blacklist(vec!["A1B2C3D4".to_string()]);
+        let now_ms = state.now_millis();
+        state.update_card_cache(
+            "A1B2C3D4".to_string(),
+            Some("student".to_string()),
+            None,
+            None,
+            None,
+            None,
+            now_ms,
+        );
+
+        reset_in_memory(&mut state);
+        let defaults = GatewaySettings::default();
+        assert_eq!(state.settings.gateway_id, defaults.gateway_id);
+        assert_eq!(state.settings.lookup_wait_ms, defaults.lookup_wait_ms);
+        assert!(!state.settings.anti_passback);
+        assert!(state.blacklist_cache.local_cards.is_empty());
+        assert!(state.card_cache.is_empty());
+    }
+}
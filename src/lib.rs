@@ -0,0 +1,36 @@
+// 模块划分：串口、协议、处理管线、网络与 Web UI。
+// 状态机、协议编解码、缓存与页面渲染等不依赖 ESP-IDF，可在主机上编译与测试；
+// 网络、Web 服务、串口收发、LED、NVS 等依赖 HAL 的模块仅在 esp 特性下编译。
+pub mod api;
+pub mod blacklist_store;
+pub mod cache;
+pub mod card_data;
+pub mod clock;
+pub mod factory_reset;
+pub mod lock;
+pub mod log_buffer;
+pub mod metrics;
+pub mod model;
+pub mod privacy;
+pub mod processor;
+pub mod proto;
+pub mod retry;
+pub mod serial;
+pub mod serial_io;
+pub mod state;
+pub mod upload;
+pub mod watchdog;
+pub mod web;
+
+#[cfg(feature = "esp")]
+pub mod auto_advance;
+#[cfg(feature = "esp")]
+pub mod net;
+#[cfg(feature = "esp")]
+pub mod pipeline;
+#[cfg(feature = "esp")]
+pub mod smart_led;
+#[cfg(feature = "esp")]
+pub mod uart_link;
+#[cfg(feature = "esp")]
+pub mod web_server;
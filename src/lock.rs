@@ -0,0 +1,60 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// 锁中毒恢复：持锁线程 panic 后清除中毒标记并继续使用内部数据，
+/// 避免一次偶发 panic 让刷卡处理与各后台线程永久停止更新状态。
+pub trait LockRecover<T> {
+    /// 获取锁；中毒时记录日志并恢复。
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockRecover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            log::error!("Mutex poisoned by a panicked thread, recovering");
+            let guard = poisoned.into_inner();
+            self.clear_poison();
+            guard
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn poisoned_mutex() -> Arc<Mutex<Vec<u32>>> {
+        let mutex = Arc::new(Mutex::new(vec![1, 2]));
+        let held = mutex.clone();
+        let _ = thread::spawn(move || {
+            let mut guard = held.lock().unwrap();
+            guard.push(3);
+            panic!("poison");
+        })
+        .join();
+        mutex
+    }
+
+    #[test]
+    fn lock_recover_returns_data_of_poisoned_mutex() {
+        let mutex = poisoned_mutex();
+        assert!(mutex.is_poisoned());
+        assert_eq!(*mutex.lock_recover(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn lock_recover_clears_poison_flag() {
+        let mutex = poisoned_mutex();
+        mutex.lock_recover().push(4);
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.lock().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn lock_recover_on_healthy_mutex() {
+        let mutex = Mutex::new(5);
+        *mutex.lock_recover() += 1;
+        assert_eq!(*mutex.lock_recover(), 6);
+    }
+}
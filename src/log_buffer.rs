@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+#[cfg(feature = "esp")]
+use std::sync::Mutex;
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::log::EspLogger;
+use log::{Level, LevelFilter, Record};
+#[cfg(feature = "esp")]
+use log::{Log, Metadata};
+use serde::Serialize;
+
+#[cfg(feature = "esp")]
+use crate::clock::{Clock, SystemClock};
+
+// 环形日志缓冲容量（条）与单条消息最大长度（字节）。
+#[cfg(feature = "esp")]
+const LOG_RING_CAPACITY: usize = 200;
+const LOG_MESSAGE_MAX_LEN: usize = 256;
+
+/// 单条日志记录（供 /logs 接口输出）。
+#[derive(Clone, Debug, Serialize)]
+pub struct LogEntry {
+    pub timestamp_ms: u64,
+    pub level: &'static str,
+    pub target: String,
+    pub message: String,
+}
+
+/// 固定容量的日志环形缓冲（满时淘汰最旧记录）。
+pub struct LogRing {
+    capacity: usize,
+    entries: VecDeque<(Level, LogEntry)>,
+}
+
+impl LogRing {
+    /// 创建指定容量的缓冲。
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// 追加一条日志，超出容量时丢弃最旧的一条。
+    pub fn push(&mut self, level: Level, entry: LogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((level, entry));
+    }
+
+    /// 记录一条 log 日志（消息按字节上限截断）。
+    pub fn push_record(&mut self, record: &Record, timestamp_ms: u64) {
+        let mut message = record.args().to_string();
+        truncate_utf8(&mut message, LOG_MESSAGE_MAX_LEN);
+        let entry = LogEntry {
+            timestamp_ms,
+            level: level_str(record.level()),
+            target: record.target().to_string(),
+            message,
+        };
+        self.push(record.level(), entry);
+    }
+
+    /// 按最低级别过滤，返回从旧到新的日志。
+    pub fn snapshot(&self, min_level: LevelFilter) -> Vec<LogEntry> {
+        self.entries
+            .iter()
+            .filter(|(level, _)| *level <= min_level)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+}
+
+/// 日志分发：串口控制台（EspLogger）+ 内存环形缓冲。
+#[cfg(feature = "esp")]
+struct RingLogger {
+    console: EspLogger,
+    ring: Mutex<LogRing>,
+}
+
+#[cfg(feature = "esp")]
+static LOGGER: RingLogger = RingLogger {
+    console: EspLogger::new(),
+    ring: Mutex::new(LogRing::new(LOG_RING_CAPACITY)),
+};
+
+#[cfg(feature = "esp")]
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.console.log(record);
+        // 日志路径上不能 panic；锁中毒时直接丢弃本条
+        if let Ok(mut ring) = self.ring.lock() {
+            ring.push_record(record, SystemClock.now_millis());
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// 安装日志器（替代 EspLogger::initialize_default，控制台输出保持不变）。
+#[cfg(feature = "esp")]
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// 读取缓冲中不低于指定级别的日志。
+#[cfg(feature = "esp")]
+pub fn recent_logs(min_level: LevelFilter) -> Vec<LogEntry> {
+    LOGGER
+        .ring
+        .lock()
+        .map(|ring| ring.snapshot(min_level))
+        .unwrap_or_default()
+}
+
+/// 解析查询参数中的日志级别（默认 info）。
+pub fn parse_level(input: Option<&str>) -> LevelFilter {
+    match input
+        .map(|value| value.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("error") => LevelFilter::Error,
+        Some("warn") => LevelFilter::Warn,
+        Some("debug") => LevelFilter::Debug,
+        Some("trace") => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    }
+}
+
+fn level_str(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// 按字节上限截断字符串（保证 UTF-8 边界）。
+fn truncate_utf8(value: &mut String, max_len: usize) {
+    if value.len() <= max_len {
+        return;
+    }
+    let mut end = max_len;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ring: &mut LogRing, level: Level, message: &str) {
+        ring.push_record(
+            &Record::builder()
+                .level(level)
+                .target("gw")
+                .args(format_args!("{}", message))
+                .build(),
+            1000,
+        );
+    }
+
+    #[test]
+    fn ring_evicts_oldest_entries() {
+        let mut ring = LogRing::new(2);
+        record(&mut ring, Level::Info, "a");
+        record(&mut ring, Level::Info, "b");
+        record(&mut ring, Level::Info, "c");
+        let messages: Vec<_> = ring
+            .snapshot(LevelFilter::Trace)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(messages, ["b", "c"]);
+        let mut empty = LogRing::new(0);
+        record(&mut empty, Level::Error, "x");
+        assert!(empty.snapshot(LevelFilter::Trace).is_empty());
+    }
+
+    #[test]
+    fn snapshot_filters_by_level() {
+        let mut ring = LogRing::new(8);
+        record(&mut ring, Level::Error, "e");
+        record(&mut ring, Level::Warn, "w");
+        record(&mut ring, Level::Debug, "d");
+        let warn = ring.snapshot(parse_level(Some(" WARN ")));
+        assert_eq!(warn.len(), 2);
+        assert_eq!(warn[1].level, "warn");
+        assert_eq!(ring.snapshot(parse_level(None)).len(), 2);
+        assert_eq!(ring.snapshot(parse_level(Some("trace"))).len(), 3);
+    }
+
+    #[test]
+    fn long_messages_are_truncated_on_char_boundary() {
+        let mut ring = LogRing::new(1);
+        record(&mut ring, Level::Info, &"票".repeat(100));
+        let entry = &ring.snapshot(LevelFilter::Info)[0];
+        assert!(entry.message.len() <= LOG_MESSAGE_MAX_LEN);
+        assert_eq!(entry.message.chars().count(), LOG_MESSAGE_MAX_LEN / 3);
+        assert_eq!(entry.timestamp_ms, 1000);
+    }
+}
@@ -1,34 +1,22 @@
-// 模块划分：串口、协议、处理管线、网络与 Web UI
-mod api;
-mod card_data;
-mod cache;
-mod model;
-mod net;
-mod pipeline;
-mod processor;
-mod proto;
-mod serial;
-mod serial_io;
-mod state;
-mod upload;
-mod web;
-mod web_server;
-mod uart_link;
-mod smart_led;
-
 use std::sync::{mpsc, Arc, Mutex};
 
 use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::gpio::{AnyInputPin, AnyOutputPin};
 use esp_idf_hal::prelude::*;
 use esp_idf_hal::uart;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use taptransit_gateway::{
+    auto_advance, blacklist_store, lock, log_buffer, model, net, pipeline, processor,
+    serial, smart_led, state, uart_link, watchdog, web, web_server,
+};
+use lock::LockRecover;
 use pipeline::spawn_processor_loop;
 use processor::GatewayProcessor;
 
 fn main() {
     // ESP-IDF 运行时初始化（链接补丁 & 日志）
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    log_buffer::init();
 
     log::info!("TapTransit gateway booting (ESP-IDF)...");
 
@@ -52,6 +40,16 @@ fn main() {
     // 共享状态（线路、站点、健康状态等）
     let settings = model::GatewaySettings::default();
     let state = Arc::new(Mutex::new(state::GatewayState::bootstrap(settings.clone())));
+    // NVS 分区只能获取一次：Wi-Fi 与本地黑名单共用
+    let nvs_partition = EspDefaultNvsPartition::take().ok();
+    let blacklist_store = nvs_partition
+        .clone()
+        .and_then(|partition| blacklist_store::BlacklistStore::open(partition).ok());
+    if let Some(store) = blacklist_store.as_ref() {
+        let local_cards = store.load();
+        log::info!("Local blacklist loaded: {} cards", local_cards.len());
+        state.lock_recover().update_local_blacklist(local_cards);
+    }
     // 智能灯条任务：反映系统状态
     smart_led::spawn_led_task(rmt_channel, pins.gpio48, state.clone());
 
@@ -65,21 +63,71 @@ fn main() {
         upload_rx,
         write_result_tx,
         write_result_rx,
+        hello_tx,
+        hello_rx,
+        config_req_tx,
+        config_req_rx,
+        ack_resend_tx,
+        ack_resend_rx,
+        heartbeat_tx,
+        heartbeat_rx,
     } = pipeline::GatewayChannels::new();
     let (net_cmd_tx, net_cmd_rx) = mpsc::channel();
-    let processor = GatewayProcessor::new(state.clone());
-    let _processor_handle =
-        spawn_processor_loop(processor, card_rx, cmd_tx.clone(), upload_tx.clone(), net_cmd_tx.clone());
-    let _write_result_handle = pipeline::spawn_write_result_loop(state.clone(), write_result_rx);
-    let (_uart_rx_handle, _uart_tx_handle) =
-        uart_link::spawn_uart_tasks(uart_rx, uart_tx, card_tx.clone(), write_result_tx, cmd_rx);
+    let heartbeat = watchdog::Heartbeat::new();
+    let processor = GatewayProcessor::new(state.clone()).with_heartbeat(heartbeat.clone());
+    let _processor_handle = spawn_processor_loop(
+        processor,
+        card_rx,
+        cmd_tx.clone(),
+        upload_tx.clone(),
+        net_cmd_tx.clone(),
+        heartbeat.clone(),
+    );
+    // 看门狗：处理器线程退出或卡死时重启设备
+    let _watchdog_handle = if settings.watchdog_stall_secs > 0 {
+        Some(watchdog::spawn_watchdog(heartbeat, settings.watchdog_stall_secs))
+    } else {
+        None
+    };
+    let _write_result_handle =
+        pipeline::spawn_write_result_loop(state.clone(), write_result_rx, cmd_tx.clone());
+    let _hello_handle = pipeline::spawn_reader_hello_loop(state.clone(), hello_rx);
+    let _config_req_handle =
+        pipeline::spawn_config_request_loop(state.clone(), config_req_rx, cmd_tx.clone());
+    let _ack_resend_handle =
+        pipeline::spawn_ack_resend_loop(state.clone(), ack_resend_rx, cmd_tx.clone());
+    let _reader_poll_handle = pipeline::spawn_reader_poll_loop(
+        state.clone(),
+        heartbeat_rx,
+        cmd_tx.clone(),
+        settings.reader_poll_secs,
+    );
+    let (_uart_rx_handle, _uart_tx_handle) = uart_link::spawn_uart_tasks(
+        state.clone(),
+        uart_rx,
+        uart_tx,
+        card_tx.clone(),
+        write_result_tx,
+        hello_tx,
+        config_req_tx,
+        ack_resend_tx,
+        heartbeat_tx,
+        cmd_rx,
+        settings.serial_mode,
+        settings.reader_id,
+    );
+    // 启动握手：告知读卡器网关支持的协议能力
+    let _ = cmd_tx.send(serial::SerialCommand::Hello(serial::ReaderHello::gateway()));
 
     // 连接 Wi-Fi（失败不阻塞主流程，保持离线可用）
-    let _wifi = match net::connect_wifi(modem) {
+    let _wifi = match net::connect_wifi(
+        modem,
+        nvs_partition,
+        settings.wifi_connect_attempts,
+        &state,
+    ) {
         Ok(wifi) => {
-            if let Ok(mut state) = state.lock() {
-                state.update_health(Some(true), None);
-            }
+            state.lock_recover().update_health(Some(true), None);
             Some(wifi)
         }
         Err(err) => {
@@ -95,29 +143,62 @@ fn main() {
         .unwrap_or(1);
 
     // 启动网络上传与 Web 管理界面
+    let auto_advance_secs = settings.auto_advance_secs;
+    let http_probe_secs = settings.http_probe_secs;
+    let web_port = web::WebServerParams::from_settings(&settings).port;
     let _net_handle = net::spawn_network_loop(state.clone(), upload_rx, net_cmd_rx, settings);
+    // 可选：定时自动报站
+    let _auto_advance_handle = if auto_advance_secs > 0 {
+        Some(auto_advance::spawn_auto_advance_task(
+            state.clone(),
+            net_cmd_tx.clone(),
+            auto_advance_secs,
+        ))
+    } else {
+        None
+    };
 
     // 启动后立即尝试拉取一次配置（本地空缓存时避免“首刷卡未注册”）。
     if default_route_id > 0 {
-        if let Ok(mut state) = state.lock() {
-            let direction = state.route_state.direction;
-            state.update_route(default_route_id, 0, "未设置".to_string(), direction);
-        }
+        let mut guard = state.lock_recover();
+        let direction = guard.route_state.direction;
+        guard.update_route(default_route_id, 0, "未设置".to_string(), direction);
+        drop(guard);
         let _ = net_cmd_tx.send(net::NetCommand::SyncConfig {
             route_id: default_route_id,
         });
+    } else {
+        // 不做启动同步，无需预热
+        state.lock_recover().mark_initial_sync_done();
     }
-    let _server = match web_server::start_server(state.clone(), net_cmd_tx.clone()) {
-        Ok(server) => Some(server),
-        Err(err) => {
-            log::warn!("Web server start failed: {:?}", err);
-            None
+    let blacklist_store = Arc::new(Mutex::new(blacklist_store));
+    let start_web = || {
+        match web_server::start_server(state.clone(), net_cmd_tx.clone(), blacklist_store.clone()) {
+            Ok(server) => Some(server),
+            Err(err) => {
+                log::warn!("Web server start failed: {:?}", err);
+                None
+            }
         }
     };
+    let mut server = start_web();
     let _ = card_tx;
 
-    // 主循环保持任务存活
+    // 主循环保持任务存活，并定期自检 HTTP 服务（套接字耗尽等卡死时重建）
+    let mut server_watch = watchdog::ServerWatch::new(watchdog::SELF_PROBE_MAX_FAILURES);
+    let mut since_probe_secs = 0;
     loop {
         FreeRtos::delay_ms(1000);
+        since_probe_secs += 1;
+        if http_probe_secs == 0 || since_probe_secs < http_probe_secs {
+            continue;
+        }
+        since_probe_secs = 0;
+        if server_watch.record(web_server::probe_server(web_port)) {
+            log::error!("Web server unresponsive, restarting it");
+            // 先释放旧服务的端口与套接字再重建
+            let _ = server.take();
+            server = start_web();
+        }
     }
 }
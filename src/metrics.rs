@@ -0,0 +1,193 @@
+use std::fmt::Write as _;
+
+use crate::serial::CardAck;
+use crate::state::GatewayState;
+
+/// 运行计数（供 /metrics 以 Prometheus 文本格式导出）。
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    pub taps_accepted: u64,
+    pub taps_rejected: u64,
+    pub taps_ignored: u64,
+    pub uploads_ok: u64,
+    pub uploads_failed: u64,
+    // 成功上报批次的耗时累计与最近一次耗时（毫秒）。
+    pub upload_latency_ms_sum: u64,
+    pub upload_latency_ms_last: u64,
+}
+
+impl Metrics {
+    /// 按 ACK 结果累计刷卡次数（忽略的误刷不响提示音）。
+    pub fn record_tap(&mut self, ack: &CardAck) {
+        if ack.result == 1 {
+            self.taps_accepted += 1;
+        } else if ack.beep_pattern == 0 {
+            self.taps_ignored += 1;
+        } else {
+            self.taps_rejected += 1;
+        }
+    }
+
+    /// 累计上报批次结果与耗时。
+    pub fn record_upload(&mut self, ok: bool, latency_ms: u64) {
+        if ok {
+            self.uploads_ok += 1;
+            self.upload_latency_ms_sum = self.upload_latency_ms_sum.saturating_add(latency_ms);
+            self.upload_latency_ms_last = latency_ms;
+        } else {
+            self.uploads_failed += 1;
+        }
+    }
+}
+
+/// 渲染 Prometheus 文本格式（手工拼接，不引入依赖）。
+pub fn render(state: &GatewayState) -> String {
+    let m = &state.metrics;
+    let mut out = String::with_capacity(2048);
+    write_metric(
+        &mut out,
+        "taptransit_taps_total",
+        "Card taps by result.",
+        "counter",
+        &[
+            ("result=\"accepted\"", m.taps_accepted),
+            ("result=\"rejected\"", m.taps_rejected),
+            ("result=\"ignored\"", m.taps_ignored),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_uploads_total",
+        "Upload batches by status.",
+        "counter",
+        &[
+            ("status=\"ok\"", m.uploads_ok),
+            ("status=\"failed\"", m.uploads_failed),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_upload_latency_ms_sum",
+        "Total latency of successful upload batches in milliseconds.",
+        "counter",
+        &[("", m.upload_latency_ms_sum)],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_upload_latency_ms_last",
+        "Latency of the last successful upload batch in milliseconds.",
+        "gauge",
+        &[("", m.upload_latency_ms_last)],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_upload_dropped_total",
+        "Upload records dropped because the buffer was full.",
+        "counter",
+        &[("", state.upload_dropped)],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_backend_reachable",
+        "Whether the backend was reachable on the last request.",
+        "gauge",
+        &[("", state.backend_reachable as u64)],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_wifi_connected",
+        "Whether Wi-Fi is connected.",
+        "gauge",
+        &[("", state.wifi_connected as u64)],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_cache_entries",
+        "Entries held in each in-memory cache.",
+        "gauge",
+        &[
+            ("cache=\"tap_events\"", state.tap_cache.len() as u64),
+            ("cache=\"card_states\"", state.card_state_cache.len() as u64),
+            ("cache=\"card_profiles\"", state.card_cache.len() as u64),
+            ("cache=\"active_trips\"", state.active_trips.len() as u64),
+            (
+                "cache=\"blacklist\"",
+                (state.blacklist_cache.cards.len() + state.blacklist_cache.local_cards.len())
+                    as u64,
+            ),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "taptransit_pending_writes",
+        "Card writes sent to the reader without a result yet.",
+        "gauge",
+        &[("", state.pending_write_count())],
+    );
+    out
+}
+
+/// 写入一个指标（HELP/TYPE 行 + 各标签样本）。
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::GatewaySettings;
+
+    #[test]
+    fn taps_are_counted_by_ack_result() {
+        let mut metrics = Metrics::default();
+        metrics.record_tap(&CardAck::accepted());
+        metrics.record_tap(&CardAck::rejected());
+        metrics.record_tap(&CardAck::ignored());
+        metrics.record_tap(&CardAck::accepted());
+        assert_eq!(
+            (
+                metrics.taps_accepted,
+                metrics.taps_rejected,
+                metrics.taps_ignored
+            ),
+            (2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn upload_latency_only_counts_successes() {
+        let mut metrics = Metrics::default();
+        metrics.record_upload(true, 120);
+        metrics.record_upload(false, 5000);
+        metrics.record_upload(true, 80);
+        assert_eq!((metrics.uploads_ok, metrics.uploads_failed), (2, 1));
+        assert_eq!(metrics.upload_latency_ms_sum, 200);
+        assert_eq!(metrics.upload_latency_ms_last, 80);
+    }
+
+    #[test]
+    fn render_emits_prometheus_text() {
+        let mut state = GatewayState::bootstrap(GatewaySettings::with_gateway_id("gw-test"));
+        state.metrics.record_tap(&CardAck::accepted());
+        state.wifi_connected = true;
+        let text = render(&state);
+        assert!(text.contains("# HELP taptransit_taps_total Card taps by result.\n"));
+        assert!(text.contains("# TYPE taptransit_taps_total counter\n"));
+        assert!(text.contains("taptransit_taps_total{result=\"accepted\"} 1\n"));
+        assert!(text.contains("taptransit_wifi_connected 1\n"));
+        assert!(text.contains("taptransit_cache_entries{cache=\"active_trips\"} 0\n"));
+        // 每行都是注释或 “名称[标签] 数值”
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<u64>().is_ok(), "{}", line);
+        }
+    }
+}
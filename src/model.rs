@@ -1,6 +1,10 @@
 use std::fmt;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+// 启动时 Wi-Fi 连接失败的重试退避：首次等待与上限（毫秒）。
+const WIFI_RETRY_BASE_MS: u64 = 2000;
+const WIFI_RETRY_MAX_MS: u64 = 30_000;
 
 /// 刷卡类型（上车/下车）。
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -19,7 +23,8 @@ impl TapType {
 }
 
 /// 刷卡模式（单次/进出站）。
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TapMode {
     SingleTap,
     TapInOut,
@@ -34,6 +39,17 @@ impl TapMode {
     }
 }
 
+/// 上下车刷卡线路的结算方式。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementMode {
+    /// 上车不扣费，下车按实际票价扣费。
+    #[default]
+    PayOnExit,
+    /// 上车预扣最高票价，下车按实际票价退还差额（避免下车时余额不足）。
+    Deposit,
+}
+
 /// 线路方向。
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
@@ -51,7 +67,8 @@ impl Direction {
 }
 
 /// 计价类型。
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FareType {
     Uniform,
     Segment,
@@ -68,8 +85,130 @@ impl FareType {
     }
 }
 
-/// 乘客提示音色/标签（用于 UI 或蜂鸣提示）。
+/// 无上车记录下车（卡内为行程中，但网关无未完成行程）的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrayTapOutPolicy {
+    /// 拒绝本次下车，并将卡片状态复位为空闲。
+    Reject,
+    /// 按固定罚金结算。
+    Penalty,
+}
+
+/// 上车读卡器遇到已有未完成行程的卡（未下车刷卡又上车）的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatBoardPolicy {
+    /// 丢弃上一行程，开启新行程。
+    Restart,
+    /// 拒绝本次上车，提示先下车刷卡。
+    Reject,
+    /// 本次刷卡按下车结算上一行程，乘客再刷一次上车。
+    Settle,
+}
+
+/// 刷卡已处理后，后端查询才返回“已冻结”时的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostTapBlockPolicy {
+    /// 不追溯，下次刷卡时按黑名单拒绝。
+    Ignore,
+    /// 立即写卡冻结、退还本次扣费，并上报冲正记录。
+    Reverse,
+}
+
+/// 后端返回的卡类型既不在线路映射表、也不是内置票种时的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownCardTypePolicy {
+    /// 按普通票处理。
+    Normal,
+    /// 按普通票收费，提示司机留意。
+    Warn,
+    /// 拒绝本次刷卡。
+    Reject,
+}
+
+/// 尚未同步到线路配置（无线路、无票价）时刷卡的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoRoutePolicy {
+    /// 照常处理（线路/站点为 0，不扣费）。
+    Allow,
+    /// 拒绝本次刷卡，提示线路未设置。
+    Reject,
+    /// 放行不扣费，记录刷卡，待线路同步后按当前线路上报（带 no_route 标记）。
+    Queue,
+}
+
+/// 启动后首次配置同步完成前（预热期）刷卡的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupPolicy {
+    /// 照常处理（无配置时按无线路策略处理）。
+    Off,
+    /// 短暂等待首次同步完成，超时后照常处理。
+    Wait,
+    /// 拒绝本次刷卡，提示系统启动中。
+    Reject,
+}
+
+/// 疑似复制卡/重放（不可能的行程）的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TamperPolicy {
+    /// 正常放行，上报记录附带告警标记。
+    Alert,
+    /// 拒绝本次刷卡。
+    Block,
+}
+
+/// 上传缓冲已满时的处理策略。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadOverflowPolicy {
+    /// 丢弃最旧的记录，保留最新记录。
+    DropOldest,
+    /// 不再接收新记录（保留已缓冲记录）。
+    DropNewest,
+}
+
+/// 读卡器角色（双读卡器车辆：上车/下车分开）。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaderRole {
+    /// 按未完成行程推断上/下车（单读卡器）。
+    Any,
+    /// 上车读卡器：始终视为上车。
+    Board,
+    /// 下车读卡器：始终视为下车。
+    Alight,
+}
+
+/// 读卡器串口工作模式。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialMode {
+    /// 完整帧协议（AA55 帧头 + 校验，支持 ACK/写卡）。
+    Framed,
+    /// 简易读卡器：每行一个十六进制 UID，不支持 ACK/写卡。
+    LineUid,
+}
+
+/// 线路配置更新范围（由后端响应的 changed 字段指定）。
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigUpdateScope {
+    /// 整体替换。
+    Full,
+    /// 仅站点变化，保留本地票价规则。
+    Stations,
+    /// 仅票价变化，保留本地站点与当前站点选择。
+    Fares,
+}
+
+/// 乘客提示音色/标签（用于 UI 或蜂鸣提示）。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PassengerTone {
     Normal,
     Student,
@@ -89,6 +228,17 @@ impl PassengerTone {
         }
     }
 
+    /// 按名称解析（后端配置用，不接受 error）。
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "normal" => Some(PassengerTone::Normal),
+            "student" => Some(PassengerTone::Student),
+            "elder" => Some(PassengerTone::Elder),
+            "disabled" => Some(PassengerTone::Disabled),
+            _ => None,
+        }
+    }
+
     pub fn label(&self) -> &'static str {
         match self {
             PassengerTone::Normal => "普通票",
@@ -101,16 +251,123 @@ impl PassengerTone {
 }
 
 /// 网关运行参数（可配置项）。
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct GatewaySettings {
     pub gateway_id: String,
     pub reader_id: u16,
+    // 读卡器 ID 与角色映射（仅上下车刷卡线路生效），未列出的读卡器按 Any 处理。
+    pub reader_roles: Vec<(u16, ReaderRole)>,
+    // 读卡器串口协议模式（默认完整帧协议）。
+    pub serial_mode: SerialMode,
     pub debounce_window_secs: u32,
+    // 读卡器重复上报同一次刷卡的去重窗口（毫秒），0 表示关闭；充值/注册等模式及员工卡均生效。
+    pub dedup_window_ms: u32,
+    // 员工/稽查卡号（规范化大写十六进制）：不受刷卡防抖限制，仍受黑名单约束。
+    pub staff_card_ids: Vec<String>,
+    // 员工卡免票（上报记录带 exempt 标记）。
+    pub staff_cards_fare_exempt: bool,
     pub tap_cache_max: usize,
     pub config_ttl_secs: u32,
     pub blacklist_ttl_secs: u32,
     pub active_trip_ttl_secs: u32,
     pub batch_size: usize,
+    // 网络中断期间内存中最多缓冲的上报记录/卡片快照条数，及超出后的丢弃策略。
+    pub upload_buffer_max: usize,
+    pub upload_overflow_policy: UploadOverflowPolicy,
+    // 自适应批量上报的上下限（AIMD 调节）。
+    pub batch_size_min: usize,
+    pub batch_size_max: usize,
+    // 未达批量阈值时按该间隔（秒）上报记录与卡片状态快照，最小 1 秒。
+    pub upload_flush_secs: u32,
+    // 最低票价（分），0 表示不启用；仅对非免费行程生效。
+    pub min_fare_cents: u32,
+    // 超时附加费：行程超过免费时长（分钟）后每满/不足一个计费间隔加收（分），
+    // 不超过线路封顶价；需已校时，加收金额为 0 表示不启用。
+    pub late_fee_free_mins: u32,
+    pub late_fee_step_mins: u32,
+    pub late_fee_step_cents: u32,
+    // 无上车记录下车的处理策略与罚金（分，0 表示按标准票价）。
+    pub stray_tap_out_policy: StrayTapOutPolicy,
+    pub stray_tap_out_penalty_cents: u32,
+    // 下车余额不足时仍结束行程并上报欠款记录（关闭时拒绝下车、保留行程）。
+    pub unpaid_tap_out_records: bool,
+    // 已下发但未收到结果的写卡数超过该值时提示“写卡未确认”。
+    pub write_unconfirmed_threshold: u32,
+    // 同步配置时从后端预加载的有效卡画像条数上限，0 表示不预加载（受卡片缓存容量限制）。
+    pub card_preload_max: usize,
+    // 卡片无缓存画像时，等待后端查询结果的最长时间（毫秒），0 表示不等待。
+    pub lookup_wait_ms: u32,
+    // 自动报站间隔（秒），0 表示关闭。
+    pub auto_advance_secs: u32,
+    // 司机手动切站后暂停自动报站的时长（秒）。
+    pub auto_advance_pause_secs: u32,
+    // 刷卡后该时间内（秒）司机改了方向且同卡再刷，则更正上一条记录的方向，0 表示关闭。
+    pub direction_correction_secs: u32,
+    // 下车结算后该时间内（秒）同卡再刷视为误刷并忽略，不开启新行程，0 表示关闭。
+    pub retap_ignore_secs: u32,
+    // 同一张卡在不同网关间的最短通行时间（秒），0 表示不检测；需已校时。
+    pub tamper_min_travel_secs: u32,
+    pub tamper_policy: TamperPolicy,
+    // 读卡器上报的刷卡时间与网关时钟相差超过该值（秒）时改用网关时间，0 表示关闭；需已校时。
+    pub max_tap_time_skew_secs: u32,
+    pub post_tap_block_policy: PostTapBlockPolicy,
+    pub repeat_board_policy: RepeatBoardPolicy,
+    pub unknown_card_type_policy: UnknownCardTypePolicy,
+    pub no_route_policy: NoRoutePolicy,
+    // 预热期（首次配置同步完成前）刷卡策略，及等待策略下单次刷卡最长等待时间（毫秒）。
+    pub warmup_policy: WarmupPolicy,
+    pub warmup_wait_ms: u32,
+    // 防回传：下车必须有本线路的未完成行程，否则拒绝（关闭时按无记录下车策略处理）。
+    pub anti_passback: bool,
+    // 启动时 Wi-Fi 连接最多尝试次数（失败间隔指数退避）。
+    pub wifi_connect_attempts: u32,
+    // 处理器心跳停止超过该时长（秒）则重启设备，0 表示关闭看门狗。
+    pub watchdog_stall_secs: u32,
+    // HTTP 服务自检间隔（秒），连续多次无响应时重建服务，0 表示关闭。
+    pub http_probe_secs: u32,
+    // 无刷卡、无司机操作超过该时长（秒）进入停运待机：乘客屏熄屏、状态灯关闭，0 表示关闭。
+    pub idle_timeout_secs: u32,
+    // 内置 HTTP 服务的任务栈大小（字节）与监听端口。
+    pub web_stack_size: usize,
+    pub web_port: u16,
+    // 后端请求连续失败该次数后才显示“不可达”，连续成功该次数后恢复“可达”。
+    pub backend_unreachable_after: u32,
+    pub backend_reachable_after: u32,
+    // 读卡器返回的卡数据超过约定块数（超读/配置错误）时拒绝解析，关闭时仅告警并忽略多余字节。
+    pub strict_card_data_len: bool,
+    // 卡内数据最低版本，低于该版本的卡提示“请更新卡片”并需重新注册；0 表示接受所有支持的版本，
+    // 高于当前写卡版本时按当前版本处理。
+    pub min_card_version: u8,
+    // 写卡后要求读卡器回传回读数据并由网关比对，不一致时按校验失败重试（需读卡器支持）。
+    pub write_readback_verify: bool,
+    // 注册模式成功注册一张卡后自动退出（充值模式始终单次有效）。
+    pub one_shot_modes: bool,
+    // 上下车模式上车时显示本站出发的最高可能票价（“预估最高”），而非起步价。
+    pub tap_in_max_fare_estimate: bool,
+    // 乘客屏提示时长（毫秒）的网关级设置，None 表示使用内置默认值。
+    pub message_ttl: MessageTtlOverride,
+    // 上车后卡内须保留的最低余额（分），0 表示不限制。
+    pub min_board_balance_cents: u32,
+    // 卡内余额上限（分），充值后超出则拒绝。
+    pub max_balance_cents: u32,
+    // 日志与界面中隐藏卡号（仅显示末 4 位），上报后端不受影响。
+    pub mask_card_ids: bool,
+    // 对端网关地址（如 http://192.168.4.1），非空时从对端拉取配置与黑名单而非后端。
+    pub peer_base_url: String,
+    // 备用后端地址（如 http://10.0.0.2:8080），空表示不启用；
+    // 主后端不可达时上报记录与卡片状态改发至此。
+    pub secondary_base_url: String,
+    // 向后端发送心跳的间隔（秒），0 表示关闭。
+    pub heartbeat_interval_secs: u32,
+    // 向读卡器下发轮询的间隔（秒，需读卡器支持），0 表示关闭；连续未回复该次数后判定读卡器离线。
+    pub reader_poll_secs: u32,
+    pub reader_poll_max_missed: u32,
+    // SNTP 校时服务器（空表示使用 ESP-IDF 默认服务器）。
+    pub ntp_server: String,
+    // 本地时区相对 UTC 的偏移（分钟），用于界面时间显示与按时段计算。
+    pub tz_offset_minutes: i16,
+    // 维护接口（/logs）访问口令，空表示不校验。
+    pub admin_pin: String,
 }
 
 impl GatewaySettings {
@@ -119,16 +376,113 @@ impl GatewaySettings {
         Self {
             gateway_id: id.into(),
             reader_id: 1,
+            reader_roles: Vec::new(),
+            serial_mode: SerialMode::Framed,
             debounce_window_secs: 2,
+            dedup_window_ms: 300,
+            staff_card_ids: Vec::new(),
+            staff_cards_fare_exempt: false,
             tap_cache_max: 512,
             config_ttl_secs: 300,
             blacklist_ttl_secs: 300,
             active_trip_ttl_secs: 3600,
             batch_size: 50,
+            upload_buffer_max: 1000,
+            upload_overflow_policy: UploadOverflowPolicy::DropOldest,
+            batch_size_min: 5,
+            batch_size_max: 200,
+            upload_flush_secs: 5,
+            min_fare_cents: 0,
+            late_fee_free_mins: 90,
+            late_fee_step_mins: 10,
+            late_fee_step_cents: 0,
+            stray_tap_out_policy: StrayTapOutPolicy::Reject,
+            stray_tap_out_penalty_cents: 0,
+            unpaid_tap_out_records: false,
+            write_unconfirmed_threshold: 3,
+            card_preload_max: 128,
+            lookup_wait_ms: 150,
+            auto_advance_secs: 0,
+            auto_advance_pause_secs: 120,
+            direction_correction_secs: 10,
+            retap_ignore_secs: 30,
+            tamper_min_travel_secs: 0,
+            max_tap_time_skew_secs: 300,
+            tamper_policy: TamperPolicy::Alert,
+            post_tap_block_policy: PostTapBlockPolicy::Ignore,
+            repeat_board_policy: RepeatBoardPolicy::Restart,
+            unknown_card_type_policy: UnknownCardTypePolicy::Normal,
+            no_route_policy: NoRoutePolicy::Reject,
+            warmup_policy: WarmupPolicy::Off,
+            warmup_wait_ms: 3000,
+            anti_passback: false,
+            watchdog_stall_secs: 30,
+            http_probe_secs: 30,
+            idle_timeout_secs: 0,
+            web_stack_size: 8192,
+            web_port: 80,
+            backend_unreachable_after: 3,
+            backend_reachable_after: 1,
+            wifi_connect_attempts: 5,
+            strict_card_data_len: false,
+            min_card_version: 0,
+            write_readback_verify: false,
+            one_shot_modes: false,
+            tap_in_max_fare_estimate: false,
+            message_ttl: MessageTtlOverride::default(),
+            min_board_balance_cents: 0,
+            max_balance_cents: 9_999_999,
+            mask_card_ids: false,
+            peer_base_url: String::new(),
+            secondary_base_url: String::new(),
+            heartbeat_interval_secs: 60,
+            reader_poll_secs: 10,
+            reader_poll_max_missed: 3,
+            ntp_server: "pool.ntp.org".to_string(),
+            tz_offset_minutes: 480,
+            admin_pin: option_env!("ADMIN_PIN").unwrap_or("").to_string(),
         }
     }
 }
 
+impl GatewaySettings {
+    /// 查询读卡器角色。
+    pub fn reader_role(&self, reader_id: u16) -> ReaderRole {
+        self.reader_roles
+            .iter()
+            .find(|(id, _)| *id == reader_id)
+            .map(|(_, role)| *role)
+            .unwrap_or(ReaderRole::Any)
+    }
+
+    /// 是否为员工/稽查卡。
+    pub fn is_staff_card(&self, card_id: &str) -> bool {
+        self.staff_card_ids
+            .iter()
+            .any(|id| id.eq_ignore_ascii_case(card_id))
+    }
+
+    /// 网络循环轮询间隔（毫秒）：取查询等待时长的三分之一（20~200ms），
+    /// 保证等待期内查询命令能被处理；不等待时按 200ms 轮询，避免空转。
+    pub fn net_poll_ms(&self) -> u64 {
+        match self.lookup_wait_ms {
+            0 => 200,
+            wait_ms => (wait_ms as u64 / 3).clamp(20, 200),
+        }
+    }
+
+    /// 记录与卡片状态快照的定时上报间隔（毫秒，最小 1 秒）。
+    pub fn upload_flush_ms(&self) -> u64 {
+        self.upload_flush_secs.max(1) as u64 * 1000
+    }
+
+    /// 启动时第 `attempt` 次 Wi-Fi 连接失败后的等待时长（毫秒，2s 起翻倍，封顶 30s）。
+    pub fn wifi_retry_delay_ms(attempt: u32) -> u64 {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        WIFI_RETRY_BASE_MS.saturating_mul(factor).min(WIFI_RETRY_MAX_MS)
+    }
+}
+
 impl Default for GatewaySettings {
     fn default() -> Self {
         Self::with_gateway_id("gateway-unknown")
@@ -136,36 +490,103 @@ impl Default for GatewaySettings {
 }
 
 /// 站点配置（来自后端下发）。
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StationConfig {
     pub id: u16,
     pub name: String,
     pub sequence: u16,
+    #[serde(default)]
     pub zone_id: Option<u16>,
+    #[serde(default)]
     pub is_transfer: bool,
+    // 距上一站距离（米），首站可缺省；全部站点给出时按里程计价。
+    #[serde(default)]
+    pub distance_m: Option<u32>,
 }
 
 /// 票价规则（简化字段）。
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FareRule {
     pub base_price: f32,
+    #[serde(default)]
     pub fare_type: Option<String>,
+    #[serde(default)]
     pub segment_count: Option<u16>,
+    #[serde(default)]
     pub extra_price: Option<f32>,
+    #[serde(default)]
     pub start_station: Option<u16>,
+    #[serde(default)]
     pub end_station: Option<u16>,
+    // 每公里单价（元），距离计价时使用。
+    #[serde(default)]
+    pub per_km_price: Option<f32>,
+    // 生效区间（epoch 秒，含起不含止），未设置表示不限。
+    #[serde(default)]
+    pub effective_from: Option<u64>,
+    #[serde(default)]
+    pub effective_to: Option<u64>,
+}
+
+impl FareRule {
+    /// 规则在 `now` 时是否生效；`now` 为 None（未校时）时带生效区间的规则一律不生效。
+    pub fn is_effective(&self, now: Option<u64>) -> bool {
+        if self.effective_from.is_none() && self.effective_to.is_none() {
+            return true;
+        }
+        let Some(now) = now else {
+            return false;
+        };
+        !matches!(self.effective_from, Some(from) if now < from)
+            && !matches!(self.effective_to, Some(to) if now >= to)
+    }
+}
+
+/// 卡类型展示配置（后端下发，用于新增票种的提示色与优惠标签）。
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CardTypeStyle {
+    pub card_type: String,
+    pub tone: PassengerTone,
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
 /// 线路配置（站点 + 票价 + 模式）。
-#[derive(Clone, Debug)]
+///
+/// JSON 格式与字段名一致，枚举取 snake_case（如 `"tap_in_out"`），可选字段可省略。
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RouteConfig {
     pub route_id: u16,
     pub route_name: String,
     pub fare_type: FareType,
     pub tap_mode: TapMode,
+    #[serde(default)]
+    pub settlement_mode: SettlementMode,
+    #[serde(default)]
     pub max_fare: Option<f32>,
+    // 上下车模式下未完成行程的超时（秒），None 表示使用全局 active_trip_ttl_secs。
+    #[serde(default)]
+    pub trip_timeout_secs: Option<u32>,
+    // 乘客屏提示时长覆盖（毫秒），None 表示使用网关设置。
+    #[serde(default)]
+    pub message_ttl: MessageTtlOverride,
+    #[serde(default)]
     pub stations: Vec<StationConfig>,
+    #[serde(default)]
     pub fares: Vec<FareRule>,
+    #[serde(default)]
+    pub card_type_styles: Vec<CardTypeStyle>,
+}
+
+/// 乘客屏提示时长（毫秒），按提示类别分别配置。
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct MessageTtlOverride {
+    #[serde(default)]
+    pub ok_ms: Option<u32>,
+    #[serde(default)]
+    pub action_ms: Option<u32>,
+    #[serde(default)]
+    pub error_ms: Option<u32>,
 }
 
 /// 刷卡事件（网关内部事件模型）。
@@ -179,10 +600,13 @@ pub struct TapEvent {
     pub tap_type: TapType,
     pub tap_time: u64,
     pub gateway_id: String,
+    // 本次实际扣费（分），决策完成后填入；上车不扣费时为 0。
+    pub fare_cents: Option<u32>,
 }
 
 impl TapEvent {
     /// 构造刷卡事件。
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         record_id: String,
         card_id: String,
@@ -202,6 +626,7 @@ impl TapEvent {
             tap_type,
             tap_time,
             gateway_id,
+            fare_cents: None,
         }
     }
 }
@@ -219,6 +644,22 @@ pub struct UploadRecord {
     pub alight_station_id: Option<u16>,
     pub alight_station: Option<String>,
     pub gateway_id: Option<String>,
+    // 行驶方向（"up"/"down"），方向更正时以同一 record_id 重新上报。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<String>,
+    // 异常标记（如 "impossible_travel"），正常记录不输出该字段。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert: Option<String>,
+    // 员工卡免票记录标记，普通记录不输出该字段。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exempt: Option<bool>,
+    // 卡内实际扣费（分），供后端对账，无需重新计算票价；
+    // 押金模式下上车记录为预扣金额，下车记录为行程实际票价。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fare_cents: Option<u32>,
+    // 余额不足仍放行下车时的欠款（分），供后端后续追缴；正常记录不输出该字段。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owed_cents: Option<u32>,
 }
 
 impl UploadRecord {
@@ -236,6 +677,11 @@ impl UploadRecord {
             alight_station_id: None,
             alight_station: None,
             gateway_id: Some(event.gateway_id.clone()),
+            direction: None,
+            alert: None,
+            exempt: None,
+            fare_cents: event.fare_cents,
+            owed_cents: None,
         }
     }
 
@@ -259,6 +705,11 @@ impl UploadRecord {
             alight_station_id: Some(event.station_id),
             alight_station: Some(event.station_name.clone()),
             gateway_id: Some(event.gateway_id.clone()),
+            direction: None,
+            alert: None,
+            exempt: None,
+            fare_cents: event.fare_cents,
+            owed_cents: None,
         }
     }
 }
@@ -294,12 +745,62 @@ fn format_time(epoch_secs: u64) -> String {
 }
 
 impl RouteConfig {
-    /// 获取线路的基础票价（取最小非零值作为默认）。
-    pub fn standard_fare(&self) -> Option<f32> {
+    /// 站点序号重复时（切站无法定位当前站）按（序号、下发顺序）重新编号为 1..n，
+    /// 返回是否重新编号；仅有间隔时保留原序号（分段计价按序号差计算）。
+    pub fn normalize_station_sequences(&mut self) -> bool {
+        let mut sequences: Vec<u16> = self.stations.iter().map(|s| s.sequence).collect();
+        sequences.sort_unstable();
+        if sequences.windows(2).all(|pair| pair[0] != pair[1]) {
+            return false;
+        }
+        self.stations.sort_by_key(|s| s.sequence);
+        for (idx, station) in self.stations.iter_mut().enumerate() {
+            station.sequence = (idx + 1) as u16;
+        }
+        true
+    }
+
+    /// 将负数票价（起步价、加价、每公里单价）钳制为 0，返回是否有修改。
+    /// 缓存或同行网关下发的配置不经过后端清洗，负加价会让长途票价被算成免费。
+    pub fn clamp_negative_prices(&mut self) -> bool {
+        let mut clamped = false;
+        for fare in &mut self.fares {
+            if fare.base_price < 0.0 {
+                fare.base_price = 0.0;
+                clamped = true;
+            }
+            for price in [&mut fare.extra_price, &mut fare.per_km_price].into_iter().flatten() {
+                if *price < 0.0 {
+                    *price = 0.0;
+                    clamped = true;
+                }
+            }
+        }
+        clamped
+    }
+
+    /// 站点序号是否不连续。
+    pub fn has_sequence_gaps(&self) -> bool {
+        let mut sequences: Vec<u16> = self.stations.iter().map(|s| s.sequence).collect();
+        sequences.sort_unstable();
+        sequences.windows(2).any(|pair| pair[1] - pair[0] > 1)
+    }
+
+    /// 查询卡类型的展示配置（忽略大小写）。
+    pub fn card_type_style(&self, card_type: &str) -> Option<&CardTypeStyle> {
+        let card_type = card_type.trim();
+        self.card_type_styles
+            .iter()
+            .find(|style| style.card_type.eq_ignore_ascii_case(card_type))
+    }
+
+    /// 获取线路的基础票价（取 `now` 时生效规则中的最小非零值作为默认）。
+    /// 当前无生效规则（如新规则尚未下发）时沿用最近结束的规则；未校时无法判断，返回 None。
+    pub fn standard_fare(&self, now: Option<u64>) -> Option<f32> {
         let mut best: Option<f32> = None;
         for fare in &self.fares {
             let base = fare.base_price;
-            if base <= 0.0 {
+            if base <= 0.0 || !fare.is_effective(now) {
                 continue;
             }
             best = Some(match best {
@@ -307,18 +808,31 @@ impl RouteConfig {
                 None => base,
             });
         }
-        best
+        best.or_else(|| {
+            let now = now?;
+            self.fares
+                .iter()
+                .filter(|fare| fare.base_price > 0.0)
+                .filter(|fare| fare.effective_to.is_some_and(|to| to <= now))
+                .max_by_key(|fare| fare.effective_to)
+                .map(|fare| fare.base_price)
+        })
+    }
+
+    /// 是否配置了收费的票价规则（全部为 0 元视为免费线路）。
+    pub fn has_priced_fares(&self) -> bool {
+        self.fares.iter().any(|fare| fare.base_price > 0.0)
     }
 }
 
 impl fmt::Display for TapEvent {
-    /// 便于日志输出的格式化展示。
+    /// 便于日志输出的格式化展示（无法获知脱敏设置，卡号一律脱敏）。
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "{} {} route={} station={}({}) type={}",
             self.record_id,
-            self.card_id,
+            crate::privacy::mask_card_id(&self.card_id),
             self.route_id,
             self.station_id,
             self.station_name,
@@ -326,3 +840,200 @@ impl fmt::Display for TapEvent {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_poll_tracks_lookup_wait() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        assert_eq!(settings.net_poll_ms(), 50);
+        settings.lookup_wait_ms = 0;
+        assert_eq!(settings.net_poll_ms(), 200);
+        settings.lookup_wait_ms = 30;
+        assert_eq!(settings.net_poll_ms(), 20);
+        settings.lookup_wait_ms = 3000;
+        assert_eq!(settings.net_poll_ms(), 200);
+    }
+
+    #[test]
+    fn upload_flush_interval_has_one_second_floor() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        assert_eq!(settings.upload_flush_ms(), 5000);
+        settings.upload_flush_secs = 0;
+        assert_eq!(settings.upload_flush_ms(), 1000);
+        settings.upload_flush_secs = 60;
+        assert_eq!(settings.upload_flush_ms(), 60_000);
+    }
+
+    #[test]
+    fn route_config_json_round_trip() {
+        let fixture = serde_json::json!({
+            "route_id": 12,
+            "route_name": "12路",
+            "fare_type": "segment",
+            "tap_mode": "tap_in_out",
+            "settlement_mode": "deposit",
+            "max_fare": 6.0,
+            "trip_timeout_secs": 7200,
+            "stations": [
+                {"id": 1, "name": "起点", "sequence": 1, "zone_id": 1, "distance_m": 0},
+                {"id": 2, "name": "终点", "sequence": 2, "is_transfer": true, "distance_m": 1500}
+            ],
+            "fares": [
+                {"base_price": 2.0, "segment_count": 3, "extra_price": 1.0, "effective_from": 100}
+            ],
+            "card_type_styles": [{"card_type": "student", "tone": "student", "label": "学生"}]
+        });
+        let config: RouteConfig = serde_json::from_value(fixture).unwrap();
+        assert_eq!(config.fare_type, FareType::Segment);
+        assert_eq!(config.tap_mode, TapMode::TapInOut);
+        assert_eq!(config.settlement_mode, SettlementMode::Deposit);
+        assert!(config.stations[1].is_transfer);
+        assert_eq!(config.fares[0].effective_from, Some(100));
+        assert_eq!(config.card_type_styles[0].tone, PassengerTone::Student);
+
+        let encoded = serde_json::to_value(&config).unwrap();
+        let decoded: RouteConfig = serde_json::from_value(encoded.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), encoded);
+    }
+
+    #[test]
+    fn route_config_optional_sections_default() {
+        let config: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 1,
+            "route_name": "1路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap"
+        }))
+        .unwrap();
+        assert_eq!(config.settlement_mode, SettlementMode::PayOnExit);
+        assert!(config.stations.is_empty());
+        assert!(config.fares.is_empty());
+        assert!(config.max_fare.is_none());
+    }
+
+    #[test]
+    fn wifi_retry_backoff_doubles_and_caps() {
+        let delays: Vec<u64> = (1..=6).map(GatewaySettings::wifi_retry_delay_ms).collect();
+        assert_eq!(delays, vec![2000, 4000, 8000, 16_000, 30_000, 30_000]);
+        assert_eq!(GatewaySettings::wifi_retry_delay_ms(0), 2000);
+        assert_eq!(GatewaySettings::wifi_retry_delay_ms(u32::MAX), 30_000);
+    }
+
+    fn sequenced_route(stations: &[(u16, u16)]) -> RouteConfig {
+        let stations: Vec<serde_json::Value> = stations
+        .iter()
+        .map(|(id, sequence)| {
+            serde_json::json!({"id": id, "name": format!("站{}", id), "sequence": sequence})
+        })
+        .collect();
+        serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap",
+            "stations": stations
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn duplicate_sequences_are_renumbered_in_order() {
+        let mut route = sequenced_route(&[(10, 1), (20, 2), (30, 2), (40, 5)]);
+        assert!(route.normalize_station_sequences());
+        let order: Vec<(u16, u16)> = route.stations.iter().map(|s| (s.id, s.sequence)).collect();
+        assert_eq!(order, vec![(10, 1), (20, 2), (30, 3), (40, 4)]);
+        assert!(!route.has_sequence_gaps());
+    }
+
+    #[test]
+    fn gapped_sequences_are_kept() {
+        let mut route = sequenced_route(&[(10, 1), (20, 3), (30, 4)]);
+        assert!(!route.normalize_station_sequences());
+        assert!(route.has_sequence_gaps());
+        assert_eq!(route.stations[1].sequence, 3);
+    }
+
+    #[test]
+    fn negative_prices_are_clamped_to_zero() {
+        let mut route: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "distance",
+            "tap_mode": "tap_in_out",
+            "fares": [
+                {"base_price": -2.0, "extra_price": -1.0, "per_km_price": 0.5},
+                {"base_price": 3.0, "per_km_price": -0.3}
+            ]
+        }))
+        .unwrap();
+        assert!(route.clamp_negative_prices());
+        assert_eq!(route.fares[0].base_price, 0.0);
+        assert_eq!(route.fares[0].extra_price, Some(0.0));
+        assert_eq!(route.fares[0].per_km_price, Some(0.5));
+        assert_eq!(route.fares[1].base_price, 3.0);
+        assert_eq!(route.fares[1].per_km_price, Some(0.0));
+        // 已无负价时不再报告修改
+        assert!(!route.clamp_negative_prices());
+    }
+
+    #[test]
+    fn standard_fare_skips_rules_outside_effective_window() {
+        let route: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap",
+            "fares": [
+                {"base_price": 2.0, "effective_to": 1000},
+                {"base_price": 3.0, "effective_from": 1000}
+            ]
+        }))
+        .unwrap();
+        // 生效区间含起不含止
+        assert_eq!(route.standard_fare(Some(999)), Some(2.0));
+        assert_eq!(route.standard_fare(Some(1000)), Some(3.0));
+        // 未校时带生效区间的规则均不生效
+        assert_eq!(route.standard_fare(None), None);
+    }
+
+    #[test]
+    fn standard_fare_falls_back_to_last_expired_rule() {
+        let route: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap",
+            "fares": [
+                {"base_price": 2.0, "effective_to": 1000},
+                {"base_price": 3.0, "effective_from": 1000, "effective_to": 2000},
+                {"base_price": 4.0, "effective_from": 5000}
+            ]
+        }))
+        .unwrap();
+        // 规则间的空档沿用最近结束的规则，不按 0 元收费
+        assert_eq!(route.standard_fare(Some(2500)), Some(3.0));
+        assert_eq!(route.standard_fare(Some(5000)), Some(4.0));
+        assert_eq!(route.standard_fare(None), None);
+        assert!(route.has_priced_fares());
+    }
+
+    #[test]
+    fn tap_event_display_masks_card_id() {
+        let event = TapEvent::new(
+            "gw-test-1704067200-1".to_string(),
+            "A1B2C3D4".to_string(),
+            7,
+            2,
+            "二站".to_string(),
+            TapType::TapIn,
+            1_704_067_200,
+            "gw-test".to_string(),
+        );
+        let line = event.to_string();
+        assert!(line.contains("****C3D4"));
+        assert!(!line.contains("A1B2C3D4"));
+    }
+}
@@ -1,5 +1,6 @@
 use core::convert::TryInto;
-use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use core::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -14,23 +15,34 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::http::client::EspHttpConnection;
 use esp_idf_svc::io::EspIOError;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
 use crate::api::{
-    BATCH_RECORDS_PATH, CARD_REGISTER_PATH, CARD_STATE_BATCH_PATH, CARDS_PATH, CONFIG_PATH,
+    self, ApiResponse, CardProfile, CardResponse, RouteConfigResponse, BATCH_RECORDS_PATH,
+    CARD_REGISTER_PATH, CARD_STATE_BATCH_PATH, CARDS_PATH, CONFIG_PATH, HEARTBEAT_PATH, PEER_BLACKLIST_PATH,
+    PEER_CONFIG_PATH,
 };
+use crate::card_data::normalize_uid;
+use crate::lock::LockRecover;
 use crate::model::{
-    CardRegistration, CardStateSnapshot, FareRule, FareType, GatewaySettings, PassengerTone,
-    RouteConfig, StationConfig, TapMode, UploadRecord,
+    CardRegistration, CardStateSnapshot, ConfigUpdateScope, GatewaySettings, PassengerTone,
+    RouteConfig, UploadRecord,
 };
+use crate::privacy::display_card_id_with;
+use crate::retry::{failover, failover_urls, is_gateway_error_status};
 use crate::state::GatewayState;
-use crate::upload::BatchUpload;
+use crate::upload::{push_bounded, BatchSizeTuner, BatchUpload, GatewayHeartbeat};
+use crate::web::ActionResult;
 
 // Wi-Fi 与后端地址来自编译期环境变量。
 const WIFI_SSID: &str = env!("WIFI_SSID");
 const WIFI_PASS: &str = env!("WIFI_PASS");
 const BACKEND_BASE_URL: &str = env!("BACKEND_BASE_URL");
+// 全量同步时等待 SNTP 校时完成的最长时间（毫秒）。
+const SNTP_WAIT_MS: u64 = 5000;
 
 /// 网络控制命令（来自 UI 或业务逻辑）。
 #[derive(Clone, Debug)]
@@ -40,15 +52,60 @@ pub enum NetCommand {
     SetBackend { base_url: String },
     LookupCard { card_id: String },
     RegisterCard { payload: CardRegistration },
+    /// 全量同步：配置 + 黑名单 + 校时 + 后端探测，结果通过 reply 返回。
+    FullResync {
+        route_id: u16,
+        reply: Option<Sender<ActionResult>>,
+    },
+    /// 立即推送全部卡片状态快照（交班对账），结果通过 reply 返回。
+    PushCardStates { reply: Option<Sender<ActionResult>> },
 }
 
 /// 网络请求错误类型。
 #[derive(Debug)]
 pub enum NetError {
+    // 建立连接/发出请求前失败，请求未送达后端。
+    Connect(EspIOError),
     Io(EspIOError),
     Json(serde_json::Error),
     HttpStatus(u16),
     Api(String),
+    // 响应既不是约定的封装格式，也无法按裸数据解析（附响应开头片段）。
+    UnexpectedResponse(String),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Connect(err) => write!(f, "连接失败 {}", err),
+            NetError::Io(err) => write!(f, "网络错误 {}", err),
+            NetError::Json(_) => write!(f, "响应格式错误"),
+            NetError::HttpStatus(status) => write!(f, "{}", status),
+            NetError::Api(message) => write!(f, "{}", message),
+            NetError::UnexpectedResponse(preview) => write!(f, "响应格式无法识别: {}", preview),
+        }
+    }
+}
+
+impl NetError {
+    fn connect(err: impl Into<EspIOError>) -> Self {
+        NetError::Connect(err.into())
+    }
+
+    /// 是否为网络或 HTTP 状态错误（标记后端不可达）；业务错误说明后端已收到请求。
+    fn is_unreachable(&self) -> bool {
+        matches!(self, NetError::Connect(_) | NetError::Io(_) | NetError::HttpStatus(_))
+    }
+
+    /// 是否可改发备用后端：仅请求确定未送达（连接失败、网关类状态码）时改发。
+    /// 请求体已发出后的读写错误无法确认后端是否已入库，改发可能重复提交。
+    fn should_fail_over(&self) -> bool {
+        match self {
+            NetError::Connect(_) => true,
+            NetError::HttpStatus(status) => is_gateway_error_status(*status),
+            _ => false,
+        }
+    }
 }
 
 impl From<EspIOError> for NetError {
@@ -69,12 +126,9 @@ impl From<serde_json::Error> for NetError {
     }
 }
 
-/// 通用 API 响应格式（与后端保持一致）。
-#[derive(Deserialize)]
-struct ApiResponse<T> {
-    success: bool,
-    data: Option<T>,
-    message: Option<String>,
+/// 解析后端响应，无法识别时附带响应开头片段。
+fn parse_api_response<T: DeserializeOwned>(body: &[u8]) -> Result<ApiResponse<T>, NetError> {
+    api::parse_api_response(body).map_err(NetError::UnexpectedResponse)
 }
 
 #[derive(Deserialize)]
@@ -90,9 +144,14 @@ struct CardStateReject {
 }
 
 /// 连接 Wi-Fi（阻塞直到联网）。
-pub fn connect_wifi(modem: Modem) -> Result<BlockingWifi<EspWifi<'static>>, EspError> {
+/// 连接 Wi-Fi：失败时按指数退避重试，最多 `max_attempts` 次（车辆先于场站热点启动时仍可连上）。
+pub fn connect_wifi(
+    modem: Modem,
+    nvs: Option<EspDefaultNvsPartition>,
+    max_attempts: u32,
+    state: &Arc<Mutex<GatewayState>>,
+) -> Result<BlockingWifi<EspWifi<'static>>, EspError> {
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take().ok();
     let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), nvs)?, sys_loop)?;
 
     log::info!(
@@ -127,17 +186,39 @@ pub fn connect_wifi(modem: Modem) -> Result<BlockingWifi<EspWifi<'static>>, EspE
     // 连接策略：
     // - 无密码：开放网络
     // - 有密码：默认优先 WPA2（符合常见热点/课堂环境），失败则尝试 WPA2/WPA3 兼容
-    if WIFI_PASS.is_empty() {
-        try_connect(&mut wifi, AuthMethod::None)?;
-        return Ok(wifi);
+    fn connect_once(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<(), EspError> {
+        if WIFI_PASS.is_empty() {
+            return try_connect(wifi, AuthMethod::None);
+        }
+        if try_connect(wifi, AuthMethod::WPA2Personal).is_ok() {
+            return Ok(());
+        }
+        log::warn!("Wi-Fi connect retrying with WPA2WPA3Personal...");
+        try_connect(wifi, AuthMethod::WPA2WPA3Personal)
     }
 
-    if try_connect(&mut wifi, AuthMethod::WPA2Personal).is_ok() {
-        return Ok(wifi);
+    let max_attempts = max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        state.lock_recover().wifi_connect_attempts = attempt;
+        match connect_once(&mut wifi) {
+            Ok(()) => return Ok(wifi),
+            Err(err) if attempt >= max_attempts => return Err(err),
+            Err(err) => {
+                let delay = Duration::from_millis(GatewaySettings::wifi_retry_delay_ms(attempt));
+                log::warn!(
+                    "Wi-Fi attempt {}/{} failed: {:?}, retrying in {}ms",
+                    attempt,
+                    max_attempts,
+                    err,
+                    delay.as_millis()
+                );
+                let _ = wifi.stop();
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
     }
-    log::warn!("Wi-Fi connect retrying with WPA2WPA3Personal...");
-    try_connect(&mut wifi, AuthMethod::WPA2WPA3Personal)?;
-    Ok(wifi)
 }
 
 pub fn spawn_network_loop(
@@ -149,16 +230,32 @@ pub fn spawn_network_loop(
     thread::spawn(move || {
         // 上传缓冲区与配置刷新计时
         let mut buffer: Vec<UploadRecord> = Vec::with_capacity(settings.batch_size);
+        // 与 buffer 一一对应的入队时间，用于展示最早待上传记录的时长
+        let mut queued_at: Vec<Instant> = Vec::with_capacity(settings.batch_size);
         let mut card_state_buffer: Vec<CardStateSnapshot> = Vec::with_capacity(settings.batch_size);
+        let mut tuner = BatchSizeTuner::new(
+            settings.batch_size,
+            settings.batch_size_min,
+            settings.batch_size_max,
+        );
+        let buffer_max = settings.upload_buffer_max;
+        let overflow_policy = settings.upload_overflow_policy;
         let mut route_id: Option<u16> = None;
         let mut last_upload = Instant::now();
         let mut last_state_upload = Instant::now();
+        let flush_interval = Duration::from_millis(settings.upload_flush_ms());
         let refresh_secs = settings
             .config_ttl_secs
             .min(settings.blacklist_ttl_secs) as u64;
         let mut last_sync = Instant::now()
             .checked_sub(Duration::from_secs(refresh_secs))
             .unwrap_or_else(Instant::now);
+        // 网络任务随启动运行，以其启动时刻计算运行时长
+        let started = Instant::now();
+        let heartbeat_interval = Duration::from_secs(settings.heartbeat_interval_secs as u64);
+        let mut last_heartbeat = Instant::now();
+        let mut last_queue_report = Instant::now();
+        let poll_interval = Duration::from_millis(settings.net_poll_ms());
         loop {
             while let Ok(cmd) = command_rx.try_recv() {
                 match cmd {
@@ -172,20 +269,26 @@ pub fn spawn_network_loop(
                     NetCommand::UploadNow => {
                         // 立即上报当前缓冲
                         while let Ok(record) = upload_rx.try_recv() {
-                            buffer.push(record);
+                            let now = Instant::now();
+                            push_bounded(&mut queued_at, now, buffer_max, overflow_policy);
+                            if push_bounded(&mut buffer, record, buffer_max, overflow_policy) {
+                                record_upload_drops(&state, 1);
+                            }
                         }
-                        if let Err(err) = flush_batch(&state, &mut buffer) {
-                            log::warn!("Upload batch failed: {:?}", err);
+                        // 按当前批次大小分批上报，失败即停止
+                        while !buffer.is_empty() {
+                            if let Err(err) = flush_batch(&state, &mut buffer, &mut tuner) {
+                                report_error(&state, "上传记录", &err);
+                                break;
+                            }
                         }
                         if let Err(err) = flush_card_state_batch(&state, &mut card_state_buffer) {
-                            log::warn!("Card state upload failed: {:?}", err);
+                            report_error(&state, "卡片状态上传", &err);
                         }
                     }
                     NetCommand::SetBackend { base_url } => {
                         // 切换后端地址
-                        if let Ok(mut state) = state.lock() {
-                            state.update_backend_base_url(base_url);
-                        }
+                        state.lock_recover().update_backend_base_url(base_url);
                     }
                     NetCommand::LookupCard { card_id } => {
                         // 查询卡片信息（票种/折扣/状态）
@@ -194,16 +297,39 @@ pub fn spawn_network_loop(
                             Ok(Some(profile)) => {
                                 apply_card_profile(&state, &card_id, profile);
                             }
-                            Ok(None) => {}
+                            Ok(None) => {
+                                let mut state = state.lock_recover();
+                                let now_ms = state.now_millis();
+                                state.record_card_lookup_miss(&card_id, now_ms);
+                            }
                             Err(err) => {
-                                log::warn!("Card lookup failed: {:?}", err);
+                                report_error(&state, "卡片查询", &err);
                             }
                         }
                     }
                     NetCommand::RegisterCard { payload } => {
                         let base_url = resolve_base_url(&state);
                         if let Err(err) = register_card(&base_url, payload) {
-                            log::warn!("Card register failed: {:?}", err);
+                            report_error(&state, "卡片注册", &err);
+                        }
+                    }
+                    NetCommand::FullResync {
+                        route_id: next_route,
+                        reply,
+                    } => {
+                        route_id = Some(next_route);
+                        let result = full_resync(&state, next_route);
+                        if result.success {
+                            last_sync = Instant::now();
+                        }
+                        if let Some(reply) = reply {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    NetCommand::PushCardStates { reply } => {
+                        let result = push_all_card_states(&state, &mut card_state_buffer);
+                        if let Some(reply) = reply {
+                            let _ = reply.send(result);
                         }
                     }
                 }
@@ -218,53 +344,123 @@ pub fn spawn_network_loop(
                 }
             }
 
-            match upload_rx.recv_timeout(Duration::from_millis(200)) {
+            // 轮询间隔需短于处理器等待卡片查询的时长，否则查询命令来不及处理
+            match upload_rx.recv_timeout(poll_interval) {
                 Ok(record) => {
-                    buffer.push(record);
+                    push_bounded(&mut queued_at, Instant::now(), buffer_max, overflow_policy);
+                    if push_bounded(&mut buffer, record, buffer_max, overflow_policy) {
+                        record_upload_drops(&state, 1);
+                    }
                     last_upload = Instant::now();
-                    if buffer.len() >= settings.batch_size {
+                    if buffer.len() >= tuner.current() {
                         // 达到批量阈值触发上传
-                        if let Err(err) = flush_batch(&state, &mut buffer) {
-                            log::warn!("Upload batch failed: {:?}", err);
+                        if let Err(err) = flush_batch(&state, &mut buffer, &mut tuner) {
+                            report_error(&state, "上传记录", &err);
                         }
                     }
                 }
                 Err(RecvTimeoutError::Timeout) => {
                     // 超时且有缓存，按时间间隔触发上传
-                    if !buffer.is_empty() && last_upload.elapsed() >= Duration::from_secs(5) {
-                        if let Err(err) = flush_batch(&state, &mut buffer) {
-                            log::warn!("Upload batch failed: {:?}", err);
+                    if !buffer.is_empty() && last_upload.elapsed() >= flush_interval {
+                        if let Err(err) = flush_batch(&state, &mut buffer, &mut tuner) {
+                            report_error(&state, "上传记录", &err);
                         }
                     }
                 }
                 Err(RecvTimeoutError::Disconnected) => break,
             }
 
+            // 定期向后端发送心跳（兼作保活与在线状态）
+            if settings.heartbeat_interval_secs > 0
+                && last_heartbeat.elapsed() >= heartbeat_interval
+            {
+                last_heartbeat = Instant::now();
+                let heartbeat = GatewayHeartbeat::from_state(
+                    &state.lock_recover(),
+                    started.elapsed().as_secs(),
+                    buffer.len(),
+                );
+                // 与上报共用主/备用后端，主后端不可达时不误判离线
+                let result = serde_json::to_string(&heartbeat)
+                    .map_err(NetError::from)
+                    .and_then(|payload| {
+                        post_with_failover(&state, |base_url| post_heartbeat(base_url, &payload))
+                    });
+                if let Err(err) = result {
+                    report_error(&state, "心跳", &err);
+                }
+            }
+
             // 按时间间隔刷新卡片状态快照
-            if last_state_upload.elapsed() >= Duration::from_secs(5) {
-                if let Ok(mut state) = state.lock() {
-                    let drained = state.card_state_cache.drain_batch(settings.batch_size);
-                    card_state_buffer.extend(drained);
+            if last_state_upload.elapsed() >= flush_interval {
+                let drained =
+                    state.lock_recover().card_state_cache.drain_batch(settings.batch_size);
+                let mut dropped = 0;
+                for snapshot in drained {
+                    if push_bounded(&mut card_state_buffer, snapshot, buffer_max, overflow_policy) {
+                        dropped += 1;
+                    }
+                }
+                if dropped > 0 {
+                    record_upload_drops(&state, dropped);
                 }
                 if !card_state_buffer.is_empty() {
                     if let Err(err) = flush_card_state_batch(&state, &mut card_state_buffer) {
-                        log::warn!("Card state upload failed: {:?}", err);
+                        report_error(&state, "卡片状态上传", &err);
                     } else {
                         last_state_upload = Instant::now();
                     }
                 }
             }
+
+            // 上报后从队首移除的记录同步移除入队时间，并定期更新司机页的待上传队列信息
+            queued_at.drain(..queued_at.len().saturating_sub(buffer.len()));
+            if last_queue_report.elapsed() >= Duration::from_secs(1) {
+                last_queue_report = Instant::now();
+                let oldest_age_secs = queued_at.first().map(|at| at.elapsed().as_secs());
+                state.lock_recover().update_upload_queue(buffer.len(), oldest_age_secs);
+            }
         }
     })
 }
 
-/// 上报一批记录到后端。
-fn flush_batch(state: &Arc<Mutex<GatewayState>>, buffer: &mut Vec<UploadRecord>) -> Result<(), NetError> {
+/// 按自适应批次上报一批记录，并根据结果调节批次大小。
+fn flush_batch(
+    state: &Arc<Mutex<GatewayState>>,
+    buffer: &mut Vec<UploadRecord>,
+    tuner: &mut BatchSizeTuner,
+) -> Result<(), NetError> {
     if buffer.is_empty() {
         return Ok(());
     }
-    let payload = BatchUpload::new(buffer.clone()).to_json_string();
-    let base_url = resolve_base_url(state);
+    let take = tuner.current().min(buffer.len());
+    let started = Instant::now();
+    let result = post_with_failover(state, |base_url| post_batch(base_url, &buffer[..take]));
+    match result {
+        Ok(()) => {
+            let elapsed = started.elapsed();
+            tuner.record_success(elapsed);
+            buffer.drain(..take);
+            {
+                let mut state = state.lock_recover();
+                state.tap_cache.clear();
+                state.metrics.record_upload(true, elapsed.as_millis() as u64);
+                state.record_upload_success();
+            }
+            Ok(())
+        }
+        Err(err) => {
+            tuner.record_failure();
+            state.lock_recover().metrics.record_upload(false, 0);
+            log::info!("Upload batch size reduced to {}", tuner.current());
+            Err(err)
+        }
+    }
+}
+
+/// 上报一批记录到指定后端。
+fn post_batch(base_url: &str, records: &[UploadRecord]) -> Result<(), NetError> {
+    let payload = BatchUpload::new(records.to_vec()).to_json_string();
     let url = format!("{}{}", base_url, BATCH_RECORDS_PATH);
     let content_length = payload.len().to_string();
     let headers = [
@@ -272,137 +468,314 @@ fn flush_batch(state: &Arc<Mutex<GatewayState>>, buffer: &mut Vec<UploadRecord>)
         ("content-length", content_length.as_str()),
     ];
 
-    let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default())?);
-    let mut request = client.request(Method::Post, &url, &headers)?;
+    let connection = EspHttpConnection::new(&Default::default()).map_err(NetError::connect)?;
+    let mut client = HttpClient::wrap(connection);
+    let mut request = client
+        .request(Method::Post, &url, &headers)
+        .map_err(NetError::connect)?;
     request.write_all(payload.as_bytes())?;
     request.flush()?;
-    log::info!("Uploading batch to {}", url);
+    log::info!("Uploading batch of {} to {}", records.len(), url);
     let response = request.submit()?;
     let status = response.status();
     log::info!("Upload response status {}", status);
     if !(200..300).contains(&status) {
-        update_backend_status(state, false);
         return Err(NetError::HttpStatus(status));
     }
-    buffer.clear();
-    if let Ok(mut state) = state.lock() {
-        state.tap_cache.clear();
-    }
-    update_backend_status(state, true);
     Ok(())
 }
 
+/// 上报地址：主后端（运行时设置优先），配置了备用后端时排在其后。
+fn upload_base_urls(state: &Arc<Mutex<GatewayState>>) -> Vec<String> {
+    let primary = resolve_base_url(state);
+    failover_urls(primary, &state.lock_recover().settings.secondary_base_url)
+}
+
+/// 依次向主/备用后端发送，首个成功即停止；仅连接失败或 502/503/504 时改发下一个地址，
+/// 请求可能已被后端处理时不再改发，避免同一批数据重复提交。任一地址成功即视为后端可达。
+fn post_with_failover<T>(
+    state: &Arc<Mutex<GatewayState>>,
+    send: impl FnMut(&str) -> Result<T, NetError>,
+) -> Result<T, NetError> {
+    let urls = upload_base_urls(state);
+    let result = failover(&urls, send, NetError::should_fail_over, |err, base_url| {
+        log::warn!("Primary backend failed ({}), trying {}", err, base_url);
+    });
+    match &result {
+        Ok(_) => update_backend_status(state, true),
+        Err(err) if err.is_unreachable() => update_backend_status(state, false),
+        Err(_) => {}
+    }
+    result
+}
+
 /// 上报卡片状态快照批次。
 fn flush_card_state_batch(
     state: &Arc<Mutex<GatewayState>>,
     buffer: &mut Vec<CardStateSnapshot>,
-) -> Result<(), NetError> {
+) -> Result<usize, NetError> {
     if buffer.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
     let payload = serde_json::to_string(&buffer)?;
-    let base_url = resolve_base_url(state);
-    let url = format!("{}{}", base_url, CARD_STATE_BATCH_PATH);
-    let content_length = payload.len().to_string();
-    let headers = [
-        ("content-type", "application/json"),
-        ("content-length", content_length.as_str()),
-    ];
-
-    let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default())?);
-    let mut request = client.request(Method::Post, &url, &headers)?;
-    request.write_all(payload.as_bytes())?;
-    request.flush()?;
-    let mut response = request.submit()?;
-    let status = response.status();
-    let body = read_response_body(&mut response)?;
-    if !(200..300).contains(&status) {
-        update_backend_status(state, false);
-        return Err(NetError::HttpStatus(status));
-    }
-    let payload: ApiResponse<CardStateBatchResponse> = serde_json::from_slice(&body)?;
+    let body = post_with_failover(state, |base_url| post_card_states(base_url, &payload))?;
+    let payload: ApiResponse<CardStateBatchResponse> = parse_api_response(&body)?;
     if !payload.success {
         return Err(NetError::Api(
             payload.message.unwrap_or_else(|| "card state upload failed".to_string()),
         ));
     }
+    let mut rejected_count = 0;
     if let Some(result) = payload.data {
         if let Some(rejected) = result.rejected {
+            rejected_count = rejected.len();
             // rejected 代表“状态校验失败”，不等价于“应封禁”。
             // 只在后端明确返回“card blocked”时，才将卡加入黑名单缓存。
             let mut to_blacklist: Vec<String> = Vec::new();
+            let masked = state.lock_recover().settings.mask_card_ids;
             for item in rejected {
                 if matches!(item.reason.as_deref(), Some("card blocked")) {
                     to_blacklist.push(item.card_id);
                 } else {
                     log::warn!(
                         "Card state rejected (not blacklisting): card_id={}, reason={:?}",
-                        item.card_id,
+                        display_card_id_with(&item.card_id, masked),
                         item.reason
                     );
                 }
             }
             if !to_blacklist.is_empty() {
-                let now = current_epoch();
-                if let Ok(mut state) = state.lock() {
-                    for card_id in to_blacklist {
-                        if !state.blacklist_cache.is_blocked(&card_id) {
-                            state.blacklist_cache.cards.push(card_id);
-                        }
+                let mut state = state.lock_recover();
+                let now = state.now_secs();
+                for card_id in to_blacklist {
+                    if !state.blacklist_cache.is_blocked(&card_id) {
+                        state.blacklist_cache.cards.push(card_id);
                     }
-                    state.blacklist_cache.fetched_at = now;
                 }
+                state.blacklist_cache.fetched_at = now;
             }
         }
     }
     buffer.clear();
-    update_backend_status(state, true);
+    state.lock_recover().record_upload_success();
+    Ok(rejected_count)
+}
+
+/// 向指定后端发送卡片状态快照，返回 2xx 响应体。
+fn post_card_states(base_url: &str, payload: &str) -> Result<Vec<u8>, NetError> {
+    let url = format!("{}{}", base_url, CARD_STATE_BATCH_PATH);
+    let content_length = payload.len().to_string();
+    let headers = [
+        ("content-type", "application/json"),
+        ("content-length", content_length.as_str()),
+    ];
+
+    let connection = EspHttpConnection::new(&Default::default()).map_err(NetError::connect)?;
+    let mut client = HttpClient::wrap(connection);
+    let mut request = client
+        .request(Method::Post, &url, &headers)
+        .map_err(NetError::connect)?;
+    request.write_all(payload.as_bytes())?;
+    request.flush()?;
+    let mut response = request.submit()?;
+    let status = response.status();
+    let body = read_response_body(&mut response)?;
+    if !(200..300).contains(&status) {
+        return Err(NetError::HttpStatus(status));
+    }
+    Ok(body)
+}
+
+/// 向指定后端发送网关心跳（可达状态由 post_with_failover 统一更新）。
+fn post_heartbeat(base_url: &str, payload: &str) -> Result<(), NetError> {
+    let url = format!("{}{}", base_url, HEARTBEAT_PATH);
+    let content_length = payload.len().to_string();
+    let headers = [
+        ("content-type", "application/json"),
+        ("content-length", content_length.as_str()),
+    ];
+
+    let connection = EspHttpConnection::new(&Default::default()).map_err(NetError::connect)?;
+    let mut client = HttpClient::wrap(connection);
+    let mut request = client
+        .request(Method::Post, &url, &headers)
+        .map_err(NetError::connect)?;
+    request.write_all(payload.as_bytes())?;
+    request.flush()?;
+    let status = request.submit()?.status();
+    // 后端已响应（如未实现心跳接口返回 404）不代表不可达，按业务错误记录
+    if !(200..300).contains(&status) {
+        return Err(NetError::Api(format!("心跳接口返回 {}", status)));
+    }
     Ok(())
 }
 
+/// 立即推送缓存中的全部卡片状态快照，汇总发送与接受条数。
+fn push_all_card_states(
+    state: &Arc<Mutex<GatewayState>>,
+    buffer: &mut Vec<CardStateSnapshot>,
+) -> ActionResult {
+    let drained = state.lock_recover().card_state_cache.drain_all();
+    buffer.extend(drained);
+    let sent = buffer.len();
+    if sent == 0 {
+        return ActionResult::card_state_push(0, Ok(0));
+    }
+    let outcome = flush_card_state_batch(state, buffer).map_err(|err| {
+        report_error(state, "卡片状态上传", &err);
+        err.to_string()
+    });
+    ActionResult::card_state_push(sent, outcome)
+}
+
 /// 同步线路配置与黑名单。
 fn sync_config(state: &Arc<Mutex<GatewayState>>, route_id: u16) -> bool {
-    let now = current_epoch();
     let mut ok = false;
-    let base_url = resolve_base_url(state);
+    let (config_url, blacklist_url) = resolve_sync_urls(state);
 
     log::info!(
-        "Sync config: route_id={}, base_url='{}'",
+        "Sync config: route_id={}, config_url='{}'",
         route_id,
-        base_url
+        config_url
     );
 
-    match fetch_route_config(&base_url, route_id) {
-        Ok(config) => {
-            if let Ok(mut state) = state.lock() {
-                state.update_route_config(config, now);
+    match fetch_route_config(&config_url, route_id) {
+        Ok((config, scope)) => {
+            {
+                let mut state = state.lock_recover();
+                let now = state.now_secs();
+                state.merge_route_config(config, scope, now);
             }
             ok = true;
         }
         Err(err) => {
-            log::warn!("Route config fetch failed: {:?}", err);
+            report_error(state, "配置同步", &err);
         }
     }
 
-    match fetch_blacklist(&base_url) {
+    match fetch_blacklist(&blacklist_url) {
         Ok(cards) => {
-            if let Ok(mut state) = state.lock() {
+            {
+                let mut state = state.lock_recover();
+                let now = state.now_secs();
                 state.update_blacklist(cards, now);
             }
             ok = true;
         }
         Err(err) => {
-            log::warn!("Blacklist fetch failed: {:?}", err);
+            report_error(state, "黑名单同步", &err);
         }
     }
 
+    preload_card_cache(state);
+
     update_backend_status(state, ok);
+    state.lock_recover().mark_initial_sync_done();
     ok
 }
 
-/// 请求后端线路配置。
-fn fetch_route_config(base_url: &str, route_id: u16) -> Result<RouteConfig, NetError> {
-    let url = format!("{}{}?route_id={}", base_url, CONFIG_PATH, route_id);
+/// 从后端批量预加载有效卡画像，使首次刷卡即可应用优惠；只填充空余容量，不覆盖已有缓存。
+fn preload_card_cache(state: &Arc<Mutex<GatewayState>>) {
+    let limit = {
+        let state = state.lock_recover();
+        state.settings.card_preload_max.min(state.card_cache_free_slots())
+    };
+    if limit == 0 {
+        return;
+    }
+    let base_url = resolve_base_url(state);
+    let profiles = match fetch_card_profiles(&base_url, limit) {
+        Ok(profiles) => profiles,
+        Err(err) => {
+            report_error(state, "卡片预加载", &err);
+            return;
+        }
+    };
+    {
+        let mut state = state.lock_recover();
+        let loaded = state.preload_card_profiles(profiles, limit);
+        log::info!("Card cache preloaded: {} profiles", loaded);
+    }
+}
+
+/// 全量同步：强制拉取配置与黑名单、重新校时，并汇总各项结果。
+fn full_resync(state: &Arc<Mutex<GatewayState>>, route_id: u16) -> ActionResult {
+    let (config_url, blacklist_url) = resolve_sync_urls(state);
+    log::info!(
+        "Full resync: route_id={}, config_url='{}'",
+        route_id,
+        config_url
+    );
+
+    let config_ok = match fetch_route_config(&config_url, route_id) {
+        Ok((config, scope)) => {
+            {
+                let mut state = state.lock_recover();
+                let now = state.now_secs();
+                state.merge_route_config(config, scope, now);
+            }
+            true
+        }
+        Err(err) => {
+            report_error(state, "配置同步", &err);
+            false
+        }
+    };
+    let blacklist_ok = match fetch_blacklist(&blacklist_url) {
+        Ok(cards) => {
+            {
+                let mut state = state.lock_recover();
+                let now = state.now_secs();
+                state.update_blacklist(cards, now);
+            }
+            true
+        }
+        Err(err) => {
+            report_error(state, "黑名单同步", &err);
+            false
+        }
+    };
+    let ntp_server = state.lock_recover().settings.ntp_server.clone();
+    let time_ok = resync_time(&ntp_server);
+    // 任一后端请求成功即视为后端可达
+    update_backend_status(state, config_ok || blacklist_ok);
+    ActionResult::full_resync(config_ok, blacklist_ok, time_ok)
+}
+
+/// 重新进行 SNTP 校时（阻塞等待完成或超时）；未配置服务器时使用默认服务器。
+fn resync_time(ntp_server: &str) -> bool {
+    let sntp = if ntp_server.is_empty() {
+        EspSntp::new_default()
+    } else {
+        EspSntp::new(&SntpConf {
+            servers: [ntp_server],
+            ..Default::default()
+        })
+    };
+    let sntp = match sntp {
+        Ok(sntp) => sntp,
+        Err(err) => {
+            log::warn!("SNTP init failed: {:?}", err);
+            return false;
+        }
+    };
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(SNTP_WAIT_MS) {
+        if sntp.get_sync_status() == SyncStatus::Completed {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    log::warn!("SNTP sync timed out");
+    false
+}
+
+/// 请求线路配置（后端或对端网关）。
+fn fetch_route_config(
+    config_url: &str,
+    route_id: u16,
+) -> Result<(RouteConfig, ConfigUpdateScope), NetError> {
+    let url = format!("{}?route_id={}", config_url, route_id);
     log::info!("HTTP GET {}", url);
     let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default())?);
     let headers = [("accept", "application/json")];
@@ -413,35 +786,39 @@ fn fetch_route_config(base_url: &str, route_id: u16) -> Result<RouteConfig, NetE
     if !(200..300).contains(&status) {
         return Err(NetError::HttpStatus(status));
     }
-    let payload: ApiResponse<RouteConfigResponse> = serde_json::from_slice(&body)?;
+    let payload: ApiResponse<RouteConfigResponse> = parse_api_response(&body)?;
     if !payload.success {
         return Err(NetError::Api(payload.message.unwrap_or_else(|| "request failed".to_string())));
     }
     let config = payload
         .data
         .ok_or_else(|| NetError::Api("empty config response".to_string()))?;
-    Ok(config.into())
+    let scope = config.update_scope();
+    Ok((config.into(), scope))
 }
 
-/// 请求后端黑名单列表。
-fn fetch_blacklist(base_url: &str) -> Result<Vec<String>, NetError> {
-    let url = format!("{}{}?status=blocked", base_url, CARDS_PATH);
+/// 请求黑名单列表（后端或对端网关）。
+fn fetch_blacklist(url: &str) -> Result<Vec<String>, NetError> {
     log::info!("HTTP GET {}", url);
     let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default())?);
     let headers = [("accept", "application/json")];
-    let request = client.request(Method::Get, &url, &headers)?;
+    let request = client.request(Method::Get, url, &headers)?;
     let mut response = request.submit()?;
     let status = response.status();
     let body = read_response_body(&mut response)?;
     if !(200..300).contains(&status) {
         return Err(NetError::HttpStatus(status));
     }
-    let payload: ApiResponse<Vec<CardResponse>> = serde_json::from_slice(&body)?;
+    let payload: ApiResponse<Vec<CardResponse>> = parse_api_response(&body)?;
     if !payload.success {
         return Err(NetError::Api(payload.message.unwrap_or_else(|| "request failed".to_string())));
     }
     let cards = payload.data.unwrap_or_default();
-    Ok(cards.into_iter().filter_map(|card| card.card_id).collect())
+    Ok(cards
+        .into_iter()
+        .filter_map(|card| card.card_id)
+        .map(|card_id| normalize_uid(&card_id))
+        .collect())
 }
 
 /// 上报卡片注册信息。
@@ -464,7 +841,7 @@ fn register_card(base_url: &str, payload: CardRegistration) -> Result<(), NetErr
     if !(200..300).contains(&status) {
         return Err(NetError::HttpStatus(status));
     }
-    let payload: ApiResponse<serde_json::Value> = serde_json::from_slice(&resp_body)?;
+    let payload: ApiResponse<serde_json::Value> = parse_api_response(&resp_body)?;
     if !payload.success {
         return Err(NetError::Api(
             payload.message.unwrap_or_else(|| "register failed".to_string()),
@@ -485,18 +862,43 @@ fn fetch_card_profile(base_url: &str, card_id: &str) -> Result<Option<CardProfil
     if !(200..300).contains(&status) {
         return Err(NetError::HttpStatus(status));
     }
-    let payload: ApiResponse<Vec<CardResponse>> = serde_json::from_slice(&body)?;
+    let payload: ApiResponse<Vec<CardResponse>> = parse_api_response(&body)?;
     if !payload.success {
         return Err(NetError::Api(payload.message.unwrap_or_else(|| "request failed".to_string())));
     }
     let cards = payload.data.unwrap_or_default();
-    Ok(cards.into_iter().next().map(|card| CardProfile {
-        card_type: card.card_type,
-        status: card.status,
-        balance_cents: card.balance.map(|value| (value.max(0.0) * 100.0).round() as u32),
-        discount_rate: card.discount_rate,
-        discount_amount: card.discount_amount,
-    }))
+    Ok(cards.into_iter().next().map(CardProfile::from))
+}
+
+/// 批量查询有效卡画像（最多 limit 条），返回规范化卡号与画像。
+fn fetch_card_profiles(
+    base_url: &str,
+    limit: usize,
+) -> Result<Vec<(String, CardProfile)>, NetError> {
+    let url = format!("{}{}?status=active&limit={}", base_url, CARDS_PATH, limit);
+    log::info!("HTTP GET {}", url);
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&Default::default())?);
+    let headers = [("accept", "application/json")];
+    let request = client.request(Method::Get, &url, &headers)?;
+    let mut response = request.submit()?;
+    let status = response.status();
+    let body = read_response_body(&mut response)?;
+    if !(200..300).contains(&status) {
+        return Err(NetError::HttpStatus(status));
+    }
+    let payload: ApiResponse<Vec<CardResponse>> = parse_api_response(&body)?;
+    if !payload.success {
+        return Err(NetError::Api(payload.message.unwrap_or_else(|| "request failed".to_string())));
+    }
+    let cards = payload.data.unwrap_or_default();
+    Ok(cards
+        .into_iter()
+        .filter_map(|card| {
+            let card_id = normalize_uid(card.card_id.as_deref()?);
+            (!card_id.is_empty()).then(|| (card_id, CardProfile::from(card)))
+        })
+        .take(limit)
+        .collect())
 }
 
 /// 读取 HTTP 响应体。
@@ -515,206 +917,109 @@ fn read_response_body(
     Ok(body)
 }
 
-/// 更新后端可达性状态。
+/// 更新后端可达性状态（按连续失败/成功阈值平滑）。
 fn update_backend_status(state: &Arc<Mutex<GatewayState>>, reachable: bool) {
-    if let Ok(mut state) = state.lock() {
-        state.update_health(None, Some(reachable));
-    }
+    state.lock_recover().record_backend_result(reachable);
+}
+
+/// 记录后端请求失败（日志 + 状态面板）。
+fn report_error(state: &Arc<Mutex<GatewayState>>, operation: &str, err: &NetError) {
+    log::warn!("Backend request failed ({}): {:?}", operation, err);
+    state.lock_recover().record_backend_error(operation, err.to_string());
+}
+
+/// 累计因缓冲已满而丢弃的上报条数。
+fn record_upload_drops(state: &Arc<Mutex<GatewayState>>, count: u64) {
+    let mut state = state.lock_recover();
+    state.upload_dropped = state.upload_dropped.saturating_add(count);
+    log::warn!(
+        "Upload buffer full, dropped {} (total {})",
+        count,
+        state.upload_dropped
+    );
 }
 
 /// 获取当前后端地址（优先使用运行时设置）。
 fn resolve_base_url(state: &Arc<Mutex<GatewayState>>) -> String {
-    if let Ok(state) = state.lock() {
-        if !state.backend_base_url.is_empty() {
-            return state.backend_base_url.clone();
-        }
+    let state = state.lock_recover();
+    if !state.backend_base_url.is_empty() {
+        return state.backend_base_url.clone();
     }
     BACKEND_BASE_URL.to_string()
 }
 
+/// 配置与黑名单的拉取地址：配置了对端网关时从对端拉取，否则访问后端。
+fn resolve_sync_urls(state: &Arc<Mutex<GatewayState>>) -> (String, String) {
+    let peer = state
+        .lock_recover()
+        .settings
+        .peer_base_url
+        .trim_end_matches('/')
+        .to_string();
+    if !peer.is_empty() {
+        return (
+            format!("{}{}", peer, PEER_CONFIG_PATH),
+            format!("{}{}", peer, PEER_BLACKLIST_PATH),
+        );
+    }
+    let base_url = resolve_base_url(state);
+    (
+        format!("{}{}", base_url, CONFIG_PATH),
+        format!("{}{}?status=blocked", base_url, CARDS_PATH),
+    )
+}
+
 /// 将卡片画像应用到网关状态与 UI 提示。
 fn apply_card_profile(state: &Arc<Mutex<GatewayState>>, card_id: &str, profile: CardProfile) {
-    let Some(tone) = tone_from_profile(&profile) else {
+    let mut state = state.lock_recover();
+    let Some(tone) = tone_from_profile(&profile, &state) else {
         return;
     };
-    if let Ok(mut state) = state.lock() {
-        let now_ms = current_epoch_millis();
-        state.update_card_cache(
-            card_id.to_string(),
-            profile.card_type.clone(),
-            profile.status.clone(),
-            profile.discount_rate,
-            profile.discount_amount,
-            profile.balance_cents,
-            now_ms,
-        );
-        if state.last_card_id == card_id {
-            if tone == PassengerTone::Error {
-                state.last_passenger_tone = tone;
-                if let Some(status) = profile.status.as_deref() {
-                    if status == "blocked" {
-                        state.last_passenger_message = "卡已冻结".to_string();
-                    } else if status == "lost" {
-                        state.last_passenger_message = "卡已挂失".to_string();
-                    }
-                }
-            } else {
-                state.update_passenger_tone(tone);
-                if let Some(card_type) = profile.card_type.as_deref() {
-                    state.apply_card_discount_policy(
-                        card_type,
-                        profile.discount_rate,
-                        profile.discount_amount,
-                    );
+    let now_ms = state.now_millis();
+    state.update_card_cache(
+        card_id.to_string(),
+        profile.card_type.clone(),
+        profile.status.clone(),
+        profile.discount_rate,
+        profile.discount_amount,
+        profile.balance_cents,
+        now_ms,
+    );
+    if profile.status.as_deref() == Some("blocked") {
+        state.apply_post_tap_block(card_id);
+    }
+    if state.last_card_id == card_id {
+        if tone == PassengerTone::Error {
+            state.last_passenger_tone = tone;
+            if let Some(status) = profile.status.as_deref() {
+                if status == "blocked" {
+                    state.last_passenger_message = "卡已冻结".to_string();
+                } else if status == "lost" {
+                    state.last_passenger_message = "卡已挂失".to_string();
                 }
             }
+        } else {
+            state.update_passenger_tone(tone);
+            if let Some(card_type) = profile.card_type.as_deref() {
+                state.apply_card_discount_policy(
+                    card_type,
+                    profile.discount_rate,
+                    profile.discount_amount,
+                );
+            }
         }
     }
 }
 
-/// 当前时间戳（秒）。
-fn current_epoch() -> u64 {
-    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(duration) => duration.as_secs(),
-        Err(_) => 0,
-    }
-}
-
-/// 当前时间戳（毫秒）。
-fn current_epoch_millis() -> u64 {
-    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-        Ok(duration) => duration.as_millis() as u64,
-        Err(_) => 0,
-    }
-}
-
-/// 后端返回的线路配置（网关侧解析用）。
-#[derive(Deserialize)]
-struct RouteConfigResponse {
-    route_id: u16,
-    route_name: String,
-    #[serde(default)]
-    fare_type: Option<String>,
-    #[serde(default)]
-    tap_mode: Option<String>,
-    max_fare: Option<f32>,
-    #[serde(default)]
-    stations: Vec<StationResponse>,
-    #[serde(default)]
-    fares: Vec<FareRuleResponse>,
-}
-
-#[derive(Deserialize)]
-struct StationResponse {
-    #[serde(default)]
-    id: Option<u16>,
-    name: String,
-    sequence: u16,
-    zone_id: Option<u16>,
-    #[serde(default)]
-    is_transfer: Option<bool>,
-}
-
-#[derive(Deserialize)]
-struct FareRuleResponse {
-    #[serde(default)]
-    base_price: Option<f32>,
-    #[serde(default)]
-    fare_type: Option<String>,
-    #[serde(default)]
-    segment_count: Option<u16>,
-    #[serde(default)]
-    extra_price: Option<f32>,
-    #[serde(default)]
-    start_station: Option<u16>,
-    #[serde(default)]
-    end_station: Option<u16>,
-}
-
-#[derive(Deserialize)]
-struct CardResponse {
-    #[serde(default)]
-    card_id: Option<String>,
-    #[serde(default)]
-    card_type: Option<String>,
-    #[serde(default)]
-    status: Option<String>,
-    #[serde(default)]
-    balance: Option<f64>,
-    #[serde(default)]
-    discount_rate: Option<f32>,
-    #[serde(default)]
-    discount_amount: Option<f32>,
-}
-
-/// 卡片画像（用于状态与优惠更新）。
-struct CardProfile {
-    card_type: Option<String>,
-    status: Option<String>,
-    balance_cents: Option<u32>,
-    discount_rate: Option<f32>,
-    discount_amount: Option<f32>,
-}
-
-/// 根据卡片画像确定提示音色。
-fn tone_from_profile(profile: &CardProfile) -> Option<PassengerTone> {
+/// 根据卡片画像确定提示音色（卡类型按线路配置的映射表解析，未知类型为普通票）。
+fn tone_from_profile(profile: &CardProfile, state: &GatewayState) -> Option<PassengerTone> {
     if let Some(status) = profile.status.as_deref() {
         if status == "blocked" || status == "lost" {
             return Some(PassengerTone::Error);
         }
     }
-    match profile.card_type.as_deref() {
-        Some("student") => Some(PassengerTone::Student),
-        Some("elder") => Some(PassengerTone::Elder),
-        Some("disabled") => Some(PassengerTone::Disabled),
-        Some("normal") => Some(PassengerTone::Normal),
-        _ => None,
-    }
-}
-
-/// 将后端响应转换为网关内部模型。
-impl From<RouteConfigResponse> for RouteConfig {
-    fn from(value: RouteConfigResponse) -> Self {
-        let fare_type = match value.fare_type.as_deref() {
-            Some("segment") => FareType::Segment,
-            Some("distance") => FareType::Distance,
-            _ => FareType::Uniform,
-        };
-        let tap_mode = match value.tap_mode.as_deref() {
-            Some("tap_in_out") => TapMode::TapInOut,
-            _ => TapMode::SingleTap,
-        };
-        let fares = value
-            .fares
-            .into_iter()
-            .map(|fare| FareRule {
-                base_price: fare.base_price.unwrap_or(0.0),
-                fare_type: fare.fare_type,
-                segment_count: fare.segment_count,
-                extra_price: fare.extra_price,
-                start_station: fare.start_station,
-                end_station: fare.end_station,
-            })
-            .collect();
-        let stations = value
-            .stations
-            .into_iter()
-            .map(|station| StationConfig {
-                id: station.id.unwrap_or(0),
-                name: station.name,
-                sequence: station.sequence,
-                zone_id: station.zone_id,
-                is_transfer: station.is_transfer.unwrap_or(false),
-            })
-            .collect();
-        RouteConfig {
-            route_id: value.route_id,
-            route_name: value.route_name,
-            fare_type,
-            tap_mode,
-            max_fare: value.max_fare,
-            stations,
-            fares,
-        }
-    }
+    profile
+        .card_type
+        .as_deref()
+        .map(|card_type| state.tone_for_card_type(card_type))
 }
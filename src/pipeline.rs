@@ -1,11 +1,18 @@
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
+use crate::card_data::normalize_uid;
+use crate::lock::LockRecover;
 use crate::model::UploadRecord;
 use crate::net::NetCommand;
 use crate::processor::GatewayProcessor;
-use crate::serial::{CardDetected, CardWriteResult, SerialCommand};
+use crate::proto::CAP_POLL;
+use crate::serial::{
+    AckResendRequest, CardDetected, CardWriteResult, ConfigRequest, ReaderHeartbeat, ReaderHello,
+    SerialCommand,
+};
+use crate::watchdog::{Heartbeat, HEARTBEAT_INTERVAL_MS};
 
 /// 处理管线的通道集合（刷卡事件、ACK、上传）。
 pub struct GatewayChannels {
@@ -17,6 +24,20 @@ pub struct GatewayChannels {
     pub upload_rx: Receiver<UploadRecord>,
     pub write_result_tx: Sender<CardWriteResult>,
     pub write_result_rx: Receiver<CardWriteResult>,
+    pub hello_tx: Sender<ReaderHello>,
+    pub hello_rx: Receiver<ReaderHello>,
+    pub config_req_tx: Sender<ConfigRequest>,
+    pub config_req_rx: Receiver<ConfigRequest>,
+    pub ack_resend_tx: Sender<AckResendRequest>,
+    pub ack_resend_rx: Receiver<AckResendRequest>,
+    pub heartbeat_tx: Sender<ReaderHeartbeat>,
+    pub heartbeat_rx: Receiver<ReaderHeartbeat>,
+}
+
+impl Default for GatewayChannels {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GatewayChannels {
@@ -26,6 +47,10 @@ impl GatewayChannels {
         let (cmd_tx, cmd_rx) = mpsc::channel();
         let (upload_tx, upload_rx) = mpsc::channel();
         let (write_result_tx, write_result_rx) = mpsc::channel();
+        let (hello_tx, hello_rx) = mpsc::channel();
+        let (config_req_tx, config_req_rx) = mpsc::channel();
+        let (ack_resend_tx, ack_resend_rx) = mpsc::channel();
+        let (heartbeat_tx, heartbeat_rx) = mpsc::channel();
         Self {
             card_tx,
             card_rx,
@@ -35,6 +60,14 @@ impl GatewayChannels {
             upload_rx,
             write_result_tx,
             write_result_rx,
+            hello_tx,
+            hello_rx,
+            config_req_tx,
+            config_req_rx,
+            ack_resend_tx,
+            ack_resend_rx,
+            heartbeat_tx,
+            heartbeat_rx,
         }
     }
 }
@@ -46,55 +79,193 @@ pub fn spawn_processor_loop(
     cmd_tx: Sender<SerialCommand>,
     upload_tx: Sender<UploadRecord>,
     net_cmd_tx: Sender<NetCommand>,
+    heartbeat: Heartbeat,
 ) -> thread::JoinHandle<()> {
-    thread::spawn(move || {
-        // 阻塞等待刷卡事件
-        while let Ok(card) = card_rx.recv() {
-            let now = current_epoch();
-            // 无论是否能解析卡内数据，都先尝试从后端查询卡片信息（用于补全余额/状态）。
+    thread::spawn(move || loop {
+        heartbeat.beat();
+        send_pending_reversal(&processor, &cmd_tx, &upload_tx);
+        send_no_route_records(&processor, &upload_tx);
+        // 等待刷卡事件（超时用于空闲时喂狗）
+        let mut card = match card_rx.recv_timeout(Duration::from_millis(HEARTBEAT_INTERVAL_MS)) {
+            Ok(card) => card,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // 统一卡号格式，保证黑名单/缓存/后端按同一卡号匹配
+        card.card_id = normalize_uid(&card.card_id);
+        let (now, lookup_missed) = {
+            let state = processor.state.lock_recover();
+            let missed = state.lookup_miss_cached(&card.card_id, state.now_millis());
+            (state.now_secs(), missed)
+        };
+        // 无论是否能解析卡内数据，都先尝试从后端查询卡片信息（用于补全余额/状态）；
+        // 近期后端查无此卡时不重复查询。
+        if !lookup_missed {
             let _ = net_cmd_tx.send(NetCommand::LookupCard {
                 card_id: card.card_id.clone(),
             });
-            let decision = processor.handle_card(card, now);
-            // 发送写卡请求（如有）
-            if let Some(write_req) = decision.write_request {
-                let _ = cmd_tx.send(SerialCommand::Write(write_req));
-            }
-            // 发送串口 ACK
-            let _ = cmd_tx.send(SerialCommand::Ack(decision.ack));
-            if let Some(record) = decision.upload_record {
-                // 推送上报记录
-                let _ = upload_tx.send(record);
-            }
-            if let Some(registration) = decision.registration {
-                let _ = net_cmd_tx.send(NetCommand::RegisterCard { payload: registration });
-            }
+        }
+        let decision = processor.handle_card(card, now);
+        // 发送写卡请求（如有）
+        if let Some(write_req) = decision.write_request {
+            let _ = cmd_tx.send(SerialCommand::Write(write_req));
+        }
+        // 发送串口 ACK
+        let _ = cmd_tx.send(SerialCommand::Ack(decision.ack));
+        if let Some(record) = decision.upload_record {
+            // 推送上报记录
+            let _ = upload_tx.send(record);
+        }
+        if let Some(registration) = decision.registration {
+            let _ = net_cmd_tx.send(NetCommand::RegisterCard { payload: registration });
         }
     })
 }
 
-/// 写卡结果处理线程：更新网关状态提示。
+/// 下发后端冻结后的冲正：写卡冻结（卡仍在读卡器上时生效）并上报冲正记录。
+fn send_pending_reversal(
+    processor: &GatewayProcessor,
+    cmd_tx: &Sender<SerialCommand>,
+    upload_tx: &Sender<UploadRecord>,
+) {
+    let Some(reversal) = processor.state.lock_recover().take_pending_reversal() else {
+        return;
+    };
+    if let Some(write_req) = reversal.write_request {
+        let _ = cmd_tx.send(SerialCommand::Write(write_req));
+    }
+    if let Some(record) = reversal.upload_record {
+        let _ = upload_tx.send(record);
+    }
+}
+
+/// 线路同步后上报无线路期间暂存的刷卡。
+fn send_no_route_records(processor: &GatewayProcessor, upload_tx: &Sender<UploadRecord>) {
+    let records = processor.state.lock_recover().take_no_route_records();
+    if !records.is_empty() {
+        log::info!("Uploading {} taps queued before route sync", records.len());
+    }
+    for record in records {
+        let _ = upload_tx.send(record);
+    }
+}
+
+/// 写卡结果处理线程：更新网关状态提示，可重试的失败重新下发写卡。
 pub fn spawn_write_result_loop(
     state: std::sync::Arc<std::sync::Mutex<crate::state::GatewayState>>,
     write_result_rx: Receiver<CardWriteResult>,
+    cmd_tx: Sender<SerialCommand>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         while let Ok(result) = write_result_rx.recv() {
-            let now_ms = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map(|d| d.as_millis() as u64)
-                .unwrap_or(0);
-            if let Ok(mut state) = state.lock() {
-                state.handle_write_result(result, now_ms);
+            let retry = {
+                let mut state = state.lock_recover();
+                let now_ms = state.now_millis();
+                state.handle_write_result(result, now_ms)
+            };
+            // 可重试的写卡失败：重新下发写卡请求
+            if let Some(write_req) = retry {
+                let _ = cmd_tx.send(SerialCommand::Write(write_req));
+            }
+        }
+    })
+}
+
+/// 读卡器握手处理线程：记录固件版本与协商能力。
+pub fn spawn_reader_hello_loop(
+    state: std::sync::Arc<std::sync::Mutex<crate::state::GatewayState>>,
+    hello_rx: Receiver<ReaderHello>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(hello) = hello_rx.recv() {
+            log::info!(
+                "Reader hello: firmware={}, protocol={}, caps=0x{:04X}",
+                hello.firmware_version,
+                hello.protocol_version,
+                hello.capabilities
+            );
+            state.lock_recover().update_reader_hello(hello);
+        }
+    })
+}
+
+/// 读卡器轮询线程：按间隔下发轮询帧（需读卡器支持 CAP_POLL），
+/// 连续多次未收到心跳回复时判定读卡器离线；间隔为 0 时只记录读卡器主动上报的心跳。
+pub fn spawn_reader_poll_loop(
+    state: std::sync::Arc<std::sync::Mutex<crate::state::GatewayState>>,
+    heartbeat_rx: Receiver<ReaderHeartbeat>,
+    cmd_tx: Sender<SerialCommand>,
+    poll_secs: u32,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let interval = Duration::from_secs(poll_secs.max(1) as u64);
+        let mut next_poll = Instant::now() + interval;
+        loop {
+            let received = if poll_secs == 0 {
+                heartbeat_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                heartbeat_rx.recv_timeout(next_poll.saturating_duration_since(Instant::now()))
+            };
+            match received {
+                Ok(_) => {
+                    state.lock_recover().record_reader_heartbeat();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            next_poll = Instant::now() + interval;
+            let poll = {
+                let mut state = state.lock_recover();
+                let supported = state.reader_supports(CAP_POLL);
+                if supported && state.record_reader_poll() {
+                    log::warn!(
+                        "Reader offline: {} polls unanswered",
+                        state.reader_polls_unanswered - 1
+                    );
+                }
+                supported
+            };
+            if poll {
+                let _ = cmd_tx.send(SerialCommand::Poll);
             }
         }
     })
 }
 
-/// 获取当前时间戳（秒）。
-fn current_epoch() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0)
+/// 线路信息请求处理线程：读卡器请求时回复当前线路/站点。
+pub fn spawn_config_request_loop(
+    state: std::sync::Arc<std::sync::Mutex<crate::state::GatewayState>>,
+    config_req_rx: Receiver<ConfigRequest>,
+    cmd_tx: Sender<SerialCommand>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while config_req_rx.recv().is_ok() {
+            let info = state.lock_recover().route_info();
+            log::info!(
+                "Reader config request: route={}, station={}",
+                info.route_id,
+                info.station_id
+            );
+            let _ = cmd_tx.send(SerialCommand::RouteInfo(info));
+        }
+    })
+}
+
+/// ACK 重发请求处理线程：重发缓存的最近一次 ACK，不重新计算扣费。
+pub fn spawn_ack_resend_loop(
+    state: std::sync::Arc<std::sync::Mutex<crate::state::GatewayState>>,
+    ack_resend_rx: Receiver<AckResendRequest>,
+    cmd_tx: Sender<SerialCommand>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(request) = ack_resend_rx.recv() {
+            let Some(ack) = state.lock_recover().last_ack(request.reader_id) else {
+                log::warn!("No cached ACK to resend: reader={:?}", request.reader_id);
+                continue;
+            };
+            log::info!("Resending last ACK: reader={:?}", request.reader_id);
+            let _ = cmd_tx.send(SerialCommand::Ack(ack));
+        }
+    })
 }
@@ -0,0 +1,38 @@
+// 脱敏后保留的末尾字符数。
+const VISIBLE_SUFFIX_CHARS: usize = 4;
+
+/// 卡号脱敏：仅保留末 4 位，如 `A1B2C3D4` -> `****C3D4`。
+pub fn mask_card_id(card_id: &str) -> String {
+    let chars: Vec<char> = card_id.chars().collect();
+    let visible = chars.len().saturating_sub(VISIBLE_SUFFIX_CHARS);
+    let mut masked = String::from("****");
+    masked.extend(&chars[visible..]);
+    masked
+}
+
+/// 按脱敏开关（GatewaySettings::mask_card_ids）返回展示用卡号（空卡号原样返回）。
+pub fn display_card_id_with(card_id: &str, masked: bool) -> String {
+    if card_id.is_empty() || !masked {
+        return card_id.to_string();
+    }
+    mask_card_id(card_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_keeps_last_four() {
+        assert_eq!(mask_card_id("A1B2C3D4"), "****C3D4");
+        assert_eq!(mask_card_id("04A1B2C3D4E5F6"), "****E5F6");
+        assert_eq!(mask_card_id("C3"), "****C3");
+    }
+
+    #[test]
+    fn display_follows_toggle() {
+        assert_eq!(display_card_id_with("A1B2C3D4", false), "A1B2C3D4");
+        assert_eq!(display_card_id_with("A1B2C3D4", true), "****C3D4");
+        assert_eq!(display_card_id_with("", true), "");
+    }
+}
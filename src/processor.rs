@@ -1,23 +1,79 @@
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::lock::LockRecover;
+use crate::model::{SerialMode, WarmupPolicy};
+use crate::proto::CAP_INLINE_WRITE;
 use crate::serial::CardDetected;
 use crate::state::{Decision, GatewayState};
+use crate::watchdog::Heartbeat;
+
+// 等待后端查询结果时的轮询间隔。
+const LOOKUP_POLL_MS: u64 = 10;
 
 /// 网关业务处理器（串口事件 -> 决策）。
 pub struct GatewayProcessor {
     pub state: Arc<Mutex<GatewayState>>,
+    // 处理器线程心跳：刷卡处理中的等待期间也需喂狗。
+    heartbeat: Option<Heartbeat>,
+    // 本次启动是否已做过预热等待（只等待一次，之后照常处理）。
+    warmup_waited: bool,
 }
 
 impl GatewayProcessor {
     /// 创建处理器，持有共享状态。
     pub fn new(state: Arc<Mutex<GatewayState>>) -> Self {
-        Self { state }
+        Self {
+            state,
+            heartbeat: None,
+            warmup_waited: false,
+        }
+    }
+
+    /// 绑定看门狗心跳，等待预热或查询结果时持续喂狗。
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    fn beat(&self) {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.beat();
+        }
     }
 
     /// 处理刷卡事件，生成 ACK 与上传记录。
     pub fn handle_card(&mut self, detected: CardDetected, now: u64) -> Decision {
-        let mut state = self.state.lock().expect("state lock poisoned");
-        let decision = state.handle_card_detected(detected, now);
+        self.wait_for_warmup();
+        self.wait_for_card_profile(&detected.card_id);
+        let reader_id = detected.reader_id;
+        let mut state = self.state.lock_recover();
+        let mut decision = state.handle_card_detected(detected, now);
+        // 行模式读卡器无法写卡，丢弃写卡请求并撤销写卡跟踪
+        if state.settings.serial_mode == SerialMode::LineUid
+            && decision.write_request.take().is_some()
+        {
+            state.cancel_last_write();
+        }
+        // 写卡数据超出协议长度时拒绝本次刷卡，避免截断后写坏卡片
+        if decision
+            .write_request
+            .as_ref()
+            .is_some_and(|req| req.to_frame().is_none())
+        {
+            decision = state.reject_oversized_write();
+        }
+        // 按最终下发的 ACK 计数，写卡数据过长被拒的刷卡不计为成功
+        state.metrics.record_tap(&decision.ack);
+        // 读卡器支持内联写卡时，写卡数据随 ACK 一并下发
+        if state.reader_supports(CAP_INLINE_WRITE) {
+            if let Some(write_req) = decision.write_request.take() {
+                decision.ack = decision.ack.with_inline_write(&write_req);
+            }
+        }
+        // 缓存最终下发的 ACK，供读卡器请求重发
+        state.remember_ack(reader_id, decision.ack.clone());
         if decision.upload_record.is_some() {
             if let Some(ref event) = decision.event {
                 // 缓存 tap 事件，供 UI 或离线上报
@@ -26,4 +82,258 @@ impl GatewayProcessor {
         }
         decision
     }
+
+    /// 预热期且策略为等待时，短暂等待首次配置同步完成；超时则照常处理。
+    /// 每次启动只等待一次，避免后端长时间不可达时每次刷卡都被拖慢。
+    fn wait_for_warmup(&mut self) {
+        if self.warmup_waited {
+            return;
+        }
+        let wait_ms = {
+            let state = self.state.lock_recover();
+            if !state.warming_up() || state.settings.warmup_policy != WarmupPolicy::Wait {
+                return;
+            }
+            state.settings.warmup_wait_ms as u64
+        };
+        self.warmup_waited = true;
+        let deadline = Instant::now() + Duration::from_millis(wait_ms);
+        while Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(LOOKUP_POLL_MS));
+            self.beat();
+            if !self.state.lock_recover().warming_up() {
+                return;
+            }
+        }
+        log::info!(
+            "Initial config sync not finished after {}ms, processing tap",
+            wait_ms
+        );
+    }
+
+    /// 卡片无缓存画像时短暂等待后端查询结果，使本次刷卡即可应用优惠；超时则按卡内数据处理。
+    /// 后端不可达或近期查无此卡时不等待。
+    fn wait_for_card_profile(&self, card_id: &str) {
+        let wait_ms = {
+            let state = self.state.lock_recover();
+            state.card_lookup_wait_ms(card_id, state.now_millis())
+        };
+        if wait_ms == 0 {
+            return;
+        }
+        let deadline = Instant::now() + Duration::from_millis(wait_ms);
+        while Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(LOOKUP_POLL_MS));
+            self.beat();
+            let state = self.state.lock_recover();
+            if state.has_cached_profile(card_id, state.now_millis()) {
+                return;
+            }
+        }
+        log::info!(
+            "Card lookup not ready after {}ms, using on-card data",
+            wait_ms
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::card_data::{CardData, CardUid};
+    use crate::clock::MockClock;
+    use crate::model::GatewaySettings;
+    use crate::model::{RouteConfig, SerialMode};
+
+    const CARD_ID: &str = "A1B2C3D4";
+
+    fn online_processor(lookup_wait_ms: u32) -> GatewayProcessor {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.lookup_wait_ms = lookup_wait_ms;
+        let mut state = GatewayState::bootstrap(settings)
+            .with_clock(Arc::new(MockClock::new(1_704_067_200_000)));
+        state.wifi_connected = true;
+        state.backend_reachable = true;
+        GatewayProcessor::new(Arc::new(Mutex::new(state)))
+    }
+
+    fn cache_profile(state: &Arc<Mutex<GatewayState>>) {
+        let mut state = state.lock().unwrap();
+        let now_ms = state.now_millis();
+        state.update_card_cache(
+            CARD_ID.to_string(),
+            Some("student".to_string()),
+            Some("active".to_string()),
+            None,
+            None,
+            None,
+            now_ms,
+        );
+    }
+
+    #[test]
+    fn lookup_wait_returns_once_profile_arrives() {
+        let processor = online_processor(5000);
+        let state = processor.state.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            cache_profile(&state);
+        });
+        let started = Instant::now();
+        processor.wait_for_card_profile(CARD_ID);
+        writer.join().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(2000));
+        let state = processor.state.lock().unwrap();
+        assert!(state.has_cached_profile(CARD_ID, state.now_millis()));
+    }
+
+    #[test]
+    fn lookup_wait_times_out() {
+        let processor = online_processor(60);
+        let started = Instant::now();
+        processor.wait_for_card_profile(CARD_ID);
+        assert!(started.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn lookup_wait_skipped_when_backend_unreachable() {
+        let processor = online_processor(5000);
+        processor.state.lock().unwrap().backend_reachable = false;
+        let started = Instant::now();
+        processor.wait_for_card_profile(CARD_ID);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn lookup_miss_is_negatively_cached() {
+        let processor = online_processor(5000);
+        {
+            let mut state = processor.state.lock().unwrap();
+            let now_ms = state.now_millis();
+            state.record_card_lookup_miss(CARD_ID, now_ms);
+            assert_eq!(state.card_lookup_wait_ms(CARD_ID, now_ms), 0);
+            assert_eq!(state.card_lookup_wait_ms("0102030405060708", now_ms), 5000);
+            // 负缓存过期后重新等待查询
+            assert_eq!(state.card_lookup_wait_ms(CARD_ID, now_ms + 60_000), 5000);
+        }
+        let started = Instant::now();
+        processor.wait_for_card_profile(CARD_ID);
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        cache_profile(&processor.state);
+        let state = processor.state.lock().unwrap();
+        assert!(!state.lookup_miss_cached(CARD_ID, state.now_millis()));
+    }
+
+    /// 已同步线路（单次刷卡、票价 2 元）的处理器。
+    fn routed_processor() -> GatewayProcessor {
+        let processor = online_processor(0);
+        {
+            let mut state = processor.state.lock().unwrap();
+            let route: RouteConfig = serde_json::from_value(serde_json::json!({
+                "route_id": 7,
+                "route_name": "7路",
+                "fare_type": "uniform",
+                "tap_mode": "single_tap",
+                "stations": [{"id": 1, "name": "一站", "sequence": 1}],
+                "fares": [{"base_price": 2.0}]
+            }))
+            .unwrap();
+            let now = state.now_secs();
+            state.update_route_config(route, now);
+        }
+        processor
+    }
+
+    fn tap_card(processor: &mut GatewayProcessor, reader_id: u16, balance_cents: u32) -> Decision {
+        let mut card = CardData::new(CardUid::from_slice(&[0xA1, 0xB2, 0xC3, 0xD4]).unwrap());
+        card.balance_cents = balance_cents;
+        let now = processor.state.lock().unwrap().now_secs();
+        processor.handle_card(
+            CardDetected {
+                card_id: CARD_ID.to_string(),
+                tap_time: now,
+                reader_id,
+                card_data: card.to_bytes().to_vec(),
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn line_mode_drops_write_requests() {
+        let mut processor = routed_processor();
+        processor.state.lock().unwrap().settings.serial_mode = SerialMode::LineUid;
+        let decision = tap_card(&mut processor, 1, 1000);
+        assert_eq!(decision.ack.result, 1);
+        assert!(decision.write_request.is_none());
+        let mut state = processor.state.lock().unwrap();
+        assert_eq!(state.pending_write_count(), 0);
+        let now_ms = state.now_millis();
+        assert!(!state.write_pending_for(CARD_ID, now_ms));
+    }
+
+    #[test]
+    fn resend_returns_cached_ack_without_new_decision() {
+        let mut processor = routed_processor();
+        let decision = tap_card(&mut processor, 2, 1000);
+        let state = processor.state.lock().unwrap();
+        let accepted = state.metrics.taps_accepted;
+        let pending = state.pending_write_count();
+        let resent = state.last_ack(Some(2)).expect("cached ack");
+        assert_eq!(resent.result, decision.ack.result);
+        assert_eq!(resent.beep_pattern, decision.ack.beep_pattern);
+        assert_eq!(resent.write_data, decision.ack.write_data);
+        assert!(state.last_ack(None).is_some());
+        assert!(state.last_ack(Some(1)).is_none());
+        // 重发只读取缓存，不重新扣费
+        assert_eq!(state.metrics.taps_accepted, accepted);
+        assert_eq!(state.pending_write_count(), pending);
+    }
+
+    fn warmup_processor(wait_ms: u32) -> GatewayProcessor {
+        let processor = online_processor(0);
+        {
+            let mut state = processor.state.lock().unwrap();
+            state.settings.warmup_policy = WarmupPolicy::Wait;
+            state.settings.warmup_wait_ms = wait_ms;
+            assert!(state.warming_up());
+        }
+        processor
+    }
+
+    #[test]
+    fn warmup_wait_feeds_heartbeat() {
+        let heartbeat = Heartbeat::new();
+        let mut processor = warmup_processor(300).with_heartbeat(heartbeat.clone());
+        processor.wait_for_warmup();
+        // 未喂狗时距上次心跳约为整个等待时长
+        assert!(heartbeat.since_last_beat_ms() < 100);
+    }
+
+    #[test]
+    fn warmup_wait_happens_once_per_boot() {
+        let mut processor = warmup_processor(80);
+        let started = Instant::now();
+        processor.wait_for_warmup();
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        // 首次同步仍未完成，后续刷卡不再等待
+        assert!(processor.state.lock().unwrap().warming_up());
+        let started = Instant::now();
+        processor.wait_for_warmup();
+        assert!(started.elapsed() < Duration::from_millis(40));
+    }
+
+    #[test]
+    fn warmup_wait_returns_once_sync_finishes() {
+        let mut processor = warmup_processor(5000);
+        let state = processor.state.clone();
+        let syncer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            state.lock().unwrap().mark_initial_sync_done();
+        });
+        let started = Instant::now();
+        processor.wait_for_warmup();
+        syncer.join().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(2000));
+    }
 }
@@ -18,6 +18,37 @@ pub const MSG_HEARTBEAT: u8 = 0x04;
 pub const MSG_ERROR_REPORT: u8 = 0x05;
 pub const MSG_CARD_WRITE_REQ: u8 = 0x06;
 pub const MSG_CARD_WRITE_RESULT: u8 = 0x07;
+pub const MSG_HELLO: u8 = 0x08;
+pub const MSG_HELLO_ACK: u8 = 0x09;
+pub const MSG_CONFIG_REQUEST: u8 = 0x0A;
+pub const MSG_ACK_RESEND_REQ: u8 = 0x0B;
+pub const MSG_CARD_WRITE_BATCH_REQ: u8 = 0x0C;
+pub const MSG_CARD_WRITE_BATCH_RESULT: u8 = 0x0D;
+
+/// 写卡结果错误码（CARD_WRITE_RESULT.error_code）。
+pub const WRITE_ERR_AUTH: u8 = 0x01;
+pub const WRITE_ERR_VERIFY: u8 = 0x02;
+pub const WRITE_ERR_CARD_REMOVED: u8 = 0x03;
+
+/// 写卡请求帧 flags / 内联写卡 write_flag 中的回读标志：要求读卡器在写卡结果中附带回读数据。
+pub const WRITE_FLAG_READBACK: u8 = 0x02;
+
+/// 握手能力位（网关与读卡器按位取交集）。
+pub const CAP_INLINE_WRITE: u16 = 0x0001;
+pub const CAP_BYTE_STUFFING: u16 = 0x0002;
+pub const CAP_TELEMETRY: u16 = 0x0004;
+pub const CAP_WRITE_READBACK: u16 = 0x0008;
+pub const CAP_BATCH_WRITE: u16 = 0x0010;
+/// 读卡器收到网关 HEARTBEAT 轮询后回复 HEARTBEAT。
+pub const CAP_POLL: u16 = 0x0020;
+/// 网关当前实现的能力。
+pub const GATEWAY_CAPABILITIES: u16 =
+    CAP_INLINE_WRITE | CAP_WRITE_READBACK | CAP_BATCH_WRITE | CAP_POLL;
+
+/// 协商双方都支持的能力。
+pub fn negotiate_capabilities(local: u16, remote: u16) -> u16 {
+    local & remote
+}
 
 /// 解码错误类型。
 #[derive(Clone, Debug)]
@@ -79,3 +110,54 @@ pub fn decode_frame(data: &[u8]) -> Result<Frame, FrameError> {
 fn checksum16(data: &[u8]) -> u16 {
     data.iter().fold(0u16, |acc, b| acc.wrapping_add(*b as u16))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Frame {
+        Frame {
+            msg_type: MSG_CARD_ACK,
+            flags: 0x01,
+            payload: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let frame = decode_frame(&encode_frame(&sample())).unwrap();
+        assert_eq!(frame.msg_type, MSG_CARD_ACK);
+        assert_eq!(frame.flags, 0x01);
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_rejects_bad_frames() {
+        let bytes = encode_frame(&sample());
+        assert!(matches!(
+            decode_frame(&bytes[..8]),
+            Err(FrameError::TooShort)
+        ));
+        let mut bad = bytes.clone();
+        bad[0] = 0;
+        assert!(matches!(decode_frame(&bad), Err(FrameError::BadHeader)));
+        let mut bad = bytes.clone();
+        bad[2] = 0x02;
+        assert!(matches!(decode_frame(&bad), Err(FrameError::BadVersion)));
+        assert!(matches!(
+            decode_frame(&bytes[..bytes.len() - 1]),
+            Err(FrameError::BadLength)
+        ));
+        let mut bad = bytes.clone();
+        bad[7] ^= 0xFF;
+        assert!(matches!(decode_frame(&bad), Err(FrameError::BadChecksum)));
+    }
+
+    #[test]
+    fn capabilities_intersect() {
+        assert_eq!(
+            negotiate_capabilities(GATEWAY_CAPABILITIES, CAP_POLL | CAP_TELEMETRY),
+            CAP_POLL
+        );
+    }
+}
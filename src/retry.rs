@@ -0,0 +1,190 @@
+/// 按次数重试初始化：成功立即返回，全部失败返回最后一次错误（次数为 0 时按 1 次处理）。
+/// 每次失败调用 `on_error(attempt, &err)`（从 1 计数），由调用方决定日志与等待。
+pub fn retry<T, E>(
+    attempts: u32,
+    mut attempt_fn: impl FnMut(u32) -> Result<T, E>,
+    mut on_error: impl FnMut(u32, &E),
+) -> Result<T, E> {
+    let attempts = attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match attempt_fn(attempt) {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                on_error(attempt, &err);
+                if attempt >= attempts {
+                    return Err(err);
+                }
+            }
+        }
+        attempt += 1;
+    }
+}
+
+/// 主地址在前、备用地址（去尾斜杠，空或与主地址相同时忽略）在后的地址列表。
+pub fn failover_urls(primary: String, secondary: &str) -> Vec<String> {
+    let secondary = secondary.trim_end_matches('/');
+    if secondary.is_empty() || secondary == primary {
+        vec![primary]
+    } else {
+        vec![primary, secondary.to_string()]
+    }
+}
+
+/// 网关类状态码（502/503/504）：请求未被后端业务处理，可改发备用地址。
+pub fn is_gateway_error_status(status: u16) -> bool {
+    matches!(status, 502..=504)
+}
+
+/// 依次向各地址（至少一个）发送，首个成功即停止；仅 `fail_over(&err)` 为真时改发下一个地址，
+/// 改发前调用 `on_fail_over(&err, 下一个地址)`。返回最后一次发送的结果。
+pub fn failover<T, E>(
+    urls: &[String],
+    mut send: impl FnMut(&str) -> Result<T, E>,
+    fail_over: impl Fn(&E) -> bool,
+    mut on_fail_over: impl FnMut(&E, &str),
+) -> Result<T, E> {
+    let mut result = send(&urls[0]);
+    for url in &urls[1..] {
+        match &result {
+            Err(err) if fail_over(err) => {
+                on_fail_over(err, url);
+                result = send(url);
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_stops_on_first_success() {
+        let mut calls = 0;
+        let mut failures = Vec::new();
+        let result: Result<u32, &str> = retry(
+            3,
+            |attempt| {
+                calls += 1;
+                if attempt < 2 {
+                    Err("busy")
+                } else {
+                    Ok(attempt)
+                }
+            },
+            |attempt, _| failures.push(attempt),
+        );
+        assert_eq!(result, Ok(2));
+        assert_eq!(calls, 2);
+        assert_eq!(failures, vec![1]);
+    }
+
+    #[test]
+    fn retry_gives_up_after_attempts() {
+        let mut failures = Vec::new();
+        let result: Result<(), u32> = retry(3, Err, |attempt, _| failures.push(attempt));
+        assert_eq!(result, Err(3));
+        assert_eq!(failures, vec![1, 2, 3]);
+        let mut calls = 0;
+        let _: Result<(), ()> = retry(0, |_| Err(()), |_, _| calls += 1);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn failover_urls_skip_empty_or_duplicate_secondary() {
+        let primary = || "http://10.0.0.1".to_string();
+        assert_eq!(failover_urls(primary(), ""), vec![primary()]);
+        assert_eq!(
+            failover_urls(primary(), "http://10.0.0.1/"),
+            vec![primary()]
+        );
+        assert_eq!(
+            failover_urls(primary(), "http://10.0.0.2:8080/"),
+            vec![primary(), "http://10.0.0.2:8080".to_string()]
+        );
+    }
+
+    #[test]
+    fn failover_only_on_retryable_errors() {
+        let urls = vec!["primary".to_string(), "secondary".to_string()];
+        let mut sent = Vec::new();
+        let mut failed_over = Vec::new();
+        let result: Result<&str, &str> = failover(
+            &urls,
+            |url| {
+                sent.push(url.to_string());
+                if url == "primary" {
+                    Err("unreachable")
+                } else {
+                    Ok("stored")
+                }
+            },
+            |err| *err == "unreachable",
+            |_, url| failed_over.push(url.to_string()),
+        );
+        assert_eq!(result, Ok("stored"));
+        assert_eq!(sent, vec!["primary", "secondary"]);
+        assert_eq!(failed_over, vec!["secondary"]);
+        // 主后端返回业务错误时不改发，避免重复提交
+        let mut sent = Vec::new();
+        let result: Result<(), &str> = failover(
+            &urls,
+            |url| {
+                sent.push(url.to_string());
+                Err("rejected")
+            },
+            |err| *err == "unreachable",
+            |_, _| {},
+        );
+        assert_eq!(result, Err("rejected"));
+        assert_eq!(sent, vec!["primary"]);
+    }
+
+    #[test]
+    fn failover_skips_secondary_when_primary_up() {
+        let urls = vec!["primary".to_string(), "secondary".to_string()];
+        let mut sent = Vec::new();
+        let result: Result<&str, &str> = failover(
+            &urls,
+            |url| {
+                sent.push(url.to_string());
+                Ok("stored")
+            },
+            |_| true,
+            |_, _| panic!("should not fail over"),
+        );
+        assert_eq!(result, Ok("stored"));
+        assert_eq!(sent, vec!["primary"]);
+    }
+
+    #[test]
+    fn failover_returns_last_error_when_both_down() {
+        let urls = vec!["primary".to_string(), "secondary".to_string()];
+        let mut sent = Vec::new();
+        let result: Result<(), String> = failover(
+            &urls,
+            |url| {
+                sent.push(url.to_string());
+                Err(format!("{} down", url))
+            },
+            |_| true,
+            |_, _| {},
+        );
+        assert_eq!(result, Err("secondary down".to_string()));
+        assert_eq!(sent, vec!["primary", "secondary"]);
+    }
+
+    #[test]
+    fn only_gateway_errors_allow_failover() {
+        for status in [502, 503, 504] {
+            assert!(is_gateway_error_status(status));
+        }
+        // 其他状态码说明后端可能已处理请求，不改发
+        for status in [400, 404, 409, 500, 501, 505] {
+            assert!(!is_gateway_error_status(status));
+        }
+    }
+}
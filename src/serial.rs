@@ -1,5 +1,10 @@
+use crate::card_data::CARD_DATA_BLOCK_SIZE;
 use crate::proto::{
-    Frame, MSG_CARD_ACK, MSG_CARD_DETECTED, MSG_CARD_WRITE_REQ, MSG_CARD_WRITE_RESULT,
+    Frame, FRAME_VERSION, GATEWAY_CAPABILITIES, MSG_CARD_ACK, MSG_CARD_DETECTED,
+    MSG_ACK_RESEND_REQ, MSG_CARD_WRITE_BATCH_REQ, MSG_CARD_WRITE_BATCH_RESULT, MSG_CARD_WRITE_REQ,
+    MSG_CARD_WRITE_RESULT, MSG_CONFIG_REQUEST, MSG_HEARTBEAT, MSG_HELLO, MSG_HELLO_ACK,
+    MSG_SET_ROUTE_INFO,
+    WRITE_ERR_AUTH, WRITE_ERR_CARD_REMOVED, WRITE_ERR_VERIFY, WRITE_FLAG_READBACK,
 };
 
 /// 读卡器上报的刷卡事件。
@@ -39,16 +44,34 @@ pub struct CardWriteRequest {
     pub card_data: Vec<u8>,
     pub block_start: u8,
     pub block_count: u8,
+    // 要求读卡器写后回传回读数据，由网关比对（需 CAP_WRITE_READBACK）。
+    pub readback: bool,
+    // 按块列表批量下发（需 CAP_BATCH_WRITE），读卡器逐块回传结果。
+    pub batch: bool,
 }
 
 impl CardWriteRequest {
-    /// 编码为串口协议帧。
-    pub fn to_frame(&self) -> Frame {
-        Frame {
-            msg_type: MSG_CARD_WRITE_REQ,
-            flags: 0,
-            payload: encode_card_write_request(self),
-        }
+    /// 编码为串口协议帧；数据长度超出协议长度字段（u8）时返回 None，不截断。
+    pub fn to_frame(&self) -> Option<Frame> {
+        let (msg_type, payload) = if self.batch {
+            (MSG_CARD_WRITE_BATCH_REQ, encode_card_write_batch(self)?)
+        } else {
+            (MSG_CARD_WRITE_REQ, encode_card_write_request(self)?)
+        };
+        Some(Frame {
+            msg_type,
+            flags: if self.readback { WRITE_FLAG_READBACK } else { 0 },
+            payload,
+        })
+    }
+
+    /// 按块拆分为 (块号, 数据) 列表。
+    pub fn blocks(&self) -> Vec<(u8, &[u8])> {
+        self.card_data
+            .chunks(CARD_DATA_BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| (self.block_start.wrapping_add(i as u8), chunk))
+            .collect()
     }
 }
 
@@ -59,13 +82,148 @@ pub struct CardWriteResult {
     pub error_code: u8,
     pub block_start: u8,
     pub block_count: u8,
+    // 回读数据（仅请求回读且读卡器支持时附带，否则为空）。
+    pub readback: Vec<u8>,
+}
+
+impl CardWriteResult {
+    /// 按错误码归类失败原因（成功时无意义）。
+    pub fn error_kind(&self) -> WriteErrorKind {
+        WriteErrorKind::from_code(self.error_code)
+    }
+}
+
+/// 写卡失败原因。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteErrorKind {
+    /// 扇区认证失败（密钥不符），重试无效
+    AuthFailed,
+    /// 写后回读校验不一致
+    VerifyMismatch,
+    /// 写卡过程中卡片离开感应区
+    CardRemoved,
+    /// 未识别的错误码
+    Unknown(u8),
+}
+
+impl WriteErrorKind {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            WRITE_ERR_AUTH => WriteErrorKind::AuthFailed,
+            WRITE_ERR_VERIFY => WriteErrorKind::VerifyMismatch,
+            WRITE_ERR_CARD_REMOVED => WriteErrorKind::CardRemoved,
+            other => WriteErrorKind::Unknown(other),
+        }
+    }
+
+    /// 是否值得重新下发写卡（认证失败与未知错误不重试）。
+    pub fn should_retry(self) -> bool {
+        matches!(self, WriteErrorKind::VerifyMismatch | WriteErrorKind::CardRemoved)
+    }
+
+    /// 司机侧原因说明。
+    pub fn label(self) -> String {
+        match self {
+            WriteErrorKind::AuthFailed => "卡片认证失败".to_string(),
+            WriteErrorKind::VerifyMismatch => "回读校验不一致".to_string(),
+            WriteErrorKind::CardRemoved => "卡片已移开".to_string(),
+            WriteErrorKind::Unknown(code) => format!("错误码 {}", code),
+        }
+    }
+
+    /// 重试时给乘客的提示。
+    pub fn retry_message(self) -> &'static str {
+        match self {
+            WriteErrorKind::CardRemoved => "请将卡片放回读卡区",
+            _ => "写卡重试中，请勿移开卡片",
+        }
+    }
+}
+
+/// 握手信息（协议版本 + 能力位 + 固件版本）。
+#[derive(Clone, Debug)]
+pub struct ReaderHello {
+    pub protocol_version: u8,
+    pub capabilities: u16,
+    pub firmware_version: String,
+}
+
+impl ReaderHello {
+    /// 网关侧发出的握手信息。
+    pub fn gateway() -> Self {
+        Self {
+            protocol_version: FRAME_VERSION,
+            capabilities: GATEWAY_CAPABILITIES,
+            firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// 编码为串口协议帧（网关 -> 读卡器）。
+    pub fn to_frame(&self) -> Frame {
+        Frame {
+            msg_type: MSG_HELLO,
+            flags: 0,
+            payload: encode_hello(self),
+        }
+    }
+}
+
+/// 读卡器请求当前线路信息（读卡器独立重启后用于恢复本地显示）。
+#[derive(Clone, Copy, Debug)]
+pub struct ConfigRequest;
+
+/// 读卡器心跳（回复网关轮询，无载荷）。
+#[derive(Clone, Copy, Debug)]
+pub struct ReaderHeartbeat;
+
+impl ReaderHeartbeat {
+    /// 网关下发的轮询帧（与心跳同类型，读卡器需原样回复）。
+    pub fn poll_frame() -> Frame {
+        Frame {
+            msg_type: MSG_HEARTBEAT,
+            flags: 0,
+            payload: Vec::new(),
+        }
+    }
 }
 
-/// 串口发送命令（ACK 或写卡）。
+/// 读卡器请求重发最近一次 ACK（ACK 帧损坏时避免乘客重刷导致重复扣费）。
+/// 载荷为可选的 `[reader_id:u16]`，为空时重发最近一次 ACK。
+#[derive(Clone, Copy, Debug)]
+pub struct AckResendRequest {
+    pub reader_id: Option<u16>,
+}
+
+/// 下发给读卡器的线路/站点信息。
+#[derive(Clone, Debug)]
+pub struct RouteInfo {
+    pub route_id: u16,
+    pub station_id: u16,
+    // 0 = 上行，1 = 下行。
+    pub direction: u8,
+    pub route_name: String,
+    pub station_name: String,
+}
+
+impl RouteInfo {
+    /// 编码为串口协议帧。
+    pub fn to_frame(&self) -> Frame {
+        Frame {
+            msg_type: MSG_SET_ROUTE_INFO,
+            flags: 0,
+            payload: encode_route_info(self),
+        }
+    }
+}
+
+/// 串口发送命令（ACK、写卡、握手或线路信息）。
 #[derive(Clone, Debug)]
 pub enum SerialCommand {
     Ack(CardAck),
     Write(CardWriteRequest),
+    Hello(ReaderHello),
+    RouteInfo(RouteInfo),
+    Poll,
 }
 
 impl CardAck {
@@ -91,6 +249,29 @@ impl CardAck {
         }
     }
 
+    /// 忽略本次刷卡（不扣费、不提示错误）。
+    pub fn ignored() -> Self {
+        Self {
+            result: 0,
+            beep_pattern: 0,
+            display_code: 0,
+            write_flag: 0,
+            write_data: Vec::new(),
+        }
+    }
+
+    /// 将写卡数据内联到 ACK（需读卡器支持 CAP_INLINE_WRITE）。
+    /// write_data 格式：block_start + block_count + 卡数据。
+    pub fn with_inline_write(mut self, req: &CardWriteRequest) -> Self {
+        let mut data = Vec::with_capacity(2 + req.card_data.len());
+        data.push(req.block_start);
+        data.push(req.block_count);
+        data.extend_from_slice(&req.card_data);
+        self.write_flag = if req.readback { 1 | WRITE_FLAG_READBACK } else { 1 };
+        self.write_data = data;
+        self
+    }
+
     /// 编码为串口协议帧。
     pub fn to_frame(&self) -> Frame {
         Frame {
@@ -102,9 +283,16 @@ impl CardAck {
 }
 
 /// 解码 CARD_DETECTED 载荷。
+///
+/// 字节布局（小端）：
+/// `[id_len:u8][card_id:id_len][tap_time:u32][reader_id:u16][data_len:u16][card_data:data_len]`。
+/// 任一字段被截断或卡号为空时返回 None；card_data 之后的多余字节忽略（留作协议扩展）。
 pub fn decode_card_detected(payload: &[u8]) -> Option<CardDetected> {
     let mut cursor = 0;
     let card_id = read_string(payload, &mut cursor)?;
+    if card_id.is_empty() {
+        return None;
+    }
     let tap_time = read_u32(payload, &mut cursor)? as u64;
     let reader_id = read_u16(payload, &mut cursor)?;
     let card_data = read_bytes(payload, &mut cursor)?;
@@ -153,11 +341,82 @@ pub fn card_ack_from_frame(frame: &Frame) -> Option<CardAck> {
 }
 
 /// 从帧中提取 CardWriteResult。
+/// 批量写卡的逐块结果汇总为一次写卡结果（任一块失败即失败）。
 pub fn card_write_result_from_frame(frame: &Frame) -> Option<CardWriteResult> {
-    if frame.msg_type != MSG_CARD_WRITE_RESULT {
+    match frame.msg_type {
+        MSG_CARD_WRITE_RESULT => decode_card_write_result(&frame.payload),
+        MSG_CARD_WRITE_BATCH_RESULT => decode_card_write_batch_result(&frame.payload),
+        _ => None,
+    }
+}
+
+/// 从帧中提取读卡器握手回复。
+pub fn reader_hello_from_frame(frame: &Frame) -> Option<ReaderHello> {
+    if frame.msg_type != MSG_HELLO_ACK {
         return None;
     }
-    decode_card_write_result(&frame.payload)
+    decode_hello(&frame.payload)
+}
+
+/// 从帧中提取读卡器的线路信息请求（无载荷）。
+pub fn config_request_from_frame(frame: &Frame) -> Option<ConfigRequest> {
+    if frame.msg_type != MSG_CONFIG_REQUEST {
+        return None;
+    }
+    Some(ConfigRequest)
+}
+
+/// 从帧中提取读卡器心跳。
+pub fn reader_heartbeat_from_frame(frame: &Frame) -> Option<ReaderHeartbeat> {
+    if frame.msg_type != MSG_HEARTBEAT {
+        return None;
+    }
+    Some(ReaderHeartbeat)
+}
+
+/// 从帧中提取读卡器的 ACK 重发请求。
+pub fn ack_resend_request_from_frame(frame: &Frame) -> Option<AckResendRequest> {
+    if frame.msg_type != MSG_ACK_RESEND_REQ {
+        return None;
+    }
+    let reader_id = if frame.payload.is_empty() {
+        None
+    } else {
+        Some(read_u16(&frame.payload, &mut 0)?)
+    };
+    Some(AckResendRequest { reader_id })
+}
+
+/// 编码线路信息载荷（线路 ID + 站点 ID + 方向 + 线路名 + 站名）。
+fn encode_route_info(msg: &RouteInfo) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&msg.route_id.to_le_bytes());
+    out.extend_from_slice(&msg.station_id.to_le_bytes());
+    out.push(msg.direction);
+    write_string(&mut out, &msg.route_name);
+    write_string(&mut out, &msg.station_name);
+    out
+}
+
+/// 编码握手载荷（版本 + 能力位 + 固件版本字符串）。
+fn encode_hello(msg: &ReaderHello) -> Vec<u8> {
+    let mut out = vec![msg.protocol_version];
+    out.extend_from_slice(&msg.capabilities.to_le_bytes());
+    write_string(&mut out, &msg.firmware_version);
+    out
+}
+
+/// 解码握手载荷。
+fn decode_hello(payload: &[u8]) -> Option<ReaderHello> {
+    let protocol_version = *payload.first()?;
+    let mut cursor = 1;
+    let capabilities = read_u16(payload, &mut cursor)?;
+    let firmware_version = read_string(payload, &mut cursor)?;
+    Some(ReaderHello {
+        protocol_version,
+        capabilities,
+        firmware_version,
+    })
 }
 
 /// 编码 CardDetected 载荷。
@@ -177,16 +436,64 @@ fn encode_card_ack(msg: &CardAck) -> Vec<u8> {
     out
 }
 
-/// 编码 CardWriteRequest 载荷。
-fn encode_card_write_request(msg: &CardWriteRequest) -> Vec<u8> {
+/// 编码 CardWriteRequest 载荷（数据长度超过 255 字节时返回 None）。
+fn encode_card_write_request(msg: &CardWriteRequest) -> Option<Vec<u8>> {
+    let data_len = u8::try_from(msg.card_data.len()).ok()?;
     let mut out = Vec::new();
     write_string(&mut out, &msg.card_id);
-    let data_len = msg.card_data.len().min(u8::MAX as usize);
-    out.push(data_len as u8);
-    out.extend_from_slice(&msg.card_data[..data_len]);
+    out.push(data_len);
+    out.extend_from_slice(&msg.card_data);
     out.push(msg.block_start);
     out.push(msg.block_count);
-    out
+    Some(out)
+}
+
+/// 编码批量写卡请求：card_id + 块数 + 逐块（块号 + 长度 + 数据）；块数超过 255 时返回 None。
+fn encode_card_write_batch(msg: &CardWriteRequest) -> Option<Vec<u8>> {
+    let blocks = msg.blocks();
+    let count = u8::try_from(blocks.len()).ok()?;
+    let mut out =
+        Vec::with_capacity(2 + msg.card_id.len() + msg.card_data.len() + blocks.len() * 2);
+    write_string(&mut out, &msg.card_id);
+    out.push(count);
+    for (block, data) in &blocks {
+        out.push(*block);
+        out.push(data.len() as u8);
+        out.extend_from_slice(data);
+    }
+    Some(out)
+}
+
+/// 解码 CARD_WRITE_BATCH_RESULT 载荷：块数 + 逐块（块号 + 结果 + 错误码 + 回读长度 + 回读数据）。
+/// 汇总为一次写卡结果：失败时块号与错误码取第一个失败块；回读数据按块拼接。
+fn decode_card_write_batch_result(payload: &[u8]) -> Option<CardWriteResult> {
+    let mut cursor = 0;
+    let count = read_u8(payload, &mut cursor)?;
+    let mut summary = CardWriteResult {
+        result: 1,
+        error_code: 0,
+        block_start: 0,
+        block_count: count,
+        readback: Vec::new(),
+    };
+    for index in 0..count {
+        let block = read_u8(payload, &mut cursor)?;
+        let result = read_u8(payload, &mut cursor)?;
+        let error_code = read_u8(payload, &mut cursor)?;
+        let readback_len = read_u8(payload, &mut cursor)? as usize;
+        let readback = payload.get(cursor..cursor + readback_len)?;
+        cursor += readback_len;
+        if index == 0 {
+            summary.block_start = block;
+        }
+        if result != 1 && summary.result == 1 {
+            summary.result = result;
+            summary.error_code = error_code;
+            summary.block_start = block;
+        }
+        summary.readback.extend_from_slice(readback);
+    }
+    Some(summary)
 }
 
 /// 解码 CARD_WRITE_RESULT 载荷。
@@ -199,13 +506,17 @@ fn decode_card_write_result(payload: &[u8]) -> Option<CardWriteResult> {
         error_code: payload[1],
         block_start: payload[2],
         block_count: payload[3],
+        readback: payload[4..].to_vec(),
     })
 }
 
-/// 写入字符串（u8 长度前缀）。
+/// 写入字符串（u8 长度前缀，超长时按字符边界截断）。
 fn write_string(out: &mut Vec<u8>, value: &str) {
     let bytes = value.as_bytes();
-    let len = bytes.len().min(u8::MAX as usize);
+    let mut len = bytes.len().min(u8::MAX as usize);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
     out.push(len as u8);
     out.extend_from_slice(&bytes[..len]);
 }
@@ -243,6 +554,13 @@ fn read_bytes(data: &[u8], cursor: &mut usize) -> Option<Vec<u8>> {
     Some(value)
 }
 
+/// 读取 u8。
+fn read_u8(data: &[u8], cursor: &mut usize) -> Option<u8> {
+    let value = *data.get(*cursor)?;
+    *cursor += 1;
+    Some(value)
+}
+
 /// 读取 u16（小端）。
 fn read_u16(data: &[u8], cursor: &mut usize) -> Option<u16> {
     if *cursor + 2 > data.len() {
@@ -267,3 +585,248 @@ fn read_u32(data: &[u8], cursor: &mut usize) -> Option<u32> {
     *cursor += 4;
     Some(value)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{
+        Frame, CAP_INLINE_WRITE, CAP_TELEMETRY, MSG_ACK_RESEND_REQ, WRITE_ERR_AUTH,
+        WRITE_ERR_CARD_REMOVED, WRITE_ERR_VERIFY,
+    };
+
+    #[test]
+    fn reader_hello_round_trip() {
+        let hello = ReaderHello {
+            protocol_version: 1,
+            capabilities: CAP_INLINE_WRITE | CAP_TELEMETRY,
+            firmware_version: "rdr-2.1.0".to_string(),
+        };
+        let frame = Frame {
+            msg_type: MSG_HELLO_ACK,
+            flags: 0,
+            payload: encode_hello(&hello),
+        };
+        let decoded = reader_hello_from_frame(&frame).unwrap();
+        assert_eq!(decoded.protocol_version, 1);
+        assert_eq!(decoded.capabilities, CAP_INLINE_WRITE | CAP_TELEMETRY);
+        assert_eq!(decoded.firmware_version, "rdr-2.1.0");
+        // 网关发出的 HELLO 不是握手回复
+        assert!(reader_hello_from_frame(&ReaderHello::gateway().to_frame()).is_none());
+        assert!(decode_hello(&frame.payload[..2]).is_none());
+    }
+
+    #[test]
+    fn inline_write_prefixes_block_range() {
+        let request = CardWriteRequest {
+            card_id: "A1B2C3D4".to_string(),
+            card_data: vec![0xAB; 32],
+            block_start: 8,
+            block_count: 2,
+            readback: false,
+            batch: false,
+        };
+        let ack = CardAck::accepted().with_inline_write(&request);
+        assert_eq!(ack.write_flag, 1);
+        assert_eq!(&ack.write_data[..2], &[8, 2]);
+        assert_eq!(ack.write_data.len(), 34);
+    }
+
+    #[test]
+    fn write_error_codes_are_classified() {
+        assert_eq!(
+            WriteErrorKind::from_code(WRITE_ERR_AUTH),
+            WriteErrorKind::AuthFailed
+        );
+        assert_eq!(
+            WriteErrorKind::from_code(WRITE_ERR_VERIFY),
+            WriteErrorKind::VerifyMismatch
+        );
+        assert_eq!(
+            WriteErrorKind::from_code(WRITE_ERR_CARD_REMOVED),
+            WriteErrorKind::CardRemoved
+        );
+        assert_eq!(
+            WriteErrorKind::from_code(0x7F),
+            WriteErrorKind::Unknown(0x7F)
+        );
+        assert!(!WriteErrorKind::AuthFailed.should_retry());
+        assert!(!WriteErrorKind::Unknown(0x7F).should_retry());
+        assert!(WriteErrorKind::VerifyMismatch.should_retry());
+        assert!(WriteErrorKind::CardRemoved.should_retry());
+        assert_eq!(WriteErrorKind::Unknown(9).label(), "错误码 9");
+    }
+
+    /// 手工拼装 CARD_DETECTED 载荷：u8 长度 + 卡号、u32 tap_time、u16 reader_id、u16 长度 + 卡数据。
+    fn card_detected_frame(card_id: &str, card_data: &[u8]) -> Vec<u8> {
+        let mut out = vec![card_id.len() as u8];
+        out.extend_from_slice(card_id.as_bytes());
+        out.extend_from_slice(&0x6592_5E00u32.to_le_bytes());
+        out.extend_from_slice(&3u16.to_le_bytes());
+        out.extend_from_slice(&(card_data.len() as u16).to_le_bytes());
+        out.extend_from_slice(card_data);
+        out
+    }
+
+    #[test]
+    fn card_detected_full_frame_decodes() {
+        let frame = [
+            0x04, b'A', b'1', b'B', b'2', // 卡号
+            0x00, 0x5E, 0x92, 0x65, // tap_time（小端）
+            0x03, 0x00, // reader_id
+            0x02, 0x00, 0xDE, 0xAD, // 卡数据
+        ];
+        let detected = decode_card_detected(&frame).unwrap();
+        assert_eq!(detected.card_id, "A1B2");
+        assert_eq!(detected.tap_time, 0x6592_5E00);
+        assert_eq!(detected.reader_id, 3);
+        assert_eq!(detected.card_data, vec![0xDE, 0xAD]);
+    }
+
+    #[test]
+    fn card_detected_allows_empty_and_maximal_card_data() {
+        let empty = decode_card_detected(&card_detected_frame("A1B2C3D4", &[])).unwrap();
+        assert!(empty.card_data.is_empty());
+        let max = vec![0x5A; u16::MAX as usize];
+        let full = decode_card_detected(&card_detected_frame("A1B2C3D4", &max)).unwrap();
+        assert_eq!(full.card_data, max);
+    }
+
+    #[test]
+    fn card_detected_rejects_truncation_at_each_field() {
+        let frame = card_detected_frame("A1B2C3D4", &[1, 2, 3]);
+        // 字段边界：卡号长度 | 卡号 | tap_time | reader_id | 数据长度 | 数据
+        let boundaries = [0, 1, 5, 9, 11, 13, 14, 15, 16, 17, 19];
+        for len in boundaries {
+            assert!(decode_card_detected(&frame[..len]).is_none(), "len {}", len);
+        }
+        assert!(decode_card_detected(&frame).is_some());
+        assert!(decode_card_detected(&card_detected_frame("", &[1])).is_none());
+    }
+
+    #[test]
+    fn read_string_checks_bounds() {
+        let mut cursor = 0;
+        assert_eq!(
+            read_string(&[2, b'o', b'k'], &mut cursor).as_deref(),
+            Some("ok")
+        );
+        assert_eq!(cursor, 3);
+        assert!(read_string(&[2, b'o', b'k'], &mut cursor).is_none());
+        let mut cursor = 0;
+        assert!(read_string(&[3, b'o', b'k'], &mut cursor).is_none());
+        let mut cursor = 0;
+        assert_eq!(read_string(&[0], &mut cursor).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn ack_resend_request_parses_optional_reader() {
+        let frame = |msg_type, payload: Vec<u8>| Frame {
+            msg_type,
+            flags: 0,
+            payload,
+        };
+        let any = ack_resend_request_from_frame(&frame(MSG_ACK_RESEND_REQ, Vec::new())).unwrap();
+        assert_eq!(any.reader_id, None);
+        let reader = ack_resend_request_from_frame(&frame(MSG_ACK_RESEND_REQ, vec![2, 0])).unwrap();
+        assert_eq!(reader.reader_id, Some(2));
+        assert!(ack_resend_request_from_frame(&frame(MSG_ACK_RESEND_REQ, vec![2])).is_none());
+        assert!(ack_resend_request_from_frame(&frame(MSG_HELLO, Vec::new())).is_none());
+    }
+
+    #[test]
+    fn write_result_carries_readback_bytes() {
+        let frame = Frame {
+            msg_type: MSG_CARD_WRITE_RESULT,
+            flags: 0,
+            payload: vec![1, 0, 8, 2, 0xAA, 0xBB],
+        };
+        let result = card_write_result_from_frame(&frame).unwrap();
+        assert_eq!(result.readback, vec![0xAA, 0xBB]);
+        let frame = Frame {
+            msg_type: MSG_CARD_WRITE_RESULT,
+            flags: 0,
+            payload: vec![1, 0, 8, 2],
+        };
+        assert!(card_write_result_from_frame(&frame)
+            .unwrap()
+            .readback
+            .is_empty());
+    }
+
+    #[test]
+    fn readback_request_sets_write_flag() {
+        let mut request = CardWriteRequest {
+            card_id: "A1B2C3D4".to_string(),
+            card_data: vec![0; 32],
+            block_start: 8,
+            block_count: 2,
+            readback: true,
+            batch: false,
+        };
+        assert_eq!(request.to_frame().unwrap().flags, WRITE_FLAG_READBACK);
+        request.readback = false;
+        assert_eq!(request.to_frame().unwrap().flags, 0);
+    }
+
+    #[test]
+    fn batch_write_encodes_each_block() {
+        let request = CardWriteRequest {
+            card_id: "A1".to_string(),
+            card_data: [vec![0x11; 16], vec![0x22; 16]].concat(),
+            block_start: 8,
+            block_count: 2,
+            readback: false,
+            batch: true,
+        };
+        let frame = request.to_frame().unwrap();
+        assert_eq!(frame.msg_type, MSG_CARD_WRITE_BATCH_REQ);
+        // 卡号 | 块数 | 块 8 + 长度 + 数据 | 块 9 + 长度 + 数据
+        assert_eq!(&frame.payload[..4], &[2, b'A', b'1', 2]);
+        assert_eq!(&frame.payload[4..6], &[8, 16]);
+        assert_eq!(frame.payload[6..22], [0x11; 16]);
+        assert_eq!(&frame.payload[22..24], &[9, 16]);
+        assert_eq!(frame.payload[24..], [0x22; 16]);
+    }
+
+    #[test]
+    fn batch_result_reports_first_failed_block() {
+        let frame = |payload| Frame {
+            msg_type: MSG_CARD_WRITE_BATCH_RESULT,
+            flags: 0,
+            payload,
+        };
+        let ok = card_write_result_from_frame(&frame(vec![2, 8, 1, 0, 1, 0xAA, 9, 1, 0, 1, 0xBB]))
+            .unwrap();
+        assert_eq!((ok.result, ok.block_start, ok.block_count), (1, 8, 2));
+        assert_eq!(ok.readback, vec![0xAA, 0xBB]);
+        let failed =
+            card_write_result_from_frame(&frame(vec![2, 8, 1, 0, 0, 9, 0, WRITE_ERR_AUTH, 0]))
+                .unwrap();
+        assert_eq!(failed.result, 0);
+        assert_eq!(failed.block_start, 9);
+        assert_eq!(failed.error_code, WRITE_ERR_AUTH);
+        // 块数与实际条目不符时拒绝
+        assert!(card_write_result_from_frame(&frame(vec![2, 8, 1, 0, 0])).is_none());
+    }
+
+    #[test]
+    fn oversized_write_request_is_not_encoded() {
+        let mut request = CardWriteRequest {
+            card_id: "A1B2C3D4".to_string(),
+            card_data: vec![0; 255],
+            block_start: 8,
+            block_count: 16,
+            readback: false,
+            batch: false,
+        };
+        assert!(request.to_frame().is_some());
+        request.card_data.push(0);
+        assert!(request.to_frame().is_none());
+        // 批量写卡按块数计长度：256 块超出 u8
+        request.card_data = vec![0; CARD_DATA_BLOCK_SIZE * 256];
+        request.batch = true;
+        assert!(request.to_frame().is_none());
+        request.card_data.truncate(CARD_DATA_BLOCK_SIZE * 255);
+        assert!(request.to_frame().is_some());
+    }
+}
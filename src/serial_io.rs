@@ -1,7 +1,9 @@
 use crate::proto::{decode_frame, encode_frame, Frame, FrameError, FRAME_HEADER, FRAME_VERSION};
 use crate::serial::{
-    card_detected_from_frame, card_write_result_from_frame, CardAck, CardDetected, CardWriteRequest,
-    CardWriteResult,
+    ack_resend_request_from_frame, card_detected_from_frame, card_write_result_from_frame,
+    config_request_from_frame, reader_heartbeat_from_frame, reader_hello_from_frame,
+    AckResendRequest, CardAck, CardDetected, CardWriteRequest, CardWriteResult, ConfigRequest,
+    ReaderHeartbeat, ReaderHello, RouteInfo,
 };
 use std::sync::mpsc::Sender;
 
@@ -11,6 +13,12 @@ pub struct FrameReader {
     expected_len: Option<usize>,
 }
 
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FrameReader {
     /// 创建新的帧读取器。
     pub fn new() -> Self {
@@ -76,6 +84,12 @@ pub struct SerialFrameCodec {
     reader: FrameReader,
 }
 
+impl Default for SerialFrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SerialFrameCodec {
     /// 创建解码器。
     pub fn new() -> Self {
@@ -95,6 +109,18 @@ impl SerialFrameCodec {
                 if let Some(result) = card_write_result_from_frame(&frame) {
                     return Some(Ok(SerialEvent::CardWriteResult(result)));
                 }
+                if let Some(hello) = reader_hello_from_frame(&frame) {
+                    return Some(Ok(SerialEvent::ReaderHello(hello)));
+                }
+                if let Some(request) = config_request_from_frame(&frame) {
+                    return Some(Ok(SerialEvent::ConfigRequest(request)));
+                }
+                if let Some(request) = ack_resend_request_from_frame(&frame) {
+                    return Some(Ok(SerialEvent::AckResendRequest(request)));
+                }
+                if let Some(heartbeat) = reader_heartbeat_from_frame(&frame) {
+                    return Some(Ok(SerialEvent::ReaderHeartbeat(heartbeat)));
+                }
                 Some(Err(FrameError::BadLength))
             }
             Err(err) => Some(Err(err)),
@@ -106,24 +132,111 @@ impl SerialFrameCodec {
         frame_to_bytes(&ack.to_frame())
     }
 
-    /// 将写卡请求编码为字节序列。
+    /// 将写卡请求编码为字节序列；数据过长无法编码时返回空（不下发）。
     pub fn write_req_to_bytes(req: &CardWriteRequest) -> Vec<u8> {
-        frame_to_bytes(&req.to_frame())
+        match req.to_frame() {
+            Some(frame) => frame_to_bytes(&frame),
+            None => {
+                log::error!("Write request too long: {} bytes", req.card_data.len());
+                Vec::new()
+            }
+        }
+    }
+
+    /// 将握手信息编码为字节序列。
+    pub fn hello_to_bytes(hello: &ReaderHello) -> Vec<u8> {
+        frame_to_bytes(&hello.to_frame())
+    }
+
+    /// 将线路信息编码为字节序列。
+    pub fn route_info_to_bytes(info: &RouteInfo) -> Vec<u8> {
+        frame_to_bytes(&info.to_frame())
+    }
+
+    /// 将轮询帧编码为字节序列。
+    pub fn poll_to_bytes() -> Vec<u8> {
+        frame_to_bytes(&ReaderHeartbeat::poll_frame())
+    }
+}
+
+// 单行 UID 最大长度（超出则丢弃该行）。
+const LINE_UID_MAX_LEN: usize = 64;
+
+/// 行模式解码器：简易读卡器每行输出一个十六进制 UID（如 "A1B2C3D4\r\n"）。
+pub struct LineUidCodec {
+    buffer: Vec<u8>,
+    overflow: bool,
+}
+
+impl Default for LineUidCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineUidCodec {
+    /// 创建行解码器。
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::with_capacity(LINE_UID_MAX_LEN),
+            overflow: false,
+        }
     }
+
+    /// 推入一个字节，遇到换行时返回解析出的 UID（无效行返回 None）。
+    pub fn push_byte(&mut self, byte: u8) -> Option<String> {
+        if byte == b'\n' {
+            let line = if self.overflow {
+                None
+            } else {
+                std::str::from_utf8(&self.buffer).ok().and_then(parse_uid_line)
+            };
+            self.buffer.clear();
+            self.overflow = false;
+            return line;
+        }
+        if self.buffer.len() >= LINE_UID_MAX_LEN {
+            self.overflow = true;
+            return None;
+        }
+        self.buffer.push(byte);
+        None
+    }
+}
+
+/// 解析单行 UID：去除首尾空白与 CR，要求为偶数位十六进制（4~20 字符），统一大写。
+pub fn parse_uid_line(line: &str) -> Option<String> {
+    let uid = line.trim();
+    if uid.len() < 4 || uid.len() > 20 || uid.len() & 1 != 0 {
+        return None;
+    }
+    if !uid.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(uid.to_ascii_uppercase())
 }
 
 /// 串口事件类型。
 pub enum SerialEvent {
     CardDetected(CardDetected),
     CardWriteResult(CardWriteResult),
+    ReaderHello(ReaderHello),
+    ConfigRequest(ConfigRequest),
+    AckResendRequest(AckResendRequest),
+    ReaderHeartbeat(ReaderHeartbeat),
 }
 
 /// 逐字节喂给解码器，解析出事件并发送到通道。
+#[allow(clippy::too_many_arguments)]
 pub fn push_bytes_to_channel(
     codec: &mut SerialFrameCodec,
     bytes: &[u8],
     card_tx: &Sender<CardDetected>,
     write_result_tx: &Sender<CardWriteResult>,
+    hello_tx: &Sender<ReaderHello>,
+    config_req_tx: &Sender<ConfigRequest>,
+    ack_resend_tx: &Sender<AckResendRequest>,
+    heartbeat_tx: &Sender<ReaderHeartbeat>,
 ) {
     for &byte in bytes {
         if let Some(Ok(event)) = codec.push_byte(byte) {
@@ -134,7 +247,137 @@ pub fn push_bytes_to_channel(
                 SerialEvent::CardWriteResult(result) => {
                     let _ = write_result_tx.send(result);
                 }
+                SerialEvent::ReaderHello(hello) => {
+                    let _ = hello_tx.send(hello);
+                }
+                SerialEvent::ConfigRequest(request) => {
+                    let _ = config_req_tx.send(request);
+                }
+                SerialEvent::AckResendRequest(request) => {
+                    let _ = ack_resend_tx.send(request);
+                }
+                SerialEvent::ReaderHeartbeat(heartbeat) => {
+                    let _ = heartbeat_tx.send(heartbeat);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{MSG_CONFIG_REQUEST, MSG_SET_ROUTE_INFO};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn line_codec_parses_uid_lines() {
+        let mut codec = LineUidCodec::new();
+        let mut uids = Vec::new();
+        for &byte in b"a1b2c3d4\r\n\r\n0102030405060708\n" {
+            uids.extend(codec.push_byte(byte));
+        }
+        assert_eq!(
+            uids,
+            vec!["A1B2C3D4".to_string(), "0102030405060708".to_string()]
+        );
+    }
+
+    #[test]
+    fn line_codec_drops_invalid_and_overlong_lines() {
+        let mut codec = LineUidCodec::new();
+        let mut uids = Vec::new();
+        let mut input = vec![b'A'; LINE_UID_MAX_LEN + 10];
+        input.extend_from_slice(b"\nABC\nA1B2C3\nXYZ123\nA1B2C3D4\n");
+        for byte in input {
+            uids.extend(codec.push_byte(byte));
+        }
+        // 溢出行、奇数位与非十六进制行都被丢弃，之后的行正常解析
+        assert_eq!(uids, vec!["A1B2C3".to_string(), "A1B2C3D4".to_string()]);
+    }
+
+    #[test]
+    fn uid_line_length_bounds() {
+        assert_eq!(parse_uid_line(" a1b2 "), Some("A1B2".to_string()));
+        assert_eq!(parse_uid_line(&"AB".repeat(10)), Some("AB".repeat(10)));
+        assert_eq!(parse_uid_line(&"AB".repeat(11)), None);
+        assert_eq!(parse_uid_line("AB"), None);
+    }
+
+    #[test]
+    fn config_request_frame_reaches_channel() {
+        let (card_tx, _card_rx) = channel();
+        let (write_result_tx, _write_result_rx) = channel();
+        let (hello_tx, _hello_rx) = channel();
+        let (config_req_tx, config_req_rx) = channel();
+        let (ack_resend_tx, _ack_resend_rx) = channel();
+        let (heartbeat_tx, _heartbeat_rx) = channel();
+        let bytes = frame_to_bytes(&Frame {
+            msg_type: MSG_CONFIG_REQUEST,
+            flags: 0,
+            payload: Vec::new(),
+        });
+        let mut codec = SerialFrameCodec::new();
+        push_bytes_to_channel(
+            &mut codec,
+            &bytes,
+            &card_tx,
+            &write_result_tx,
+            &hello_tx,
+            &config_req_tx,
+            &ack_resend_tx,
+            &heartbeat_tx,
+        );
+        assert!(config_req_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn route_info_reply_frame_layout() {
+        let info = RouteInfo {
+            route_id: 7,
+            station_id: 2,
+            direction: 1,
+            route_name: "7路".to_string(),
+            station_name: "二站".to_string(),
+        };
+        let bytes = SerialFrameCodec::route_info_to_bytes(&info);
+        let mut reader = FrameReader::new();
+        let frame = bytes
+            .iter()
+            .find_map(|&byte| reader.push(byte))
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.msg_type, MSG_SET_ROUTE_INFO);
+        let mut expected = vec![7, 0, 2, 0, 1];
+        expected.push("7路".len() as u8);
+        expected.extend_from_slice("7路".as_bytes());
+        expected.push("二站".len() as u8);
+        expected.extend_from_slice("二站".as_bytes());
+        assert_eq!(frame.payload, expected);
+    }
+
+    #[test]
+    fn heartbeat_reply_reaches_channel() {
+        let (card_tx, _card_rx) = channel();
+        let (write_result_tx, _write_result_rx) = channel();
+        let (hello_tx, _hello_rx) = channel();
+        let (config_req_tx, _config_req_rx) = channel();
+        let (ack_resend_tx, _ack_resend_rx) = channel();
+        let (heartbeat_tx, heartbeat_rx) = channel();
+        // 读卡器原样回复轮询帧
+        let bytes = SerialFrameCodec::poll_to_bytes();
+        let mut codec = SerialFrameCodec::new();
+        push_bytes_to_channel(
+            &mut codec,
+            &bytes,
+            &card_tx,
+            &write_result_tx,
+            &hello_tx,
+            &config_req_tx,
+            &ack_resend_tx,
+            &heartbeat_tx,
+        );
+        assert!(heartbeat_rx.try_recv().is_ok());
+        assert!(heartbeat_rx.try_recv().is_err());
+    }
+}
@@ -1,18 +1,23 @@
 use std::thread;
 use std::time::{Duration, Instant};
 
-use esp_idf_hal::gpio::OutputPin;
+use esp_idf_hal::gpio::{OutputPin, PinDriver};
 use esp_idf_hal::rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, TxRmtDriver};
 use esp_idf_hal::sys::EspError;
 use esp_idf_hal::{peripheral::Peripheral, rmt::RmtChannel};
 use smart_leds::{RGB8, SmartLedsWrite};
 
+use crate::lock::LockRecover;
 use crate::model::PassengerTone;
+use crate::retry::retry;
 use crate::state::GatewayState;
 use std::sync::{Arc, Mutex};
 
 // 亮度缩放（约 30%）。
 const BRIGHTNESS_SCALE: u8 = 77;
+// RMT 初始化尝试次数与间隔（启动时通道可能暂时被占用）。
+const LED_INIT_ATTEMPTS: u32 = 3;
+const LED_INIT_RETRY_MS: u64 = 200;
 
 /// WS2812 智能灯封装（通过 RMT 发送）。
 pub struct SmartLed<'d> {
@@ -87,6 +92,30 @@ impl SmartLedsWrite for SmartLed<'_> {
     }
 }
 
+/// 灯的输出方式：RMT 彩色灯，或 RMT 不可用时退化为普通 GPIO 亮/灭。
+enum StatusLight<'d, Pin: OutputPin> {
+    Smart(SmartLed<'d>),
+    Plain(PinDriver<'d, Pin, esp_idf_hal::gpio::Output>),
+}
+
+impl<Pin: OutputPin> StatusLight<'_, Pin> {
+    /// 显示提示色；普通 GPIO 只能点亮。
+    fn show(&mut self, tone: PassengerTone) -> Result<(), EspError> {
+        match self {
+            StatusLight::Smart(led) => led.set_color(tone_color(tone)),
+            StatusLight::Plain(pin) => pin.set_high(),
+        }
+    }
+
+    /// 熄灭。
+    fn off(&mut self) -> Result<(), EspError> {
+        match self {
+            StatusLight::Smart(led) => led.set_color(RGB8::default()),
+            StatusLight::Plain(pin) => pin.set_low(),
+        }
+    }
+}
+
 /// 启动灯带任务：根据刷卡状态闪烁颜色。
 pub fn spawn_led_task<C, P, Ch, Pin>(channel: C, pin: P, state: Arc<Mutex<GatewayState>>)
 where
@@ -96,21 +125,48 @@ where
     Pin: OutputPin + Send + 'static,
 {
     thread::spawn(move || {
-        let mut led = match SmartLed::new(channel, pin) {
-            Ok(led) => led,
+        let mut channel = channel;
+        let mut pin = pin;
+        // RMT 初始化失败时重试（通道可能暂时被占用）；探测成功后再正式占用外设
+        let smart_ready = retry(
+            LED_INIT_ATTEMPTS,
+            |_| SmartLed::new(&mut channel, &mut pin).map(|_| ()),
+            |attempt, err| {
+                log::warn!(
+                    "Smart LED init failed (attempt {}/{}): {:?}",
+                    attempt,
+                    LED_INIT_ATTEMPTS,
+                    err
+                );
+                if attempt < LED_INIT_ATTEMPTS {
+                    thread::sleep(Duration::from_millis(LED_INIT_RETRY_MS));
+                }
+            },
+        )
+        .is_ok();
+        // 仍失败则改用普通 GPIO 亮/灭，避免状态灯完全不亮
+        let light = if smart_ready {
+            SmartLed::new(channel, pin).map(StatusLight::Smart)
+        } else {
+            log::warn!("Smart LED unavailable, falling back to plain GPIO");
+            PinDriver::output(pin).map(StatusLight::Plain)
+        };
+        let mut light = match light {
+            Ok(light) => light,
             Err(err) => {
-                log::warn!("Smart LED init failed: {:?}", err);
+                log::warn!("Status LED init failed: {:?}", err);
                 return;
             }
         };
-        let _ = led.set_color(RGB8::default());
+        let _ = light.off();
         let mut last_nonce: u32 = 0;
         let mut last_tone = PassengerTone::Normal;
         let mut led_on = false;
         let mut display_until: Option<Instant> = None;
         loop {
             let mut next_tone = None;
-            if let Ok(state) = state.lock() {
+            {
+                let state = state.lock_recover();
                 let current_tone = state.last_passenger_tone;
                 // 新刷卡触发或提示音改变则更新灯色
                 if state.last_tap_nonce != last_nonce {
@@ -123,8 +179,7 @@ where
                 }
             }
             if let Some(tone) = next_tone {
-                let color = tone_color(tone);
-                if let Err(err) = led.set_color(color) {
+                if let Err(err) = light.show(tone) {
                     log::warn!("Smart LED update failed: {:?}", err);
                 }
                 display_until = Some(Instant::now() + Duration::from_secs(1));
@@ -133,7 +188,7 @@ where
             if led_on {
                 if let Some(until) = display_until {
                     if Instant::now() >= until {
-                        if let Err(err) = led.set_color(RGB8::default()) {
+                        if let Err(err) = light.off() {
                             log::warn!("Smart LED update failed: {:?}", err);
                         }
                         led_on = false;
@@ -1,26 +1,71 @@
+use crate::api::CardProfile;
+use crate::clock::{Clock, SystemClock};
 use crate::cache::{
-    ActiveTripCache, BlacklistCache, CardStateSnapshotCache, ConfigCache, TapDebounce, TapEventCache,
+    ActiveTripCache, BlacklistCache, CardStateSnapshotCache, ConfigCache, TapDebounce, TapDedup,
+    TapEventCache,
 };
-use crate::card_data::{decode_uid_hex, CardData, CardStatus, CARD_DATA_BLOCK_COUNT, CARD_DATA_BLOCK_START, CARD_DATA_LEN};
+use crate::card_data::{decode_uid_hex, gateway_tag, normalize_uid, CardData, CardStatus, CardUid, CARD_DATA_BLOCK_COUNT, CARD_DATA_BLOCK_SIZE, CARD_DATA_BLOCK_START, CARD_DATA_LEN, MAX_DEPOSIT_CENTS};
+use crate::metrics::Metrics;
 use crate::model::{
-    CardRegistration, CardStateSnapshot, Direction, GatewaySettings, PassengerTone, RouteConfig,
-    TapEvent, TapMode, TapType, UploadRecord,
+    CardRegistration, CardStateSnapshot, ConfigUpdateScope, Direction, GatewaySettings,
+    MessageTtlOverride, NoRoutePolicy, PassengerTone, PostTapBlockPolicy, ReaderRole, RepeatBoardPolicy, RouteConfig, SerialMode,
+    SettlementMode,
+    StrayTapOutPolicy, TamperPolicy, TapEvent, TapMode, TapType, UnknownCardTypePolicy,
+    UploadRecord, WarmupPolicy,
 };
-use crate::serial::{CardAck, CardDetected, CardWriteRequest, CardWriteResult};
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::privacy::{display_card_id_with, mask_card_id};
+use crate::proto::{
+    negotiate_capabilities, CAP_BATCH_WRITE, CAP_WRITE_READBACK, GATEWAY_CAPABILITIES,
+    WRITE_ERR_VERIFY,
+};
+use crate::serial::{
+    CardAck, CardDetected, CardWriteRequest, CardWriteResult, ReaderHello, RouteInfo,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 
 // 卡片缓存过期时间（10 分钟）。
 const CARD_CACHE_TTL_MS: u64 = 10 * 60 * 1000;
+// 卡片画像缓存最多条数（满时淘汰最久未更新的条目）。
+pub const CARD_CACHE_MAX: usize = 256;
+// 后端查无此卡的记录有效期（毫秒），期间同卡刷卡不再等待查询结果。
+const LOOKUP_MISS_TTL_MS: u64 = 60 * 1000;
+// 查无此卡记录最多条数（超出后丢弃最早的）。
+const LOOKUP_MISS_MAX: usize = 64;
 const RECHARGE_MODE_TTL_MS: u64 = 60 * 1000;
 const REGISTER_MODE_TTL_MS: u64 = 60 * 1000;
+const BALANCE_OVERRIDE_TTL_MS: u64 = 60 * 1000;
+// 维护模式有效期（毫秒），到期自动退出。
+const MAINTENANCE_MODE_TTL_MS: u64 = 10 * 60 * 1000;
 // 乘客屏消息显示时长（毫秒）。
-// “调高一点”：默认成功提示 2s；错误/写卡失败/注册充值提示 3s。
+// “调高一点”：默认成功提示 2s；错误/写卡失败/注册充值提示 3s（可被网关设置与线路配置覆盖）。
 const PASSENGER_MSG_TTL_OK_MS: u64 = 2000;
 const PASSENGER_MSG_TTL_ACTION_MS: u64 = 3000;
 const PASSENGER_MSG_TTL_ERROR_MS: u64 = 3000;
 const DEFAULT_REGISTER_BALANCE_CENTS: u32 = 0;
 const MAX_RECHARGE_CENTS: u32 = 20_000;
+// 写卡锁最长保持时间（毫秒），防止写卡结果丢失导致卡片一直被锁。
+const WRITE_LOCK_TTL_MS: u64 = 5000;
+// 无线路配置时最多暂存的刷卡数（超出后丢弃最早的）。
+const NO_ROUTE_QUEUE_MAX: usize = 64;
+// 待复位的已清除行程卡号上限。
+const CLEARED_TRIP_CARDS_MAX: usize = 32;
+// 同一张卡在不同读卡器（前后门）上的刷卡在该时间内（毫秒）视为同时刷卡，只处理先到的一次。
+const CROSS_READER_LOCK_MS: u64 = 1500;
+// 早于该时间（2020-09-13）视为尚未校时，不做跨网关时间比对。
+const TIME_SYNCED_MIN_EPOCH: u64 = 1_600_000_000;
+// 刷卡后该时间内（毫秒）后端返回“已冻结”才追溯冲正。
+const POST_TAP_BLOCK_WINDOW_MS: u64 = 60 * 1000;
+// 可重试的写卡失败（卡片移开、回读不符）最多重新下发次数。
+const MAX_WRITE_RETRIES: u8 = 2;
+
+/// 乘客屏提示类别（决定显示时长）。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageKind {
+    Ok,
+    Action,
+    Error,
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum WriteContext {
@@ -29,6 +74,54 @@ enum WriteContext {
     Recharge,
     Register,
     Blacklist,
+    Correction,
+    BalanceOverride,
+}
+
+/// 宽限期内可更正方向的最近一次刷卡。
+#[derive(Clone, Debug)]
+struct DirectionCorrection {
+    card_id: String,
+    record: UploadRecord,
+    direction: Direction,
+    charged_cents: u32,
+    expires_at_ms: u64,
+}
+
+/// 最近一次后端请求失败（供现场安装人员在面板上排查）。
+#[derive(Clone, Debug)]
+pub struct BackendError {
+    // 失败的操作（如“配置同步”）。
+    pub operation: String,
+    pub message: String,
+    pub at_ms: u64,
+}
+
+impl BackendError {
+    /// 面板展示文本，如“配置同步失败: 404”。
+    pub fn summary(&self) -> String {
+        format!("{}失败: {}", self.operation, self.message)
+    }
+}
+
+/// 卡内记录的上次行程（展示用，站名按已同步配置解析，无法解析时为 "#站点ID"）。
+#[derive(Clone, Debug)]
+pub struct CardLastTrip {
+    pub route_id: Option<u16>,
+    pub direction: Option<Direction>,
+    pub board_station: Option<String>,
+    pub alight_station: Option<String>,
+}
+
+/// 最近一次已扣费/开启行程的刷卡（后端随后返回冻结时用于冲正）。
+#[derive(Clone, Debug)]
+struct ChargedTap {
+    card_id: String,
+    record: UploadRecord,
+    charged_cents: u32,
+    // 本次刷卡写入后的卡内数据。
+    card_data: CardData,
+    at_ms: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -42,6 +135,13 @@ pub struct RegisterMode {
     pub expires_at_ms: u64,
 }
 
+/// 维护用：下一张卡强制写入指定余额（绕过票价逻辑）。
+#[derive(Clone, Debug)]
+pub struct BalanceOverrideMode {
+    pub balance_cents: u32,
+    pub expires_at_ms: u64,
+}
+
 /// 卡片缓存的用户画像（票种/状态/优惠）。
 #[derive(Clone, Debug)]
 pub struct CachedCardProfile {
@@ -77,37 +177,107 @@ impl RouteState {
 /// 网关全局状态（缓存、健康、上次刷卡等）。
 pub struct GatewayState {
     pub settings: GatewaySettings,
+    // 时间来源（默认系统时钟，测试时可替换）。
+    pub clock: Arc<dyn Clock>,
     pub route_state: RouteState,
     pub config_cache: ConfigCache,
     pub blacklist_cache: BlacklistCache,
     pub tap_cache: TapEventCache,
     pub debounce: TapDebounce,
+    pub tap_dedup: TapDedup,
     pub active_trips: ActiveTripCache,
     pub wifi_connected: bool,
+    // 启动时 Wi-Fi 已尝试连接的次数。
+    pub wifi_connect_attempts: u32,
     pub backend_reachable: bool,
+    // 后端请求连续失败/成功次数（达到阈值才切换可达状态，避免界面闪烁）。
+    pub backend_fail_streak: u32,
+    pub backend_ok_streak: u32,
     pub backend_base_url: String,
+    pub last_backend_error: Option<BackendError>,
+    // 最近一次上报成功（后端 2xx）的时间（毫秒），用于发现“可达但数据未送达”。
+    pub last_successful_upload_ms: Option<u64>,
+    // 网络任务缓冲中待上传的记录数与最早一条的等待时长（秒），由网络任务定期更新。
+    pub upload_queue_len: usize,
+    pub upload_oldest_age_secs: Option<u64>,
     pub last_card_id: String,
     pub last_card_data_len: usize,
     pub last_card_data_prefix_hex: Option<String>,
     pub last_card_data_error: Option<String>,
     pub last_tap_nonce: u32,
+    // 最近一次刷卡或司机操作的时间（毫秒），用于停运待机判断。
+    pub last_activity_ms: u64,
     pub last_message_deadline_ms: u64,
     pub last_passenger_tone: PassengerTone,
     pub last_passenger_message: String,
-    pub last_fare_base: Option<f32>,
-    pub last_fare: Option<f32>,
+    // 本次票价（分）：折扣前基准价与实际应付，金额全程以分计算，显示时再换算为元。
+    pub last_fare_base_cents: Option<u32>,
+    pub last_fare_cents: Option<u32>,
     pub last_fare_label: String,
     // 最近一次从“卡内数据”读到的余额（不经过后端校验）。
     // 注意：如果本次刷卡卡内数据无效（读不出/UID 不匹配），这里会是 None。
     pub last_balance_cents: Option<u32>,
+    // 最近一次刷卡读到的卡内上次行程（本次刷卡前）。
+    pub last_card_trip: Option<CardLastTrip>,
     pub last_tap_type: Option<TapType>,
+    // 最近一次下车对应的上车站与乘车时长（秒，仅网关有上车记录时可知），用于漏刷争议核对。
+    pub last_board_station: Option<String>,
+    pub last_trip_elapsed_secs: Option<u64>,
     pub card_cache: HashMap<String, CachedCardProfile>,
+    // 后端查无此卡的卡号与查询时间（毫秒）。
+    card_lookup_misses: VecDeque<(String, u64)>,
     pub card_state_cache: CardStateSnapshotCache,
     pub recharge_mode: Option<RechargeMode>,
+    // 司机设置的一次性手动票价（分），下一次扣费刷卡使用后清除。
+    pub next_fare_override_cents: Option<u32>,
     pub register_mode: Option<RegisterMode>,
+    // 维护模式截止时间（毫秒），强制写余额等维护操作仅在此期间可用。
+    pub maintenance_until_ms: Option<u64>,
+    pub balance_override: Option<BalanceOverrideMode>,
+    // 充值时因“行程中”被拒的卡，以及司机确认后待强制清除行程的卡。
+    pub stuck_trip_card: Option<String>,
+    // 被拒卡变化时递增：界面提交脱敏卡号时据此确认仍是司机看到的那张卡。
+    pub stuck_trip_seq: u32,
+    pub force_clear_card: Option<String>,
+    // 司机通过 /trips 清除的卡住行程，下次刷卡时复位卡内行程状态。
+    pub cleared_trip_cards: Vec<String>,
+    // 启动后首次配置同步是否已完成（无论成功与否），之前为预热期。
+    pub initial_sync_done: bool,
+    // 写卡请求下发数与写卡结果回传数（用于发现读卡器丢写）。
+    pub writes_emitted: u64,
+    pub write_results_received: u64,
+    // 网络中断期间因上传缓冲已满而丢弃的记录数。
+    pub upload_dropped: u64,
+    pub metrics: Metrics,
+    // 读卡器握手信息与协商后的能力位（未握手时为 0，按旧协议工作）。
+    pub reader_hello: Option<ReaderHello>,
+    pub reader_capabilities: u16,
+    // 已下发但未收到回复的轮询数；连续未回复达到阈值时判定读卡器离线。
+    pub reader_polls_unanswered: u32,
+    pub reader_offline: bool,
     last_write_context: Option<WriteContext>,
+    // 最近一次写卡请求及已重试次数（可重试的失败时重新下发）。
+    last_write_request: Option<CardWriteRequest>,
+    write_retries: u8,
     // 保存最近一次写卡时的新余额，用于在写卡成功后更新显示
     last_written_balance_cents: Option<u32>,
+    // 已下发写卡、尚未收到结果的卡（按下发顺序，附下发时间毫秒）。
+    pending_write_cards: VecDeque<(String, u64)>,
+    // 最近被处理的刷卡（卡号、读卡器、时间毫秒），用于串行化前后门同时刷同一张卡。
+    reader_tap_locks: VecDeque<(String, u16, u64)>,
+    // 各读卡器最近一次下发的 ACK（按下发先后排列，末尾最新）。
+    last_acks: Vec<(u16, CardAck)>,
+    // 无线路配置时暂存的刷卡（记录 ID、卡号、刷卡时间），线路同步后上报。
+    no_route_taps: VecDeque<(String, String, u64)>,
+    // 最近一次可更正方向的刷卡（司机选错方向时同卡再刷即可更正）。
+    last_correctable_tap: Option<DirectionCorrection>,
+    last_charged_tap: Option<ChargedTap>,
+    // 待处理器线程下发的冲正（写卡冻结 + 冲正记录）。
+    pending_reversal: Option<Decision>,
+    // 最近一次下车结算的卡及误刷忽略截止时间（毫秒）。
+    recent_tap_out: Option<(String, u64)>,
+    // 司机手动切站后，自动报站暂停到该时间点（毫秒）。
+    auto_advance_paused_until_ms: u64,
     record_seq: u32,
 }
 
@@ -132,17 +302,29 @@ impl GatewayState {
         active_trips: ActiveTripCache,
     ) -> Self {
         let tap_cache_max = settings.tap_cache_max;
+        let tap_dedup = TapDedup::new(settings.dedup_window_ms);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
         Self {
             settings,
+            last_activity_ms: clock.now_millis(),
+            clock,
             route_state,
             config_cache,
             blacklist_cache,
             tap_cache,
             debounce,
+            tap_dedup,
             active_trips,
             wifi_connected: false,
+            wifi_connect_attempts: 0,
             backend_reachable: false,
+            backend_fail_streak: 0,
+            backend_ok_streak: 0,
             backend_base_url: String::new(),
+            last_backend_error: None,
+            last_successful_upload_ms: None,
+            upload_queue_len: 0,
+            upload_oldest_age_secs: None,
             last_card_id: String::new(),
             last_card_data_len: 0,
             last_card_data_prefix_hex: None,
@@ -151,17 +333,48 @@ impl GatewayState {
             last_message_deadline_ms: 0,
             last_passenger_tone: PassengerTone::Normal,
             last_passenger_message: "等待刷卡".to_string(),
-            last_fare_base: None,
-            last_fare: None,
+            last_fare_base_cents: None,
+            last_fare_cents: None,
             last_fare_label: "应付".to_string(),
             last_balance_cents: None,
+            last_card_trip: None,
             last_tap_type: None,
+            last_board_station: None,
+            last_trip_elapsed_secs: None,
             card_cache: HashMap::new(),
+            card_lookup_misses: VecDeque::new(),
             card_state_cache: CardStateSnapshotCache::new(tap_cache_max),
             recharge_mode: None,
+            next_fare_override_cents: None,
             register_mode: None,
+            maintenance_until_ms: None,
+            balance_override: None,
+            stuck_trip_card: None,
+            stuck_trip_seq: 0,
+            force_clear_card: None,
+            cleared_trip_cards: Vec::new(),
+            initial_sync_done: false,
+            writes_emitted: 0,
+            write_results_received: 0,
+            upload_dropped: 0,
+            metrics: Metrics::default(),
+            reader_hello: None,
+            reader_capabilities: 0,
+            reader_polls_unanswered: 0,
+            reader_offline: false,
             last_write_context: None,
+            last_write_request: None,
+            write_retries: 0,
             last_written_balance_cents: None,
+            pending_write_cards: VecDeque::new(),
+            reader_tap_locks: VecDeque::new(),
+            last_acks: Vec::new(),
+            no_route_taps: VecDeque::new(),
+            last_correctable_tap: None,
+            last_charged_tap: None,
+            pending_reversal: None,
+            recent_tap_out: None,
+            auto_advance_paused_until_ms: 0,
             record_seq: 0,
         }
     }
@@ -180,6 +393,60 @@ impl GatewayState {
         )
     }
 
+    /// 替换时间来源。
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        // 空闲计时从替换后的时钟起算
+        self.last_activity_ms = clock.now_millis();
+        self.clock = clock;
+        self
+    }
+
+    /// 当前时间戳（毫秒）。
+    pub fn now_millis(&self) -> u64 {
+        self.clock.now_millis()
+    }
+
+    /// 当前时间戳（秒）。
+    pub fn now_secs(&self) -> u64 {
+        self.clock.now_secs()
+    }
+
+    /// 首次配置同步结束（成功或失败），退出预热期。
+    pub fn mark_initial_sync_done(&mut self) {
+        if !self.initial_sync_done {
+            self.initial_sync_done = true;
+            log::info!("Initial config sync finished, warm-up over");
+        }
+    }
+
+    /// 是否处于预热期（策略为 Off 时始终为 false）。
+    pub fn warming_up(&self) -> bool {
+        !self.initial_sync_done && self.settings.warmup_policy != WarmupPolicy::Off
+    }
+
+    /// 记录刷卡或司机操作，退出停运待机。
+    pub fn touch_activity(&mut self, now_ms: u64) {
+        self.last_activity_ms = now_ms;
+    }
+
+    /// 是否处于停运待机（长时间无刷卡、无司机操作）。
+    pub fn is_idle(&self, now_ms: u64) -> bool {
+        let timeout_ms = self.settings.idle_timeout_secs as u64 * 1000;
+        timeout_ms > 0 && now_ms.saturating_sub(self.last_activity_ms) >= timeout_ms
+    }
+
+    /// 日志/界面展示用卡号（按 settings.mask_card_ids 脱敏，上报仍用完整卡号）。
+    pub fn display_card_id(&self, card_id: &str) -> String {
+        display_card_id_with(card_id, self.settings.mask_card_ids)
+    }
+
+    /// 本地时间 HH:MM（未校时返回 None）。
+    pub fn local_time_label(&self) -> Option<String> {
+        let now = self.now_secs();
+        (now >= TIME_SYNCED_MIN_EPOCH)
+            .then(|| crate::clock::format_local_time(now, self.settings.tz_offset_minutes))
+    }
+
     pub fn update_route(
         &mut self,
         route_id: u16,
@@ -194,8 +461,16 @@ impl GatewayState {
         self.route_state.direction = direction;
     }
 
-    pub fn update_route_config(&mut self, config: RouteConfig, now: u64) {
+    pub fn update_route_config(&mut self, mut config: RouteConfig, now: u64) {
         let route_id = config.route_id;
+        if config.normalize_station_sequences() {
+            log::warn!("Duplicate station sequences renumbered: route={}", route_id);
+        } else if config.has_sequence_gaps() {
+            log::warn!("Station sequences have gaps: route={}", route_id);
+        }
+        if config.clamp_negative_prices() {
+            log::warn!("Negative fare prices clamped to 0: route={}", route_id);
+        }
         let station_ids: Vec<u16> = config.stations.iter().map(|s| s.id).collect();
         self.config_cache.update(config.clone(), now);
 
@@ -220,6 +495,31 @@ impl GatewayState {
         }
     }
 
+    /// 按更新范围合并线路配置：仅票价变化时保留站点（不影响当前站点选择），
+    /// 仅站点变化时保留票价规则；线路不同或无本地配置时整体替换。
+    pub fn merge_route_config(&mut self, config: RouteConfig, scope: ConfigUpdateScope, now: u64) {
+        let cached = self
+            .config_cache
+            .route
+            .as_ref()
+            .filter(|cached| cached.route_id == config.route_id);
+        let merged = match (scope, cached) {
+            (ConfigUpdateScope::Fares, Some(cached)) => RouteConfig {
+                stations: cached.stations.clone(),
+                ..config
+            },
+            (ConfigUpdateScope::Stations, Some(cached)) => RouteConfig {
+                fare_type: cached.fare_type,
+                settlement_mode: cached.settlement_mode,
+                max_fare: cached.max_fare,
+                fares: cached.fares.clone(),
+                ..config
+            },
+            _ => config,
+        };
+        self.update_route_config(merged, now);
+    }
+
     pub fn set_direction(&mut self, direction: Direction) {
         self.route_state.direction = direction;
     }
@@ -229,11 +529,22 @@ impl GatewayState {
         self.blacklist_cache.replace(cards, now);
     }
 
+    /// 更新本地导入的黑名单（与后端名单合并生效）。
+    pub fn update_local_blacklist(&mut self, cards: Vec<String>) {
+        self.blacklist_cache.replace_local(cards);
+    }
+
     /// 更新后端基础 URL。
     pub fn update_backend_base_url(&mut self, url: String) {
         self.backend_base_url = url;
     }
 
+    /// 更新待上传队列深度与最早记录等待时长。
+    pub fn update_upload_queue(&mut self, len: usize, oldest_age_secs: Option<u64>) {
+        self.upload_queue_len = len;
+        self.upload_oldest_age_secs = oldest_age_secs;
+    }
+
     /// 更新网络健康状态。
     pub fn update_health(&mut self, wifi_connected: Option<bool>, backend_reachable: Option<bool>) {
         if let Some(connected) = wifi_connected {
@@ -244,6 +555,27 @@ impl GatewayState {
         }
     }
 
+    /// 记录一次后端请求结果：连续失败/成功达到阈值后才切换可达状态。
+    pub fn record_backend_result(&mut self, ok: bool) {
+        if ok {
+            self.backend_fail_streak = 0;
+            self.backend_ok_streak = self.backend_ok_streak.saturating_add(1);
+            if !self.backend_reachable
+                && self.backend_ok_streak >= self.settings.backend_reachable_after.max(1)
+            {
+                self.backend_reachable = true;
+            }
+        } else {
+            self.backend_ok_streak = 0;
+            self.backend_fail_streak = self.backend_fail_streak.saturating_add(1);
+            if self.backend_reachable
+                && self.backend_fail_streak >= self.settings.backend_unreachable_after.max(1)
+            {
+                self.backend_reachable = false;
+            }
+        }
+    }
+
     pub fn set_recharge_mode(&mut self, amount_cents: u32, now_ms: u64) {
         if amount_cents == 0 || amount_cents > MAX_RECHARGE_CENTS {
             return;
@@ -255,8 +587,66 @@ impl GatewayState {
         });
     }
 
+    /// 司机确认强制清除行程（仅限充值被拒的同一张卡），下次刷该卡时生效。
+    pub fn arm_force_clear_trip(&mut self, card_id: &str, seq: Option<u32>) -> bool {
+        let Some(stuck) = self.stuck_trip_card.as_deref() else {
+            return false;
+        };
+        // 界面开启卡号脱敏时，司机提交的是脱敏后的卡号；末 4 位相同的其他卡
+        // 可能已替换被拒卡，须同时核对界面显示时的序号
+        let matched = stuck == card_id
+            || (mask_card_id(stuck) == card_id && seq == Some(self.stuck_trip_seq));
+        if !matched {
+            return false;
+        }
+        self.force_clear_card = Some(stuck.to_string());
+        true
+    }
+
+    /// 司机清除指定的未完成行程，返回完整卡号。`trip` 可为上车记录号或完整卡号；
+    /// 脱敏卡号仅在唯一匹配时接受，末 4 位相同的多个行程须改用记录号。
+    pub fn clear_active_trip(&mut self, trip: &str) -> Option<String> {
+        let now = self.now_secs();
+        let trips = self.active_trips.list(now);
+        let exact = trips.iter().find(|t| t.record_id == trip || t.card_id == trip);
+        let full_id = match exact {
+            Some(t) => t.card_id.clone(),
+            None => {
+                let mut masked = trips.iter().filter(|t| mask_card_id(&t.card_id) == trip);
+                match (masked.next(), masked.next()) {
+                    (Some(t), None) => t.card_id.clone(),
+                    (Some(_), Some(_)) => {
+                        log::warn!("Clear trip ambiguous: {} matches several trips", trip);
+                        return None;
+                    }
+                    _ => return None,
+                }
+            }
+        };
+        let _ = self.active_trips.take(&full_id, now);
+        if !self.cleared_trip_cards.contains(&full_id) {
+            if self.cleared_trip_cards.len() >= CLEARED_TRIP_CARDS_MAX {
+                self.cleared_trip_cards.remove(0);
+            }
+            self.cleared_trip_cards.push(full_id.clone());
+        }
+        log::warn!("Active trip cleared by driver: card={}", self.display_card_id(&full_id));
+        Some(full_id)
+    }
+
+    /// 取出待复位的已清除行程卡号（命中即移除）。
+    fn take_cleared_trip(&mut self, card_id: &str) -> bool {
+        let Some(pos) = self.cleared_trip_cards.iter().position(|id| id == card_id) else {
+            return false;
+        };
+        self.cleared_trip_cards.remove(pos);
+        true
+    }
+
     pub fn clear_recharge_mode(&mut self) {
         self.recharge_mode = None;
+        self.stuck_trip_card = None;
+        self.force_clear_card = None;
     }
 
     pub fn set_register_mode(&mut self, now_ms: u64) {
@@ -270,10 +660,48 @@ impl GatewayState {
         self.register_mode = None;
     }
 
+    /// 进入维护模式（限时）。
+    pub fn set_maintenance_mode(&mut self, now_ms: u64) {
+        self.maintenance_until_ms = Some(now_ms.saturating_add(MAINTENANCE_MODE_TTL_MS));
+        log::warn!("Maintenance mode enabled");
+    }
+
+    pub fn clear_maintenance_mode(&mut self) {
+        self.maintenance_until_ms = None;
+        self.balance_override = None;
+    }
+
+    pub fn maintenance_active(&self, now_ms: u64) -> bool {
+        self.maintenance_until_ms.is_some_and(|until| now_ms < until)
+    }
+
+    /// 下一张卡强制写入指定余额；仅维护模式下可用，且不超过余额上限。
+    pub fn set_balance_override(&mut self, balance_cents: u32, now_ms: u64) -> bool {
+        if !self.maintenance_active(now_ms) || balance_cents > self.settings.max_balance_cents {
+            return false;
+        }
+        self.recharge_mode = None;
+        self.register_mode = None;
+        self.balance_override = Some(BalanceOverrideMode {
+            balance_cents,
+            expires_at_ms: now_ms.saturating_add(BALANCE_OVERRIDE_TTL_MS),
+        });
+        log::warn!("Balance override armed: {} cents", balance_cents);
+        true
+    }
+
     fn refresh_modes(&mut self, now_ms: u64) {
+        if self.maintenance_until_ms.is_some() && !self.maintenance_active(now_ms) {
+            self.clear_maintenance_mode();
+        }
+        if let Some(mode) = &self.balance_override {
+            if now_ms >= mode.expires_at_ms {
+                self.balance_override = None;
+            }
+        }
         if let Some(mode) = &self.recharge_mode {
             if now_ms >= mode.expires_at_ms {
-                self.recharge_mode = None;
+                self.clear_recharge_mode();
             }
         }
         if let Some(mode) = &self.register_mode {
@@ -283,29 +711,312 @@ impl GatewayState {
         }
     }
 
-    pub fn handle_write_result(&mut self, result: CardWriteResult, now_ms: u64) {
+    /// 当前线路信息（回复读卡器的线路信息请求）。
+    pub fn route_info(&self) -> RouteInfo {
+        RouteInfo {
+            route_id: self.route_state.route_id,
+            station_id: self.route_state.station_id,
+            direction: match self.route_state.direction {
+                Direction::Up => 0,
+                Direction::Down => 1,
+            },
+            route_name: self
+                .config_cache
+                .route
+                .as_ref()
+                .map(|cfg| cfg.route_name.clone())
+                .unwrap_or_default(),
+            station_name: self.route_state.station_name.clone(),
+        }
+    }
+
+    /// 记录读卡器握手回复并协商能力。
+    pub fn update_reader_hello(&mut self, hello: ReaderHello) {
+        self.reader_capabilities = negotiate_capabilities(GATEWAY_CAPABILITIES, hello.capabilities);
+        self.reader_hello = Some(hello);
+    }
+
+    /// 记录一次轮询下发；此前的轮询已连续未回复达到阈值时判定离线，返回是否刚转为离线。
+    pub fn record_reader_poll(&mut self) -> bool {
+        let went_offline = !self.reader_offline
+            && self.reader_polls_unanswered >= self.settings.reader_poll_max_missed.max(1);
+        if went_offline {
+            self.reader_offline = true;
+        }
+        self.reader_polls_unanswered = self.reader_polls_unanswered.saturating_add(1);
+        went_offline
+    }
+
+    /// 收到读卡器心跳：清零未回复计数，离线时恢复在线。
+    pub fn record_reader_heartbeat(&mut self) {
+        self.reader_polls_unanswered = 0;
+        if self.reader_offline {
+            self.reader_offline = false;
+            log::info!("Reader back online");
+        }
+    }
+
+    /// 协商结果是否包含指定能力。
+    pub fn reader_supports(&self, capability: u16) -> bool {
+        self.reader_capabilities & capability != 0
+    }
+
+    /// 已下发但尚未收到结果的写卡数。
+    pub fn pending_write_count(&self) -> u64 {
+        self.writes_emitted.saturating_sub(self.write_results_received)
+    }
+
+    /// 未确认写卡数是否超过阈值（需提示运营人员检查读卡器）。
+    pub fn write_unconfirmed(&self) -> bool {
+        self.pending_write_count() > self.settings.write_unconfirmed_threshold as u64
+    }
+
+    /// 该卡是否仍有未完成的写卡（超时的写卡锁自动释放）。
+    pub fn write_pending_for(&mut self, card_id: &str, now_ms: u64) -> bool {
+        self.pending_write_cards
+            .retain(|(_, issued_ms)| now_ms.saturating_sub(*issued_ms) < WRITE_LOCK_TTL_MS);
+        self.pending_write_cards.iter().any(|(id, _)| id == card_id)
+    }
+
+    /// 读卡器时钟偏差过大（如 1970/2099 年）时以网关时间为准；网关未校时不做判断。
+    fn correct_tap_time(&self, detected: &mut CardDetected, now: u64) {
+        let max_skew = self.settings.max_tap_time_skew_secs as u64;
+        if max_skew == 0 || now < TIME_SYNCED_MIN_EPOCH {
+            return;
+        }
+        if detected.tap_time.abs_diff(now) > max_skew {
+            log::warn!(
+                "Reader clock skew: reader={} tap_time={} gateway={}, using gateway time",
+                detected.reader_id,
+                detected.tap_time,
+                now
+            );
+            detected.tap_time = now;
+        }
+    }
+
+    /// 该卡是否刚在另一读卡器上被处理（前后门同时刷卡）；未锁定时为本读卡器加锁。
+    /// 不依赖写卡锁：行模式读卡器或无写卡的刷卡同样串行化。
+    fn card_locked_by_other_reader(&mut self, card_id: &str, reader_id: u16, now_ms: u64) -> bool {
+        self.reader_tap_locks
+            .retain(|(_, _, seen_ms)| now_ms.saturating_sub(*seen_ms) < CROSS_READER_LOCK_MS);
+        if self
+            .reader_tap_locks
+            .iter()
+            .any(|(id, reader, _)| id == card_id && *reader != reader_id)
+        {
+            return true;
+        }
+        self.reader_tap_locks.retain(|(id, _, _)| id != card_id);
+        self.reader_tap_locks.push_back((card_id.to_string(), reader_id, now_ms));
+        false
+    }
+
+    /// 记录下发给读卡器的 ACK。
+    pub fn remember_ack(&mut self, reader_id: u16, ack: CardAck) {
+        self.last_acks.retain(|(id, _)| *id != reader_id);
+        self.last_acks.push((reader_id, ack));
+    }
+
+    /// 指定读卡器最近一次的 ACK（未指定读卡器时取最新一条）。
+    pub fn last_ack(&self, reader_id: Option<u16>) -> Option<CardAck> {
+        match reader_id {
+            Some(reader_id) => self
+                .last_acks
+                .iter()
+                .find(|(id, _)| *id == reader_id)
+                .map(|(_, ack)| ack.clone()),
+            None => self.last_acks.last().map(|(_, ack)| ack.clone()),
+        }
+    }
+
+    /// 撤销最近一次写卡的跟踪（读卡器不支持写卡时，写卡请求不会下发）。
+    pub fn cancel_last_write(&mut self) {
+        self.writes_emitted = self.writes_emitted.saturating_sub(1);
+        self.pending_write_cards.pop_back();
+        self.last_write_context = None;
+        self.last_write_request = None;
+        // 不会写卡，按计算后的余额直接更新显示
+        if let Some(new_balance) = self.last_written_balance_cents.take() {
+            self.last_balance_cents = Some(new_balance);
+        }
+    }
+
+    /// 写卡数据过长无法编码：撤销写卡跟踪并提示“写卡数据过长”。
+    pub fn reject_oversized_write(&mut self) -> Decision {
+        let len = self.last_write_request.as_ref().map_or(0, |req| req.card_data.len());
+        log::error!("Refusing truncated write request: {} bytes", len);
+        // 卡未写入上车状态，撤销本次上车新开的行程
+        if self.last_write_context == Some(WriteContext::TapIn) {
+            if let Some(card_id) = self.last_write_request.as_ref().map(|req| req.card_id.clone()) {
+                let now = self.now_secs();
+                let _ = self.active_trips.take(&card_id, now);
+            }
+        }
+        // 卡未写入，余额显示保持读卡时的值
+        self.last_written_balance_cents = None;
+        self.cancel_last_write();
+        let now_ms = self.now_millis();
+        self.reject_card("写卡数据过长", now_ms)
+    }
+
+    pub fn handle_write_result(
+        &mut self,
+        mut result: CardWriteResult,
+        now_ms: u64,
+    ) -> Option<CardWriteRequest> {
+        // 网关侧回读校验：读卡器报成功但回读数据与写入不一致，按校验失败处理（可重试）
+        let readback_failed = result.result == 1 && self.readback_mismatch(&result);
+        if readback_failed {
+            log::warn!("Card write readback mismatch");
+            result.result = 0;
+            result.error_code = WRITE_ERR_VERIFY;
+        }
+        self.write_results_received = self
+            .write_results_received
+            .saturating_add(1)
+            .min(self.writes_emitted);
+        // 写卡结果按下发顺序回传，释放最早的写卡锁
+        self.pending_write_cards.pop_front();
         let context = self.last_write_context.take();
         if result.result == 1 {
             // 写卡成功，更新显示的余额为刚刚写入的新余额
             if let Some(new_balance) = self.last_written_balance_cents.take() {
                 self.last_balance_cents = Some(new_balance);
             }
-            if matches!(context, Some(WriteContext::Recharge)) {
-                self.recharge_mode = None;
+            match context {
+                Some(WriteContext::Recharge) => self.recharge_mode = None,
+                Some(WriteContext::BalanceOverride) => self.balance_override = None,
+                // 单次模式：注册成功一张卡后退出，避免误注册下一位乘客的卡
+                Some(WriteContext::Register) if self.settings.one_shot_modes => {
+                    self.register_mode = None;
+                }
+                _ => {}
+            }
+            self.last_write_request = None;
+            return None;
+        }
+        let kind = result.error_kind();
+        log::warn!("Card write failed: {}", kind.label());
+        // 可重试的失败：重新下发同一写卡请求并恢复跟踪
+        if kind.should_retry() && self.write_retries < MAX_WRITE_RETRIES {
+            if let (Some(request), Some(context)) = (self.last_write_request.clone(), context) {
+                self.write_retries += 1;
+                self.last_write_context = Some(context);
+                self.writes_emitted = self.writes_emitted.saturating_add(1);
+                self.pending_write_cards.push_back((request.card_id.clone(), now_ms));
+                self.last_passenger_tone = PassengerTone::Error;
+                self.last_passenger_message = kind.retry_message().to_string();
+                self.last_message_deadline_ms = self.message_deadline(MessageKind::Error, now_ms);
+                return Some(request);
             }
-            return;
         }
         // 写卡失败，清除保存的余额
         self.last_written_balance_cents = None;
+        self.last_write_request = None;
         let message = match context {
             Some(WriteContext::Recharge) => "充值写卡失败",
             Some(WriteContext::Register) => "注册写卡失败",
             Some(WriteContext::Blacklist) => "冻结写卡失败",
+            Some(WriteContext::BalanceOverride) => "余额写卡失败",
+            _ if readback_failed => "写卡校验失败",
             _ => "写卡失败",
         };
         self.last_passenger_tone = PassengerTone::Error;
-        self.last_passenger_message = message.to_string();
-        self.last_message_deadline_ms = now_ms.saturating_add(PASSENGER_MSG_TTL_ERROR_MS);
+        self.last_passenger_message = format!("{}（{}）", message, kind.label());
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Error, now_ms);
+        None
+    }
+
+    /// 写卡回读数据是否与写入数据不一致；未请求回读或读卡器未附带回读数据时视为一致。
+    fn readback_mismatch(&self, result: &CardWriteResult) -> bool {
+        let Some(request) = self.last_write_request.as_ref().filter(|req| req.readback) else {
+            return false;
+        };
+        if result.readback.is_empty() {
+            log::warn!("Readback requested but not returned by reader");
+            return false;
+        }
+        result.readback.get(..request.card_data.len()) != Some(request.card_data.as_slice())
+    }
+
+    /// 读取卡内上次行程；卡内无记录时为 None。
+    fn card_last_trip(&self, data: &CardData) -> Option<CardLastTrip> {
+        if data.last_route_id.is_none()
+            && data.last_board_station_id.is_none()
+            && data.last_alight_station_id.is_none()
+        {
+            return None;
+        }
+        // 仅当上次线路与当前同步的线路一致时才能按配置解析站名
+        let same_route = data.last_route_id.is_some()
+            && self.config_cache.route.as_ref().map(|cfg| cfg.route_id) == data.last_route_id;
+        let resolve = |station_id: u16| {
+            same_route
+                .then(|| self.station_name(station_id))
+                .flatten()
+                .unwrap_or_else(|| format!("#{}", station_id))
+        };
+        Some(CardLastTrip {
+            route_id: data.last_route_id,
+            direction: data.last_direction,
+            board_station: data.last_board_station_id.map(resolve),
+            alight_station: data.last_alight_station_id.map(resolve),
+        })
+    }
+
+    /// 按站点 ID 查站名（未同步配置或未知站点时为 None）。
+    pub fn station_name(&self, station_id: u16) -> Option<String> {
+        self.config_cache
+            .route
+            .as_ref()?
+            .stations
+            .iter()
+            .find(|s| s.id == station_id)
+            .map(|s| s.name.clone())
+    }
+
+    /// 站点是否为换乘站（未同步配置或未知站点时为 false）。
+    pub fn is_transfer_station(&self, station_id: u16) -> bool {
+        self.config_cache.route.as_ref().is_some_and(|cfg| {
+            cfg.stations.iter().any(|s| s.id == station_id && s.is_transfer)
+        })
+    }
+
+    /// 记录一次上报成功。
+    pub fn record_upload_success(&mut self) {
+        self.last_successful_upload_ms = Some(self.now_millis());
+    }
+
+    /// 距最近一次上报成功的秒数（从未成功为 None）。
+    pub fn last_upload_age_secs(&self, now_ms: u64) -> Option<u64> {
+        self.last_successful_upload_ms
+            .map(|at_ms| now_ms.saturating_sub(at_ms) / 1000)
+    }
+
+    /// 记录后端请求失败。
+    pub fn record_backend_error(&mut self, operation: &str, message: String) {
+        let at_ms = self.now_millis();
+        self.last_backend_error = Some(BackendError {
+            operation: operation.to_string(),
+            message,
+            at_ms,
+        });
+    }
+
+    /// 临时覆盖当前站显示名（刷卡记录随之使用），切站或下次配置同步时恢复配置站名。
+    pub fn set_station_name_override(&mut self, name: String) -> bool {
+        if self.route_state.station_id == 0 {
+            return false;
+        }
+        log::info!(
+            "Station name override: station={} '{}' -> '{}'",
+            self.route_state.station_id,
+            self.route_state.station_name,
+            name
+        );
+        self.route_state.station_name = name;
+        true
     }
 
     pub fn set_station_by_id(&mut self, station_id: u16) -> bool {
@@ -346,10 +1057,43 @@ impl GatewayState {
         true
     }
 
-    pub fn handle_card_detected(&mut self, detected: CardDetected, now: u64) -> Decision {
-        let now_ms = current_epoch_millis();
+    /// 司机手动操作站点/方向后，暂停自动报站一段时间。
+    pub fn pause_auto_advance(&mut self, now_ms: u64) {
+        let pause_ms = self.settings.auto_advance_pause_secs as u64 * 1000;
+        self.auto_advance_paused_until_ms = now_ms.saturating_add(pause_ms);
+    }
+
+    /// 自动报站：按当前方向前进一站，到达终点站时掉头（站点不变）。
+    pub fn auto_advance_station(&mut self) -> bool {
+        if self.now_millis() < self.auto_advance_paused_until_ms {
+            return false;
+        }
+        let station_id = self.route_state.station_id;
+        let Some(cfg) = self.config_cache.route.as_ref() else {
+            return false;
+        };
+        if cfg.stations.len() < 2 || !cfg.stations.iter().any(|s| s.id == station_id) {
+            return false;
+        }
+        let forward = self.route_state.direction == Direction::Up;
+        if self.step_station(forward) && self.route_state.station_id != station_id {
+            return true;
+        }
+        self.route_state.direction = if forward {
+            Direction::Down
+        } else {
+            Direction::Up
+        };
+        true
+    }
+
+    pub fn handle_card_detected(&mut self, mut detected: CardDetected, now: u64) -> Decision {
+        let now_ms = self.now_millis();
         self.refresh_modes(now_ms);
+        self.touch_activity(now_ms);
         self.last_tap_nonce = self.last_tap_nonce.wrapping_add(1);
+        // 统一卡号格式（分隔符/大小写），与黑名单、缓存、后端一致
+        detected.card_id = normalize_uid(&detected.card_id);
         let card_id = detected.card_id.clone();
         self.last_card_id = card_id.clone();
         self.last_card_data_len = detected.card_data.len();
@@ -359,15 +1103,81 @@ impl GatewayState {
             Some(hex_prefix(&detected.card_data, 16))
         };
         self.last_card_data_error = None;
+        self.last_board_station = None;
+        self.last_trip_elapsed_secs = None;
+        self.correct_tap_time(&mut detected, now);
 
-        if !self.debounce.allow(&detected.card_id, now) {
+        // 读卡器重复上报同一次刷卡：重发已下发的 ACK，不再重复充值/注册/扣费
+        if !self.tap_dedup.allow(&card_id, &detected.card_data, now_ms) {
+            log::warn!("Duplicate detection ignored: card={}", self.display_card_id(&card_id));
+            return match self.last_ack(Some(detected.reader_id)) {
+                Some(ack) => Decision {
+                    ack,
+                    event: None,
+                    upload_record: None,
+                    write_request: None,
+                    registration: None,
+                },
+                None => self.reject_card("刷卡过快", now_ms),
+            };
+        }
+
+        // 员工/稽查卡不受防抖限制（黑名单检查照常）
+        let staff_card = self.settings.is_staff_card(&card_id);
+        let fare_exempt = staff_card && self.settings.staff_cards_fare_exempt;
+        if !staff_card && !self.debounce.allow(&detected.card_id, now) {
             return self.reject_card("刷卡过快", now_ms);
         }
+        // 预热期：配置与卡片缓存尚未就绪，按策略拒绝以免票价/优惠计算错误
+        if self.warming_up() && self.settings.warmup_policy == WarmupPolicy::Reject {
+            return self.reject_card("系统启动中", now_ms);
+        }
+
+        // 上一次写卡尚未确认时不再基于旧卡内数据计算，避免重复扣费/充值
+        if self.write_pending_for(&card_id, now_ms) {
+            return self.reject_card("处理中", now_ms);
+        }
+        // 同一张卡在前后门同时刷卡：只处理先到的一次，避免重复开启/结算行程
+        if self.card_locked_by_other_reader(&card_id, detected.reader_id, now_ms) {
+            return self.reject_card("处理中", now_ms);
+        }
 
         let uid = decode_uid_hex(&card_id);
-        let mut card_data = if detected.card_data.len() >= CARD_DATA_LEN {
-            match CardData::from_bytes_verbose(&detected.card_data) {
-                Ok(data) => Some(data),
+        let data_len = detected.card_data.len();
+        if data_len > CARD_DATA_LEN {
+            // 多余的字节（含不足一块的尾部）不参与解析，提示检查读卡器读取块数
+            log::warn!(
+                "Card data over-length: {} bytes ({} blocks + {} bytes), expected {} blocks",
+                data_len,
+                data_len / CARD_DATA_BLOCK_SIZE,
+                data_len % CARD_DATA_BLOCK_SIZE,
+                CARD_DATA_BLOCK_COUNT
+            );
+        }
+        let mut outdated_card = false;
+        let mut outdated_balance = None;
+        let mut card_data = if data_len > CARD_DATA_LEN && self.settings.strict_card_data_len {
+            self.last_card_data_error = Some("over_length_card_data".to_string());
+            None
+        } else if let Err(err) =
+            CardData::check_min_version(&detected.card_data, self.settings.min_card_version)
+        {
+            self.last_card_data_error = Some(err.as_str().to_string());
+            outdated_card = true;
+            // 重新注册时沿用旧卡余额（卡内 UID 须与本卡一致，防止复制数据带入余额）
+            outdated_balance = CardData::from_bytes(&detected.card_data)
+                .filter(|data| uid.as_ref().is_some_and(|uid| data.matches_uid(uid)))
+                .map(|data| data.balance_cents);
+            None
+        } else if data_len >= CARD_DATA_LEN {
+            match CardData::from_bytes_recover(&detected.card_data) {
+                Ok((data, damaged)) => {
+                    // 仅行程块损坏时仍使用余额块，同时记录损坏块供诊断
+                    if let Some(err) = damaged {
+                        self.last_card_data_error = Some(err.as_str().to_string());
+                    }
+                    Some(data)
+                }
                 Err(err) => {
                     self.last_card_data_error = Some(err.as_str().to_string());
                     None
@@ -377,8 +1187,8 @@ impl GatewayState {
             self.last_card_data_error = Some("short_card_data".to_string());
             None
         };
-        if let (Some(uid), Some(ref data)) = (uid, card_data.as_ref()) {
-            if data.uid != uid {
+        if let (Some(uid), Some(data)) = (uid, card_data.as_ref()) {
+            if !data.matches_uid(&uid) {
                 self.last_card_data_error = Some("uid_mismatch".to_string());
                 card_data = None;
             }
@@ -386,13 +1196,18 @@ impl GatewayState {
 
         // 余额展示以“读到的卡内数据”为准（不使用后端补全的数据）。
         self.last_balance_cents = card_data.as_ref().map(|data| data.balance_cents);
+        self.last_card_trip = card_data.as_ref().and_then(|data| self.card_last_trip(data));
 
         if self.blacklist_cache.is_blocked(&detected.card_id) {
             return self.reject_blacklisted(&card_id, card_data, now_ms);
         }
 
+        if self.balance_override.is_some() {
+            return self.handle_balance_override(card_id, uid, card_data, now_ms);
+        }
+
         if self.register_mode.is_some() {
-            return self.handle_register(card_id, uid, card_data, now_ms);
+            return self.handle_register(card_id, uid, card_data, outdated_balance, now_ms);
         }
 
         if self.recharge_mode.is_some() {
@@ -401,6 +1216,8 @@ impl GatewayState {
 
         let mut card_data = match card_data {
             Some(data) => data,
+            // 旧版本卡不按后端余额补全，须在注册模式下重写为新版本
+            None if outdated_card => return self.reject_card("请更新卡片", now_ms),
             None => {
                 // 若读到的卡内数据无效，但后端已存在该卡，则允许按后端余额进行“补全”。
                 // 这能修复“数据库已注册但仍提示未注册”的情况（例如卡片未写入/数据损坏/读错块）。
@@ -430,6 +1247,69 @@ impl GatewayState {
             return self.reject_card("卡已冻结", now_ms);
         }
 
+        // 司机已清除的卡住行程：复位卡内行程状态后按新刷卡处理
+        if self.take_cleared_trip(&card_id) && card_data.status == CardStatus::InTrip {
+            card_data.status = CardStatus::Idle;
+            card_data.entry_station_id = None;
+            card_data.entry_time = None;
+            // 退还押金模式上车预扣的金额
+            let deposit = card_data.deposit_cents.take().unwrap_or(0);
+            card_data.balance_cents = card_data.balance_cents.saturating_add(deposit);
+            self.push_card_snapshot(&card_id, &card_data, "clear_trip", now_ms);
+            log::warn!("Cleared trip reset on card: card={}", self.display_card_id(&card_id));
+        }
+
+        // 尚未同步线路配置：无法确定线路与票价
+        if self.config_cache.route.is_none() {
+            match self.settings.no_route_policy {
+                NoRoutePolicy::Allow => {}
+                NoRoutePolicy::Reject => return self.reject_card("线路未设置", now_ms),
+                NoRoutePolicy::Queue => return self.queue_no_route_tap(&detected, now, now_ms),
+            }
+        }
+        // 线路收费但当前无可用票价规则（如未校时且规则均带生效区间）：拒绝而非按 0 元放行
+        if !fare_exempt && self.next_fare_override_cents.is_none() && self.fare_unavailable() {
+            return self.reject_card("票价未生效", now_ms);
+        }
+
+        if let Some(decision) = self.try_direction_correction(&card_id, &mut card_data, now_ms) {
+            return decision;
+        }
+
+        // 后端画像中的未知卡类型：按策略放行、提示或拒绝
+        let mut unknown_card_type = false;
+        if let Some(card_type) = self.unknown_card_type(&card_id, now_ms) {
+            log::warn!(
+                "Unknown card type: card={} type={}",
+                self.display_card_id(&card_id),
+                card_type
+            );
+            match self.settings.unknown_card_type_policy {
+                UnknownCardTypePolicy::Normal => {}
+                UnknownCardTypePolicy::Warn => unknown_card_type = true,
+                UnknownCardTypePolicy::Reject => return self.reject_card("未知卡类型", now_ms),
+            }
+        }
+
+        // 跨网关时间比对：短时间内在另一网关刷过卡，疑似复制卡/重放
+        let mut tamper_alert = false;
+        if now >= TIME_SYNCED_MIN_EPOCH {
+            let tag = gateway_tag(&self.settings.gateway_id);
+            if card_data.impossible_travel(tag, now, self.settings.tamper_min_travel_secs) {
+                log::warn!(
+                    "Impossible travel: card={} last_tap_time={:?}",
+                    self.display_card_id(&card_id),
+                    card_data.last_tap_time
+                );
+                if self.settings.tamper_policy == TamperPolicy::Block {
+                    return self.reject_card("异常刷卡", now_ms);
+                }
+                tamper_alert = true;
+            }
+            card_data.last_tap_gateway = Some(tag);
+            card_data.last_tap_time = u32::try_from(now).ok();
+        }
+
         let tap_mode = self
             .config_cache
             .route
@@ -439,21 +1319,81 @@ impl GatewayState {
 
         let mut board_event: Option<TapEvent> = None;
         let mut removed_trip: Option<TapEvent> = None;
+        // 上车读卡器按下车结算了上一行程（不启用误刷忽略，乘客需再刷上车）
+        let mut settled_prior_trip = false;
         let tap_type = match tap_mode {
             TapMode::SingleTap => TapType::TapIn,
-            TapMode::TapInOut => {
-                if let Some(prev) = self.active_trips.take(&card_id, now) {
-                    removed_trip = Some(prev.clone());
-                    board_event = Some(prev);
+            TapMode::TapInOut => match self.settings.reader_role(detected.reader_id) {
+                ReaderRole::Board => {
+                    if self.is_recent_tap_out(&card_id, now_ms) {
+                        return self.ignore_retap(now_ms);
+                    }
+                    match self.settings.repeat_board_policy {
+                        RepeatBoardPolicy::Reject if self.active_trips.contains(&card_id, now) => {
+                            return self.reject_card("请先下车刷卡", now_ms);
+                        }
+                        RepeatBoardPolicy::Settle if self.active_trips.contains(&card_id, now) => {
+                            // 按下车结算上一行程，乘客需再刷一次上车
+                            let prev = self.active_trips.take(&card_id, now);
+                            removed_trip = prev.clone();
+                            board_event = prev;
+                            settled_prior_trip = true;
+                            TapType::TapOut
+                        }
+                        _ => {
+                            // 开启新行程，丢弃遗留的未完成行程
+                            if let Some(prev) = self.active_trips.take(&card_id, now) {
+                                log::warn!(
+                                    "Board reader tap replaced open trip: card={} record={}",
+                                    self.display_card_id(&card_id),
+                                    prev.record_id
+                                );
+                            }
+                            TapType::TapIn
+                        }
+                    }
+                }
+                ReaderRole::Alight => {
+                    // 下车读卡器：始终视为下车，无上车记录时按无记录下车策略处理
+                    if let Some(decision) = self.enforce_anti_passback(&card_id, now, now_ms) {
+                        return decision;
+                    }
+                    if let Some(prev) = self.active_trips.take(&card_id, now) {
+                        removed_trip = Some(prev.clone());
+                        board_event = Some(prev);
+                    } else if self.settings.stray_tap_out_policy == StrayTapOutPolicy::Reject {
+                        return self.reject_stray_tap_out(&card_id, card_data, now_ms);
+                    }
                     TapType::TapOut
-                } else {
-                    TapType::TapIn
                 }
-            }
+                ReaderRole::Any => {
+                    // 卡内显示行程中即为下车意图，防回传时须有本线路上车记录
+                    if card_data.status == CardStatus::InTrip {
+                        if let Some(decision) = self.enforce_anti_passback(&card_id, now, now_ms) {
+                            return decision;
+                        }
+                    }
+                    if let Some(prev) = self.active_trips.take(&card_id, now) {
+                        removed_trip = Some(prev.clone());
+                        board_event = Some(prev);
+                        TapType::TapOut
+                    } else if card_data.status == CardStatus::InTrip {
+                        // 卡内显示行程中，但网关没有对应上车记录（漏刷/重启/已结算）
+                        if self.settings.stray_tap_out_policy == StrayTapOutPolicy::Reject {
+                            return self.reject_stray_tap_out(&card_id, card_data, now_ms);
+                        }
+                        TapType::TapOut
+                    } else if self.is_recent_tap_out(&card_id, now_ms) {
+                        return self.ignore_retap(now_ms);
+                    } else {
+                        TapType::TapIn
+                    }
+                }
+            },
         };
 
         let record_id = self.next_record_id(now);
-        let event = TapEvent::new(
+        let mut event = TapEvent::new(
             record_id,
             card_id.clone(),
             self.route_state.route_id,
@@ -467,34 +1407,96 @@ impl GatewayState {
         self.last_passenger_tone = PassengerTone::Normal;
         let mut upload_record = None;
         let mut write_request = None;
-        let standard_fare = self.standard_fare();
+        // 本次实际扣费（分）；None 表示不支持方向更正（如补票罚金）
+        let mut charged_cents = None;
+        // 卡内实际扣减金额（分，含补票罚金），写入事件与上报记录
+        let mut deducted_cents = None;
+        // 押金模式上车预扣（不支持方向更正）
+        let mut deposit_held = false;
+        // 余额不足仍放行下车时的欠款（分）
+        let mut owed_cents = None;
+        let deposit_mode = self.settlement_mode() == SettlementMode::Deposit;
+        let standard_fare = self.standard_fare_cents();
         match (tap_mode, tap_type) {
             (TapMode::SingleTap, TapType::TapIn) => {
                 upload_record = Some(UploadRecord::from_tap_in(&event));
-                self.last_fare_base = standard_fare;
-                self.last_fare = standard_fare;
+                self.last_fare_base_cents = standard_fare;
+                self.last_fare_cents = standard_fare;
                 self.last_fare_label = "应付".to_string();
                 self.apply_cached_profile(&card_id, now_ms);
+                let fare_override = !fare_exempt && self.apply_fare_override();
+                if fare_exempt {
+                    self.apply_fare_exemption();
+                } else if !fare_override {
+                    self.apply_min_fare_floor();
+                }
                 let fare_cents = self.fare_to_cents();
+                if !fare_exempt
+                    && card_data.balance_cents >= fare_cents
+                    && self.below_min_board_balance(card_data.balance_cents, fare_cents)
+                {
+                    return self.reject_card("余额过低请充值", now_ms);
+                }
                 if !self.apply_balance(&mut card_data, fare_cents) {
                     return self.reject_card("余额不足", now_ms);
                 }
+                if fare_override {
+                    self.next_fare_override_cents = None;
+                }
+                charged_cents = Some(fare_cents);
+                deducted_cents = Some(fare_cents);
                 self.update_last_trip(&mut card_data, None, Some(event.station_id));
                 card_data.status = CardStatus::Idle;
                 card_data.entry_station_id = None;
+                card_data.entry_time = None;
                 write_request = Some(self.build_write_request(&card_id, &card_data, WriteContext::TapIn));
                 self.push_card_snapshot(&card_id, &card_data, "tap_in", now_ms);
             }
             (TapMode::TapInOut, TapType::TapIn) => {
-                self.active_trips.insert(event.clone(), now);
-                upload_record = Some(UploadRecord::from_tap_in(&event));
-                let fare = self.estimate_trip_fare(event.station_id, event.station_id);
-                self.last_fare_base = fare.or(standard_fare);
-                self.last_fare = fare.or(standard_fare);
-                self.last_fare_label = "起步价".to_string();
+                let (fare, label) = if deposit_mode {
+                    (self.max_trip_fare_from(event.station_id), "预扣")
+                } else if self.settings.tap_in_max_fare_estimate {
+                    (self.max_trip_fare_from(event.station_id), "预估最高")
+                } else {
+                    (self.estimate_trip_fare(event.station_id, event.station_id), "起步价")
+                };
+                self.last_fare_base_cents = fare.or(standard_fare);
+                self.last_fare_cents = fare.or(standard_fare);
+                self.last_fare_label = label.to_string();
                 self.apply_cached_profile(&card_id, now_ms);
+                if fare_exempt {
+                    self.apply_fare_exemption();
+                }
+                // 重新上车丢弃的未完成行程：先退还卡内押金
+                let prior_deposit = card_data.deposit_cents.take().unwrap_or(0);
+                card_data.balance_cents = card_data.balance_cents.saturating_add(prior_deposit);
+                // 上车不扣费，按上车提示的票价预估下车后余额
+                if !fare_exempt
+                    && self.below_min_board_balance(card_data.balance_cents, self.fare_to_cents())
+                {
+                    return self.reject_card("余额过低请充值", now_ms);
+                }
+                // 押金模式：上车预扣最高票价，记入行程供下车退差
+                let held_cents = if deposit_mode {
+                    self.fare_to_cents().min(MAX_DEPOSIT_CENTS)
+                } else {
+                    0
+                };
+                if !self.apply_balance(&mut card_data, held_cents) {
+                    return self.reject_card("余额不足", now_ms);
+                }
+                deposit_held = held_cents > 0;
+                card_data.deposit_cents = deposit_held.then_some(held_cents);
+                event.fare_cents = Some(held_cents);
+                let trip_timeout = self.route_trip_timeout_secs(event.route_id);
+                self.active_trips.insert(event.clone(), now, trip_timeout);
+                upload_record = Some(UploadRecord::from_tap_in(&event));
+                charged_cents = Some(held_cents);
+                deducted_cents = Some(held_cents);
                 card_data.status = CardStatus::InTrip;
                 card_data.entry_station_id = Some(event.station_id);
+                card_data.entry_time =
+                    u32::try_from(now).ok().filter(|_| now >= TIME_SYNCED_MIN_EPOCH);
                 write_request = Some(self.build_write_request(&card_id, &card_data, WriteContext::TapIn));
                 self.push_card_snapshot(&card_id, &card_data, "tap_in", now_ms);
             }
@@ -508,38 +1510,161 @@ impl GatewayState {
                     ));
                     let fare = self
                         .estimate_trip_fare(board.station_id, event.station_id)
-                        .or(standard_fare);
-                    self.last_fare_base = fare;
-                    self.last_fare = fare;
+                        .or(standard_fare)
+                        .map(|cents| self.add_late_fee(cents, card_data.entry_time, now));
+                    self.last_fare_base_cents = fare;
+                    self.last_fare_cents = fare;
                 } else {
-                    self.last_fare_base = standard_fare;
-                    self.last_fare = standard_fare;
+                    // 无上车记录：按罚金结算，上车信息取自卡内记录以便后端配对
+                    upload_record = Some(UploadRecord::from_tap_out(
+                        &event,
+                        event.tap_time,
+                        card_data.entry_station_id,
+                        None,
+                    ));
+                    let penalty = match self.settings.stray_tap_out_penalty_cents {
+                        0 => standard_fare,
+                        cents => Some(cents),
+                    };
+                    self.last_fare_base_cents = penalty;
+                    self.last_fare_cents = penalty;
+                }
+                self.last_fare_label = if board_event.is_some() {
+                    "结算价".to_string()
+                } else {
+                    "补票罚金".to_string()
+                };
+                // 罚金不参与优惠、最低票价与手动票价调整
+                let mut fare_override = false;
+                if board_event.is_some() {
+                    self.apply_cached_profile(&card_id, now_ms);
+                    fare_override = !fare_exempt && self.apply_fare_override();
+                    if !fare_override {
+                        self.apply_min_fare_floor();
+                    }
+                }
+                if fare_exempt {
+                    self.apply_fare_exemption();
                 }
-                self.last_fare_label = "结算价".to_string();
-                self.apply_cached_profile(&card_id, now_ms);
                 let fare_cents = self.fare_to_cents();
-                if !self.apply_balance(&mut card_data, fare_cents) {
+                // 押金模式：按卡内押金结算（卡内无记录时取上车记录），多退少补；
+                // 无上车记录的补票罚金同样从押金中抵扣
+                let deposit_cents = card_data
+                    .deposit_cents
+                    .take()
+                    .or_else(|| {
+                        board_event
+                            .as_ref()
+                            .filter(|_| deposit_mode)
+                            .and_then(|board| board.fare_cents)
+                    })
+                    .unwrap_or(0);
+                let debit_cents = fare_cents.saturating_sub(deposit_cents);
+                let refund_cents = deposit_cents.saturating_sub(fare_cents);
+                let paid = self.apply_balance(&mut card_data, debit_cents);
+                if !paid && self.settings.unpaid_tap_out_records {
+                    // 余额不足：不扣卡内余额，结束行程并记欠款，由后端后续追缴
+                    owed_cents = Some(debit_cents);
+                    log::warn!(
+                        "Unpaid tap-out: card={} owed={}",
+                        self.display_card_id(&card_id),
+                        debit_cents
+                    );
+                } else if !paid {
                     if let Some(prev) = removed_trip {
-                        self.active_trips.insert(prev, now);
+                        let trip_timeout = self.route_trip_timeout_secs(prev.route_id);
+                        self.active_trips.insert(prev, now, trip_timeout);
                     }
                     return self.reject_card("余额不足", now_ms);
                 }
-                let board_station = board_event.as_ref().map(|e| e.station_id);
+                card_data.balance_cents = card_data.balance_cents.saturating_add(refund_cents);
+                if fare_override {
+                    self.next_fare_override_cents = None;
+                }
+                // 欠款记录不支持方向更正，实际扣费仅为押金
+                if board_event.is_some() && owed_cents.is_none() {
+                    charged_cents = Some(fare_cents);
+                }
+                deducted_cents = Some(fare_cents.saturating_sub(owed_cents.unwrap_or(0)));
+                let board_station = board_event
+                    .as_ref()
+                    .map(|e| e.station_id)
+                    .or(card_data.entry_station_id);
+                self.last_board_station = match board_event.as_ref() {
+                    Some(board) => Some(board.station_name.clone()),
+                    None => board_station.and_then(|id| self.station_name(id)),
+                };
+                self.last_trip_elapsed_secs = board_event
+                    .as_ref()
+                    .map(|board| event.tap_time.saturating_sub(board.tap_time));
                 self.update_last_trip(&mut card_data, board_station, Some(event.station_id));
                 card_data.status = CardStatus::Idle;
                 card_data.entry_station_id = None;
+                card_data.entry_time = None;
                 write_request = Some(self.build_write_request(&card_id, &card_data, WriteContext::TapOut));
                 self.push_card_snapshot(&card_id, &card_data, "tap_out", now_ms);
+                let ignore_secs = self.settings.retap_ignore_secs;
+                self.recent_tap_out = (ignore_secs > 0 && !settled_prior_trip)
+                    .then(|| (card_id.clone(), now_ms.saturating_add(ignore_secs as u64 * 1000)));
             }
             _ => {}
         }
 
         self.last_tap_type = Some(tap_type);
+        event.fare_cents = deducted_cents;
+        if let Some(record) = upload_record.as_mut() {
+            record.fare_cents = deducted_cents;
+            record.direction = Some(self.route_state.direction.as_str().to_string());
+            if tamper_alert {
+                record.alert = Some("impossible_travel".to_string());
+            }
+            if fare_exempt {
+                record.exempt = Some(true);
+            }
+            record.owed_cents = owed_cents;
+        }
+        // 记录可更正方向的刷卡（宽限期内有效）
+        self.last_correctable_tap = match (upload_record.as_ref(), charged_cents) {
+            (Some(record), Some(charged_cents))
+                if self.settings.direction_correction_secs > 0 && !deposit_held =>
+            {
+                Some(DirectionCorrection {
+                    card_id: card_id.clone(),
+                    record: record.clone(),
+                    direction: self.route_state.direction,
+                    charged_cents,
+                    expires_at_ms: now_ms
+                        .saturating_add(self.settings.direction_correction_secs as u64 * 1000),
+                })
+            }
+            _ => None,
+        };
+        self.last_charged_tap = match (upload_record.as_ref(), charged_cents) {
+            (Some(record), Some(charged_cents)) => Some(ChargedTap {
+                card_id: card_id.clone(),
+                record: record.clone(),
+                charged_cents,
+                card_data: card_data.clone(),
+                at_ms: now_ms,
+            }),
+            _ => None,
+        };
 
         if self.last_passenger_tone != PassengerTone::Error {
-        self.last_passenger_message = "刷卡成功".to_string();
+            self.last_passenger_message = match self.last_board_station.as_deref() {
+                _ if settled_prior_trip => "上一行程已结算，请再刷卡上车".to_string(),
+                _ if owed_cents.is_some() => "余额不足，已记欠款请充值".to_string(),
+                Some(board) => format_board_message(board, self.last_trip_elapsed_secs),
+                None => "刷卡成功".to_string(),
+            };
+            if unknown_card_type {
+                self.last_passenger_message.push_str("（未知卡类型）");
+            }
+            if self.is_transfer_station(self.route_state.station_id) {
+                self.last_passenger_message.push_str("（换乘站，可换乘其他线路）");
+            }
         }
-        self.last_message_deadline_ms = now_ms.saturating_add(PASSENGER_MSG_TTL_OK_MS);
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Ok, now_ms);
 
         Decision {
             ack: CardAck::accepted(),
@@ -553,8 +1678,9 @@ impl GatewayState {
     fn handle_register(
         &mut self,
         card_id: String,
-        uid: Option<[u8; 4]>,
+        uid: Option<CardUid>,
         card_data: Option<CardData>,
+        carried_balance: Option<u32>,
         now_ms: u64,
     ) -> Decision {
         let uid = match uid {
@@ -566,7 +1692,7 @@ impl GatewayState {
         }
 
         let mut new_data = CardData::new(uid);
-        new_data.balance_cents = DEFAULT_REGISTER_BALANCE_CENTS;
+        new_data.balance_cents = carried_balance.unwrap_or(DEFAULT_REGISTER_BALANCE_CENTS);
         new_data.status = CardStatus::Idle;
         let write_request = self.build_write_request(&card_id, &new_data, WriteContext::Register);
         let registration = CardRegistration {
@@ -581,7 +1707,7 @@ impl GatewayState {
         self.last_balance_cents = None;
         self.last_passenger_tone = PassengerTone::Normal;
         self.last_passenger_message = "注册成功".to_string();
-        self.last_message_deadline_ms = now_ms.saturating_add(PASSENGER_MSG_TTL_ACTION_MS);
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Action, now_ms);
         Decision {
             ack: CardAck::accepted(),
             event: None,
@@ -591,6 +1717,53 @@ impl GatewayState {
         }
     }
 
+    /// 维护写余额：按指定余额写卡（不计票价、不改行程状态），卡片快照上报留痕。
+    /// 卡内数据损坏时按新卡数据写入。
+    fn handle_balance_override(
+        &mut self,
+        card_id: String,
+        uid: Option<CardUid>,
+        card_data: Option<CardData>,
+        now_ms: u64,
+    ) -> Decision {
+        let Some(mode) = self.balance_override.clone() else {
+            return self.reject_card("维护模式已结束", now_ms);
+        };
+        let mut card_data = match (card_data, uid) {
+            (Some(data), _) => data,
+            (None, Some(uid)) => CardData::new(uid),
+            (None, None) => return self.reject_card("卡号无效", now_ms),
+        };
+        if card_data.status == CardStatus::Blocked {
+            return self.reject_card("卡已冻结", now_ms);
+        }
+        log::warn!(
+            "Balance override: card={} {} -> {} cents",
+            self.display_card_id(&card_id),
+            card_data.balance_cents,
+            mode.balance_cents
+        );
+        card_data.balance_cents = mode.balance_cents;
+        let write_request =
+            self.build_write_request(&card_id, &card_data, WriteContext::BalanceOverride);
+        self.push_card_snapshot(&card_id, &card_data, "balance_override", now_ms);
+        self.last_balance_cents = Some(mode.balance_cents);
+        self.last_passenger_tone = PassengerTone::Normal;
+        self.last_passenger_message = format!(
+            "维护：余额已设为 {}.{:02} 元",
+            mode.balance_cents / 100,
+            mode.balance_cents % 100
+        );
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Action, now_ms);
+        Decision {
+            ack: CardAck::accepted(),
+            event: None,
+            upload_record: None,
+            write_request: Some(write_request),
+            registration: None,
+        }
+    }
+
     fn handle_recharge(
         &mut self,
         card_id: String,
@@ -628,15 +1801,46 @@ impl GatewayState {
         // 充值展示的余额以“刷卡时读到的卡内余额”为准。
         self.last_balance_cents = Some(card_data.balance_cents);
 
+        // 司机已确认强制清除（或已在 /trips 清除）：复位行程状态后继续充值
+        if card_data.status == CardStatus::InTrip
+            && (self.force_clear_card.as_deref() == Some(card_id.as_str())
+                || self.take_cleared_trip(&card_id))
+        {
+            self.force_clear_card = None;
+            self.stuck_trip_card = None;
+            card_data.status = CardStatus::Idle;
+            card_data.entry_station_id = None;
+            card_data.entry_time = None;
+            let _ = self.active_trips.take(&card_id, now_ms / 1000);
+            self.push_card_snapshot(&card_id, &card_data, "force_clear_trip", now_ms);
+            log::warn!(
+                "Trip force-cleared before recharge: card={}",
+                self.display_card_id(&card_id)
+            );
+        }
         if card_data.status != CardStatus::Idle {
+            if card_data.status == CardStatus::InTrip
+                && self.stuck_trip_card.as_deref() != Some(card_id.as_str())
+            {
+                self.stuck_trip_card = Some(card_id.clone());
+                self.stuck_trip_seq = self.stuck_trip_seq.wrapping_add(1);
+            }
             return self.reject_card("卡状态异常", now_ms);
         }
-        card_data.balance_cents = card_data.balance_cents.saturating_add(mode.amount_cents);
+        // 超出余额上限（含 u32 溢出）时拒绝，卡内余额保持不变
+        let Some(new_balance) = card_data
+            .balance_cents
+            .checked_add(mode.amount_cents)
+            .filter(|balance| *balance <= self.settings.max_balance_cents)
+        else {
+            return self.reject_card("超出余额上限", now_ms);
+        };
+        card_data.balance_cents = new_balance;
         let write_request = self.build_write_request(&card_id, &card_data, WriteContext::Recharge);
         self.push_card_snapshot(&card_id, &card_data, "recharge", now_ms);
         self.last_passenger_tone = PassengerTone::Normal;
         self.last_passenger_message = "充值成功".to_string();
-        self.last_message_deadline_ms = now_ms.saturating_add(PASSENGER_MSG_TTL_ACTION_MS);
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Action, now_ms);
         Decision {
             ack: CardAck::accepted(),
             event: None,
@@ -646,6 +1850,215 @@ impl GatewayState {
         }
     }
 
+    /// 方向更正：宽限期内同卡再刷且司机已切换方向时，以同一 record_id 重新上报，
+    /// 并按更正后的票价退补差额。不满足条件时返回 None，按正常刷卡处理。
+    fn try_direction_correction(
+        &mut self,
+        card_id: &str,
+        card_data: &mut CardData,
+        now_ms: u64,
+    ) -> Option<Decision> {
+        let pending = self.last_correctable_tap.as_ref()?;
+        if pending.card_id != card_id
+            || now_ms >= pending.expires_at_ms
+            || pending.direction == self.route_state.direction
+        {
+            return None;
+        }
+        let pending = self.last_correctable_tap.take()?;
+        let direction = self.route_state.direction;
+        let corrected_cents = if pending.charged_cents == 0 {
+            0
+        } else {
+            self.corrected_fare_cents(card_id, &pending.record, now_ms)
+        };
+        if corrected_cents > pending.charged_cents {
+            if !self.apply_balance(card_data, corrected_cents - pending.charged_cents) {
+                self.last_correctable_tap = Some(pending);
+                return Some(self.reject_card("余额不足", now_ms));
+            }
+        } else {
+            card_data.balance_cents = card_data
+                .balance_cents
+                .saturating_add(pending.charged_cents - corrected_cents);
+        }
+        card_data.last_direction = Some(direction);
+
+        let mut record = pending.record;
+        record.direction = Some(direction.as_str().to_string());
+        record.fare_cents = Some(corrected_cents);
+        let write_request = self.build_write_request(card_id, card_data, WriteContext::Correction);
+        self.push_card_snapshot(card_id, card_data, "direction_correction", now_ms);
+        self.last_passenger_tone = PassengerTone::Normal;
+        self.last_passenger_message = "方向已更正".to_string();
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Action, now_ms);
+        Some(Decision {
+            ack: CardAck::accepted(),
+            event: None,
+            upload_record: Some(record),
+            write_request: Some(write_request),
+            registration: None,
+        })
+    }
+
+    /// 后端查询返回“已冻结”：若该卡刚刚完成扣费，按策略写卡冻结并冲正本次刷卡。
+    pub fn apply_post_tap_block(&mut self, card_id: &str) {
+        if self.settings.post_tap_block_policy != PostTapBlockPolicy::Reverse {
+            return;
+        }
+        let now_ms = self.now_millis();
+        let Some(tap) = self.last_charged_tap.take() else {
+            return;
+        };
+        if tap.card_id != card_id || now_ms.saturating_sub(tap.at_ms) > POST_TAP_BLOCK_WINDOW_MS {
+            self.last_charged_tap = Some(tap);
+            return;
+        }
+        if !self.blacklist_cache.is_blocked(card_id) {
+            self.blacklist_cache.cards.push(card_id.to_string());
+        }
+        // 冲正后不再允许方向更正，也不保留未完成行程
+        if matches!(&self.last_correctable_tap, Some(pending) if pending.card_id == card_id) {
+            self.last_correctable_tap = None;
+        }
+        let now = now_ms / 1000;
+        let _ = self.active_trips.take(card_id, now);
+
+        let mut card_data = tap.card_data;
+        card_data.balance_cents = card_data.balance_cents.saturating_add(tap.charged_cents);
+        card_data.status = CardStatus::Blocked;
+        card_data.entry_station_id = None;
+        card_data.entry_time = None;
+        // 行模式读卡器无法写卡，仅上报冲正
+        let write_request = (self.settings.serial_mode != SerialMode::LineUid)
+            .then(|| self.build_write_request(card_id, &card_data, WriteContext::Blacklist));
+        self.push_card_snapshot(card_id, &card_data, "blocked_reversal", now_ms);
+        let mut record = tap.record;
+        record.alert = Some("blocked_reversal".to_string());
+        // 已全额退还
+        record.fare_cents = Some(0);
+        log::warn!(
+            "Reversing tap for blocked card: card={} record={} refund={}",
+            self.display_card_id(card_id),
+            record.record_id,
+            tap.charged_cents
+        );
+        if self.last_card_id == card_id {
+            self.last_balance_cents = Some(card_data.balance_cents);
+        }
+        self.pending_reversal = Some(Decision {
+            ack: CardAck::rejected(),
+            event: None,
+            upload_record: Some(record),
+            write_request,
+            registration: None,
+        });
+    }
+
+    /// 取出待下发的冲正（处理器线程调用）。
+    pub fn take_pending_reversal(&mut self) -> Option<Decision> {
+        self.pending_reversal.take()
+    }
+
+    /// 无线路配置时放行并暂存刷卡，不扣费、不写卡。
+    fn queue_no_route_tap(&mut self, detected: &CardDetected, now: u64, now_ms: u64) -> Decision {
+        if self.no_route_taps.len() >= NO_ROUTE_QUEUE_MAX {
+            self.no_route_taps.pop_front();
+            log::warn!("No-route tap queue full, dropped oldest");
+        }
+        let record_id = self.next_record_id(now);
+        self.no_route_taps.push_back((record_id, detected.card_id.clone(), detected.tap_time));
+        self.last_passenger_tone = PassengerTone::Normal;
+        self.last_passenger_message = "刷卡成功".to_string();
+        self.last_fare_base_cents = None;
+        self.last_fare_cents = None;
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Ok, now_ms);
+        Decision {
+            ack: CardAck::accepted(),
+            event: None,
+            upload_record: None,
+            write_request: None,
+            registration: None,
+        }
+    }
+
+    /// 线路同步后取出暂存的刷卡，按当前线路/站点生成上报记录（带 no_route 标记）。
+    pub fn take_no_route_records(&mut self) -> Vec<UploadRecord> {
+        if self.config_cache.route.is_none() || self.no_route_taps.is_empty() {
+            return Vec::new();
+        }
+        let taps: Vec<_> = self.no_route_taps.drain(..).collect();
+        taps.into_iter()
+            .map(|(record_id, card_id, tap_time)| {
+                let event = TapEvent::new(
+                    record_id,
+                    card_id,
+                    self.route_state.route_id,
+                    self.route_state.station_id,
+                    self.route_state.station_name.clone(),
+                    TapType::TapIn,
+                    tap_time,
+                    self.settings.gateway_id.clone(),
+                );
+                let mut record = UploadRecord::from_tap_in(&event);
+                record.alert = Some("no_route".to_string());
+                record
+            })
+            .collect()
+    }
+
+    /// 按当前方向重新计算记录对应的票价（分），含优惠与最低票价。
+    fn corrected_fare_cents(&mut self, card_id: &str, record: &UploadRecord, now_ms: u64) -> u32 {
+        let fare = match (record.board_station_id, record.alight_station_id) {
+            (Some(board), Some(alight)) if record.alight_time.is_some() => self
+                .estimate_trip_fare(board, alight)
+                .or_else(|| self.standard_fare_cents()),
+            _ => self.standard_fare_cents(),
+        };
+        self.last_fare_base_cents = fare;
+        self.last_fare_cents = fare;
+        self.apply_cached_profile(card_id, now_ms);
+        self.apply_min_fare_floor();
+        self.fare_to_cents()
+    }
+
+    /// 防回传检查：开启时下车须有本线路的未完成行程，否则拒绝（保留其他线路的行程）。
+    fn enforce_anti_passback(&mut self, card_id: &str, now: u64, now_ms: u64) -> Option<Decision> {
+        if !self.settings.anti_passback {
+            return None;
+        }
+        let route_id = self.route_state.route_id;
+        let matched = self
+            .active_trips
+            .get(card_id, now)
+            .is_some_and(|prev| prev.route_id == route_id);
+        if matched {
+            return None;
+        }
+        Some(self.reject_card("未检测到上车", now_ms))
+    }
+
+    /// 是否为刚下车结算的卡（误刷宽限期内）。
+    fn is_recent_tap_out(&self, card_id: &str, now_ms: u64) -> bool {
+        matches!(&self.recent_tap_out, Some((id, until_ms)) if id == card_id && now_ms < *until_ms)
+    }
+
+    /// 忽略下车后的误刷：不开启新行程，仅提示已出站。
+    fn ignore_retap(&mut self, now_ms: u64) -> Decision {
+        self.last_passenger_tone = PassengerTone::Normal;
+        self.last_passenger_message = "已下车，无需再刷".to_string();
+        self.last_fare_base_cents = None;
+        self.last_fare_cents = None;
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Action, now_ms);
+        Decision {
+            ack: CardAck::ignored(),
+            event: None,
+            upload_record: None,
+            write_request: None,
+            registration: None,
+        }
+    }
+
     fn reject_card(&mut self, message: &str, now_ms: u64) -> Decision {
         self.reject_with_write(message, None, now_ms)
     }
@@ -658,9 +2071,9 @@ impl GatewayState {
     ) -> Decision {
         self.last_passenger_tone = PassengerTone::Error;
         self.last_passenger_message = message.to_string();
-        self.last_fare_base = None;
-        self.last_fare = None;
-        self.last_message_deadline_ms = now_ms.saturating_add(PASSENGER_MSG_TTL_ERROR_MS);
+        self.last_fare_base_cents = None;
+        self.last_fare_cents = None;
+        self.last_message_deadline_ms = self.message_deadline(MessageKind::Error, now_ms);
         Decision {
             ack: CardAck::rejected(),
             event: None,
@@ -681,6 +2094,7 @@ impl GatewayState {
             if data.status != CardStatus::Blocked {
                 data.status = CardStatus::Blocked;
                 data.entry_station_id = None;
+                data.entry_time = None;
                 write_request = Some(self.build_write_request(card_id, &data, WriteContext::Blacklist));
                 self.push_card_snapshot(card_id, &data, "blacklist", now_ms);
             }
@@ -688,11 +2102,74 @@ impl GatewayState {
         self.reject_with_write("卡已冻结", write_request, now_ms)
     }
 
+    /// 拒绝无上车记录的下车，同时把卡片复位为空闲，避免卡片一直卡在“行程中”。
+    fn reject_stray_tap_out(
+        &mut self,
+        card_id: &str,
+        mut card_data: CardData,
+        now_ms: u64,
+    ) -> Decision {
+        card_data.status = CardStatus::Idle;
+        card_data.entry_station_id = None;
+        card_data.entry_time = None;
+        let write_request = self.build_write_request(card_id, &card_data, WriteContext::TapOut);
+        self.push_card_snapshot(card_id, &card_data, "stray_tap_out", now_ms);
+        self.reject_with_write("未找到上车记录", Some(write_request), now_ms)
+    }
+
+    /// 扣除本次票价后余额是否低于上车最低余额（余额不足以支付票价时也视为低于）。
+    fn below_min_board_balance(&self, balance_cents: u32, fare_cents: u32) -> bool {
+        let min = self.settings.min_board_balance_cents;
+        min > 0 && balance_cents < fare_cents.saturating_add(min)
+    }
+
     fn fare_to_cents(&self) -> u32 {
-        self.last_fare
-            .or(self.last_fare_base)
-            .map(|fare| (fare * 100.0).round().max(0.0) as u32)
-            .unwrap_or(0)
+        self.last_fare_cents.or(self.last_fare_base_cents).unwrap_or(0)
+    }
+
+    /// 应用司机设置的手动票价（不参与优惠与最低票价，但不超过线路封顶价）；返回是否生效。
+    /// 扣费成功后由调用方清除，余额不足时保留到下一次刷卡。
+    fn apply_fare_override(&mut self) -> bool {
+        let Some(mut cents) = self.next_fare_override_cents else {
+            return false;
+        };
+        if let Some(max_fare) = self.config_cache.route.as_ref().and_then(|cfg| cfg.max_fare) {
+            if max_fare > 0.0 {
+                cents = cents.min(yuan_to_cents(max_fare));
+            }
+        }
+        self.last_fare_base_cents = Some(cents);
+        self.last_fare_cents = Some(cents);
+        self.last_fare_label = "手动票价".to_string();
+        true
+    }
+
+    /// 员工卡免票：本次票价归零。
+    fn apply_fare_exemption(&mut self) {
+        self.last_fare_base_cents = Some(0);
+        self.last_fare_cents = Some(0);
+        self.last_fare_label = "员工免票".to_string();
+    }
+
+    /// 最低票价兜底：折扣与距离计价之后，非零票价不低于设定值（但不超过线路封顶价）。
+    fn apply_min_fare_floor(&mut self) {
+        let floor_cents = self.settings.min_fare_cents;
+        let fare_cents = self.fare_to_cents();
+        // 免费行程（如长者/残障全免）保持免费
+        if floor_cents == 0 || fare_cents == 0 || fare_cents >= floor_cents {
+            return;
+        }
+        let mut floor = floor_cents;
+        if let Some(max_fare) = self.config_cache.route.as_ref().and_then(|cfg| cfg.max_fare) {
+            if max_fare > 0.0 {
+                floor = floor.min(yuan_to_cents(max_fare));
+            }
+        }
+        if floor <= fare_cents {
+            return;
+        }
+        self.last_fare_cents = Some(floor);
+        self.last_fare_label = "最低票价".to_string();
     }
 
     fn apply_balance(&mut self, card_data: &mut CardData, fare_cents: u32) -> bool {
@@ -725,6 +2202,9 @@ impl GatewayState {
         context: WriteContext,
     ) -> CardWriteRequest {
         self.last_write_context = Some(context);
+        self.writes_emitted = self.writes_emitted.saturating_add(1);
+        self.pending_write_cards
+            .push_back((card_id.to_string(), self.clock.now_millis()));
         // 保存写入的新余额，以便写卡成功后更新显示
         self.last_written_balance_cents = Some(card_data.balance_cents);
 
@@ -734,12 +2214,18 @@ impl GatewayState {
         let bytes = card_data.to_bytes();
         debug_assert_eq!(bytes.len(), CARD_DATA_LEN);
 
-        CardWriteRequest {
+        let request = CardWriteRequest {
             card_id: card_id.to_string(),
             card_data: bytes.to_vec(),
             block_start: CARD_DATA_BLOCK_START,
             block_count: CARD_DATA_BLOCK_COUNT,
-        }
+            readback: self.settings.write_readback_verify
+                && self.reader_supports(CAP_WRITE_READBACK),
+            batch: self.reader_supports(CAP_BATCH_WRITE),
+        };
+        self.last_write_request = Some(request.clone());
+        self.write_retries = 0;
+        request
     }
 
     fn push_card_snapshot(&mut self, card_id: &str, card_data: &CardData, source: &str, now_ms: u64) {
@@ -769,7 +2255,7 @@ impl GatewayState {
 
     /// 依据卡类型应用默认折扣策略（网关侧预估）。
     pub fn apply_card_discount(&mut self, card_type: &str) {
-        let base = self.last_fare_base.or(self.last_fare);
+        let base = self.last_fare_base_cents.or(self.last_fare_cents);
         let Some(base) = base else {
             return;
         };
@@ -781,9 +2267,8 @@ impl GatewayState {
             _ => return,
         };
         let discount_rate = discount_rate.clamp(0.0_f32, 1.0_f32);
-        let value = base * (1.0 - discount_rate);
-        let discounted = round_currency(value);
-        self.last_fare = Some(discounted);
+        let discount = (base as f32 * discount_rate).round() as u32;
+        self.last_fare_cents = Some(base.saturating_sub(discount));
         let _ = label;
         self.last_fare_label = self.discount_label().to_string();
     }
@@ -795,38 +2280,57 @@ impl GatewayState {
         discount_rate: Option<f32>,
         discount_amount: Option<f32>,
     ) {
-        let base = self.last_fare_base.or(self.last_fare);
+        let base = self.last_fare_base_cents.or(self.last_fare_cents);
         let Some(base) = base else {
             return;
         };
         let card_type = card_type.trim().to_lowercase();
         let _ = card_type;
         let has_policy = discount_rate.is_some() || discount_amount.is_some();
-        let mut discount = 0.0;
+        let mut discount = 0;
         if let Some(amount) = discount_amount {
             if amount > 0.0 {
-                discount = amount;
+                discount = yuan_to_cents(amount);
             }
         }
-        if discount == 0.0 {
+        if discount == 0 {
             if let Some(rate) = discount_rate {
                 if rate >= 0.0 {
                     let rate = rate.clamp(0.0, 1.0);
-                    discount = base * rate;
+                    discount = (base as f32 * rate).round() as u32;
                 }
             }
         }
-        if discount == 0.0 && !has_policy {
+        if discount == 0 && !has_policy {
             self.apply_card_discount(&card_type);
             return;
         }
-        if discount > base {
-            discount = base;
+        self.last_fare_cents = Some(base.saturating_sub(discount));
+        self.last_fare_label = self
+            .card_type_label(&card_type)
+            .unwrap_or_else(|| self.discount_label().to_string());
+    }
+
+    /// 卡类型对应的提示色：优先使用线路配置的映射表，其次内置票种，未知类型为普通票。
+    pub fn tone_for_card_type(&self, card_type: &str) -> PassengerTone {
+        if let Some(style) = self
+            .config_cache
+            .route
+            .as_ref()
+            .and_then(|cfg| cfg.card_type_style(card_type))
+        {
+            return style.tone;
         }
-        let value = base - discount;
-        let discounted = round_currency(value);
-        self.last_fare = Some(discounted);
-        self.last_fare_label = self.discount_label().to_string();
+        PassengerTone::from_name(card_type).unwrap_or(PassengerTone::Normal)
+    }
+
+    /// 线路配置中卡类型的优惠标签。
+    fn card_type_label(&self, card_type: &str) -> Option<String> {
+        self.config_cache
+            .route
+            .as_ref()
+            .and_then(|cfg| cfg.card_type_style(card_type))
+            .and_then(|style| style.label.clone())
     }
 
     fn discount_label(&self) -> &'static str {
@@ -838,6 +2342,10 @@ impl GatewayState {
             .unwrap_or(TapMode::SingleTap);
         if tap_mode == TapMode::TapInOut {
             match self.last_tap_type {
+                Some(TapType::TapIn) if self.settlement_mode() == SettlementMode::Deposit => {
+                    "优惠预扣"
+                }
+                Some(TapType::TapIn) if self.settings.tap_in_max_fare_estimate => "优惠预估最高",
                 Some(TapType::TapIn) => "优惠起步价",
                 Some(TapType::TapOut) => "优惠结算价",
                 None => "优惠票价",
@@ -848,6 +2356,7 @@ impl GatewayState {
     }
 
     /// 更新卡片缓存（LRU 简化策略）。
+    #[allow(clippy::too_many_arguments)]
     pub fn update_card_cache(
         &mut self,
         card_id: String,
@@ -858,7 +2367,8 @@ impl GatewayState {
         balance_cents: Option<u32>,
         now_ms: u64,
     ) {
-        if self.card_cache.len() >= 256 && !self.card_cache.contains_key(&card_id) {
+        self.card_lookup_misses.retain(|(id, _)| *id != card_id);
+        if self.card_cache.len() >= CARD_CACHE_MAX && !self.card_cache.contains_key(&card_id) {
             if let Some((oldest_id, _)) = self
                 .card_cache
                 .iter()
@@ -881,10 +2391,105 @@ impl GatewayState {
         );
     }
 
+    /// 缓存剩余容量（预加载不淘汰刷卡时查询到的画像）。
+    pub fn card_cache_free_slots(&self) -> usize {
+        CARD_CACHE_MAX.saturating_sub(self.card_cache.len())
+    }
+
+    /// 卡片是否已有画像缓存（不论是否过期）。
+    pub fn card_cached(&self, card_id: &str) -> bool {
+        self.card_cache.contains_key(card_id)
+    }
+
+    /// 批量预加载卡画像：最多 limit 条，只填充空余容量、不覆盖已有缓存；返回加载条数。
+    pub fn preload_card_profiles(
+        &mut self,
+        profiles: Vec<(String, CardProfile)>,
+        limit: usize,
+    ) -> usize {
+        let now_ms = self.now_millis();
+        let mut loaded = 0;
+        for (card_id, profile) in profiles {
+            if loaded >= limit || self.card_cache_free_slots() == 0 {
+                break;
+            }
+            if self.card_cached(&card_id) {
+                continue;
+            }
+            self.update_card_cache(
+                card_id,
+                profile.card_type,
+                profile.status,
+                profile.discount_rate,
+                profile.discount_amount,
+                profile.balance_cents,
+                now_ms,
+            );
+            loaded += 1;
+        }
+        loaded
+    }
+
+    /// 清空卡片画像缓存，下次刷卡重新查询后端；返回清除条数。
+    pub fn clear_card_cache(&mut self) -> usize {
+        let count = self.card_cache.len();
+        self.card_cache.clear();
+        self.card_lookup_misses.clear();
+        count
+    }
+
+    /// 使单张卡的画像缓存失效（后端推送卡类型/状态变更时调用）。
+    pub fn invalidate_card_profile(&mut self, card_id: &str) -> bool {
+        self.card_cache.remove(card_id).is_some()
+    }
+
+    /// 是否已有未过期的卡片画像缓存。
+    pub fn has_cached_profile(&self, card_id: &str, now_ms: u64) -> bool {
+        self.cached_profile(card_id, now_ms).is_some()
+    }
+
+    /// 记录后端查无此卡（负缓存），避免每次刷卡都等待必然落空的查询。
+    pub fn record_card_lookup_miss(&mut self, card_id: &str, now_ms: u64) {
+        self.card_lookup_misses.retain(|(id, _)| id != card_id);
+        if self.card_lookup_misses.len() >= LOOKUP_MISS_MAX {
+            self.card_lookup_misses.pop_front();
+        }
+        self.card_lookup_misses.push_back((card_id.to_string(), now_ms));
+    }
+
+    /// 近期是否查询过且后端查无此卡。
+    pub fn lookup_miss_cached(&self, card_id: &str, now_ms: u64) -> bool {
+        self.card_lookup_misses
+            .iter()
+            .any(|(id, at)| id == card_id && now_ms.saturating_sub(*at) < LOOKUP_MISS_TTL_MS)
+    }
+
+    /// 刷卡前需等待后端查询的时长（毫秒）：已有画像、近期查无此卡或后端不可达时不等待。
+    pub fn card_lookup_wait_ms(&self, card_id: &str, now_ms: u64) -> u64 {
+        if !self.wifi_connected
+            || !self.backend_reachable
+            || self.has_cached_profile(card_id, now_ms)
+            || self.lookup_miss_cached(card_id, now_ms)
+        {
+            return 0;
+        }
+        self.settings.lookup_wait_ms as u64
+    }
+
+    /// 缓存画像中的卡类型既无线路映射、也非内置票种时返回该类型。
+    fn unknown_card_type(&self, card_id: &str, now_ms: u64) -> Option<String> {
+        let card_type = self.cached_profile(card_id, now_ms)?.card_type?;
+        let known = PassengerTone::from_name(&card_type).is_some()
+            || self
+                .config_cache
+                .route
+                .as_ref()
+                .is_some_and(|cfg| cfg.card_type_style(&card_type).is_some());
+        (!known).then_some(card_type)
+    }
+
     fn cached_profile(&self, card_id: &str, now_ms: u64) -> Option<CachedCardProfile> {
-        let Some(profile) = self.card_cache.get(card_id).cloned() else {
-            return None;
-        };
+        let profile = self.card_cache.get(card_id).cloned()?;
         if now_ms.saturating_sub(profile.updated_at_ms) > CARD_CACHE_TTL_MS {
             return None;
         }
@@ -903,25 +2508,20 @@ impl GatewayState {
             if status == "blocked" {
                 self.last_passenger_tone = PassengerTone::Error;
                 self.last_passenger_message = "卡已冻结".to_string();
-                self.last_fare_base = None;
-                self.last_fare = None;
+                self.last_fare_base_cents = None;
+                self.last_fare_cents = None;
                 return;
             }
             if status == "lost" {
                 self.last_passenger_tone = PassengerTone::Error;
                 self.last_passenger_message = "卡已挂失".to_string();
-                self.last_fare_base = None;
-                self.last_fare = None;
+                self.last_fare_base_cents = None;
+                self.last_fare_cents = None;
                 return;
             }
         }
         if let Some(card_type) = profile.card_type.as_deref() {
-            match card_type {
-                "student" => self.last_passenger_tone = PassengerTone::Student,
-                "elder" => self.last_passenger_tone = PassengerTone::Elder,
-                "disabled" => self.last_passenger_tone = PassengerTone::Disabled,
-                _ => {}
-            }
+            self.last_passenger_tone = self.tone_for_card_type(card_type);
             self.apply_card_discount_policy(
                 card_type,
                 profile.discount_rate,
@@ -930,61 +2530,158 @@ impl GatewayState {
         }
     }
 
-    pub fn standard_fare(&self) -> Option<f32> {
+    /// 线路标准票价（分），仅取当前生效的票价规则。
+    pub fn standard_fare_cents(&self) -> Option<u32> {
+        let now = self.fare_rule_now();
+        self.config_cache
+            .route
+            .as_ref()
+            .and_then(|cfg| cfg.standard_fare(now))
+            .map(yuan_to_cents)
+    }
+
+    /// 线路配置了收费规则，但当前时间没有可用于计价的规则。
+    fn fare_unavailable(&self) -> bool {
+        let now = self.fare_rule_now();
+        self.config_cache
+            .route
+            .as_ref()
+            .is_some_and(|cfg| cfg.has_priced_fares() && cfg.standard_fare(now).is_none())
+    }
+
+    /// 判断票价规则生效区间所用的当前时间：带生效区间的规则需已校时，未校时为 None。
+    fn fare_rule_now(&self) -> Option<u64> {
+        Some(self.now_secs()).filter(|now| *now >= TIME_SYNCED_MIN_EPOCH)
+    }
+
+    /// 乘客屏提示截止时间：线路覆盖优先，其次网关设置，最后内置默认值。
+    fn message_deadline(&self, kind: MessageKind, now_ms: u64) -> u64 {
+        let pick = |ttl: &MessageTtlOverride| match kind {
+            MessageKind::Ok => ttl.ok_ms,
+            MessageKind::Action => ttl.action_ms,
+            MessageKind::Error => ttl.error_ms,
+        };
+        let default_ms = match kind {
+            MessageKind::Ok => PASSENGER_MSG_TTL_OK_MS,
+            MessageKind::Action => PASSENGER_MSG_TTL_ACTION_MS,
+            MessageKind::Error => PASSENGER_MSG_TTL_ERROR_MS,
+        };
+        let ttl_ms = self
+            .config_cache
+            .route
+            .as_ref()
+            .and_then(|cfg| pick(&cfg.message_ttl))
+            .or_else(|| pick(&self.settings.message_ttl))
+            .map(u64::from)
+            .unwrap_or(default_ms);
+        now_ms.saturating_add(ttl_ms)
+    }
+
+    /// 指定线路的未完成行程超时（秒）；无线路级配置时返回 None（使用全局值）。
+    fn route_trip_timeout_secs(&self, route_id: u16) -> Option<u32> {
         self.config_cache
             .route
             .as_ref()
-            .and_then(|cfg| cfg.standard_fare())
-            .map(round_currency)
+            .filter(|cfg| cfg.route_id == route_id)
+            .and_then(|cfg| cfg.trip_timeout_secs)
     }
 
-    /// 网关侧估算票价（用于即时提示，不作为最终结算）。
-    fn estimate_trip_fare(&self, start_station_id: u16, end_station_id: u16) -> Option<f32> {
+    /// 网关侧估算票价（分，用于即时提示，不作为最终结算）。
+    fn estimate_trip_fare(&self, start_station_id: u16, end_station_id: u16) -> Option<u32> {
         let cfg = self.config_cache.route.as_ref()?;
+        let index = &self.config_cache.fare_index;
+        let now = self.fare_rule_now();
         if start_station_id == 0 || end_station_id == 0 {
-            return cfg.standard_fare().map(round_currency);
+            return cfg.standard_fare(now).map(yuan_to_cents);
         }
-        if let Some(rule) = cfg.fares.iter().find(|fare| {
-            fare.start_station == Some(start_station_id) && fare.end_station == Some(end_station_id)
-        }) {
-            if rule.base_price > 0.0 {
-                return Some(round_currency(rule.base_price));
+        if let Some(price) = index.pair_price(start_station_id, end_station_id, now) {
+            if price > 0.0 {
+                return Some(yuan_to_cents(price));
             }
         }
         match cfg.fare_type {
-            crate::model::FareType::Uniform => cfg.standard_fare().map(round_currency),
+            crate::model::FareType::Uniform => cfg.standard_fare(now).map(yuan_to_cents),
             crate::model::FareType::Segment | crate::model::FareType::Distance => {
-                let start_seq = cfg
-                    .stations
-                    .iter()
-                    .find(|s| s.id == start_station_id)
-                    .map(|s| s.sequence)?;
-                let end_seq = cfg
-                    .stations
-                    .iter()
-                    .find(|s| s.id == end_station_id)
-                    .map(|s| s.sequence)?;
-                let diff = if start_seq >= end_seq {
-                    start_seq - end_seq
-                } else {
-                    end_seq - start_seq
-                };
-                let base_rule = cfg.fares.iter().find(|fare| {
-                    fare.start_station.unwrap_or(0) == 0 && fare.end_station.unwrap_or(0) == 0
-                });
+                let start_seq = index.sequence(start_station_id)?;
+                let end_seq = index.sequence(end_station_id)?;
+                let diff = start_seq.abs_diff(end_seq);
+                let base_rule = index.base_rule(now);
                 let base_price = base_rule.map(|r| r.base_price).unwrap_or(0.0);
                 if base_price <= 0.0 {
-                    return cfg.standard_fare().map(round_currency);
+                    return cfg.standard_fare(now).map(yuan_to_cents);
+                }
+                // 距离计价：起步价 + 每公里单价 × 里程
+                if cfg.fare_type == crate::model::FareType::Distance {
+                    let per_km = base_rule.and_then(|r| r.per_km_price).unwrap_or(0.0);
+                    if per_km > 0.0 {
+                        if let Some(distance_m) = index.distance_m(start_station_id, end_station_id) {
+                            let per_km_cents = yuan_to_cents(per_km) as u64;
+                            let distance_cents = (per_km_cents * distance_m + 500) / 1000;
+                            let distance_cents = distance_cents.min(u32::MAX as u64) as u32;
+                            return Some(yuan_to_cents(base_price).saturating_add(distance_cents));
+                        }
+                    }
                 }
                 let extra = base_rule.and_then(|r| r.extra_price).unwrap_or(0.0);
                 let included = base_rule.and_then(|r| r.segment_count).unwrap_or(1);
                 if diff <= included || extra <= 0.0 {
-                    return Some(round_currency(base_price));
+                    return Some(yuan_to_cents(base_price));
                 }
-                let extra_segments = diff.saturating_sub(included) as f32;
-                Some(round_currency(base_price + extra * extra_segments))
+                let extra_segments = diff.saturating_sub(included) as u32;
+                Some(yuan_to_cents(base_price).saturating_add(yuan_to_cents(extra).saturating_mul(extra_segments)))
+            }
+        }
+    }
+
+    /// 按卡内进站时间计算超时附加费：超出免费时长后每个计费间隔（不足按一个计）加收，
+    /// 合计不超过线路封顶价（基础票价本身已超过封顶价时不再加收）。
+    fn add_late_fee(&self, fare_cents: u32, entry_time: Option<u32>, now: u64) -> u32 {
+        let step_cents = self.settings.late_fee_step_cents;
+        let step_secs = self.settings.late_fee_step_mins as u64 * 60;
+        if step_cents == 0 || step_secs == 0 || now < TIME_SYNCED_MIN_EPOCH {
+            return fare_cents;
+        }
+        let Some(entry_time) = entry_time else {
+            return fare_cents;
+        };
+        let free_secs = self.settings.late_fee_free_mins as u64 * 60;
+        let elapsed = now.saturating_sub(entry_time as u64);
+        if elapsed <= free_secs {
+            return fare_cents;
+        }
+        let steps = (elapsed - free_secs).div_ceil(step_secs);
+        let late_fee = steps.saturating_mul(step_cents as u64).min(u32::MAX as u64) as u32;
+        let mut total = fare_cents.saturating_add(late_fee);
+        if let Some(max_fare) = self.config_cache.route.as_ref().and_then(|cfg| cfg.max_fare) {
+            if max_fare > 0.0 {
+                total = total.min(yuan_to_cents(max_fare).max(fare_cents));
             }
         }
+        if total > fare_cents {
+            log::info!("Late fee applied: elapsed={}s fee={}", elapsed, total - fare_cents);
+        }
+        total
+    }
+
+    /// 当前线路的结算方式（未同步配置时为下车扣费）。
+    fn settlement_mode(&self) -> SettlementMode {
+        self.config_cache
+            .route
+            .as_ref()
+            .map(|cfg| cfg.settlement_mode)
+            .unwrap_or_default()
+    }
+
+    /// 从指定站上车的最高可能票价：优先线路封顶票价，否则取到各站票价的最大值。
+    fn max_trip_fare_from(&self, start_station_id: u16) -> Option<u32> {
+        let cfg = self.config_cache.route.as_ref()?;
+        if let Some(max_fare) = cfg.max_fare.filter(|fare| *fare > 0.0) {
+            return Some(yuan_to_cents(max_fare));
+        }
+        cfg.stations
+            .iter()
+            .filter_map(|station| self.estimate_trip_fare(start_station_id, station.id))
+            .max()
     }
 
     fn next_record_id(&mut self, now: u64) -> String {
@@ -1006,15 +2703,2373 @@ fn hex_prefix(bytes: &[u8], max_len: usize) -> String {
     out
 }
 
-/// 获取当前毫秒时间戳。
-fn current_epoch_millis() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_millis() as u64)
-        .unwrap_or(0)
+/// 金额（元）换算为分（四舍五入，负数与 NaN/Inf 按 0）；配置中的元金额只在此处转换一次。
+fn yuan_to_cents(value: f32) -> u32 {
+    if !value.is_finite() {
+        return 0;
+    }
+    (value * 100.0).round().max(0.0) as u32
 }
 
-/// 金额保留两位小数（四舍五入）。
-fn round_currency(value: f32) -> f32 {
-    (value * 100.0).round() / 100.0
+/// 下车提示：上车站与乘车时长（不足 1 分钟按 1 分钟显示）。
+fn format_board_message(board_station: &str, elapsed_secs: Option<u64>) -> String {
+    match elapsed_secs {
+        Some(secs) => format!("从{}上车，用时{}分钟", board_station, secs.div_ceil(60).max(1)),
+        None => format!("从{}上车", board_station),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::model::CardTypeStyle;
+    use crate::proto::{CAP_INLINE_WRITE, CAP_TELEMETRY};
+
+    // 2024-01-01 00:00:00 UTC，已校时。
+    const T0_MS: u64 = 1_704_067_200_000;
+
+    fn route_config(tap_mode: &str) -> RouteConfig {
+        serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": tap_mode,
+            "stations": [
+                {"id": 1, "name": "一站", "sequence": 1},
+                {"id": 2, "name": "二站", "sequence": 2},
+                {"id": 3, "name": "三站", "sequence": 3}
+            ],
+            "fares": [{"base_price": 2.0}]
+        }))
+        .unwrap()
+    }
+
+    fn test_state(settings: GatewaySettings) -> (GatewayState, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(T0_MS));
+        let mut state = GatewayState::bootstrap(settings).with_clock(clock.clone());
+        state.update_route_config(route_config("single_tap"), T0_MS / 1000);
+        (state, clock)
+    }
+
+    fn default_state() -> (GatewayState, Arc<MockClock>) {
+        test_state(GatewaySettings::with_gateway_id("gw-test"))
+    }
+
+    fn in_out_state(settings: GatewaySettings) -> (GatewayState, Arc<MockClock>) {
+        let (mut state, clock) = test_state(settings);
+        state.update_route_config(route_config("tap_in_out"), T0_MS / 1000);
+        (state, clock)
+    }
+
+    const CARD_ID: &str = "A1B2C3D4";
+
+    fn card(balance_cents: u32) -> CardData {
+        let mut card = CardData::new(CardUid::from_slice(&[0xA1, 0xB2, 0xC3, 0xD4]).unwrap());
+        card.balance_cents = balance_cents;
+        card
+    }
+
+    fn tap_bytes(state: &mut GatewayState, reader_id: u16, card_data: Vec<u8>) -> Decision {
+        let now = state.now_secs();
+        let detected = CardDetected {
+            card_id: CARD_ID.to_string(),
+            tap_time: now,
+            reader_id,
+            card_data,
+        };
+        state.handle_card_detected(detected, now)
+    }
+
+    fn tap_from(state: &mut GatewayState, reader_id: u16, card: &CardData) -> Decision {
+        tap_bytes(state, reader_id, card.to_bytes().to_vec())
+    }
+
+    fn tap(state: &mut GatewayState, card: &CardData) -> Decision {
+        tap_from(state, 1, card)
+    }
+
+    /// 模拟读卡器写卡成功，返回写入后的卡内数据。
+    fn complete_write(state: &mut GatewayState, decision: &Decision) -> CardData {
+        let request = decision.write_request.as_ref().expect("write request");
+        let result = CardWriteResult {
+            result: 1,
+            error_code: 0,
+            block_start: request.block_start,
+            block_count: request.block_count,
+            readback: Vec::new(),
+        };
+        let now_ms = state.now_millis();
+        state.handle_write_result(result, now_ms);
+        CardData::from_bytes(&request.card_data).unwrap()
+    }
+
+    #[test]
+    fn auto_advance_steps_and_turns_at_terminus() {
+        let (mut state, _) = default_state();
+        assert_eq!(state.route_state.station_id, 1);
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 2);
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 3);
+        assert_eq!(state.route_state.station_name, "三站");
+        // 终点站掉头，站点不变
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 3);
+        assert_eq!(state.route_state.direction, Direction::Down);
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 2);
+        assert!(state.auto_advance_station());
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 1);
+        assert_eq!(state.route_state.direction, Direction::Up);
+    }
+
+    #[test]
+    fn auto_advance_respects_manual_pause() {
+        let (mut state, clock) = default_state();
+        let pause_ms = state.settings.auto_advance_pause_secs as u64 * 1000;
+        state.pause_auto_advance(state.now_millis());
+        clock.advance(pause_ms - 1);
+        assert!(!state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 1);
+        clock.advance(1);
+        assert!(state.auto_advance_station());
+        assert_eq!(state.route_state.station_id, 2);
+    }
+
+    #[test]
+    fn auto_advance_needs_known_station() {
+        let clock = Arc::new(MockClock::new(T0_MS));
+        let mut state =
+            GatewayState::bootstrap(GatewaySettings::with_gateway_id("gw-test")).with_clock(clock);
+        assert!(!state.auto_advance_station());
+        state.update_route_config(route_config("single_tap"), T0_MS / 1000);
+        state.route_state.station_id = 99;
+        assert!(!state.auto_advance_station());
+        assert_eq!(state.route_state.direction, Direction::Up);
+    }
+
+    #[test]
+    fn min_fare_floor_raises_low_fares() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_fare_cents = 300;
+        let (mut state, _) = test_state(settings);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_fare_label, "最低票价");
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 700);
+    }
+
+    #[test]
+    fn min_fare_floor_is_capped_by_max_fare() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_fare_cents = 300;
+        let (mut state, _) = test_state(settings);
+        let mut cfg = route_config("single_tap");
+        cfg.max_fare = Some(2.5);
+        state.update_route_config(cfg, state.now_secs());
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 750);
+    }
+
+    fn in_trip_card(balance_cents: u32, entry_station_id: u16) -> CardData {
+        let mut card = card(balance_cents);
+        card.status = CardStatus::InTrip;
+        card.entry_station_id = Some(entry_station_id);
+        card
+    }
+
+    #[test]
+    fn stray_tap_out_is_rejected_and_card_reset() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &in_trip_card(1000, 1));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.upload_record.is_none());
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.status, CardStatus::Idle);
+        assert_eq!(written.entry_station_id, None);
+        assert_eq!(written.balance_cents, 1000);
+    }
+
+    #[test]
+    fn stray_tap_out_penalty_is_charged() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.stray_tap_out_policy = StrayTapOutPolicy::Penalty;
+        settings.stray_tap_out_penalty_cents = 500;
+        let (mut state, _) = in_out_state(settings);
+        let decision = tap(&mut state, &in_trip_card(1000, 1));
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_fare_label, "补票罚金");
+        let record = decision.upload_record.clone().unwrap();
+        assert_eq!(record.board_station_id, Some(1));
+        assert_eq!(record.fare_cents, Some(500));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 500);
+    }
+
+    #[test]
+    fn unanswered_writes_raise_unconfirmed_warning() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.write_unconfirmed_threshold = 0;
+        let (mut state, _) = test_state(settings);
+        assert!(!state.write_unconfirmed());
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.pending_write_count(), 1);
+        assert!(state.write_unconfirmed());
+        complete_write(&mut state, &decision);
+        assert_eq!(state.pending_write_count(), 0);
+        assert!(!state.write_unconfirmed());
+        // 多余的写卡结果不会让计数变为负数
+        let now_ms = state.now_millis();
+        state.handle_write_result(
+            CardWriteResult {
+                result: 1,
+                error_code: 0,
+                block_start: CARD_DATA_BLOCK_START,
+                block_count: CARD_DATA_BLOCK_COUNT,
+                readback: Vec::new(),
+            },
+            now_ms,
+        );
+        assert_eq!(state.pending_write_count(), 0);
+    }
+
+    #[test]
+    fn damaged_trip_block_still_charges_from_balance_block() {
+        let (mut state, _) = default_state();
+        let mut bytes = card(1000).to_bytes();
+        bytes[17] ^= 0xFF;
+        let decision = tap_bytes(&mut state, 1, bytes.to_vec());
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(
+            state.last_card_data_error.as_deref(),
+            Some("bad_block_crc_1")
+        );
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 800);
+    }
+
+    #[test]
+    fn damaged_balance_block_is_not_charged() {
+        let (mut state, _) = default_state();
+        let mut bytes = card(1000).to_bytes();
+        bytes[9] ^= 0xFF;
+        let decision = tap_bytes(&mut state, 1, bytes.to_vec());
+        assert!(decision.write_request.is_none());
+        assert_eq!(
+            state.last_card_data_error.as_deref(),
+            Some("bad_block_crc_0")
+        );
+    }
+
+    #[test]
+    fn reader_hello_negotiates_capabilities() {
+        let (mut state, _) = default_state();
+        assert!(!state.reader_supports(CAP_INLINE_WRITE));
+        state.update_reader_hello(ReaderHello {
+            protocol_version: 1,
+            capabilities: CAP_INLINE_WRITE | CAP_TELEMETRY,
+            firmware_version: "rdr-2.1.0".to_string(),
+        });
+        assert!(state.reader_supports(CAP_INLINE_WRITE));
+        // 网关未实现的能力不会被启用
+        assert!(!state.reader_supports(CAP_TELEMETRY));
+    }
+
+    #[test]
+    fn route_trip_timeout_overrides_global_ttl() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let mut cfg = route_config("tap_in_out");
+        cfg.trip_timeout_secs = Some(600);
+        state.update_route_config(cfg, state.now_secs());
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        let now = state.now_secs();
+        assert!(state.active_trips.contains(CARD_ID, now + 600));
+        assert!(!state.active_trips.contains(CARD_ID, now + 601));
+    }
+
+    #[test]
+    fn tap_is_rejected_while_previous_write_pending() {
+        let (mut state, clock) = default_state();
+        let first = tap(&mut state, &card(1000));
+        assert_eq!(first.ack.result, 1);
+        clock.advance(3000);
+        let second = tap(&mut state, &card(1000));
+        assert_eq!(second.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "处理中");
+        assert!(second.write_request.is_none());
+        // 写卡结果回传后按新卡内数据处理
+        let written = complete_write(&mut state, &first);
+        clock.advance(3000);
+        let third = tap(&mut state, &written);
+        assert_eq!(third.ack.result, 1);
+        assert_eq!(complete_write(&mut state, &third).balance_cents, 600);
+    }
+
+    #[test]
+    fn write_lock_expires_without_result() {
+        let (mut state, clock) = default_state();
+        tap(&mut state, &card(1000));
+        let now_ms = state.now_millis();
+        assert!(state.write_pending_for(CARD_ID, now_ms + WRITE_LOCK_TTL_MS - 1));
+        assert!(!state.write_pending_for(CARD_ID, now_ms + WRITE_LOCK_TTL_MS));
+        clock.advance(WRITE_LOCK_TTL_MS);
+        assert_eq!(tap(&mut state, &card(1000)).ack.result, 1);
+    }
+
+    fn partial_config(stations: &[(u16, &str)], base_price: f32) -> RouteConfig {
+        let stations: Vec<serde_json::Value> = stations
+        .iter()
+        .enumerate()
+        .map(|(index, (id, name))| serde_json::json!({"id": id, "name": name, "sequence": index + 1}))
+        .collect();
+        serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap",
+            "max_fare": 5.0,
+            "stations": stations,
+            "fares": [{"base_price": base_price}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fares_update_keeps_cached_stations() {
+        let (mut state, _) = default_state();
+        state.merge_route_config(
+            partial_config(&[], 3.0),
+            ConfigUpdateScope::Fares,
+            T0_MS / 1000,
+        );
+        let route = state.config_cache.route.as_ref().unwrap();
+        assert_eq!(route.stations.len(), 3);
+        assert_eq!(route.fares[0].base_price, 3.0);
+        assert_eq!(route.max_fare, Some(5.0));
+    }
+
+    #[test]
+    fn stations_update_keeps_cached_fares() {
+        let (mut state, _) = default_state();
+        state.merge_route_config(
+            partial_config(&[(1, "一站"), (4, "四站")], 9.0),
+            ConfigUpdateScope::Stations,
+            T0_MS / 1000,
+        );
+        let route = state.config_cache.route.as_ref().unwrap();
+        assert_eq!(route.stations.len(), 2);
+        assert_eq!(route.stations[1].name, "四站");
+        assert_eq!(route.fares[0].base_price, 2.0);
+        assert_eq!(route.max_fare, None);
+    }
+
+    #[test]
+    fn partial_update_for_other_route_replaces_config() {
+        let (mut state, _) = default_state();
+        let mut config = partial_config(&[(9, "九站")], 4.0);
+        config.route_id = 8;
+        state.merge_route_config(config, ConfigUpdateScope::Fares, T0_MS / 1000);
+        let route = state.config_cache.route.as_ref().unwrap();
+        assert_eq!(route.route_id, 8);
+        assert_eq!(route.stations.len(), 1);
+        assert_eq!(route.fares[0].base_price, 4.0);
+    }
+
+    fn card_from_other_gateway(state: &GatewayState, secs_ago: u64) -> CardData {
+        let mut card = card(1000);
+        let tag = gateway_tag(&state.settings.gateway_id);
+        card.last_tap_gateway = Some(tag.wrapping_add(1).max(1));
+        card.last_tap_time = Some((state.now_secs() - secs_ago) as u32);
+        card
+    }
+
+    #[test]
+    fn impossible_travel_alerts_by_default() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.tamper_min_travel_secs = 120;
+        let (mut state, _) = test_state(settings);
+        let card = card_from_other_gateway(&state, 30);
+        let decision = tap(&mut state, &card);
+        assert_eq!(decision.ack.result, 1);
+        let record = decision.upload_record.as_ref().unwrap();
+        assert_eq!(record.alert.as_deref(), Some("impossible_travel"));
+        // 写回卡内的是本网关标记与本次时间
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.last_tap_gateway, Some(gateway_tag("gw-test")));
+        assert_eq!(written.last_tap_time, Some(state.now_secs() as u32));
+    }
+
+    #[test]
+    fn impossible_travel_blocks_when_configured() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.tamper_min_travel_secs = 120;
+        settings.tamper_policy = TamperPolicy::Block;
+        let (mut state, clock) = test_state(settings);
+        let card = card_from_other_gateway(&state, 30);
+        let decision = tap(&mut state, &card);
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        // 超过最短通行时间则正常放行
+        clock.advance(3000);
+        let card = card_from_other_gateway(&state, 120);
+        let decision = tap(&mut state, &card);
+        assert_eq!(decision.ack.result, 1);
+        assert!(decision.upload_record.unwrap().alert.is_none());
+    }
+
+    #[test]
+    fn direction_correction_reuses_record_within_grace() {
+        let (mut state, clock) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        let record_id = decision.upload_record.as_ref().unwrap().record_id.clone();
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.balance_cents, 800);
+
+        clock.advance(3000);
+        state.set_direction(Direction::Down);
+        let decision = tap(&mut state, &written);
+        assert_eq!(decision.ack.result, 1);
+        let record = decision.upload_record.as_ref().unwrap();
+        assert_eq!(record.record_id, record_id);
+        assert_eq!(record.direction.as_deref(), Some("down"));
+        assert_eq!(record.fare_cents, Some(200));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 800);
+        assert_eq!(state.last_passenger_message, "方向已更正");
+    }
+
+    #[test]
+    fn direction_correction_expires_after_grace() {
+        let (mut state, clock) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        let record_id = decision.upload_record.as_ref().unwrap().record_id.clone();
+        let written = complete_write(&mut state, &decision);
+
+        clock.advance(10_000);
+        state.set_direction(Direction::Down);
+        let decision = tap(&mut state, &written);
+        // 宽限期已过，按新一次刷卡扣费
+        assert_ne!(state.last_passenger_message, "方向已更正");
+        assert_ne!(
+            decision.upload_record.as_ref().unwrap().record_id,
+            record_id
+        );
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 600);
+    }
+
+    fn recharge_state() -> (GatewayState, Arc<MockClock>) {
+        let (mut state, clock) = default_state();
+        let now_ms = state.now_millis();
+        state.set_recharge_mode(1000, now_ms);
+        (state, clock)
+    }
+
+    #[test]
+    fn recharge_rejects_card_in_trip() {
+        let (mut state, _) = recharge_state();
+        let decision = tap(&mut state, &in_trip_card(500, 2));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "卡状态异常");
+        assert_eq!(state.stuck_trip_card.as_deref(), Some(CARD_ID));
+        assert_eq!(state.stuck_trip_seq, 1);
+        // 同一张卡再次被拒不改变序号
+        let _ = tap(&mut state, &in_trip_card(500, 2));
+        assert_eq!(state.stuck_trip_seq, 1);
+    }
+
+    #[test]
+    fn forced_clear_allows_recharge() {
+        let (mut state, clock) = recharge_state();
+        let card = in_trip_card(500, 2);
+        let _ = tap(&mut state, &card);
+        assert!(state.arm_force_clear_trip(CARD_ID, None));
+
+        clock.advance(3000);
+        let decision = tap(&mut state, &card);
+        assert_eq!(decision.ack.result, 1);
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.status, CardStatus::Idle);
+        assert_eq!(written.entry_station_id, None);
+        assert_eq!(written.balance_cents, 1500);
+        assert!(state.stuck_trip_card.is_none());
+        assert!(state.force_clear_card.is_none());
+    }
+
+    #[test]
+    fn masked_force_clear_requires_matching_seq() {
+        let (mut state, _) = recharge_state();
+        let _ = tap(&mut state, &in_trip_card(500, 2));
+        assert!(!state.arm_force_clear_trip("****C3D4", None));
+        assert!(!state.arm_force_clear_trip("****C3D4", Some(0)));
+        assert!(!state.arm_force_clear_trip("****FFFF", Some(1)));
+        assert!(state.arm_force_clear_trip("****C3D4", Some(1)));
+        assert_eq!(state.force_clear_card.as_deref(), Some(CARD_ID));
+    }
+
+    fn role_state() -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.reader_roles = vec![(1, ReaderRole::Board), (2, ReaderRole::Alight)];
+        in_out_state(settings)
+    }
+
+    #[test]
+    fn alight_reader_settles_open_trip() {
+        let (mut state, clock) = role_state();
+        let decision = tap_from(&mut state, 1, &card(1000));
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        let boarded = complete_write(&mut state, &decision);
+        assert_eq!(boarded.status, CardStatus::InTrip);
+
+        clock.advance(60_000);
+        assert!(state.set_station_by_id(2));
+        let decision = tap_from(&mut state, 2, &boarded);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapOut);
+        let record = decision.upload_record.as_ref().unwrap();
+        assert_eq!(record.board_station_id, Some(1));
+        assert_eq!(record.alight_station_id, Some(2));
+        assert_eq!(
+            complete_write(&mut state, &decision).status,
+            CardStatus::Idle
+        );
+    }
+
+    #[test]
+    fn alight_reader_never_opens_a_trip() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.reader_roles = vec![(2, ReaderRole::Alight)];
+        settings.stray_tap_out_policy = StrayTapOutPolicy::Reject;
+        let (mut state, _) = in_out_state(settings);
+        let decision = tap_from(&mut state, 2, &card(1000));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.active_trips.len(), 0);
+    }
+
+    #[test]
+    fn unlisted_reader_defaults_to_any() {
+        let (state, _) = role_state();
+        assert_eq!(state.settings.reader_role(1), ReaderRole::Board);
+        assert_eq!(state.settings.reader_role(2), ReaderRole::Alight);
+        assert_eq!(state.settings.reader_role(3), ReaderRole::Any);
+    }
+
+    #[test]
+    fn idle_timer_starts_from_injected_clock() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.idle_timeout_secs = 60;
+        let (mut state, clock) = test_state(settings);
+        assert!(!state.is_idle(state.now_millis()));
+        clock.advance(59_999);
+        assert!(!state.is_idle(state.now_millis()));
+        clock.advance(1);
+        assert!(state.is_idle(state.now_millis()));
+        // 刷卡即退出待机
+        let _ = tap(&mut state, &card(1000));
+        assert!(!state.is_idle(state.now_millis()));
+    }
+
+    #[test]
+    fn zero_idle_timeout_never_idles() {
+        let (mut state, clock) = default_state();
+        assert_eq!(state.settings.idle_timeout_secs, 0);
+        clock.advance(24 * 3600 * 1000);
+        assert!(!state.is_idle(state.now_millis()));
+        // 司机操作同样刷新空闲计时
+        state.settings.idle_timeout_secs = 60;
+        state.touch_activity(state.now_millis());
+        clock.advance(59_000);
+        assert!(!state.is_idle(state.now_millis()));
+    }
+
+    #[test]
+    fn recharge_mode_expires_exactly_at_ttl() {
+        let (mut state, clock) = recharge_state();
+        clock.advance(RECHARGE_MODE_TTL_MS - 1);
+        let decision = tap(&mut state, &card(500));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 1500);
+
+        let (mut state, clock) = recharge_state();
+        clock.advance(RECHARGE_MODE_TTL_MS);
+        let decision = tap(&mut state, &card(500));
+        // 充值模式已过期，按普通刷卡扣费
+        assert!(state.recharge_mode.is_none());
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 300);
+    }
+
+    #[test]
+    fn message_deadline_follows_clock_and_overrides() {
+        let (mut state, _) = default_state();
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(
+            state.last_message_deadline_ms,
+            T0_MS + PASSENGER_MSG_TTL_OK_MS
+        );
+
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.message_ttl.ok_ms = Some(5000);
+        let (mut state, clock) = test_state(settings);
+        clock.advance(1000);
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(state.last_message_deadline_ms, T0_MS + 6000);
+    }
+
+    #[test]
+    fn card_type_tone_table_overrides_builtin_names() {
+        let (mut state, _) = default_state();
+        let mut config = route_config("single_tap");
+        config.card_type_styles = vec![
+            CardTypeStyle {
+                card_type: "veteran".to_string(),
+                tone: PassengerTone::Elder,
+                label: Some("优待票".to_string()),
+            },
+            CardTypeStyle {
+                card_type: "student".to_string(),
+                tone: PassengerTone::Normal,
+                label: None,
+            },
+        ];
+        state.update_route_config(config, T0_MS / 1000);
+        assert_eq!(state.tone_for_card_type(" Veteran "), PassengerTone::Elder);
+        assert_eq!(state.card_type_label("veteran").as_deref(), Some("优待票"));
+        // 线路表优先于内置名称，未知类型按普通票
+        assert_eq!(state.tone_for_card_type("student"), PassengerTone::Normal);
+        assert_eq!(state.tone_for_card_type("elder"), PassengerTone::Elder);
+        assert_eq!(state.tone_for_card_type("tourist"), PassengerTone::Normal);
+    }
+
+    /// 进出站模式下完成一次上车（站 1）与下车（站 2），返回下车后的卡。
+    fn ride(state: &mut GatewayState, clock: &MockClock, card: &CardData) -> CardData {
+        let decision = tap(state, card);
+        let boarded = complete_write(state, &decision);
+        clock.advance(5 * 60_000);
+        assert!(state.set_station_by_id(2));
+        let decision = tap(state, &boarded);
+        complete_write(state, &decision)
+    }
+
+    #[test]
+    fn retap_after_tap_out_is_ignored_within_grace() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let alighted = ride(&mut state, &clock, &card(1000));
+        assert_eq!(alighted.status, CardStatus::Idle);
+
+        clock.advance(5000);
+        let decision = tap(&mut state, &alighted);
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.upload_record.is_none());
+        assert!(decision.write_request.is_none());
+        assert_eq!(state.last_passenger_message, "已下车，无需再刷");
+        assert_eq!(state.active_trips.len(), 0);
+    }
+
+    #[test]
+    fn tap_after_grace_opens_new_trip() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.retap_ignore_secs = 10;
+        let (mut state, clock) = in_out_state(settings);
+        let alighted = ride(&mut state, &clock, &card(1000));
+
+        clock.advance(10_000);
+        let decision = tap(&mut state, &alighted);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        assert_eq!(state.active_trips.len(), 1);
+    }
+
+    #[test]
+    fn tap_out_shows_board_station_and_duration() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let _ = ride(&mut state, &clock, &card(1000));
+        assert_eq!(state.last_board_station.as_deref(), Some("一站"));
+        assert_eq!(state.last_trip_elapsed_secs, Some(300));
+        assert_eq!(state.last_passenger_message, "从一站上车，用时5分钟");
+    }
+
+    #[test]
+    fn board_message_rounds_duration_up() {
+        assert_eq!(format_board_message("一站", None), "从一站上车");
+        assert_eq!(
+            format_board_message("一站", Some(0)),
+            "从一站上车，用时1分钟"
+        );
+        assert_eq!(
+            format_board_message("一站", Some(61)),
+            "从一站上车，用时2分钟"
+        );
+    }
+
+    #[test]
+    fn tap_out_without_cached_trip_uses_on_card_entry() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.stray_tap_out_policy = StrayTapOutPolicy::Penalty;
+        let (mut state, _) = in_out_state(settings);
+        assert!(state.set_station_by_id(3));
+        let _ = tap(&mut state, &in_trip_card(1000, 2));
+        assert_eq!(state.last_board_station.as_deref(), Some("二站"));
+        assert_eq!(state.last_trip_elapsed_secs, None);
+    }
+
+    #[test]
+    fn backend_error_keeps_latest_failure() {
+        let (mut state, clock) = default_state();
+        assert!(state.last_backend_error.is_none());
+        state.record_backend_error("配置同步", "404".to_string());
+        clock.advance(2000);
+        state.record_backend_error("记录上报", "连接超时".to_string());
+        let error = state.last_backend_error.as_ref().unwrap();
+        assert_eq!(error.summary(), "记录上报失败: 连接超时");
+        assert_eq!(error.at_ms, T0_MS + 2000);
+    }
+
+    fn priced_state(base_price: f32) -> (GatewayState, Arc<MockClock>) {
+        let (mut state, clock) = default_state();
+        let mut config = route_config("single_tap");
+        config.fares[0].base_price = base_price;
+        state.update_route_config(config, T0_MS / 1000);
+        (state, clock)
+    }
+
+    #[test]
+    fn yuan_amounts_convert_without_float_drift() {
+        assert_eq!(yuan_to_cents(0.07), 7);
+        assert_eq!(yuan_to_cents(1.15), 115);
+        assert_eq!(yuan_to_cents(2.45), 245);
+        assert_eq!(yuan_to_cents(-1.0), 0);
+    }
+
+    #[test]
+    fn displayed_fare_matches_deduction() {
+        for (price, cents) in [(0.07, 7), (1.15, 115), (2.45, 245)] {
+            let (mut state, _) = priced_state(price);
+            let decision = tap(&mut state, &card(1000));
+            assert_eq!(state.last_fare_cents, Some(cents));
+            assert_eq!(
+                decision.upload_record.as_ref().unwrap().fare_cents,
+                Some(cents)
+            );
+            assert_eq!(
+                complete_write(&mut state, &decision).balance_cents,
+                1000 - cents
+            );
+        }
+    }
+
+    #[test]
+    fn route_info_reflects_current_station() {
+        let (mut state, _) = default_state();
+        assert!(state.set_station_by_id(2));
+        state.set_direction(Direction::Down);
+        let info = state.route_info();
+        assert_eq!((info.route_id, info.station_id, info.direction), (7, 2, 1));
+        assert_eq!(info.route_name, "7路");
+        assert_eq!(info.station_name, "二站");
+    }
+
+    fn reverse_state() -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.post_tap_block_policy = PostTapBlockPolicy::Reverse;
+        test_state(settings)
+    }
+
+    #[test]
+    fn post_tap_block_reverses_charge_and_blocks_card() {
+        let (mut state, _) = reverse_state();
+        let decision = tap(&mut state, &card(1000));
+        let record_id = decision.upload_record.as_ref().unwrap().record_id.clone();
+        let _ = complete_write(&mut state, &decision);
+
+        state.apply_post_tap_block(CARD_ID);
+        let reversal = state.take_pending_reversal().expect("reversal");
+        assert_eq!(reversal.ack.result, 0);
+        let record = reversal.upload_record.as_ref().unwrap();
+        assert_eq!(record.record_id, record_id);
+        assert_eq!(record.fare_cents, Some(0));
+        assert_eq!(record.alert.as_deref(), Some("blocked_reversal"));
+        let written = complete_write(&mut state, &reversal);
+        assert_eq!(written.status, CardStatus::Blocked);
+        assert_eq!(written.balance_cents, 1000);
+        assert!(state.blacklist_cache.is_blocked(CARD_ID));
+        assert!(state.take_pending_reversal().is_none());
+    }
+
+    #[test]
+    fn post_tap_block_ignores_other_cards_and_stale_taps() {
+        let (mut state, clock) = reverse_state();
+        let decision = tap(&mut state, &card(1000));
+        let _ = complete_write(&mut state, &decision);
+        state.apply_post_tap_block("0102030405060708");
+        assert!(state.take_pending_reversal().is_none());
+
+        clock.advance(POST_TAP_BLOCK_WINDOW_MS + 1);
+        state.apply_post_tap_block(CARD_ID);
+        assert!(state.take_pending_reversal().is_none());
+    }
+
+    #[test]
+    fn post_tap_block_is_off_by_default() {
+        let (mut state, _) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        let _ = complete_write(&mut state, &decision);
+        state.apply_post_tap_block(CARD_ID);
+        assert!(state.take_pending_reversal().is_none());
+    }
+
+    #[test]
+    fn manual_fare_applies_to_one_tap() {
+        let (mut state, clock) = default_state();
+        state.next_fare_override_cents = Some(550);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_label, "手动票价");
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.balance_cents, 450);
+        assert!(state.next_fare_override_cents.is_none());
+
+        clock.advance(3000);
+        let decision = tap(&mut state, &written);
+        assert_ne!(state.last_fare_label, "手动票价");
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 250);
+    }
+
+    #[test]
+    fn manual_fare_is_capped_and_kept_on_insufficient_balance() {
+        let (mut state, clock) = default_state();
+        let mut config = route_config("single_tap");
+        config.max_fare = Some(4.0);
+        state.update_route_config(config, T0_MS / 1000);
+        state.next_fare_override_cents = Some(900);
+        let decision = tap(&mut state, &card(300));
+        assert_eq!(decision.ack.result, 0);
+        // 余额不足时保留手动票价到下一次刷卡
+        assert_eq!(state.next_fare_override_cents, Some(900));
+
+        clock.advance(3000);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_cents, Some(400));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 600);
+    }
+
+    #[test]
+    fn non_finite_discounts_do_not_change_fare() {
+        let (mut state, _) = default_state();
+        for (rate, amount) in [
+            (Some(f32::NAN), None),
+            (None, Some(f32::INFINITY)),
+            (Some(-0.5), Some(f32::NAN)),
+        ] {
+            state.last_fare_base_cents = Some(200);
+            state.last_fare_cents = Some(200);
+            state.apply_card_discount_policy("tourist", rate, amount);
+            assert_eq!(state.last_fare_cents, Some(200));
+        }
+    }
+
+    fn board_reader_state(policy: RepeatBoardPolicy) -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.reader_roles = vec![(1, ReaderRole::Board)];
+        settings.repeat_board_policy = policy;
+        in_out_state(settings)
+    }
+
+    /// 在站 1 上车后移动到站 2，返回上车后的卡。
+    fn board_and_move(state: &mut GatewayState, clock: &MockClock) -> CardData {
+        let decision = tap_from(state, 1, &card(1000));
+        let boarded = complete_write(state, &decision);
+        clock.advance(60_000);
+        assert!(state.set_station_by_id(2));
+        boarded
+    }
+
+    #[test]
+    fn repeat_board_rejected_when_configured() {
+        let (mut state, clock) = board_reader_state(RepeatBoardPolicy::Reject);
+        let boarded = board_and_move(&mut state, &clock);
+        let decision = tap_from(&mut state, 1, &boarded);
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "请先下车刷卡");
+        assert!(state.active_trips.contains(CARD_ID, state.now_secs()));
+    }
+
+    #[test]
+    fn repeat_board_settles_prior_trip() {
+        let (mut state, clock) = board_reader_state(RepeatBoardPolicy::Settle);
+        let boarded = board_and_move(&mut state, &clock);
+        let decision = tap_from(&mut state, 1, &boarded);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapOut);
+        assert_eq!(state.last_passenger_message, "上一行程已结算，请再刷卡上车");
+        let settled = complete_write(&mut state, &decision);
+        assert_eq!(settled.status, CardStatus::Idle);
+
+        // 结算后不启用误刷忽略，再刷即上车
+        clock.advance(3000);
+        let decision = tap_from(&mut state, 1, &settled);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+    }
+
+    #[test]
+    fn any_reader_treats_in_trip_card_as_tap_out() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap_from(&mut state, 1, &card(1000));
+        let boarded = complete_write(&mut state, &decision);
+        clock.advance(60_000);
+        let decision = tap_from(&mut state, 1, &boarded);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapOut);
+    }
+
+    #[test]
+    fn last_trip_is_read_from_card() {
+        let (mut state, clock) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        assert!(state.last_card_trip.is_none());
+        let _ = complete_write(&mut state, &decision);
+
+        clock.advance(3000);
+        let mut ridden = card(1000);
+        ridden.last_route_id = Some(7);
+        ridden.last_direction = Some(Direction::Down);
+        ridden.last_board_station_id = Some(1);
+        ridden.last_alight_station_id = Some(3);
+        let _ = tap(&mut state, &ridden);
+        let trip = state.last_card_trip.clone().unwrap();
+        assert_eq!(trip.route_id, Some(7));
+        assert_eq!(trip.direction, Some(Direction::Down));
+        assert_eq!(trip.board_station.as_deref(), Some("一站"));
+        assert_eq!(trip.alight_station.as_deref(), Some("三站"));
+    }
+
+    #[test]
+    fn last_trip_on_other_route_shows_station_ids() {
+        let (mut state, _) = default_state();
+        let mut ridden = card(1000);
+        ridden.last_route_id = Some(9);
+        ridden.last_board_station_id = Some(1);
+        let _ = tap(&mut state, &ridden);
+        let trip = state.last_card_trip.clone().unwrap();
+        assert_eq!(trip.board_station.as_deref(), Some("#1"));
+        assert_eq!(trip.alight_station, None);
+    }
+
+    #[test]
+    fn tap_out_records_trip_on_card() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let alighted = ride(&mut state, &clock, &card(1000));
+        assert_eq!(alighted.last_route_id, Some(7));
+        assert_eq!(alighted.last_board_station_id, Some(1));
+        assert_eq!(alighted.last_alight_station_id, Some(2));
+    }
+
+    fn anti_passback_state() -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.anti_passback = true;
+        in_out_state(settings)
+    }
+
+    #[test]
+    fn anti_passback_rejects_tap_out_without_boarding() {
+        let (mut state, _) = anti_passback_state();
+        let decision = tap(&mut state, &in_trip_card(1000, 1));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "未检测到上车");
+        assert!(decision.write_request.is_none());
+    }
+
+    #[test]
+    fn anti_passback_allows_tap_out_after_boarding() {
+        let (mut state, clock) = anti_passback_state();
+        let alighted = ride(&mut state, &clock, &card(1000));
+        assert_eq!(alighted.status, CardStatus::Idle);
+        assert_eq!(state.last_board_station.as_deref(), Some("一站"));
+    }
+
+    #[test]
+    fn anti_passback_keeps_trips_from_other_routes() {
+        let (mut state, clock) = anti_passback_state();
+        let decision = tap(&mut state, &card(1000));
+        let boarded = complete_write(&mut state, &decision);
+        let mut config = route_config("tap_in_out");
+        config.route_id = 8;
+        state.update_route_config(config, T0_MS / 1000);
+
+        clock.advance(60_000);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.ack.result, 0);
+        assert!(state.active_trips.contains(CARD_ID, state.now_secs()));
+    }
+
+    fn cache_student_profile(state: &mut GatewayState, card_id: &str) {
+        let now_ms = state.now_millis();
+        state.update_card_cache(
+            card_id.to_string(),
+            Some("student".to_string()),
+            Some("active".to_string()),
+            None,
+            None,
+            None,
+            now_ms,
+        );
+    }
+
+    #[test]
+    fn clearing_card_cache_drops_profiles_and_misses() {
+        let (mut state, _) = default_state();
+        cache_student_profile(&mut state, CARD_ID);
+        cache_student_profile(&mut state, "0102030405060708");
+        let now_ms = state.now_millis();
+        state.record_card_lookup_miss("DEADBEEF", now_ms);
+        assert!(state.has_cached_profile(CARD_ID, now_ms));
+
+        assert_eq!(state.clear_card_cache(), 2);
+        assert!(!state.has_cached_profile(CARD_ID, now_ms));
+        assert!(!state.lookup_miss_cached("DEADBEEF", now_ms));
+        assert_eq!(state.clear_card_cache(), 0);
+    }
+
+    #[test]
+    fn invalidating_one_profile_keeps_others() {
+        let (mut state, _) = default_state();
+        cache_student_profile(&mut state, CARD_ID);
+        cache_student_profile(&mut state, "0102030405060708");
+        assert!(state.invalidate_card_profile(CARD_ID));
+        assert!(!state.invalidate_card_profile(CARD_ID));
+        let now_ms = state.now_millis();
+        assert!(state.has_cached_profile("0102030405060708", now_ms));
+    }
+
+    fn distance_config(distances: [Option<u32>; 3]) -> RouteConfig {
+        let stations: Vec<serde_json::Value> = distances
+            .iter()
+            .enumerate()
+            .map(|(index, distance_m)| {
+                serde_json::json!({
+                    "id": index + 1,
+                    "name": format!("站{}", index + 1),
+                    "sequence": index + 1,
+                    "distance_m": distance_m
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+        "route_id": 7,
+        "route_name": "7路",
+        "fare_type": "distance",
+        "tap_mode": "tap_in_out",
+        "stations": stations,
+        "fares": [{"base_price": 2.0, "per_km_price": 0.5, "segment_count": 1, "extra_price": 1.0}]
+    }))
+    .unwrap()
+    }
+
+    #[test]
+    fn distance_fare_uses_summed_station_distances() {
+        let (mut state, _) = default_state();
+        state.update_route_config(
+            distance_config([None, Some(1500), Some(2500)]),
+            T0_MS / 1000,
+        );
+        assert_eq!(state.estimate_trip_fare(1, 2), Some(275));
+        assert_eq!(state.estimate_trip_fare(1, 3), Some(400));
+        assert_eq!(state.estimate_trip_fare(3, 1), Some(400));
+    }
+
+    #[test]
+    fn distance_fare_falls_back_to_sequence() {
+        let (mut state, _) = default_state();
+        // 缺少任一站间距离时按站数分段计价
+        state.update_route_config(distance_config([None, None, Some(2500)]), T0_MS / 1000);
+        assert_eq!(state.estimate_trip_fare(1, 2), Some(200));
+        assert_eq!(state.estimate_trip_fare(1, 3), Some(300));
+    }
+
+    fn fail_write(state: &mut GatewayState, error_code: u8) -> Option<CardWriteRequest> {
+        let result = CardWriteResult {
+            result: 0,
+            error_code,
+            block_start: CARD_DATA_BLOCK_START,
+            block_count: CARD_DATA_BLOCK_COUNT,
+            readback: Vec::new(),
+        };
+        let now_ms = state.now_millis();
+        state.handle_write_result(result, now_ms)
+    }
+
+    #[test]
+    fn removed_card_write_is_retried() {
+        let (mut state, _) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        let retry = fail_write(&mut state, crate::proto::WRITE_ERR_CARD_REMOVED).expect("retry");
+        assert_eq!(
+            retry.card_data,
+            decision.write_request.as_ref().unwrap().card_data
+        );
+        assert_eq!(state.last_passenger_message, "请将卡片放回读卡区");
+        assert_eq!(state.pending_write_count(), 1);
+    }
+
+    #[test]
+    fn auth_failure_is_not_retried() {
+        let (mut state, _) = default_state();
+        let _ = tap(&mut state, &card(1000));
+        assert!(fail_write(&mut state, crate::proto::WRITE_ERR_AUTH).is_none());
+        assert_eq!(state.last_passenger_message, "写卡失败（卡片认证失败）");
+        assert_eq!(state.pending_write_count(), 0);
+    }
+
+    #[test]
+    fn write_retries_are_bounded() {
+        let (mut state, _) = default_state();
+        let _ = tap(&mut state, &card(1000));
+        let mut retries = 0;
+        while fail_write(&mut state, WRITE_ERR_VERIFY).is_some() {
+            retries += 1;
+            assert!(retries <= MAX_WRITE_RETRIES);
+        }
+        assert_eq!(retries, MAX_WRITE_RETRIES);
+        assert_eq!(state.last_passenger_message, "写卡失败（回读校验不一致）");
+    }
+
+    #[test]
+    fn local_time_label_uses_tz_offset() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-1".to_string());
+        settings.tz_offset_minutes = 480;
+        let (state, clock) = test_state(settings);
+        assert_eq!(state.local_time_label().as_deref(), Some("08:00"));
+        clock.set(0);
+        assert!(state.local_time_label().is_none());
+    }
+
+    #[test]
+    fn forced_card_state_push_drains_cache() {
+        let (mut state, _) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        complete_write(&mut state, &decision);
+        let queued = state.card_state_cache.len();
+        assert!(queued > 0);
+        assert_eq!(state.card_state_cache.drain_all().len(), queued);
+        assert!(state.card_state_cache.is_empty());
+        assert!(state.card_state_cache.drain_all().is_empty());
+    }
+
+    #[test]
+    fn recharge_up_to_max_balance_is_accepted() {
+        let (mut state, _) = recharge_state();
+        let max = state.settings.max_balance_cents;
+        let decision = tap(&mut state, &card(max - 1000));
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, max);
+    }
+
+    #[test]
+    fn recharge_over_max_balance_is_rejected() {
+        let (mut state, clock) = recharge_state();
+        let max = state.settings.max_balance_cents;
+        let decision = tap(&mut state, &card(max - 999));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert_eq!(state.last_passenger_message, "超出余额上限");
+
+        // u32 溢出同样拒绝，不饱和到上限
+        state.settings.max_balance_cents = u32::MAX;
+        clock.advance(3000);
+        let decision = tap(&mut state, &card(u32::MAX - 10));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert_eq!(state.last_passenger_message, "超出余额上限");
+        assert_eq!(state.card_state_cache.len(), 0);
+    }
+
+    fn register_once(one_shot: bool) -> GatewayState {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.one_shot_modes = one_shot;
+        let (mut state, _) = test_state(settings);
+        let now_ms = state.now_millis();
+        state.set_register_mode(now_ms);
+        let decision = tap_bytes(&mut state, 1, Vec::new());
+        assert!(decision.registration.is_some());
+        complete_write(&mut state, &decision);
+        state
+    }
+
+    #[test]
+    fn one_shot_register_mode_clears_after_success() {
+        assert!(register_once(true).register_mode.is_none());
+        assert!(register_once(false).register_mode.is_some());
+    }
+
+    #[test]
+    fn separated_lowercase_uid_is_normalized() {
+        let (mut state, _) = default_state();
+        state.update_blacklist(vec![CARD_ID.to_string()], T0_MS / 1000);
+        let now = state.now_secs();
+        let detected = CardDetected {
+            card_id: "a1:b2:c3:d4".to_string(),
+            tap_time: now,
+            reader_id: 1,
+            card_data: card(1000).to_bytes().to_vec(),
+        };
+        let decision = state.handle_card_detected(detected, now);
+        assert_eq!(state.last_card_id, CARD_ID);
+        assert_eq!(decision.ack.result, 0);
+    }
+
+    #[test]
+    fn route_message_ttl_overrides_settings() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.message_ttl.ok_ms = Some(1500);
+        settings.message_ttl.error_ms = Some(4000);
+        let (mut state, _) = test_state(settings);
+        let mut cfg = route_config("single_tap");
+        cfg.message_ttl.ok_ms = Some(800);
+        state.update_route_config(cfg, T0_MS / 1000);
+
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(state.last_message_deadline_ms, T0_MS + 800);
+        // 线路未覆盖的类别回落到网关设置
+        let _ = tap(&mut state, &card(0));
+        assert_eq!(state.last_message_deadline_ms, T0_MS + 4000);
+    }
+
+    #[test]
+    fn message_ttl_defaults_without_overrides() {
+        let (mut state, _) = default_state();
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(
+            state.last_message_deadline_ms,
+            T0_MS + PASSENGER_MSG_TTL_OK_MS
+        );
+    }
+
+    #[test]
+    fn masking_changes_display_but_not_upload() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.mask_card_ids = true;
+        let (mut state, _) = test_state(settings);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.display_card_id(&state.last_card_id), "****C3D4");
+        let record = serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap();
+        assert_eq!(record["card_id"], CARD_ID);
+
+        state.settings.mask_card_ids = false;
+        assert_eq!(state.display_card_id(&state.last_card_id), CARD_ID);
+    }
+
+    fn min_board_state(min_cents: u32) -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_board_balance_cents = min_cents;
+        test_state(settings)
+    }
+
+    #[test]
+    fn min_board_balance_boundaries() {
+        for (balance, accepted) in [(600, true), (500, true), (499, false)] {
+            let (mut state, _) = min_board_state(300);
+            let decision = tap(&mut state, &card(balance));
+            assert_eq!(decision.ack.result == 1, accepted, "balance {}", balance);
+            if !accepted {
+                assert_eq!(state.last_passenger_message, "余额过低请充值");
+                assert!(decision.write_request.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn insufficient_balance_is_distinct_from_min_board() {
+        let (mut state, _) = min_board_state(300);
+        let decision = tap(&mut state, &card(100));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "余额不足");
+    }
+
+    #[test]
+    fn min_board_balance_applies_on_tap_in() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_board_balance_cents = 300;
+        let (mut state, _) = in_out_state(settings);
+        let decision = tap(&mut state, &card(499));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "余额过低请充值");
+        assert_eq!(state.active_trips.len(), 0);
+    }
+
+    #[test]
+    fn simultaneous_taps_on_two_readers_open_one_trip() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let card = card(1000);
+        let first = tap_from(&mut state, 1, &card);
+        let second = tap_from(&mut state, 2, &card);
+        assert_eq!(first.ack.result, 1);
+        assert_eq!(second.ack.result, 0);
+        assert!(second.event.is_none());
+        assert!(second.write_request.is_none());
+        assert_eq!(state.active_trips.len(), 1);
+    }
+
+    #[test]
+    fn cross_reader_lock_expires() {
+        // 员工卡不受防抖限制、关闭重复上报去重，只由前后门锁串行化
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.staff_card_ids = vec![CARD_ID.to_string()];
+        settings.dedup_window_ms = 0;
+        let (mut state, clock) = in_out_state(settings);
+        let first = tap_from(&mut state, 1, &card(1000));
+        let written = complete_write(&mut state, &first);
+        clock.advance(CROSS_READER_LOCK_MS - 1);
+        assert_eq!(tap_from(&mut state, 2, &written).ack.result, 0);
+        assert_eq!(state.last_passenger_message, "处理中");
+        clock.advance(1);
+        let decision = tap_from(&mut state, 2, &written);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(decision.event.unwrap().tap_type, TapType::TapOut);
+    }
+
+    #[test]
+    fn different_cards_on_two_readers_are_independent() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let _ = tap_from(&mut state, 1, &card(1000));
+        let now = state.now_secs();
+        let other = CardDetected {
+            card_id: "0A0B0C0D".to_string(),
+            tap_time: now,
+            reader_id: 2,
+            card_data: Vec::new(),
+        };
+        let _ = state.handle_card_detected(other, now);
+        assert_ne!(state.last_passenger_message, "处理中");
+    }
+
+    fn mystery_card_tap(policy: UnknownCardTypePolicy) -> (GatewayState, Decision) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.unknown_card_type_policy = policy;
+        let (mut state, _) = test_state(settings);
+        let now_ms = state.now_millis();
+        state.update_card_cache(
+            CARD_ID.to_string(),
+            Some("mystery".to_string()),
+            Some("active".to_string()),
+            None,
+            None,
+            None,
+            now_ms,
+        );
+        let decision = tap(&mut state, &card(1000));
+        (state, decision)
+    }
+
+    #[test]
+    fn unknown_card_type_normal_charges_standard_fare() {
+        let (state, decision) = mystery_card_tap(UnknownCardTypePolicy::Normal);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_fare_cents, Some(200));
+        assert_eq!(state.last_passenger_tone, PassengerTone::Normal);
+        assert!(!state.last_passenger_message.contains("未知卡类型"));
+    }
+
+    #[test]
+    fn unknown_card_type_warn_flags_message() {
+        let (state, decision) = mystery_card_tap(UnknownCardTypePolicy::Warn);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_fare_cents, Some(200));
+        assert!(state.last_passenger_message.ends_with("（未知卡类型）"));
+    }
+
+    #[test]
+    fn unknown_card_type_reject_blocks_tap() {
+        let (state, decision) = mystery_card_tap(UnknownCardTypePolicy::Reject);
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert_eq!(state.last_passenger_message, "未知卡类型");
+    }
+
+    fn max_estimate_state(max_fare: Option<f32>) -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.tap_in_max_fare_estimate = true;
+        let (mut state, clock) = test_state(settings);
+        let mut cfg = distance_config([None, Some(1500), Some(2500)]);
+        cfg.max_fare = max_fare;
+        state.update_route_config(cfg, T0_MS / 1000);
+        state.set_station_by_id(1);
+        (state, clock)
+    }
+
+    #[test]
+    fn tap_in_shows_max_estimate_then_actual_fare() {
+        let (mut state, clock) = max_estimate_state(None);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_cents, Some(400));
+        assert_eq!(state.last_fare_label, "预估最高");
+        let written = complete_write(&mut state, &decision);
+
+        clock.advance(5 * 60 * 1000);
+        state.set_station_by_id(2);
+        let decision = tap(&mut state, &written);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapOut);
+        assert_eq!(state.last_fare_cents, Some(275));
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 725);
+    }
+
+    #[test]
+    fn tap_in_max_estimate_prefers_route_cap() {
+        let (mut state, _) = max_estimate_state(Some(3.5));
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_cents, Some(350));
+        assert_eq!(state.last_fare_label, "预估最高");
+    }
+
+    fn staff_state(fare_exempt: bool) -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.staff_card_ids = vec!["a1b2c3d4".to_string()];
+        settings.staff_cards_fare_exempt = fare_exempt;
+        test_state(settings)
+    }
+
+    #[test]
+    fn staff_card_skips_debounce_and_rides_free() {
+        let (mut state, clock) = staff_state(true);
+        let mut current = card(1000);
+        for _ in 0..3 {
+            let decision = tap(&mut state, &current);
+            assert_eq!(decision.ack.result, 1);
+            assert_eq!(state.last_fare_cents, Some(0));
+            assert_eq!(state.last_fare_label, "员工免票");
+            let record = serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap();
+            assert_eq!(record["exempt"], true);
+            current = complete_write(&mut state, &decision);
+            assert_eq!(current.balance_cents, 1000);
+            clock.advance(500);
+        }
+    }
+
+    #[test]
+    fn staff_card_without_exemption_pays_and_is_unmarked() {
+        let (mut state, _) = staff_state(false);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_cents, Some(200));
+        let record = serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap();
+        assert!(record.get("exempt").is_none());
+    }
+
+    #[test]
+    fn staff_card_still_honours_blacklist() {
+        let (mut state, _) = staff_state(true);
+        state.update_blacklist(vec![CARD_ID.to_string()], T0_MS / 1000);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.upload_record.is_none());
+        assert_eq!(state.last_passenger_message, "卡已冻结");
+    }
+
+    #[test]
+    fn stepping_works_after_duplicate_sequences() {
+        let (mut state, _) = default_state();
+        let cfg: RouteConfig = serde_json::from_value(serde_json::json!({
+            "route_id": 7,
+            "route_name": "7路",
+            "fare_type": "uniform",
+            "tap_mode": "single_tap",
+            "stations": [
+                {"id": 1, "name": "一站", "sequence": 1},
+                {"id": 2, "name": "二站", "sequence": 1},
+                {"id": 3, "name": "三站", "sequence": 2}
+            ],
+            "fares": [{"base_price": 2.0}]
+        }))
+        .unwrap();
+        state.update_route_config(cfg, T0_MS / 1000);
+        state.set_station_by_id(1);
+        assert!(state.step_station(true));
+        assert_eq!(state.route_state.station_id, 2);
+        assert!(state.step_station(true));
+        assert_eq!(state.route_state.station_id, 3);
+        assert!(state.step_station(false));
+        assert_eq!(state.route_state.station_id, 2);
+    }
+
+    #[test]
+    fn upload_timestamp_updates_only_on_success() {
+        let (mut state, clock) = default_state();
+        state.record_backend_result(false);
+        state.record_backend_error("批量上报", "timeout".to_string());
+        assert!(state.last_successful_upload_ms.is_none());
+        assert!(state.last_upload_age_secs(T0_MS).is_none());
+
+        state.record_upload_success();
+        assert_eq!(state.last_successful_upload_ms, Some(T0_MS));
+        clock.advance(90_000);
+        state.record_backend_result(false);
+        state.record_backend_error("批量上报", "timeout".to_string());
+        assert_eq!(state.last_successful_upload_ms, Some(T0_MS));
+        assert_eq!(state.last_upload_age_secs(state.now_millis()), Some(90));
+    }
+
+    fn tap_with_len(strict: bool, len: usize) -> (GatewayState, Decision) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.strict_card_data_len = strict;
+        let (mut state, _) = test_state(settings);
+        let mut bytes = card(1000).to_bytes().to_vec();
+        bytes.resize(len, 0xEE);
+        let decision = tap_bytes(&mut state, 1, bytes);
+        (state, decision)
+    }
+
+    #[test]
+    fn exact_length_card_data_parses() {
+        let (state, decision) = tap_with_len(true, CARD_DATA_LEN);
+        assert_eq!(decision.ack.result, 1);
+        assert!(state.last_card_data_error.is_none());
+    }
+
+    #[test]
+    fn over_length_card_data_depends_on_strict_mode() {
+        let over = CARD_DATA_LEN + CARD_DATA_BLOCK_SIZE;
+        let (state, decision) = tap_with_len(false, over);
+        assert_eq!(decision.ack.result, 1);
+        assert!(state.last_card_data_error.is_none());
+        assert_eq!(state.last_card_data_len, over);
+
+        let (state, decision) = tap_with_len(true, over + 3);
+        assert!(decision.event.is_none());
+        assert_eq!(
+            state.last_card_data_error.as_deref(),
+            Some("over_length_card_data")
+        );
+    }
+
+    #[test]
+    fn short_card_data_is_reported() {
+        let (state, decision) = tap_with_len(false, CARD_DATA_BLOCK_SIZE);
+        assert!(decision.event.is_none());
+        assert_eq!(
+            state.last_card_data_error.as_deref(),
+            Some("short_card_data")
+        );
+    }
+
+    fn student_profiles(count: usize) -> Vec<(String, CardProfile)> {
+        (0..count)
+            .map(|idx| {
+                let profile = CardProfile {
+                    card_type: Some("student".to_string()),
+                    status: Some("active".to_string()),
+                    balance_cents: None,
+                    discount_rate: Some(0.5),
+                    discount_amount: None,
+                };
+                (format!("{:08X}", idx), profile)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn preload_populates_cache_for_first_tap() {
+        let (mut state, _) = default_state();
+        let mut profiles = student_profiles(3);
+        let (_, profile) = student_profiles(1).pop().unwrap();
+        profiles.push((CARD_ID.to_string(), profile));
+        assert_eq!(state.preload_card_profiles(profiles, 10), 4);
+        let now_ms = state.now_millis();
+        assert!(state.has_cached_profile(CARD_ID, now_ms));
+        let _ = tap(&mut state, &card(1000));
+        assert_eq!(state.last_fare_cents, Some(100));
+    }
+
+    #[test]
+    fn preload_honours_limit_and_cache_cap() {
+        let (mut state, _) = default_state();
+        assert_eq!(state.preload_card_profiles(student_profiles(5), 2), 2);
+        // 已缓存的卡不覆盖也不计数
+        assert_eq!(state.preload_card_profiles(student_profiles(3), 10), 1);
+        assert_eq!(state.card_cache.len(), 3);
+        let loaded = state.preload_card_profiles(student_profiles(CARD_CACHE_MAX + 10), usize::MAX);
+        assert_eq!(loaded, CARD_CACHE_MAX - 3);
+        assert_eq!(state.card_cache_free_slots(), 0);
+    }
+
+    fn no_route_state(policy: NoRoutePolicy) -> GatewayState {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.no_route_policy = policy;
+        GatewayState::bootstrap(settings).with_clock(Arc::new(MockClock::new(T0_MS)))
+    }
+
+    #[test]
+    fn no_route_reject_policy() {
+        let mut state = no_route_state(NoRoutePolicy::Reject);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.upload_record.is_none());
+        assert_eq!(state.last_passenger_message, "线路未设置");
+    }
+
+    #[test]
+    fn no_route_allow_policy_rides_free() {
+        let mut state = no_route_state(NoRoutePolicy::Allow);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(decision.event.as_ref().unwrap().route_id, 0);
+        assert_eq!(state.fare_to_cents(), 0);
+    }
+
+    #[test]
+    fn no_route_queue_policy_uploads_after_sync() {
+        let mut state = no_route_state(NoRoutePolicy::Queue);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 1);
+        assert!(decision.upload_record.is_none());
+        assert!(decision.write_request.is_none());
+        assert!(state.take_no_route_records().is_empty());
+
+        state.update_route_config(route_config("single_tap"), T0_MS / 1000);
+        let records = state.take_no_route_records();
+        assert_eq!(records.len(), 1);
+        let record = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(record["card_id"], CARD_ID);
+        assert_eq!(record["route_id"], 7);
+        assert_eq!(record["alert"], "no_route");
+        assert!(state.take_no_route_records().is_empty());
+    }
+
+    fn record_fare(decision: &Decision) -> serde_json::Value {
+        serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap()["fare_cents"]
+            .clone()
+    }
+
+    #[test]
+    fn recorded_fare_matches_deduction() {
+        let (mut state, _) = default_state();
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.event.as_ref().unwrap().fare_cents, Some(200));
+        assert_eq!(record_fare(&decision), 200);
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 800);
+    }
+
+    #[test]
+    fn tap_in_records_zero_and_tap_out_records_fare() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(record_fare(&decision), 0);
+        let written = complete_write(&mut state, &decision);
+        clock.advance(5 * 60 * 1000);
+        state.set_station_by_id(3);
+        let decision = tap(&mut state, &written);
+        let charged = record_fare(&decision).as_u64().unwrap() as u32;
+        assert_eq!(Some(charged), decision.event.as_ref().unwrap().fare_cents);
+        assert_eq!(
+            complete_write(&mut state, &decision).balance_cents,
+            1000 - charged
+        );
+    }
+
+    fn deposit_state(settings: GatewaySettings) -> (GatewayState, Arc<MockClock>) {
+        let (mut state, clock) = test_state(settings);
+        let mut cfg = distance_config([None, Some(1500), Some(2500)]);
+        cfg.settlement_mode = SettlementMode::Deposit;
+        state.update_route_config(cfg, T0_MS / 1000);
+        state.set_station_by_id(1);
+        (state, clock)
+    }
+
+    /// 在站 1 上车（押金 400），移动到 `station_id`，返回上车后的卡。
+    fn deposit_board(state: &mut GatewayState, clock: &MockClock, station_id: u16) -> CardData {
+        let decision = tap(state, &card(1000));
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_fare_label, "预扣");
+        let boarded = complete_write(state, &decision);
+        clock.advance(5 * 60 * 1000);
+        assert!(state.set_station_by_id(station_id));
+        boarded
+    }
+
+    #[test]
+    fn deposit_is_held_at_tap_in_and_stored_on_card() {
+        let (mut state, clock) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let boarded = deposit_board(&mut state, &clock, 2);
+        assert_eq!(boarded.status, CardStatus::InTrip);
+        assert_eq!(boarded.balance_cents, 600);
+        assert_eq!(boarded.deposit_cents, Some(400));
+        assert_eq!(state.last_balance_cents, Some(600));
+    }
+
+    #[test]
+    fn deposit_difference_is_refunded_at_tap_out() {
+        let (mut state, clock) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let boarded = deposit_board(&mut state, &clock, 2);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapOut);
+        assert_eq!(record_fare(&decision), 275);
+        let alighted = complete_write(&mut state, &decision);
+        assert_eq!(alighted.balance_cents, 725);
+        assert_eq!(alighted.deposit_cents, None);
+        assert_eq!(alighted.status, CardStatus::Idle);
+    }
+
+    #[test]
+    fn fare_above_deposit_debits_the_difference() {
+        let (mut state, clock) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let boarded = deposit_board(&mut state, &clock, 3);
+        state.next_fare_override_cents = Some(500);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(record_fare(&decision), 500);
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 500);
+    }
+
+    #[test]
+    fn insufficient_deposit_with_low_balance_is_rejected() {
+        let (mut state, clock) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let mut boarded = deposit_board(&mut state, &clock, 3);
+        boarded.balance_cents = 50;
+        state.next_fare_override_cents = Some(500);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "余额不足");
+        // 行程保留，充值后仍可按押金结算
+        assert!(state.active_trips.contains(CARD_ID, state.now_secs()));
+    }
+
+    #[test]
+    fn deposit_above_balance_rejects_tap_in() {
+        let (mut state, _) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &card(300));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert!(!state.active_trips.contains(CARD_ID, state.now_secs()));
+    }
+
+    #[test]
+    fn deposit_is_settled_against_penalty_after_reboot() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.stray_tap_out_policy = StrayTapOutPolicy::Penalty;
+        settings.stray_tap_out_penalty_cents = 300;
+        let (mut state, clock) = deposit_state(settings.clone());
+        let boarded = deposit_board(&mut state, &clock, 2);
+        // 重启后内存中的行程丢失，押金仍记录在卡内
+        let (mut rebooted, _) = deposit_state(settings);
+        let decision = tap(&mut rebooted, &boarded);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(rebooted.last_fare_label, "补票罚金");
+        let alighted = complete_write(&mut rebooted, &decision);
+        assert_eq!(alighted.balance_cents, 700);
+        assert_eq!(alighted.deposit_cents, None);
+    }
+
+    #[test]
+    fn deposit_is_settled_after_trip_expires() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.stray_tap_out_policy = StrayTapOutPolicy::Penalty;
+        settings.stray_tap_out_penalty_cents = 300;
+        let ttl_ms = settings.active_trip_ttl_secs as u64 * 1000;
+        let (mut state, clock) = deposit_state(settings);
+        let boarded = deposit_board(&mut state, &clock, 2);
+        clock.advance(ttl_ms);
+        assert!(!state.active_trips.contains(CARD_ID, state.now_secs()));
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 700);
+    }
+
+    #[test]
+    fn reboarding_refunds_previous_deposit() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.reader_roles = vec![(1, ReaderRole::Board)];
+        settings.repeat_board_policy = RepeatBoardPolicy::Restart;
+        let (mut state, clock) = deposit_state(settings);
+        let boarded = deposit_board(&mut state, &clock, 2);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        let reboarded = complete_write(&mut state, &decision);
+        let held = state.last_fare_cents.unwrap();
+        assert_eq!(reboarded.deposit_cents, Some(held));
+        assert_eq!(reboarded.balance_cents, 1000 - held);
+    }
+
+    #[test]
+    fn cleared_trip_refunds_deposit() {
+        let (mut state, clock) = deposit_state(GatewaySettings::with_gateway_id("gw-test"));
+        let boarded = deposit_board(&mut state, &clock, 2);
+        assert!(state.clear_active_trip(CARD_ID).is_some());
+        state.config_cache.route.as_mut().unwrap().settlement_mode = SettlementMode::PayOnExit;
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.balance_cents, 1000);
+        assert_eq!(written.deposit_cents, None);
+    }
+
+    fn open_trip(state: &mut GatewayState, record_id: &str, card_id: &str) {
+        let now = state.now_secs();
+        let event = TapEvent::new(
+            record_id.to_string(),
+            card_id.to_string(),
+            7,
+            1,
+            "一站".to_string(),
+            TapType::TapIn,
+            now,
+            "gw-test".to_string(),
+        );
+        state.active_trips.insert(event, now, None);
+    }
+
+    #[test]
+    fn clear_trip_by_record_id_targets_one_trip() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        open_trip(&mut state, "r-1", CARD_ID);
+        open_trip(&mut state, "r-2", "FFEEC3D4");
+        assert_eq!(state.clear_active_trip("r-2").as_deref(), Some("FFEEC3D4"));
+        let now = state.now_secs();
+        assert!(state.active_trips.contains(CARD_ID, now));
+        assert!(!state.active_trips.contains("FFEEC3D4", now));
+    }
+
+    #[test]
+    fn clear_trip_by_full_or_unique_masked_card_id() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        open_trip(&mut state, "r-1", CARD_ID);
+        open_trip(&mut state, "r-2", "11223344");
+        assert_eq!(
+            state.clear_active_trip("11223344").as_deref(),
+            Some("11223344")
+        );
+        assert_eq!(
+            state.clear_active_trip("****C3D4").as_deref(),
+            Some(CARD_ID)
+        );
+        assert!(state.active_trips.list(state.now_secs()).is_empty());
+        assert_eq!(state.clear_active_trip(CARD_ID), None);
+    }
+
+    #[test]
+    fn clear_trip_rejects_ambiguous_masked_card_id() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        open_trip(&mut state, "r-1", CARD_ID);
+        open_trip(&mut state, "r-2", "FFEEC3D4");
+        assert_eq!(state.clear_active_trip("****C3D4"), None);
+        assert_eq!(state.active_trips.list(state.now_secs()).len(), 2);
+        assert!(state.cleared_trip_cards.is_empty());
+    }
+
+    #[test]
+    fn cleared_trip_resets_card_on_next_tap() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &card(1000));
+        let boarded = complete_write(&mut state, &decision);
+        let record_id = decision.event.as_ref().unwrap().record_id.clone();
+        assert_eq!(
+            state.clear_active_trip(&record_id).as_deref(),
+            Some(CARD_ID)
+        );
+        clock.advance(60_000);
+        state.set_station_by_id(2);
+        let decision = tap(&mut state, &boarded);
+        // 卡内行程状态已复位，本次按新上车处理且不收罚金
+        assert_eq!(decision.event.as_ref().unwrap().tap_type, TapType::TapIn);
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.entry_station_id, Some(2));
+        assert_eq!(written.balance_cents, 1000);
+    }
+
+    #[test]
+    fn single_backend_failure_keeps_reachable() {
+        let (mut state, _) = default_state();
+        state.record_backend_result(true);
+        assert!(state.backend_reachable);
+        for ok in [false, true, false, false, true] {
+            state.record_backend_result(ok);
+            assert!(state.backend_reachable);
+        }
+    }
+
+    #[test]
+    fn consecutive_backend_failures_mark_unreachable() {
+        let (mut state, _) = default_state();
+        state.record_backend_result(true);
+        let threshold = state.settings.backend_unreachable_after;
+        for _ in 1..threshold {
+            state.record_backend_result(false);
+        }
+        assert!(state.backend_reachable);
+        state.record_backend_result(false);
+        assert!(!state.backend_reachable);
+    }
+
+    #[test]
+    fn backend_recovers_after_success_threshold() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.backend_unreachable_after = 1;
+        settings.backend_reachable_after = 2;
+        let (mut state, _) = test_state(settings);
+        state.record_backend_result(true);
+        state.record_backend_result(true);
+        state.record_backend_result(false);
+        assert!(!state.backend_reachable);
+        state.record_backend_result(true);
+        assert!(!state.backend_reachable);
+        state.record_backend_result(true);
+        assert!(state.backend_reachable);
+    }
+
+    fn unpaid_tap_out(record_unpaid: bool) -> (GatewayState, Decision) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.unpaid_tap_out_records = record_unpaid;
+        let (mut state, clock) = in_out_state(settings);
+        let decision = tap(&mut state, &card(150));
+        let boarded = complete_write(&mut state, &decision);
+        clock.advance(60_000);
+        state.set_station_by_id(3);
+        let decision = tap(&mut state, &boarded);
+        (state, decision)
+    }
+
+    #[test]
+    fn unpaid_tap_out_closes_trip_with_owed_record() {
+        let (mut state, decision) = unpaid_tap_out(true);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(state.last_passenger_message, "余额不足，已记欠款请充值");
+        let record = serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap();
+        assert_eq!(record["alight_station_id"], 3);
+        assert_eq!(record["owed_cents"], 200);
+        assert_eq!(record["fare_cents"], 0);
+        assert!(!state.active_trips.contains(CARD_ID, state.now_secs()));
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.status, CardStatus::Idle);
+        assert_eq!(written.balance_cents, 150);
+    }
+
+    #[test]
+    fn unpaid_tap_out_is_rejected_when_disabled() {
+        let (mut state, decision) = unpaid_tap_out(false);
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.upload_record.is_none());
+        assert!(state.active_trips.contains(CARD_ID, state.now_secs()));
+    }
+
+    #[test]
+    fn paid_tap_out_record_omits_owed_amount() {
+        let (mut state, clock) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &card(1000));
+        let boarded = complete_write(&mut state, &decision);
+        clock.advance(60_000);
+        let decision = tap(&mut state, &boarded);
+        let record = serde_json::to_value(decision.upload_record.as_ref().unwrap()).unwrap();
+        assert!(record.get("owed_cents").is_none());
+    }
+
+    fn detected_at(tap_time: u64) -> CardDetected {
+        CardDetected {
+            card_id: CARD_ID.to_string(),
+            tap_time,
+            reader_id: 1,
+            card_data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn tap_time_within_skew_is_kept() {
+        let (state, _) = default_state();
+        let now = state.now_secs();
+        let max_skew = state.settings.max_tap_time_skew_secs as u64;
+        for tap_time in [now - max_skew, now + max_skew] {
+            let mut detected = detected_at(tap_time);
+            state.correct_tap_time(&mut detected, now);
+            assert_eq!(detected.tap_time, tap_time);
+        }
+    }
+
+    #[test]
+    fn tap_time_out_of_skew_uses_gateway_time() {
+        let (mut state, _) = default_state();
+        let now = state.now_secs();
+        let max_skew = state.settings.max_tap_time_skew_secs as u64;
+        for tap_time in [0, now - max_skew - 1, 4_070_908_800] {
+            let mut detected = detected_at(tap_time);
+            state.correct_tap_time(&mut detected, now);
+            assert_eq!(detected.tap_time, now);
+        }
+        let mut detected = detected_at(0);
+        detected.card_data = card(1000).to_bytes().to_vec();
+        let decision = state.handle_card_detected(detected, now);
+        assert_eq!(decision.event.as_ref().unwrap().tap_time, now);
+    }
+
+    #[test]
+    fn tap_time_is_kept_before_time_sync() {
+        let (state, _) = default_state();
+        let unsynced_now = 1_000;
+        let mut detected = detected_at(4_070_908_800);
+        state.correct_tap_time(&mut detected, unsynced_now);
+        assert_eq!(detected.tap_time, 4_070_908_800);
+    }
+
+    #[test]
+    fn tap_time_skew_check_can_be_disabled() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.max_tap_time_skew_secs = 0;
+        let (state, _) = test_state(settings);
+        let mut detected = detected_at(0);
+        state.correct_tap_time(&mut detected, state.now_secs());
+        assert_eq!(detected.tap_time, 0);
+    }
+
+    #[test]
+    fn balance_override_requires_maintenance_mode() {
+        let (mut state, _) = default_state();
+        let now_ms = state.now_millis();
+        assert!(!state.set_balance_override(500, now_ms));
+        state.set_maintenance_mode(now_ms);
+        let over_limit = state.settings.max_balance_cents + 1;
+        assert!(!state.set_balance_override(over_limit, now_ms));
+        assert!(state.set_balance_override(500, now_ms));
+    }
+
+    #[test]
+    fn balance_override_writes_exact_balance_then_resumes_fares() {
+        let (mut state, clock) = default_state();
+        let now_ms = state.now_millis();
+        state.set_maintenance_mode(now_ms);
+        assert!(state.set_balance_override(1234, now_ms));
+        let decision = tap(&mut state, &card(50));
+        assert_eq!(decision.ack.result, 1);
+        assert!(decision.upload_record.is_none());
+        assert_eq!(state.last_passenger_message, "维护：余额已设为 12.34 元");
+        let written = complete_write(&mut state, &decision);
+        assert_eq!(written.balance_cents, 1234);
+        assert_eq!(written.status, CardStatus::Idle);
+        assert!(state.balance_override.is_none());
+        let audit = state.card_state_cache.drain_all();
+        assert!(audit.iter().any(
+            |snapshot| snapshot.source == "balance_override" && snapshot.balance_cents == 1234
+        ));
+
+        // 写入完成后恢复正常扣费
+        clock.advance(5_000);
+        let decision = tap(&mut state, &written);
+        assert!(decision.upload_record.is_some());
+        assert_eq!(complete_write(&mut state, &decision).balance_cents, 1034);
+    }
+
+    #[test]
+    fn balance_override_expires_with_maintenance_mode() {
+        let (mut state, clock) = default_state();
+        let now_ms = state.now_millis();
+        state.set_maintenance_mode(now_ms);
+        assert!(state.set_balance_override(1234, now_ms));
+        clock.advance(MAINTENANCE_MODE_TTL_MS);
+        let decision = tap(&mut state, &card(1000));
+        assert!(decision.upload_record.is_some());
+        assert!(state.balance_override.is_none());
+        assert!(!state.maintenance_active(state.now_millis()));
+    }
+
+    fn readback_state() -> (GatewayState, Decision) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.write_readback_verify = true;
+        let (mut state, _) = test_state(settings);
+        state.update_reader_hello(ReaderHello {
+            protocol_version: 1,
+            capabilities: crate::proto::CAP_WRITE_READBACK,
+            firmware_version: "rdr-2.2.0".to_string(),
+        });
+        let decision = tap(&mut state, &card(1000));
+        (state, decision)
+    }
+
+    fn write_ok_with_readback(
+        state: &mut GatewayState,
+        readback: Vec<u8>,
+    ) -> Option<CardWriteRequest> {
+        let result = CardWriteResult {
+            result: 1,
+            error_code: 0,
+            block_start: CARD_DATA_BLOCK_START,
+            block_count: CARD_DATA_BLOCK_COUNT,
+            readback,
+        };
+        let now_ms = state.now_millis();
+        state.handle_write_result(result, now_ms)
+    }
+
+    #[test]
+    fn readback_is_requested_when_reader_supports_it() {
+        let (_, decision) = readback_state();
+        assert!(decision.write_request.as_ref().unwrap().readback);
+        let (mut plain, _) = default_state();
+        let decision = tap(&mut plain, &card(1000));
+        assert!(!decision.write_request.as_ref().unwrap().readback);
+    }
+
+    #[test]
+    fn matching_readback_confirms_write() {
+        let (mut state, decision) = readback_state();
+        let written = decision.write_request.as_ref().unwrap().card_data.clone();
+        assert!(write_ok_with_readback(&mut state, written).is_none());
+        assert_eq!(state.pending_write_count(), 0);
+        assert_ne!(state.last_passenger_tone, PassengerTone::Error);
+    }
+
+    #[test]
+    fn mismatching_readback_is_retried_as_verify_failure() {
+        let (mut state, decision) = readback_state();
+        let request = decision.write_request.as_ref().unwrap();
+        let mut readback = request.card_data.clone();
+        readback[8] ^= 0xFF;
+        let retry = write_ok_with_readback(&mut state, readback.clone()).expect("retry");
+        assert_eq!(retry.card_data, request.card_data);
+        for _ in 1..MAX_WRITE_RETRIES {
+            assert!(write_ok_with_readback(&mut state, readback.clone()).is_some());
+        }
+        // 重试用尽后提示校验失败
+        assert!(write_ok_with_readback(&mut state, readback).is_none());
+        assert!(state.last_passenger_message.starts_with("写卡校验失败"));
+    }
+
+    #[test]
+    fn transfer_station_adds_passenger_hint() {
+        let (mut state, clock) = default_state();
+        let mut cfg = route_config("single_tap");
+        cfg.stations[1].is_transfer = true;
+        state.update_route_config(cfg, T0_MS / 1000);
+        assert!(!state.is_transfer_station(1));
+        assert!(state.is_transfer_station(2));
+        assert!(!state.is_transfer_station(99));
+        state.set_station_by_id(1);
+        let _ = tap(&mut state, &card(1000));
+        assert!(!state.last_passenger_message.contains("换乘站"));
+        clock.advance(5_000);
+        state.set_station_by_id(2);
+        let _ = tap(&mut state, &card(1000));
+        assert!(state
+            .last_passenger_message
+            .ends_with("（换乘站，可换乘其他线路）"));
+    }
+
+    #[test]
+    fn v1_card_is_rejected_then_reregistered_with_balance() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_card_version = 2;
+        let (mut state, clock) = test_state(settings);
+        let v1 = crate::card_data::v1_bytes(&[0xA1, 0xB2, 0xC3, 0xD4], 1234).to_vec();
+        let decision = tap_bytes(&mut state, 1, v1.clone());
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "请更新卡片");
+        assert_eq!(
+            state.last_card_data_error.as_deref(),
+            Some("outdated_version")
+        );
+        clock.advance(5_000);
+        let now_ms = state.now_millis();
+        state.set_register_mode(now_ms);
+        let decision = tap_bytes(&mut state, 1, v1);
+        assert!(decision.registration.is_some());
+        // 重新注册沿用旧卡余额，写入的新版本卡照常使用
+        let registered = complete_write(&mut state, &decision);
+        assert_eq!(registered.balance_cents, 1234);
+        state.clear_register_mode();
+        clock.advance(5_000);
+        assert_eq!(tap(&mut state, &registered).ack.result, 1);
+    }
+
+    #[test]
+    fn outdated_card_from_other_uid_does_not_carry_balance() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.min_card_version = 2;
+        let (mut state, _) = test_state(settings);
+        let now_ms = state.now_millis();
+        state.set_register_mode(now_ms);
+        // 复制自另一张卡的旧版本数据
+        let cloned = crate::card_data::v1_bytes(&[1, 2, 3, 4], 5000).to_vec();
+        let decision = tap_bytes(&mut state, 1, cloned);
+        assert!(decision.registration.is_some());
+        assert_eq!(
+            complete_write(&mut state, &decision).balance_cents,
+            DEFAULT_REGISTER_BALANCE_CENTS
+        );
+    }
+
+    #[test]
+    fn oversized_write_is_rejected_and_untracked() {
+        let (mut state, _) = in_out_state(GatewaySettings::with_gateway_id("gw-test"));
+        let decision = tap(&mut state, &card(1000));
+        assert!(decision.write_request.is_some());
+        assert_eq!(state.pending_write_count(), 1);
+        assert_eq!(state.active_trips.len(), 1);
+        let decision = state.reject_oversized_write();
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert_eq!(state.pending_write_count(), 0);
+        assert_eq!(state.last_passenger_message, "写卡数据过长");
+        // 卡未写入，余额显示保持读卡时的值
+        assert_eq!(state.last_balance_cents, Some(1000));
+        // 卡内未记上车，不应留下未完成行程
+        assert_eq!(state.active_trips.len(), 0);
+    }
+
+    #[test]
+    fn reader_goes_offline_after_missed_polls_and_recovers() {
+        let (mut state, _) = default_state();
+        assert_eq!(state.settings.reader_poll_max_missed, 3);
+        // 前 3 次轮询未回复时尚不判定离线，第 4 次下发时转为离线
+        for _ in 0..3 {
+            assert!(!state.record_reader_poll());
+        }
+        assert!(!state.reader_offline);
+        assert!(state.record_reader_poll());
+        assert!(state.reader_offline);
+        // 已离线不重复上报
+        assert!(!state.record_reader_poll());
+        state.record_reader_heartbeat();
+        assert!(!state.reader_offline);
+        assert_eq!(state.reader_polls_unanswered, 0);
+    }
+
+    #[test]
+    fn reject_policy_refuses_taps_until_initial_sync() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.warmup_policy = WarmupPolicy::Reject;
+        let (mut state, clock) = test_state(settings);
+        assert!(state.warming_up());
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "系统启动中");
+        state.mark_initial_sync_done();
+        assert!(!state.warming_up());
+        clock.advance(5_000);
+        assert_eq!(tap(&mut state, &card(1000)).ack.result, 1);
+        // 策略为 Off 时不存在预热期
+        state.settings.warmup_policy = WarmupPolicy::Off;
+        state.initial_sync_done = false;
+        assert!(!state.warming_up());
+    }
+
+    #[test]
+    fn standard_fare_follows_effective_dates() {
+        let (mut state, clock) = default_state();
+        let switch_at = T0_MS / 1000 + 60;
+        let mut cfg = route_config("single_tap");
+        cfg.fares = serde_json::from_value(serde_json::json!([
+            {"base_price": 2.0, "effective_to": switch_at},
+            {"base_price": 3.0, "effective_from": switch_at}
+        ]))
+        .unwrap();
+        state.update_route_config(cfg, T0_MS / 1000);
+        clock.advance(59_000);
+        assert_eq!(state.standard_fare_cents(), Some(200));
+        assert_eq!(record_fare(&tap(&mut state, &card(1000))), 200);
+        clock.advance(1_000);
+        assert_eq!(state.standard_fare_cents(), Some(300));
+        clock.advance(5_000);
+        assert_eq!(record_fare(&tap(&mut state, &card(1000))), 300);
+    }
+
+    #[test]
+    fn duplicate_detection_resends_ack_without_charging() {
+        let (mut state, _) = default_state();
+        let first = tap(&mut state, &card(1000));
+        assert_eq!(first.ack.result, 1);
+        // 处理器下发 ACK 后缓存，供重发
+        state.remember_ack(1, first.ack.clone());
+        let accepted = state.metrics.taps_accepted;
+        let duplicate = tap(&mut state, &card(1000));
+        assert_eq!(duplicate.ack.result, 1);
+        assert_eq!(duplicate.ack.write_data, first.ack.write_data);
+        assert!(duplicate.upload_record.is_none());
+        assert!(duplicate.write_request.is_none());
+        assert_eq!(state.pending_write_count(), 1);
+        assert_eq!(state.metrics.taps_accepted, accepted);
+        // 其他读卡器无缓存 ACK 时按刷卡过快拒绝
+        let other = tap_from(&mut state, 2, &card(1000));
+        assert_eq!(other.ack.result, 0);
+        assert_eq!(state.last_passenger_message, "刷卡过快");
+    }
+
+    #[test]
+    fn station_name_override_applies_until_station_changes() {
+        // 未选站时无可覆盖的站名
+        let mut unrouted = GatewayState::bootstrap(GatewaySettings::with_gateway_id("gw-test"));
+        assert!(!unrouted.set_station_name_override("临时站".to_string()));
+        let (mut state, _) = default_state();
+        state.set_station_by_id(2);
+        assert!(state.set_station_name_override("临时站".to_string()));
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.event.as_ref().unwrap().station_name, "临时站");
+        // 切站后恢复配置站名
+        state.set_station_by_id(3);
+        state.set_station_by_id(2);
+        assert_eq!(state.route_state.station_name, "二站");
+    }
+
+    fn late_fee_state(max_fare: Option<f32>) -> (GatewayState, Arc<MockClock>) {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.late_fee_free_mins = 90;
+        settings.late_fee_step_mins = 10;
+        settings.late_fee_step_cents = 50;
+        settings.active_trip_ttl_secs = 4 * 3600;
+        let (mut state, clock) = in_out_state(settings);
+        let mut cfg = route_config("tap_in_out");
+        cfg.max_fare = max_fare;
+        state.update_route_config(cfg, T0_MS / 1000);
+        (state, clock)
+    }
+
+    #[test]
+    fn late_fee_steps_start_after_free_window() {
+        let (state, _) = late_fee_state(None);
+        let entry = (T0_MS / 1000) as u32;
+        let at = |mins: u64, secs: u64| T0_MS / 1000 + mins * 60 + secs;
+        assert_eq!(state.add_late_fee(200, Some(entry), at(90, 0)), 200);
+        // 不足一个计费间隔按一个计
+        assert_eq!(state.add_late_fee(200, Some(entry), at(90, 1)), 250);
+        assert_eq!(state.add_late_fee(200, Some(entry), at(100, 0)), 250);
+        assert_eq!(state.add_late_fee(200, Some(entry), at(100, 1)), 300);
+        // 无进站时间或未校时不加收
+        assert_eq!(state.add_late_fee(200, None, at(200, 0)), 200);
+        assert_eq!(state.add_late_fee(200, Some(0), 1_000_000), 200);
+    }
+
+    #[test]
+    fn late_fee_is_capped_and_disabled_by_zero_step() {
+        let (state, _) = late_fee_state(Some(3.0));
+        let entry = (T0_MS / 1000) as u32;
+        let late = T0_MS / 1000 + 600 * 60;
+        assert_eq!(state.add_late_fee(200, Some(entry), late), 300);
+        // 基础票价已超封顶价时不再加收
+        assert_eq!(state.add_late_fee(400, Some(entry), late), 400);
+        let (mut state, _) = late_fee_state(None);
+        state.settings.late_fee_step_cents = 0;
+        assert_eq!(state.add_late_fee(200, Some(entry), late), 200);
+        state.settings.late_fee_step_cents = 50;
+        state.settings.late_fee_step_mins = 0;
+        assert_eq!(state.add_late_fee(200, Some(entry), late), 200);
+    }
+
+    #[test]
+    fn tap_out_charges_late_fee_from_on_card_entry_time() {
+        let (mut state, clock) = late_fee_state(None);
+        state.set_station_by_id(1);
+        let decision = tap(&mut state, &card(1000));
+        let boarded = complete_write(&mut state, &decision);
+        assert_eq!(boarded.entry_time, Some((T0_MS / 1000) as u32));
+        clock.advance(95 * 60 * 1000);
+        state.set_station_by_id(2);
+        let decision = tap(&mut state, &boarded);
+        assert_eq!(decision.ack.result, 1);
+        assert_eq!(record_fare(&decision), 250);
+    }
+
+    #[test]
+    fn unsynced_clock_with_only_dated_fares_rejects_tap() {
+        let (mut state, clock) = default_state();
+        let mut cfg = route_config("single_tap");
+        cfg.fares = serde_json::from_value(serde_json::json!([
+            {"base_price": 2.0, "effective_from": T0_MS / 1000}
+        ]))
+        .unwrap();
+        state.update_route_config(cfg, T0_MS / 1000);
+        // 时钟未校时：无法判断规则是否生效，不按 0 元放行
+        clock.set(60_000);
+        assert_eq!(state.standard_fare_cents(), None);
+        let decision = tap(&mut state, &card(1000));
+        assert_eq!(decision.ack.result, 0);
+        assert!(decision.write_request.is_none());
+        assert!(decision.upload_record.is_none());
+        assert_eq!(state.last_passenger_message, "票价未生效");
+        // 校时后照常收费
+        clock.set(T0_MS + 60_000);
+        assert_eq!(record_fare(&tap(&mut state, &card(1000))), 200);
+    }
 }
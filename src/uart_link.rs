@@ -1,30 +1,76 @@
 use std::fmt::Write as _;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use esp_idf_hal::delay;
 use esp_idf_hal::uart::{UartRxDriver, UartTxDriver};
 
-use crate::serial::{CardDetected, CardWriteResult, SerialCommand};
-use crate::serial_io::{push_bytes_to_channel, SerialFrameCodec};
+use crate::lock::LockRecover;
+use crate::model::SerialMode;
+use crate::serial::{
+    AckResendRequest, CardDetected, CardWriteResult, ConfigRequest, ReaderHeartbeat, ReaderHello,
+    SerialCommand,
+};
+use crate::serial_io::{push_bytes_to_channel, LineUidCodec, SerialFrameCodec};
+use crate::state::GatewayState;
 
 /// 启动 UART 收发任务（RX 解码、TX 发送 ACK）。
+/// 行模式（LineUid）下 RX 按行解析 UID，TX 不发送任何数据。
+/// 共享状态仅用于读取卡号脱敏设置（原始帧日志）。
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_uart_tasks(
+    state: Arc<Mutex<GatewayState>>,
     rx: UartRxDriver<'static>,
     mut tx: UartTxDriver<'static>,
     card_tx: Sender<CardDetected>,
     write_result_tx: Sender<CardWriteResult>,
+    hello_tx: Sender<ReaderHello>,
+    config_req_tx: Sender<ConfigRequest>,
+    ack_resend_tx: Sender<AckResendRequest>,
+    heartbeat_tx: Sender<ReaderHeartbeat>,
     cmd_rx: Receiver<SerialCommand>,
+    serial_mode: SerialMode,
+    reader_id: u16,
 ) -> (thread::JoinHandle<()>, thread::JoinHandle<()>) {
+    let tx_state = state.clone();
     let rx_handle = thread::spawn(move || {
         let mut codec = SerialFrameCodec::new();
+        let mut line_codec = LineUidCodec::new();
         let mut buf = [0u8; 128];
         loop {
             match rx.read(&mut buf, delay::BLOCK) {
                 Ok(count) if count > 0 => {
-                    // 收到数据后写入帧解码器
-                    log_bytes("UART RX:", &buf[..count]);
-                    push_bytes_to_channel(&mut codec, &buf[..count], &card_tx, &write_result_tx);
+                    log_bytes(&state, "UART RX:", &buf[..count]);
+                    match serial_mode {
+                        SerialMode::Framed => {
+                            // 收到数据后写入帧解码器
+                            push_bytes_to_channel(
+                                &mut codec,
+                                &buf[..count],
+                                &card_tx,
+                                &write_result_tx,
+                                &hello_tx,
+                                &config_req_tx,
+                                &ack_resend_tx,
+                                &heartbeat_tx,
+                            );
+                        }
+                        SerialMode::LineUid => {
+                            for &byte in &buf[..count] {
+                                if let Some(card_id) = line_codec.push_byte(byte) {
+                                    // 无卡内数据，由后端/缓存画像决定
+                                    let _ = card_tx.send(CardDetected {
+                                        card_id,
+                                        tap_time: current_epoch(),
+                                        reader_id,
+                                        card_data: Vec::new(),
+                                    });
+                                }
+                            }
+                        }
+                    }
                 }
                 Ok(_) => {}
                 Err(err) => {
@@ -36,14 +82,21 @@ pub fn spawn_uart_tasks(
 
     let tx_handle = thread::spawn(move || {
         while let Ok(command) = cmd_rx.recv() {
+            // 简易读卡器不理解帧协议，丢弃 ACK/写卡/握手
+            if serial_mode == SerialMode::LineUid {
+                continue;
+            }
             let bytes = match command {
                 SerialCommand::Ack(ack) => SerialFrameCodec::ack_to_bytes(&ack),
                 SerialCommand::Write(req) => SerialFrameCodec::write_req_to_bytes(&req),
+                SerialCommand::Hello(hello) => SerialFrameCodec::hello_to_bytes(&hello),
+                SerialCommand::RouteInfo(info) => SerialFrameCodec::route_info_to_bytes(&info),
+                SerialCommand::Poll => SerialFrameCodec::poll_to_bytes(),
             };
             if bytes.is_empty() {
                 continue;
             }
-            log_bytes("UART TX:", &bytes);
+            log_bytes(&tx_state, "UART TX:", &bytes);
             if let Err(err) = tx.write(&bytes) {
                 log::warn!("UART TX error: {:?}", err);
             }
@@ -55,10 +108,15 @@ pub fn spawn_uart_tasks(
 }
 
 /// 以十六进制输出串口数据。
-fn log_bytes(prefix: &str, bytes: &[u8]) {
+fn log_bytes(state: &Arc<Mutex<GatewayState>>, prefix: &str, bytes: &[u8]) {
     if bytes.is_empty() {
         return;
     }
+    // 原始帧含卡号，开启脱敏时只记录长度
+    if state.lock_recover().settings.mask_card_ids {
+        log::info!("{} {} bytes", prefix, bytes.len());
+        return;
+    }
     let mut line = String::with_capacity(prefix.len() + bytes.len() * 3);
     line.push_str(prefix);
     line.push(' ');
@@ -70,3 +128,11 @@ fn log_bytes(prefix: &str, bytes: &[u8]) {
     }
     log::info!("{}", line);
 }
+
+/// 当前时间戳（秒）。
+fn current_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -1,15 +1,32 @@
-use crate::model::UploadRecord;
+use std::time::Duration;
 
-/// 批量上报结构封装。
-#[derive(Clone, Debug)]
+use serde::Serialize;
+
+use crate::model::{UploadOverflowPolicy, UploadRecord};
+use crate::state::GatewayState;
+
+/// 上报记录的 JSON 结构版本（当前 UploadRecord 字段即为 v1）。
+pub const UPLOAD_SCHEMA_VERSION: u32 = 1;
+
+// 单批上传耗时超过该值视为“慢链路”，成功也不再扩大批次。
+const SLOW_UPLOAD_MS: u64 = 3000;
+// 每次成功上传后批次的线性增量。
+const BATCH_GROW_STEP: usize = 5;
+
+/// 批量上报结构封装（带版本号的信封，便于后端协商）。
+#[derive(Clone, Debug, Serialize)]
 pub struct BatchUpload {
+    pub schema_version: u32,
     pub records: Vec<UploadRecord>,
 }
 
 impl BatchUpload {
     /// 构造批量记录。
     pub fn new(records: Vec<UploadRecord>) -> Self {
-        Self { records }
+        Self {
+            schema_version: UPLOAD_SCHEMA_VERSION,
+            records,
+        }
     }
 
     /// 是否为空批次。
@@ -17,8 +34,234 @@ impl BatchUpload {
         self.records.is_empty()
     }
 
-    /// 序列化为 JSON 字符串。
+    /// 序列化为 JSON 字符串：`{"schema_version":1,"records":[...]}`。
     pub fn to_json_string(&self) -> String {
-        serde_json::to_string(&self.records).unwrap_or_else(|_| "[]".to_string())
+        serde_json::to_string(self).unwrap_or_else(|_| {
+            format!(
+                "{{\"schema_version\":{},\"records\":[]}}",
+                self.schema_version
+            )
+        })
+    }
+}
+
+/// 网关心跳（在线状态 + 运行概况，刷卡稀疏时供后端确认网关存活）。
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayHeartbeat {
+    pub gateway_id: String,
+    pub route_id: u16,
+    pub station_id: u16,
+    pub uptime_secs: u64,
+    pub taps_accepted: u64,
+    pub taps_rejected: u64,
+    pub pending_uploads: usize,
+    pub upload_dropped: u64,
+    pub firmware_version: String,
+    pub reader_firmware: Option<String>,
+    pub schema_version: u32,
+}
+
+impl GatewayHeartbeat {
+    /// 由当前网关状态与已运行时长构造心跳。
+    pub fn from_state(state: &GatewayState, uptime_secs: u64, pending_uploads: usize) -> Self {
+        Self {
+            gateway_id: state.settings.gateway_id.clone(),
+            route_id: state.route_state.route_id,
+            station_id: state.route_state.station_id,
+            uptime_secs,
+            taps_accepted: state.metrics.taps_accepted,
+            taps_rejected: state.metrics.taps_rejected,
+            pending_uploads,
+            upload_dropped: state.upload_dropped,
+            firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+            reader_firmware: state
+                .reader_hello
+                .as_ref()
+                .map(|hello| hello.firmware_version.clone()),
+            schema_version: UPLOAD_SCHEMA_VERSION,
+        }
+    }
+}
+
+/// 批量大小自适应调节（AIMD：成功线性增长，失败减半）。
+#[derive(Clone, Debug)]
+pub struct BatchSizeTuner {
+    current: usize,
+    min: usize,
+    max: usize,
+}
+
+impl BatchSizeTuner {
+    /// 以初始批次与上下限构造调节器。
+    pub fn new(initial: usize, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            current: initial.clamp(min, max),
+            min,
+            max,
+        }
+    }
+
+    /// 当前生效的批次大小。
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// 上传成功：链路不慢时线性扩大批次。
+    pub fn record_success(&mut self, latency: Duration) {
+        if latency.as_millis() as u64 > SLOW_UPLOAD_MS {
+            return;
+        }
+        self.current = self.current.saturating_add(BATCH_GROW_STEP).min(self.max);
+    }
+
+    /// 上传失败：批次减半。
+    pub fn record_failure(&mut self) {
+        self.current = (self.current / 2).max(self.min);
+    }
+}
+
+/// 有界追加：缓冲达到上限时按策略丢弃一条，返回是否发生丢弃。
+pub fn push_bounded<T>(
+    buffer: &mut Vec<T>,
+    item: T,
+    max_len: usize,
+    policy: UploadOverflowPolicy,
+) -> bool {
+    if max_len == 0 || buffer.len() < max_len {
+        buffer.push(item);
+        return false;
+    }
+    match policy {
+        UploadOverflowPolicy::DropOldest => {
+            buffer.remove(0);
+            buffer.push(item);
+        }
+        UploadOverflowPolicy::DropNewest => {}
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::GatewaySettings;
+    use crate::model::{TapEvent, TapType};
+
+    #[test]
+    fn tuner_grows_additively_and_halves_on_failure() {
+        let mut tuner = BatchSizeTuner::new(50, 5, 60);
+        tuner.record_success(Duration::from_millis(200));
+        assert_eq!(tuner.current(), 55);
+        tuner.record_success(Duration::from_millis(200));
+        tuner.record_success(Duration::from_millis(200));
+        assert_eq!(tuner.current(), 60);
+        tuner.record_failure();
+        assert_eq!(tuner.current(), 30);
+        for _ in 0..5 {
+            tuner.record_failure();
+        }
+        assert_eq!(tuner.current(), 5);
+    }
+
+    #[test]
+    fn tuner_holds_on_slow_success_and_clamps_initial() {
+        let mut tuner = BatchSizeTuner::new(500, 0, 100);
+        assert_eq!(tuner.current(), 100);
+        tuner.record_failure();
+        tuner.record_success(Duration::from_millis(SLOW_UPLOAD_MS + 1));
+        assert_eq!(tuner.current(), 50);
+        let tuner = BatchSizeTuner::new(0, 0, 0);
+        assert_eq!(tuner.current(), 1);
+    }
+
+    fn tap_in_record() -> UploadRecord {
+        let event = TapEvent::new(
+            "rec-1".to_string(),
+            "A1B2C3D4".to_string(),
+            7,
+            1,
+            "一站".to_string(),
+            TapType::TapIn,
+            1_704_067_200,
+            "gw-test".to_string(),
+        );
+        UploadRecord::from_tap_in(&event)
+    }
+
+    #[test]
+    fn batch_envelope_carries_schema_version() {
+        let batch = BatchUpload::new(vec![tap_in_record()]);
+        let json: serde_json::Value = serde_json::from_str(&batch.to_json_string()).unwrap();
+        assert_eq!(json["schema_version"], UPLOAD_SCHEMA_VERSION);
+        assert_eq!(json["records"][0]["record_id"], "rec-1");
+        assert!(json["records"][0].get("alert").is_none());
+        let empty = BatchUpload::new(Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(
+            empty.to_json_string(),
+            r#"{"schema_version":1,"records":[]}"#
+        );
+    }
+
+    #[test]
+    fn bounded_push_drops_by_policy() {
+        let mut buffer = vec![1, 2, 3];
+        assert!(push_bounded(
+            &mut buffer,
+            4,
+            3,
+            UploadOverflowPolicy::DropOldest
+        ));
+        assert_eq!(buffer, vec![2, 3, 4]);
+        assert!(push_bounded(
+            &mut buffer,
+            5,
+            3,
+            UploadOverflowPolicy::DropNewest
+        ));
+        assert_eq!(buffer, vec![2, 3, 4]);
+        // 上限为 0 表示不限制
+        assert!(!push_bounded(
+            &mut buffer,
+            5,
+            0,
+            UploadOverflowPolicy::DropNewest
+        ));
+        assert_eq!(buffer.len(), 4);
+    }
+
+    #[test]
+    fn heartbeat_payload_reflects_state() {
+        let mut state = GatewayState::bootstrap(GatewaySettings::with_gateway_id("gw-hb"));
+        state.route_state.route_id = 7;
+        state.route_state.station_id = 2;
+        state.metrics.taps_accepted = 12;
+        state.metrics.taps_rejected = 3;
+        state.upload_dropped = 1;
+        let heartbeat = GatewayHeartbeat::from_state(&state, 3600, 4);
+        let json = serde_json::to_value(&heartbeat).unwrap();
+        assert_eq!(json["gateway_id"], "gw-hb");
+        assert_eq!(json["route_id"], 7);
+        assert_eq!(json["station_id"], 2);
+        assert_eq!(json["uptime_secs"], 3600);
+        assert_eq!(json["taps_accepted"], 12);
+        assert_eq!(json["taps_rejected"], 3);
+        assert_eq!(json["pending_uploads"], 4);
+        assert_eq!(json["upload_dropped"], 1);
+        assert_eq!(json["firmware_version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["reader_firmware"].is_null());
+        assert_eq!(json["schema_version"], UPLOAD_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn fare_cents_is_serialized_only_when_known() {
+        let record = tap_in_record();
+        let json = serde_json::to_value(&record).unwrap();
+        assert!(json.get("fare_cents").is_none());
+        let mut record = record;
+        record.fare_cents = Some(250);
+        assert_eq!(serde_json::to_value(&record).unwrap()["fare_cents"], 250);
     }
 }
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "esp")]
+use std::thread;
+#[cfg(feature = "esp")]
+use std::time::Duration;
+use std::time::Instant;
+
+// 看门狗检查间隔。
+#[cfg(feature = "esp")]
+const CHECK_INTERVAL_MS: u64 = 1000;
+// 处理器空闲时的心跳间隔（无刷卡事件也需定期喂狗）。
+pub const HEARTBEAT_INTERVAL_MS: u64 = 1000;
+// HTTP 服务自检判定卡死所需的连续失败次数。
+pub const SELF_PROBE_MAX_FAILURES: u32 = 3;
+
+/// 处理器心跳（单调时钟，不受校时影响）。
+#[derive(Clone)]
+pub struct Heartbeat {
+    start: Instant,
+    last_beat_ms: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// 创建心跳，初始即视为已跳动一次。
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_beat_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 记录一次心跳。
+    pub fn beat(&self) {
+        self.last_beat_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+    }
+
+    /// 距上次心跳的毫秒数。
+    pub fn since_last_beat_ms(&self) -> u64 {
+        self.elapsed_ms()
+            .saturating_sub(self.last_beat_ms.load(Ordering::Relaxed))
+    }
+
+    /// 心跳停止超过阈值时返回停止时长（毫秒）。
+    pub fn stalled_ms(&self, threshold_ms: u64) -> Option<u64> {
+        let stalled_ms = self.since_last_beat_ms();
+        (stalled_ms > threshold_ms).then_some(stalled_ms)
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// HTTP 服务卡死判定：连续自检失败达到阈值即需重建服务。
+pub struct ServerWatch {
+    failures: u32,
+    max_failures: u32,
+}
+
+impl ServerWatch {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            failures: 0,
+            max_failures: max_failures.max(1),
+        }
+    }
+
+    /// 记录一次自检结果，返回是否需要重建服务（触发后计数清零）。
+    pub fn record(&mut self, ok: bool) -> bool {
+        if ok {
+            self.failures = 0;
+            return false;
+        }
+        self.failures += 1;
+        log::warn!("Web server self-probe failed ({}/{})", self.failures, self.max_failures);
+        if self.failures < self.max_failures {
+            return false;
+        }
+        self.failures = 0;
+        true
+    }
+}
+
+/// 启动看门狗：处理器心跳停止超过阈值（线程退出或卡死）时重启设备。
+#[cfg(feature = "esp")]
+pub fn spawn_watchdog(heartbeat: Heartbeat, stall_secs: u32) -> thread::JoinHandle<()> {
+    let threshold_ms = stall_secs as u64 * 1000;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(CHECK_INTERVAL_MS));
+        if let Some(stalled_ms) = heartbeat.stalled_ms(threshold_ms) {
+            log::error!(
+                "Processor heartbeat stalled for {}ms, restarting",
+                stalled_ms
+            );
+            // 给日志输出留出时间
+            thread::sleep(Duration::from_millis(100));
+            unsafe { esp_idf_hal::sys::esp_restart() };
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn beat_resets_stall_timer() {
+        let heartbeat = Heartbeat::new();
+        thread::sleep(Duration::from_millis(30));
+        assert!(heartbeat.since_last_beat_ms() >= 30);
+        assert!(heartbeat.stalled_ms(10).is_some());
+        heartbeat.beat();
+        assert!(heartbeat.stalled_ms(10).is_none());
+    }
+
+    #[test]
+    fn clones_share_the_same_heartbeat() {
+        let heartbeat = Heartbeat::new();
+        let processor_side = heartbeat.clone();
+        thread::sleep(Duration::from_millis(30));
+        processor_side.beat();
+        assert!(heartbeat.since_last_beat_ms() < 30);
+        assert!(heartbeat.stalled_ms(1000).is_none());
+    }
+
+    #[test]
+    fn server_watch_restarts_after_consecutive_failures() {
+        let mut watch = ServerWatch::new(SELF_PROBE_MAX_FAILURES);
+        assert!(!watch.record(false));
+        assert!(!watch.record(false));
+        assert!(watch.record(false));
+        // 触发后重新计数
+        assert!(!watch.record(false));
+    }
+
+    #[test]
+    fn server_watch_success_resets_failures() {
+        let mut watch = ServerWatch::new(3);
+        assert!(!watch.record(false));
+        assert!(!watch.record(false));
+        assert!(!watch.record(true));
+        assert!(!watch.record(false));
+        assert!(!watch.record(false));
+        assert!(watch.record(false));
+    }
+
+    #[test]
+    fn server_watch_zero_threshold_acts_on_first_failure() {
+        let mut watch = ServerWatch::new(0);
+        assert!(!watch.record(true));
+        assert!(watch.record(false));
+    }
+}
@@ -1,18 +1,82 @@
+use serde::Serialize;
+
+use crate::privacy::{display_card_id_with, mask_card_id};
+
 /// 司机操作动作（由 Web UI 触发）。
 #[derive(Clone, Debug)]
 pub enum DriverAction {
     SetRoute { route_id: u16 },
     SetDirection { direction: crate::model::Direction },
     SetStation { station_id: u16 },
+    // 临时修改当前站显示名（后端站名有误或临时增设站点），下次配置同步或切站后恢复。
+    SetStationName { name: String },
     NextStation,
     PrevStation,
     SyncConfig,
+    FullResync,
     UploadNow,
     SetBackend { base_url: String },
     StartRecharge { amount_cents: u32 },
     CancelRecharge,
+    // 强制清除充值被拒卡的行程；seq 为界面显示该卡时的序号。
+    ForceClearTrip { card_id: String, seq: Option<u32> },
+    // 清除指定的未完成行程（卡住行程恢复）；trip 为上车记录号或卡号。
+    ClearTrip { trip: String },
+    // 下一次扣费刷卡使用的手动票价（分，一次性）。
+    SetNextFare { cents: u32 },
+    ClearCardCache,
+    PushCardStates,
     StartRegister,
     CancelRegister,
+    // 维护模式与维护操作（需管理口令）。
+    StartMaintenance,
+    CancelMaintenance,
+    // 下一张卡强制写入指定余额（分）。
+    SetNextCardBalance { cents: u32 },
+}
+
+impl DriverAction {
+    /// 是否需要管理口令。
+    pub fn requires_pin(&self) -> bool {
+        matches!(
+            self,
+            DriverAction::StartMaintenance
+                | DriverAction::CancelMaintenance
+                | DriverAction::SetNextCardBalance { .. }
+        )
+    }
+}
+
+// HTTP 服务任务栈大小范围（字节）：过小时处理 JSON 接口易栈溢出。
+const WEB_STACK_SIZE_MIN: usize = 6144;
+const WEB_STACK_SIZE_MAX: usize = 32 * 1024;
+const WEB_PORT_DEFAULT: u16 = 80;
+
+/// HTTP 服务参数：栈大小限制在安全范围内，端口为 0 时使用 80。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WebServerParams {
+    pub stack_size: usize,
+    pub port: u16,
+}
+
+impl WebServerParams {
+    pub fn from_settings(settings: &crate::model::GatewaySettings) -> Self {
+        let stack_size = settings
+            .web_stack_size
+            .clamp(WEB_STACK_SIZE_MIN, WEB_STACK_SIZE_MAX);
+        if stack_size != settings.web_stack_size {
+            log::warn!(
+                "Web stack size {} out of range, using {}",
+                settings.web_stack_size,
+                stack_size
+            );
+        }
+        let port = match settings.web_port {
+            0 => WEB_PORT_DEFAULT,
+            port => port,
+        };
+        Self { stack_size, port }
+    }
 }
 
 /// Web UI 展示的状态面板数据。
@@ -22,57 +86,306 @@ pub struct StatusPanel {
     pub route_name: String,
     pub station_id: u16,
     pub station_name: String,
+    pub station_is_transfer: bool,
     pub direction: crate::model::Direction,
     pub tap_mode_label: String,
     pub fare_type_label: String,
     pub cache_count: usize,
     pub wifi_connected: bool,
+    pub wifi_connect_attempts: u32,
     pub backend_reachable: bool,
+    // 本地时间 HH:MM（未校时为 None）。
+    pub local_time: Option<String>,
     pub backend_base_url: String,
+    // 最近一次后端错误（如“配置同步失败: 404”）及距今秒数。
+    pub backend_error: Option<String>,
+    pub backend_error_age_secs: Option<u64>,
+    // 距最近一次上报成功的秒数（从未成功为 None）。
+    pub last_upload_age_secs: Option<u64>,
     pub passenger_tone: crate::model::PassengerTone,
     pub passenger_message: String,
-    pub standard_fare: Option<f32>,
-    pub last_fare: Option<f32>,
+    pub standard_fare_cents: Option<u32>,
+    pub last_fare_cents: Option<u32>,
     pub last_fare_label: String,
+    pub last_board_station: Option<String>,
+    pub last_trip_elapsed_secs: Option<u64>,
+    pub next_fare_override_cents: Option<u32>,
     pub recharge_active: bool,
     pub recharge_amount_cents: Option<u32>,
+    pub stuck_trip_card: Option<String>,
+    pub stuck_trip_seq: u32,
+    pub force_clear_armed: bool,
     pub register_active: bool,
     pub last_card_id: String,
     pub last_balance_cents: Option<u32>,
+    pub last_card_trip: Option<crate::state::CardLastTrip>,
     pub last_card_data_len: usize,
     pub last_card_data_prefix_hex: Option<String>,
     pub last_card_data_error: Option<String>,
+    pub pending_writes: u64,
+    pub upload_dropped: u64,
+    // 待上传记录数与最早一条的等待时长（秒）。
+    pub upload_queue_len: usize,
+    pub upload_oldest_age_secs: Option<u64>,
+    pub write_unconfirmed: bool,
+    pub reader_firmware: Option<String>,
+    pub reader_capabilities: u16,
+    // 轮询连续未回复，判定读卡器离线。
+    pub reader_offline: bool,
+    // 停运待机：乘客屏熄屏、状态灯关闭，刷卡或司机操作后恢复。
+    pub idle: bool,
 }
 
-/// 操作结果（预留扩展）。
-#[derive(Clone, Debug)]
+/// 页面视图：完整司机页或仅乘客屏（公共显示屏，无操作入口）。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageView {
+    /// 乘客屏 + 司机控制面板
+    Full,
+    /// 仅乘客屏，自动刷新
+    Passenger,
+}
+
+impl PageView {
+    /// 从查询参数解析（view=passenger），默认完整页面。
+    pub fn from_query(query: &str) -> Self {
+        match query_value(query, "view").as_deref() {
+            Some("passenger") => PageView::Passenger,
+            _ => PageView::Full,
+        }
+    }
+}
+
+/// 操作结果（用于需要回报执行情况的动作，如全量同步）。
+#[derive(Clone, Debug, Serialize)]
 pub struct ActionResult {
     pub success: bool,
     pub message: String,
 }
 
+impl ActionResult {
+    /// 全量同步结果汇总；任一后端请求成功即视为后端可达。
+    pub fn full_resync(config_ok: bool, blacklist_ok: bool, time_ok: bool) -> Self {
+        let label = |ok: bool| if ok { "成功" } else { "失败" };
+        let backend_ok = config_ok || blacklist_ok;
+        Self {
+            success: config_ok && blacklist_ok && time_ok,
+            message: format!(
+                "配置{} 黑名单{} 校时{} 后端{}",
+                label(config_ok),
+                label(blacklist_ok),
+                label(time_ok),
+                if backend_ok { "可达" } else { "不可达" }
+            ),
+        }
+    }
+
+    /// 卡片状态推送结果汇总：sent 为发送条数，Ok 内为后端拒绝条数，Err 为失败原因。
+    pub fn card_state_push(sent: usize, outcome: Result<usize, String>) -> Self {
+        if sent == 0 {
+            return Self {
+                success: true,
+                message: "无待推送卡片状态".to_string(),
+            };
+        }
+        match outcome {
+            Ok(rejected) => Self {
+                success: true,
+                message: format!("已推送 {} 条，接受 {} 条", sent, sent.saturating_sub(rejected)),
+            },
+            Err(err) => Self {
+                success: false,
+                message: format!("推送失败（{} 条待重试）: {}", sent, err),
+            },
+        }
+    }
+}
+
+/// `/trips` 未完成行程列表（卡号按脱敏设置展示，记录号用于精确清除）。
+pub fn trips_payload(state: &mut crate::state::GatewayState) -> serde_json::Value {
+    let now = state.now_secs();
+    let trips: Vec<serde_json::Value> = state
+        .active_trips
+        .list(now)
+        .into_iter()
+        .map(|trip| {
+            serde_json::json!({
+                "card_id": state.display_card_id(&trip.card_id),
+                "record_id": trip.record_id,
+                "route_id": trip.route_id,
+                "station_id": trip.station_id,
+                "station_name": trip.station_name,
+                "age_secs": trip.age_secs,
+            })
+        })
+        .collect();
+    serde_json::json!({ "count": trips.len(), "trips": trips })
+}
+
+/// /status 的 JSON 载荷（/api 描述接口据此生成字段说明）。
+pub fn status_payload(status: &StatusPanel) -> serde_json::Value {
+    let direction_label = match status.direction {
+        crate::model::Direction::Up => "上行",
+        crate::model::Direction::Down => "下行",
+    };
+    let tone_class = status.passenger_tone.css_class();
+    let tone_label = status.passenger_tone.label();
+    serde_json::json!({
+        "route_id": status.route_id,
+        "route_name": status.route_name,
+        "station_id": status.station_id,
+        "station_name": status.station_name,
+        "station_is_transfer": status.station_is_transfer,
+        "direction": direction_label,
+        "tap_mode_label": status.tap_mode_label,
+        "fare_type_label": status.fare_type_label,
+        "cache_count": status.cache_count,
+        "wifi_connected": status.wifi_connected,
+        "wifi_connect_attempts": status.wifi_connect_attempts,
+        "backend_reachable": status.backend_reachable,
+        "local_time": status.local_time,
+        "backend_base_url": status.backend_base_url,
+        "backend_error": status.backend_error.as_ref().map(|summary| serde_json::json!({
+            "summary": summary,
+            "age_secs": status.backend_error_age_secs,
+        })),
+        "last_successful_upload_age_secs": status.last_upload_age_secs,
+        "last_card_id": status.last_card_id,
+        "last_balance_cents": status.last_balance_cents,
+        "card_last_trip": status.last_card_trip.as_ref().map(|trip| serde_json::json!({
+            "route_id": trip.route_id,
+            "direction": trip.direction.map(|direction| direction.as_str()),
+            "board_station": trip.board_station,
+            "alight_station": trip.alight_station,
+        })),
+        "last_card_data_len": status.last_card_data_len,
+        "last_card_data_prefix_hex": status.last_card_data_prefix_hex,
+        "last_card_data_error": status.last_card_data_error,
+        "passenger": {
+            "tone_class": tone_class,
+            "tone_label": tone_label,
+            "message": status.passenger_message,
+        },
+        "fare": {
+            "standard": status.standard_fare_cents.map(cents_to_yuan),
+            "actual": status.last_fare_cents.map(cents_to_yuan),
+            "standard_cents": status.standard_fare_cents,
+            "actual_cents": status.last_fare_cents,
+            "label": status.last_fare_label,
+        },
+        "trip": {
+            "board_station": status.last_board_station,
+            "elapsed_secs": status.last_trip_elapsed_secs,
+        },
+        "recharge_active": status.recharge_active,
+        "recharge_amount_cents": status.recharge_amount_cents,
+        "next_fare_override_cents": status.next_fare_override_cents,
+        "stuck_trip_card": status.stuck_trip_card,
+        "stuck_trip_seq": status.stuck_trip_seq,
+        "force_clear_armed": status.force_clear_armed,
+        "register_active": status.register_active,
+        "pending_writes": status.pending_writes,
+        "upload_dropped": status.upload_dropped,
+        "upload_queue": {
+            "count": status.upload_queue_len,
+            "oldest_age_secs": status.upload_oldest_age_secs,
+        },
+        "write_unconfirmed": status.write_unconfirmed,
+        "idle": status.idle,
+        "reader": {
+            "firmware": status.reader_firmware,
+            "capabilities": status.reader_capabilities,
+            "offline": status.reader_offline,
+        },
+    })
+}
+
+/// 金额（分）换算为元（仅用于 JSON 展示字段）。
+fn cents_to_yuan(cents: u32) -> f64 {
+    cents as f64 / 100.0
+}
+
+/// /debug/state 的完整诊断快照：在 /status 之外附带设置、配置缓存与各缓存规模。
+/// 口令不导出；快照会随问题反馈外传，卡号一律脱敏，不受脱敏开关影响。
+pub fn debug_state_payload(
+    state: &crate::state::GatewayState,
+    status: &StatusPanel,
+) -> serde_json::Value {
+    let now = state.now_secs();
+    let mut status = status.clone();
+    status.last_card_id = display_card_id_with(&status.last_card_id, true);
+    status.stuck_trip_card = status.stuck_trip_card.as_deref().map(mask_card_id);
+    let mut settings = state.settings.clone();
+    settings.admin_pin = if settings.admin_pin.is_empty() {
+        String::new()
+    } else {
+        "***".to_string()
+    };
+    settings.staff_card_ids =
+        settings.staff_card_ids.iter().map(|id| mask_card_id(id)).collect();
+    let config = &state.config_cache;
+    serde_json::json!({
+        "firmware_version": env!("CARGO_PKG_VERSION"),
+        "now_ms": state.now_millis(),
+        "settings": settings,
+        "status": status_payload(&status),
+        "route_state": {
+            "route_id": state.route_state.route_id,
+            "station_id": state.route_state.station_id,
+            "station_name": state.route_state.station_name,
+            "direction": state.route_state.direction.as_str(),
+        },
+        "config": {
+            "synced": config.route.is_some(),
+            "route_id": config.route.as_ref().map(|cfg| cfg.route_id),
+            "station_count": config.route.as_ref().map_or(0, |cfg| cfg.stations.len()),
+            "fare_rule_count": config.route.as_ref().map_or(0, |cfg| cfg.fares.len()),
+            "fetched_at": config.fetched_at,
+            "ttl_secs": config.ttl_secs,
+            "is_expired": config.is_expired(now),
+        },
+        "blacklist": {
+            "count": state.blacklist_cache.cards.len(),
+            "local_count": state.blacklist_cache.local_cards.len(),
+            "fetched_at": state.blacklist_cache.fetched_at,
+        },
+        "caches": {
+            "tap_events": state.tap_cache.len(),
+            "active_trips": state.active_trips.len(),
+            "card_profiles": state.card_cache.len(),
+            "card_snapshots": state.card_state_cache.len(),
+        },
+        "errors": {
+            "backend": state
+                .last_backend_error
+                .as_ref()
+                .map(|err| format!("{}: {}", err.operation, err.message)),
+            "card_data": state.last_card_data_error,
+        },
+        "stuck_trip_card": state.stuck_trip_card.as_deref().map(mask_card_id),
+        "cleared_trip_cards": state
+            .cleared_trip_cards
+            .iter()
+            .map(|id| mask_card_id(id))
+            .collect::<Vec<_>>(),
+    })
+}
+
 /// 渲染司机网页（手工拼接 HTML，避免引入模板引擎）。
-pub fn render_index(status: &StatusPanel) -> String {
+pub fn render_index(status: &StatusPanel, view: PageView) -> String {
     let direction = match status.direction {
         crate::model::Direction::Up => "上行",
         crate::model::Direction::Down => "下行",
     };
     let tone_class = status.passenger_tone.css_class();
     let tone_label = status.passenger_tone.label();
-    let standard_fare = format_fare(status.standard_fare);
-    let actual_fare = format_fare(status.last_fare);
+    let standard_fare = format_cents(status.standard_fare_cents);
+    let actual_fare = format_cents(status.last_fare_cents);
     let balance_value = format_cents(status.last_balance_cents);
-    let recharge_amount = format_cents(status.recharge_amount_cents);
-    let backend_display = if status.backend_base_url.is_empty() {
-        "默认"
-    } else {
-        status.backend_base_url.as_str()
-    };
-    let backend_value = status.backend_base_url.as_str();
+    // 站名/线路名等可由外部写入，输出到页面前一律转义
     let route_name = if status.route_name.is_empty() {
-        "未同步"
+        "未同步".to_string()
     } else {
-        status.route_name.as_str()
+        escape_html(&status.route_name)
     };
 
     let mut html = String::new();
@@ -99,6 +412,7 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str(".tone-elder .badge{background:var(--elder);color:#422006;}");
     html.push_str(".tone-disabled .badge{background:var(--disabled);}");
     html.push_str(".tone-error .badge{background:var(--error);}");
+    html.push_str(".passenger.idle{background:#000;}.passenger.idle>*{visibility:hidden;}");
     html.push_str(".route{font-size:28px;font-weight:700;}"); 
     html.push_str(".station{font-size:38px;font-weight:700;}"); 
     html.push_str(".sub{color:var(--muted);font-size:14px;}");
@@ -118,15 +432,22 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str(".dot-ok{background:#22c55e}.dot-bad{background:#f97316}");
     html.push_str("@media (max-width:600px){.fare-grid{grid-template-columns:1fr;}.station{font-size:32px;}.route{font-size:22px;}}");
     html.push_str("</style>");
+    if view == PageView::Passenger {
+        // 乘客屏独占整屏
+        html.push_str("<style>.passenger{min-height:100vh;border-bottom:none;}</style>");
+    }
     html.push_str("</head><body>");
     html.push_str("<section id=\"passenger-screen\" class=\"screen passenger ");
     html.push_str(tone_class);
+    if status.idle {
+        html.push_str(" idle");
+    }
     html.push_str("\">");
     html.push_str("<div class=\"passenger-header\">");
     html.push_str("<div class=\"route\" id=\"route-line\">线路 ");
     html.push_str(&status.route_id.to_string());
     html.push_str(" · ");
-    html.push_str(route_name);
+    html.push_str(&route_name);
     html.push_str(" · ");
     html.push_str(direction);
     html.push_str("</div>");
@@ -135,12 +456,17 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("</div>");
     html.push_str("</div>");
     html.push_str("<div class=\"station\"><span id=\"station-name\">");
-    html.push_str(&status.station_name);
+    html.push_str(&escape_html(&status.station_name));
     html.push_str("</span> (#");
     html.push_str("<span id=\"station-id\">");
     html.push_str(&status.station_id.to_string());
     html.push_str("</span>");
     html.push_str(")</div>");
+    html.push_str("<div class=\"sub\" id=\"station-transfer\"");
+    if !status.station_is_transfer {
+        html.push_str(" hidden");
+    }
+    html.push_str(">换乘站，可换乘其他线路</div>");
     html.push_str("<div class=\"sub\">下一站由司机切换，屏幕将同步更新</div>");
     html.push_str("<div class=\"fare-grid\">");
     html.push_str("<div class=\"fare-card\">");
@@ -163,16 +489,103 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("</div>");
     html.push_str("<div class=\"sub\">卡号 <span id=\"last-card-id\">");
     if status.last_card_id.is_empty() {
-        html.push_str("—");
+        html.push('—');
     } else {
-        html.push_str(&status.last_card_id);
+        html.push_str(&escape_html(&status.last_card_id));
     }
     html.push_str("</span></div>");
+    html.push_str("<div class=\"sub\">上次行程 <span id=\"last-trip\">");
+    html.push_str(&escape_html(&format_last_trip(status.last_card_trip.as_ref())));
+    html.push_str("</span></div>");
     html.push_str("<div class=\"message\" id=\"passenger-message\">");
-    html.push_str(&status.passenger_message);
+    html.push_str(&escape_html(&status.passenger_message));
     html.push_str("</div>");
     html.push_str("</section>");
 
+    if view == PageView::Full {
+        push_driver_section(&mut html, status, direction, &route_name);
+    }
+    html.push_str("<script>");
+    html.push_str("const toneClasses=['tone-normal','tone-student','tone-elder','tone-disabled','tone-error'];");
+    html.push_str("const el=(id)=>document.getElementById(id);");
+    html.push_str("function formatCents(v){if(v===null||v===undefined)return '—';const c=Number(v);return '¥'+Math.floor(c/100)+'.'+String(c%100).padStart(2,'0');}");
+    html.push_str("function formatAge(v){if(v<60)return v+'秒前';if(v<3600)return Math.floor(v/60)+'分钟前';return Math.floor(v/3600)+'小时前';}");
+    html.push_str("function formatQueue(q){if(!q||!q.count)return '0 条';");
+    html.push_str("return q.count+' 条, 最早 '+Math.floor((q.oldest_age_secs||0)/60)+' 分钟前';}");
+    html.push_str("function formatLastTrip(t){if(!t)return '—';const r=t.route_id===null?'':'线路 '+t.route_id+' · ';return r+(t.board_station||'?')+' → '+(t.alight_station||'?');}");
+    html.push_str("function applyStatus(s){");
+    html.push_str("const routeName=s.route_name||'未同步';");
+    html.push_str("el('route-line').textContent=`线路 ${s.route_id} · ${routeName} · ${s.direction}`;");
+    html.push_str("el('station-name').textContent=s.station_name;");
+    html.push_str("el('station-id').textContent=s.station_id;");
+    html.push_str("el('station-transfer').hidden=!s.station_is_transfer;");
+    html.push_str("el('passenger-tone-label').textContent=s.passenger.tone_label;");
+    html.push_str("el('passenger-message').textContent=s.passenger.message;");
+    html.push_str("el('fare-standard').textContent=formatCents(s.fare.standard_cents);");
+    html.push_str("el('fare-actual').textContent=formatCents(s.fare.actual_cents);");
+    html.push_str("el('fare-label').textContent=s.fare.label;");
+    html.push_str("el('last-card-id').textContent=s.last_card_id||'—';");
+    html.push_str("el('last-balance').textContent=formatCents(s.last_balance_cents);");
+    html.push_str("el('last-trip').textContent=formatLastTrip(s.card_last_trip);");
+    if view == PageView::Full {
+        html.push_str("el('driver-route-id').textContent=s.route_id;");
+        html.push_str("el('driver-route-name').textContent=routeName;");
+        html.push_str("el('driver-station-name').textContent=s.station_name;");
+        html.push_str("el('driver-station-id').textContent=s.station_id;");
+        html.push_str("el('driver-direction').textContent=s.direction;");
+        html.push_str("el('driver-tap-mode').textContent=s.tap_mode_label;");
+        html.push_str("el('driver-fare-type').textContent=s.fare_type_label;");
+        html.push_str("el('driver-cache-count').textContent=s.cache_count;");
+        html.push_str("el('wifi-text').textContent=s.wifi_connected?'已连接':'未连接';");
+        html.push_str("el('wifi-dot').className='status-dot '+(s.wifi_connected?'dot-ok':'dot-bad');");
+        html.push_str("el('backend-text').textContent=s.backend_reachable?'可达':'不可达';");
+        html.push_str("el('backend-dot').className='status-dot '+(s.backend_reachable?'dot-ok':'dot-bad');");
+        html.push_str("el('backend-address').textContent=s.backend_base_url||'默认';");
+        html.push_str("el('local-time').textContent=s.local_time||'未校时';");
+        html.push_str("el('backend-error').textContent=s.backend_error?s.backend_error.summary:'无';");
+        html.push_str("el('backend-error-age').textContent=s.backend_error?formatAge(s.backend_error.age_secs):'';");
+        html.push_str("el('recharge-status').textContent=s.recharge_active?'进行中':'未开启';");
+        html.push_str("el('recharge-amount').textContent=formatCents(s.recharge_amount_cents);");
+        html.push_str("el('next-fare').textContent=formatCents(s.next_fare_override_cents);");
+        html.push_str("el('stuck-trip').style.display=s.stuck_trip_card?'':'none';");
+        html.push_str("el('stuck-card').textContent=s.stuck_trip_card||'';");
+        html.push_str("el('stuck-card').dataset.seq=s.stuck_trip_seq;");
+        html.push_str("el('force-clear-btn').textContent=s.force_clear_armed?'已确认，请再刷卡':'强制清除行程';");
+        html.push_str("el('register-status').textContent=s.register_active?'进行中':'未开启';");
+        html.push_str("el('write-text').textContent=s.write_unconfirmed?'写卡未确认':'正常';");
+        html.push_str("el('write-dot').className='status-dot '+(s.write_unconfirmed?'dot-bad':'dot-ok');");
+        html.push_str("el('write-pending').textContent=s.pending_writes;");
+        html.push_str("el('upload-queue').textContent=formatQueue(s.upload_queue);");
+        html.push_str("const input=document.activeElement;const backendInput=el('backend-input');");
+        html.push_str("if(input!==backendInput){backendInput.value=s.backend_base_url||'';}");
+    }
+    html.push_str("const screen=el('passenger-screen');toneClasses.forEach(c=>screen.classList.remove(c));");
+    html.push_str("screen.classList.add(s.passenger.tone_class);");
+    html.push_str("screen.classList.toggle('idle',!!s.idle);");
+    html.push('}');
+    if view == PageView::Full {
+        html.push_str("function forceClearTrip(){const id=el('stuck-card').textContent;if(!id)return;");
+        html.push_str("if(confirm('确认强制清除卡 '+id+' 的行程？清除后不再收取本次行程费用。')){");
+        html.push_str("location.href='/action?type=force_clear_trip&card_id='+encodeURIComponent(id)");
+        html.push_str("+'&seq='+el('stuck-card').dataset.seq;}}");
+    }
+    html.push_str("async function refresh(){try{const r=await fetch('/status',{cache:'no-store'});");
+    html.push_str("if(!r.ok)return;const s=await r.json();applyStatus(s);}catch(e){}}");
+    html.push_str("refresh();setInterval(refresh,1000);");
+    html.push_str("</script>");
+    html.push_str("</body></html>");
+    html
+}
+
+/// 司机控制面板（状态卡片与操作表单），乘客屏模式不渲染。
+fn push_driver_section(html: &mut String, status: &StatusPanel, direction: &str, route_name: &str) {
+    let backend_value = escape_html(&status.backend_base_url);
+    let backend_display = if backend_value.is_empty() {
+        "默认"
+    } else {
+        backend_value.as_str()
+    };
+    let recharge_amount = format_cents(status.recharge_amount_cents);
     html.push_str("<section class=\"driver\">");
     html.push_str("<h2>司机控制面板</h2>");
     html.push_str("<div class=\"driver-grid\">");
@@ -184,7 +597,7 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("</span>");
     html.push_str("</div></div>");
     html.push_str("<div class=\"driver-card\"><div class=\"sub\">当前站点</div><div class=\"route\" id=\"driver-station-name\">");
-    html.push_str(&status.station_name);
+    html.push_str(&escape_html(&status.station_name));
     html.push_str("</div><div class=\"sub\">#");
     html.push_str("<span id=\"driver-station-id\">");
     html.push_str(&status.station_id.to_string());
@@ -202,6 +615,9 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("<div class=\"driver-card\"><div class=\"sub\">缓存条目</div><div class=\"route\" id=\"driver-cache-count\">");
     html.push_str(&status.cache_count.to_string());
     html.push_str("</div></div>");
+    html.push_str("<div class=\"driver-card\"><div class=\"sub\">待上传</div><div id=\"upload-queue\">");
+    html.push_str(&format_upload_queue(status.upload_queue_len, status.upload_oldest_age_secs));
+    html.push_str("</div></div>");
     html.push_str("<div class=\"driver-card\"><div class=\"sub\">Wi-Fi</div><div>");
     html.push_str("<span id=\"wifi-dot\" class=\"status-dot ");
     html.push_str(if status.wifi_connected { "dot-ok" } else { "dot-bad" });
@@ -216,6 +632,14 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str(if status.backend_reachable { "可达" } else { "不可达" });
     html.push_str("</span>");
     html.push_str("</div></div>");
+    html.push_str("<div class=\"driver-card\"><div class=\"sub\">最近后端错误</div><div id=\"backend-error\">");
+    html.push_str(&escape_html(status.backend_error.as_deref().unwrap_or("无")));
+    html.push_str("</div><div class=\"sub\" id=\"backend-error-age\">");
+    html.push_str(&format_age(status.backend_error_age_secs));
+    html.push_str("</div></div>");
+    html.push_str("<div class=\"driver-card\"><div class=\"sub\">本地时间</div><div class=\"route\" id=\"local-time\">");
+    html.push_str(status.local_time.as_deref().unwrap_or("未校时"));
+    html.push_str("</div></div>");
     html.push_str("<div class=\"driver-card\"><div class=\"sub\">后端地址</div><div>");
     html.push_str("<span id=\"backend-address\">");
     html.push_str(backend_display);
@@ -225,10 +649,31 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str(if status.recharge_active { "进行中" } else { "未开启" });
     html.push_str("</div><div class=\"sub\">金额 <span id=\"recharge-amount\">");
     html.push_str(&recharge_amount);
-    html.push_str("</span></div></div>");
+    html.push_str("</span></div>");
+    // 充值因“卡状态异常（行程中）”被拒时，提供强制清除行程入口
+    let stuck_card = status.stuck_trip_card.as_deref().unwrap_or("");
+    html.push_str("<div id=\"stuck-trip\" class=\"sub\"");
+    if stuck_card.is_empty() {
+        html.push_str(" style=\"display:none\"");
+    }
+    html.push_str(">行程中卡 <span id=\"stuck-card\" data-seq=\"");
+    html.push_str(&status.stuck_trip_seq.to_string());
+    html.push_str("\">");
+    html.push_str(stuck_card);
+    html.push_str("</span> <button id=\"force-clear-btn\" onclick=\"forceClearTrip()\">");
+    html.push_str(if status.force_clear_armed { "已确认，请再刷卡" } else { "强制清除行程" });
+    html.push_str("</button></div></div>");
     html.push_str("<div class=\"driver-card\"><div class=\"sub\">注册模式</div><div class=\"route\" id=\"register-status\">");
     html.push_str(if status.register_active { "进行中" } else { "未开启" });
     html.push_str("</div></div>");
+    html.push_str("<div class=\"driver-card\"><div class=\"sub\">写卡确认</div><div>");
+    html.push_str("<span id=\"write-dot\" class=\"status-dot ");
+    html.push_str(if status.write_unconfirmed { "dot-bad" } else { "dot-ok" });
+    html.push_str("\"></span><span id=\"write-text\">");
+    html.push_str(if status.write_unconfirmed { "写卡未确认" } else { "正常" });
+    html.push_str("</span></div><div class=\"sub\">待确认 <span id=\"write-pending\">");
+    html.push_str(&status.pending_writes.to_string());
+    html.push_str("</span></div></div>");
     html.push_str("</div>");
 
     html.push_str("<div class=\"driver-grid\">");
@@ -238,6 +683,9 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("<button onclick=\"location.href='/action?type=dir_down'\">下行</button>");
     html.push_str("<button class=\"primary\" onclick=\"location.href='/action?type=sync'\">同步配置</button>");
     html.push_str("<button onclick=\"location.href='/action?type=upload'\">立即上报</button>");
+    html.push_str("<button onclick=\"location.href='/action?type=full_sync'\">全量同步</button>");
+    html.push_str("<button onclick=\"location.href='/action?type=clear_card_cache'\">清除卡片缓存</button>");
+    html.push_str("<button onclick=\"location.href='/action?type=push_card_states'\">推送卡片状态</button>");
     html.push_str("</div>");
 
     html.push_str("<form action=\"/action\" method=\"get\">");
@@ -251,9 +699,14 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("<button type=\"submit\">切换站点</button>");
     html.push_str("</form>");
     html.push_str("<form action=\"/action\" method=\"get\">");
+    html.push_str("<input type=\"hidden\" name=\"type\" value=\"station_name\">");
+    html.push_str("<input name=\"name\" type=\"text\" maxlength=\"32\" placeholder=\"临时站名\">");
+    html.push_str("<button type=\"submit\">修改当前站名</button>");
+    html.push_str("</form>");
+    html.push_str("<form action=\"/action\" method=\"get\">");
     html.push_str("<input type=\"hidden\" name=\"type\" value=\"set_backend\">");
     html.push_str("<input id=\"backend-input\" name=\"backend\" type=\"text\" placeholder=\"后端地址，如 172.20.1.5:80\" value=\"");
-    html.push_str(backend_value);
+    html.push_str(&backend_value);
     html.push_str("\">");
     html.push_str("<button type=\"submit\">更新后端</button>");
     html.push_str("</form>");
@@ -267,6 +720,14 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("<button type=\"submit\">取消充值模式</button>");
     html.push_str("</form>");
     html.push_str("<form action=\"/action\" method=\"get\">");
+    html.push_str("<input type=\"hidden\" name=\"type\" value=\"next_fare\">");
+    html.push_str("<input name=\"amount\" type=\"number\" step=\"0.01\" min=\"0\" placeholder=\"下一次票价(元)\">");
+    html.push_str("<button type=\"submit\">设置手动票价</button>");
+    html.push_str("<span class=\"sub\">待用 <span id=\"next-fare\">");
+    html.push_str(&format_cents(status.next_fare_override_cents));
+    html.push_str("</span></span>");
+    html.push_str("</form>");
+    html.push_str("<form action=\"/action\" method=\"get\">");
     html.push_str("<input type=\"hidden\" name=\"type\" value=\"register_on\">");
     html.push_str("<button type=\"submit\">进入注册模式</button>");
     html.push_str("</form>");
@@ -275,50 +736,77 @@ pub fn render_index(status: &StatusPanel) -> String {
     html.push_str("<button type=\"submit\">取消注册模式</button>");
     html.push_str("</form>");
     html.push_str("</section>");
-    html.push_str("<script>");
-    html.push_str("const toneClasses=['tone-normal','tone-student','tone-elder','tone-disabled','tone-error'];");
-    html.push_str("const el=(id)=>document.getElementById(id);");
-    html.push_str("function formatFare(v){if(v===null||v===undefined)return '—';return '¥'+Number(v).toFixed(2);}");
-    html.push_str("function formatCents(v){if(v===null||v===undefined)return '—';return '¥'+(Number(v)/100).toFixed(2);}");
-    html.push_str("function applyStatus(s){");
-    html.push_str("const routeName=s.route_name||'未同步';");
-    html.push_str("el('route-line').textContent=`线路 ${s.route_id} · ${routeName} · ${s.direction}`;");
-    html.push_str("el('station-name').textContent=s.station_name;");
-    html.push_str("el('station-id').textContent=s.station_id;");
-    html.push_str("el('passenger-tone-label').textContent=s.passenger.tone_label;");
-    html.push_str("el('passenger-message').textContent=s.passenger.message;");
-    html.push_str("el('fare-standard').textContent=formatFare(s.fare.standard);");
-    html.push_str("el('fare-actual').textContent=formatFare(s.fare.actual);");
-    html.push_str("el('fare-label').textContent=s.fare.label;");
-    html.push_str("el('last-card-id').textContent=s.last_card_id||'—';");
-    html.push_str("el('last-balance').textContent=formatCents(s.last_balance_cents);");
-    html.push_str("el('driver-route-id').textContent=s.route_id;");
-    html.push_str("el('driver-route-name').textContent=routeName;");
-    html.push_str("el('driver-station-name').textContent=s.station_name;");
-    html.push_str("el('driver-station-id').textContent=s.station_id;");
-    html.push_str("el('driver-direction').textContent=s.direction;");
-    html.push_str("el('driver-tap-mode').textContent=s.tap_mode_label;");
-    html.push_str("el('driver-fare-type').textContent=s.fare_type_label;");
-    html.push_str("el('driver-cache-count').textContent=s.cache_count;");
-    html.push_str("el('wifi-text').textContent=s.wifi_connected?'已连接':'未连接';");
-    html.push_str("el('wifi-dot').className='status-dot '+(s.wifi_connected?'dot-ok':'dot-bad');");
-    html.push_str("el('backend-text').textContent=s.backend_reachable?'可达':'不可达';");
-    html.push_str("el('backend-dot').className='status-dot '+(s.backend_reachable?'dot-ok':'dot-bad');");
-    html.push_str("el('backend-address').textContent=s.backend_base_url||'默认';");
-    html.push_str("el('recharge-status').textContent=s.recharge_active?'进行中':'未开启';");
-    html.push_str("el('recharge-amount').textContent=formatCents(s.recharge_amount_cents);");
-    html.push_str("el('register-status').textContent=s.register_active?'进行中':'未开启';");
-    html.push_str("const input=document.activeElement;const backendInput=el('backend-input');");
-    html.push_str("if(input!==backendInput){backendInput.value=s.backend_base_url||'';}");
-    html.push_str("const screen=el('passenger-screen');toneClasses.forEach(c=>screen.classList.remove(c));");
-    html.push_str("screen.classList.add(s.passenger.tone_class);");
-    html.push_str("}");
-    html.push_str("async function refresh(){try{const r=await fetch('/status',{cache:'no-store'});");
-    html.push_str("if(!r.ok)return;const s=await r.json();applyStatus(s);}catch(e){}}");
-    html.push_str("refresh();setInterval(refresh,1000);");
-    html.push_str("</script>");
-    html.push_str("</body></html>");
-    html
+}
+
+/// /action 支持的动作类型说明（/api 描述接口输出）。
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ActionSpec {
+    #[serde(rename = "type")]
+    pub action_type: &'static str,
+    pub params: &'static [&'static str],
+    pub description: &'static str,
+}
+
+// 司机临时站名的最大字符数。
+const STATION_NAME_MAX_CHARS: usize = 32;
+
+/// parse_action 可识别的全部动作，新增动作时须同步补充。
+pub const ACTION_SPECS: &[ActionSpec] = &[
+    ActionSpec { action_type: "next", params: &[], description: "下一站" },
+    ActionSpec { action_type: "prev", params: &[], description: "上一站" },
+    ActionSpec { action_type: "dir_up", params: &[], description: "切换为上行" },
+    ActionSpec { action_type: "dir_down", params: &[], description: "切换为下行" },
+    ActionSpec { action_type: "sync", params: &[], description: "同步线路配置" },
+    ActionSpec { action_type: "full_sync", params: &[], description: "全量重新同步" },
+    ActionSpec { action_type: "upload", params: &[], description: "立即上传刷卡记录" },
+    ActionSpec { action_type: "set_route", params: &["route_id"], description: "切换线路" },
+    ActionSpec { action_type: "set_station", params: &["station_id"], description: "切换站点" },
+    ActionSpec {
+        action_type: "station_name",
+        params: &["name"],
+        description: "临时修改当前站名（同步配置后恢复）",
+    },
+    ActionSpec { action_type: "set_backend", params: &["backend"], description: "更新后端地址" },
+    ActionSpec { action_type: "recharge", params: &["amount"], description: "进入充值模式（元）" },
+    ActionSpec { action_type: "recharge_off", params: &[], description: "取消充值模式" },
+    ActionSpec { action_type: "clear_card_cache", params: &[], description: "清空卡片缓存" },
+    ActionSpec { action_type: "push_card_states", params: &[], description: "推送卡片状态" },
+    ActionSpec {
+        action_type: "next_fare",
+        params: &["amount"],
+        description: "下一次手动票价（元）",
+    },
+    ActionSpec {
+        action_type: "force_clear_trip",
+        params: &["card_id", "seq"],
+        description: "强制清除未完成行程",
+    },
+    ActionSpec {
+        action_type: "clear_trip",
+        params: &["record_id"],
+        description: "清除卡住的行程（也可按 card_id；下次刷卡复位卡状态）",
+    },
+    ActionSpec { action_type: "register_on", params: &[], description: "进入注册模式" },
+    ActionSpec { action_type: "register_off", params: &[], description: "取消注册模式" },
+    ActionSpec { action_type: "maintenance_on", params: &["pin"], description: "进入维护模式" },
+    ActionSpec { action_type: "maintenance_off", params: &["pin"], description: "退出维护模式" },
+    ActionSpec {
+        action_type: "set_card_balance",
+        params: &["amount", "pin"],
+        description: "维护：下一张卡写入指定余额",
+    },
+];
+
+/// parse_action 解析失败的原因：缺少类型、未知类型，或参数缺失/无效。
+pub fn action_parse_error(query: &str) -> &'static str {
+    match query_value(query, "type") {
+        None => "缺少操作类型",
+        Some(action_type) if action_type.is_empty() => "缺少操作类型",
+        Some(action_type) if ACTION_SPECS.iter().any(|spec| spec.action_type == action_type) => {
+            "操作参数缺失或无效"
+        }
+        Some(_) => "未知操作",
+    }
 }
 
 /// 解析 URL 查询字符串为 DriverAction。
@@ -334,6 +822,7 @@ pub fn parse_action(query: &str) -> Option<DriverAction> {
             direction: crate::model::Direction::Down,
         }),
         "sync" => Some(DriverAction::SyncConfig),
+        "full_sync" => Some(DriverAction::FullResync),
         "upload" => Some(DriverAction::UploadNow),
         "set_route" => {
             let route_id = query_value(query, "route_id")?.parse().ok()?;
@@ -343,6 +832,14 @@ pub fn parse_action(query: &str) -> Option<DriverAction> {
             let station_id = query_value(query, "station_id")?.parse().ok()?;
             Some(DriverAction::SetStation { station_id })
         }
+        "station_name" => {
+            let name = query_value(query, "name")?.trim().to_string();
+            if name.is_empty() || name.chars().count() > STATION_NAME_MAX_CHARS {
+                None
+            } else {
+                Some(DriverAction::SetStationName { name })
+            }
+        }
         "set_backend" => {
             let base_url = query_value(query, "backend")?;
             if base_url.is_empty() {
@@ -357,14 +854,53 @@ pub fn parse_action(query: &str) -> Option<DriverAction> {
             Some(DriverAction::StartRecharge { amount_cents })
         }
         "recharge_off" => Some(DriverAction::CancelRecharge),
+        "clear_card_cache" => Some(DriverAction::ClearCardCache),
+        "push_card_states" => Some(DriverAction::PushCardStates),
+        "next_fare" => {
+            let amount = query_value(query, "amount")?;
+            let cents = parse_amount_cents(&amount)?;
+            Some(DriverAction::SetNextFare { cents })
+        }
+        "force_clear_trip" => {
+            let card_id = query_value(query, "card_id")?;
+            if card_id.is_empty() {
+                None
+            } else {
+                let seq = query_value(query, "seq").and_then(|seq| seq.parse().ok());
+                Some(DriverAction::ForceClearTrip { card_id, seq })
+            }
+        }
+        "clear_trip" => {
+            // 优先按记录号定位，兼容按卡号清除
+            let trip = query_value(query, "record_id")
+                .filter(|id| !id.is_empty())
+                .or_else(|| query_value(query, "card_id"))?;
+            if trip.is_empty() {
+                None
+            } else {
+                Some(DriverAction::ClearTrip { trip })
+            }
+        }
         "register_on" => Some(DriverAction::StartRegister),
         "register_off" => Some(DriverAction::CancelRegister),
+        "maintenance_on" => Some(DriverAction::StartMaintenance),
+        "maintenance_off" => Some(DriverAction::CancelMaintenance),
+        "set_card_balance" => {
+            // 允许写入 0 余额（清空损坏卡）
+            let amount = query_value(query, "amount")?;
+            let cents = if amount.trim().parse::<f64>() == Ok(0.0) {
+                0
+            } else {
+                parse_amount_cents(&amount)?
+            };
+            Some(DriverAction::SetNextCardBalance { cents })
+        }
         _ => None,
     }
 }
 
 /// 获取查询参数值（未进行 URL 解码）。
-fn query_value(query: &str, key: &str) -> Option<String> {
+pub fn query_value(query: &str, key: &str) -> Option<String> {
     for part in query.split('&') {
         let mut iter = part.splitn(2, '=');
         let k = iter.next()?;
@@ -376,35 +912,35 @@ fn query_value(query: &str, key: &str) -> Option<String> {
     None
 }
 
-/// URL 解码（处理 %xx 与 +）。
+/// URL 解码（处理 %xx 与 +），按 UTF-8 还原中文等多字节字符。
 fn decode_component(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
+    let mut out = Vec::with_capacity(input.len());
     let bytes = input.as_bytes();
     let mut i = 0;
     while i < bytes.len() {
         match bytes[i] {
             b'+' => {
-                out.push(' ');
+                out.push(b' ');
                 i += 1;
             }
             b'%' if i + 2 < bytes.len() => {
                 let hi = hex_value(bytes[i + 1]);
                 let lo = hex_value(bytes[i + 2]);
                 if let (Some(hi), Some(lo)) = (hi, lo) {
-                    out.push((hi << 4 | lo) as char);
+                    out.push(hi << 4 | lo);
                     i += 3;
                 } else {
-                    out.push(bytes[i] as char);
+                    out.push(bytes[i]);
                     i += 1;
                 }
             }
             _ => {
-                out.push(bytes[i] as char);
+                out.push(bytes[i]);
                 i += 1;
             }
         }
     }
-    out
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 /// 十六进制字符转数值。
@@ -417,22 +953,65 @@ fn hex_value(byte: u8) -> Option<u8> {
     }
 }
 
-/// 票价格式化为人民币显示。
-fn format_fare(fare: Option<f32>) -> String {
-    match fare {
-        Some(amount) => format!("¥{:.2}", amount),
-        None => "—".to_string(),
+/// 转义 HTML 特殊字符（文本与双引号属性值均可安全输出）。
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
     }
+    out
 }
 
 /// 金额（分）格式化为人民币。
 fn format_cents(amount_cents: Option<u32>) -> String {
     match amount_cents {
-        Some(amount) => format!("¥{:.2}", amount as f64 / 100.0),
+        Some(amount) => format!("¥{}.{:02}", amount / 100, amount % 100),
         None => "—".to_string(),
     }
 }
 
+/// 待上传队列格式化（与前端 formatQueue 一致）。
+fn format_upload_queue(len: usize, oldest_age_secs: Option<u64>) -> String {
+    if len == 0 {
+        return "0 条".to_string();
+    }
+    format!("{} 条, 最早 {} 分钟前", len, oldest_age_secs.unwrap_or(0) / 60)
+}
+
+/// 卡内上次行程格式化（与前端 formatLastTrip 一致）。
+fn format_last_trip(trip: Option<&crate::state::CardLastTrip>) -> String {
+    let Some(trip) = trip else {
+        return "—".to_string();
+    };
+    let route = trip
+        .route_id
+        .map(|id| format!("线路 {} · ", id))
+        .unwrap_or_default();
+    format!(
+        "{}{} → {}",
+        route,
+        trip.board_station.as_deref().unwrap_or("?"),
+        trip.alight_station.as_deref().unwrap_or("?")
+    )
+}
+
+/// 距今时长格式化（与前端 formatAge 一致）。
+fn format_age(age_secs: Option<u64>) -> String {
+    match age_secs {
+        Some(secs) if secs < 60 => format!("{}秒前", secs),
+        Some(secs) if secs < 3600 => format!("{}分钟前", secs / 60),
+        Some(secs) => format!("{}小时前", secs / 3600),
+        None => String::new(),
+    }
+}
+
 /// 解析充值金额（元）为分。
 fn parse_amount_cents(input: &str) -> Option<u32> {
     let value: f64 = input.trim().parse().ok()?;
@@ -445,3 +1024,519 @@ fn parse_amount_cents(input: &str) -> Option<u32> {
     }
     Some(cents as u32)
 }
+
+/// 规范化后端地址（自动补齐协议/去尾斜杠，`主机 端口` 视为 `主机:端口`）。
+/// 主机或端口无法解析（如 `172.20.1.5:80:`、仅有端口）时返回 None。
+pub fn normalize_backend_url(input: String) -> Option<String> {
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        return Some(String::new());
+    }
+    let (scheme, rest) = if let Some(rest) = trimmed.strip_prefix("https://") {
+        ("https", rest)
+    } else {
+        ("http", trimmed.strip_prefix("http://").unwrap_or(trimmed))
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, ""),
+    };
+    let parts: Vec<&str> = authority.split_whitespace().collect();
+    let (host, port) = match parts.as_slice() {
+        [host, port] => (*host, Some(*port)),
+        [authority] => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (*authority, None),
+        },
+        _ => return None,
+    };
+    // 纯数字视为误填的端口
+    let host_valid = !host.is_empty()
+        && !host.chars().all(|c| c.is_ascii_digit())
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+    if !host_valid || path.chars().any(char::is_whitespace) {
+        return None;
+    }
+    let mut url = format!("{}://{}", scheme, host);
+    if let Some(port) = port {
+        let port: u16 = port.parse().ok().filter(|port| *port > 0)?;
+        url.push_str(&format!(":{}", port));
+    }
+    url.push_str(path.trim_end_matches('/'));
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::model::{Direction, GatewaySettings, PassengerTone, TapEvent, TapType};
+    use crate::state::GatewayState;
+
+    #[test]
+    fn full_sync_action_parses() {
+        assert!(matches!(
+            parse_action("type=full_sync"),
+            Some(DriverAction::FullResync)
+        ));
+    }
+
+    #[test]
+    fn full_resync_summary_reports_each_step() {
+        let ok = ActionResult::full_resync(true, true, true);
+        assert!(ok.success);
+        assert_eq!(ok.message, "配置成功 黑名单成功 校时成功 后端可达");
+        let partial = ActionResult::full_resync(false, true, false);
+        assert!(!partial.success);
+        assert_eq!(partial.message, "配置失败 黑名单成功 校时失败 后端可达");
+        let offline = ActionResult::full_resync(false, false, true);
+        assert_eq!(offline.message, "配置失败 黑名单失败 校时成功 后端不可达");
+    }
+
+    #[test]
+    fn force_clear_action_carries_seq() {
+        assert!(matches!(
+            parse_action("type=force_clear_trip&card_id=****C3D4&seq=3"),
+            Some(DriverAction::ForceClearTrip { card_id, seq: Some(3) }) if card_id == "****C3D4"
+        ));
+        assert!(matches!(
+            parse_action("type=force_clear_trip&card_id=A1B2C3D4"),
+            Some(DriverAction::ForceClearTrip { seq: None, .. })
+        ));
+        assert!(parse_action("type=force_clear_trip&card_id=").is_none());
+    }
+
+    #[test]
+    fn error_age_is_humanized() {
+        assert_eq!(format_age(None), "");
+        assert_eq!(format_age(Some(59)), "59秒前");
+        assert_eq!(format_age(Some(60)), "1分钟前");
+        assert_eq!(format_age(Some(7200)), "2小时前");
+    }
+
+    #[test]
+    fn cents_format_exactly() {
+        assert_eq!(format_cents(Some(7)), "¥0.07");
+        assert_eq!(format_cents(Some(115)), "¥1.15");
+        assert_eq!(format_cents(None), "—");
+    }
+
+    fn sample_status() -> StatusPanel {
+        StatusPanel {
+            route_id: 7,
+            route_name: "7路".to_string(),
+            station_id: 2,
+            station_name: "二站".to_string(),
+            station_is_transfer: false,
+            direction: Direction::Up,
+            tap_mode_label: "单次刷卡".to_string(),
+            fare_type_label: "统一票价".to_string(),
+            cache_count: 0,
+            wifi_connected: true,
+            wifi_connect_attempts: 1,
+            backend_reachable: true,
+            local_time: Some("08:30".to_string()),
+            backend_base_url: String::new(),
+            backend_error: None,
+            backend_error_age_secs: None,
+            last_upload_age_secs: None,
+            passenger_tone: PassengerTone::Normal,
+            passenger_message: "等待刷卡".to_string(),
+            standard_fare_cents: Some(200),
+            last_fare_cents: None,
+            last_fare_label: "应付".to_string(),
+            last_board_station: None,
+            last_trip_elapsed_secs: None,
+            next_fare_override_cents: None,
+            recharge_active: false,
+            recharge_amount_cents: None,
+            stuck_trip_card: None,
+            stuck_trip_seq: 0,
+            force_clear_armed: false,
+            register_active: false,
+            last_card_id: String::new(),
+            last_balance_cents: None,
+            last_card_trip: None,
+            last_card_data_len: 0,
+            last_card_data_prefix_hex: None,
+            last_card_data_error: None,
+            pending_writes: 0,
+            upload_dropped: 0,
+            upload_queue_len: 0,
+            upload_oldest_age_secs: None,
+            write_unconfirmed: false,
+            reader_firmware: None,
+            reader_capabilities: 0,
+            reader_offline: false,
+            idle: false,
+        }
+    }
+
+    #[test]
+    fn page_view_from_query() {
+        assert_eq!(PageView::from_query("view=passenger"), PageView::Passenger);
+        assert_eq!(PageView::from_query("view=driver"), PageView::Full);
+        assert_eq!(PageView::from_query(""), PageView::Full);
+    }
+
+    #[test]
+    fn passenger_view_has_no_driver_controls() {
+        let status = sample_status();
+        let full = render_index(&status, PageView::Full);
+        let passenger = render_index(&status, PageView::Passenger);
+        assert!(full.contains("<div class=\"driver-card\">"));
+        assert!(full.contains("/action?type="));
+        assert!(!passenger.contains("<div class=\"driver-card\">"));
+        assert!(!passenger.contains("/action?type="));
+        assert!(!passenger.contains("<button"));
+        assert!(passenger.contains("二站"));
+        assert!(passenger.contains("等待刷卡"));
+    }
+
+    #[test]
+    fn idle_status_blanks_passenger_screen() {
+        let mut status = sample_status();
+        let class = format!(
+            "screen passenger {} idle\"",
+            status.passenger_tone.css_class()
+        );
+        assert!(!render_index(&status, PageView::Passenger).contains(&class));
+        status.idle = true;
+        assert!(render_index(&status, PageView::Passenger).contains(&class));
+    }
+
+    #[test]
+    fn card_state_push_reports_counts() {
+        let empty = ActionResult::card_state_push(0, Ok(0));
+        assert!(empty.success);
+        assert_eq!(empty.message, "无待推送卡片状态");
+        let pushed = ActionResult::card_state_push(5, Ok(2));
+        assert!(pushed.success);
+        assert_eq!(pushed.message, "已推送 5 条，接受 3 条");
+        let failed = ActionResult::card_state_push(5, Err("timeout".to_string()));
+        assert!(!failed.success);
+        assert_eq!(failed.message, "推送失败（5 条待重试）: timeout");
+    }
+
+    /// 为描述中的参数填入示例值。
+    fn sample_query(spec: &ActionSpec) -> String {
+        let mut query = format!("type={}", spec.action_type);
+        for param in spec.params {
+            let value = match *param {
+                "amount" => "1.50",
+                "backend" => "172.20.1.5:80",
+                "card_id" => "A1B2C3D4",
+                "name" => "临时站",
+                "pin" => "1234",
+                "record_id" => "gw-test-1704067200-1",
+                _ => "1",
+            };
+            query.push_str(&format!("&{}={}", param, value));
+        }
+        query
+    }
+
+    #[test]
+    fn every_described_action_parses() {
+        for spec in ACTION_SPECS {
+            let query = sample_query(spec);
+            assert!(parse_action(&query).is_some(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn every_parsed_action_is_described() {
+        let handled = [
+            "next",
+            "prev",
+            "dir_up",
+            "dir_down",
+            "sync",
+            "full_sync",
+            "upload",
+            "set_route",
+            "set_station",
+            "station_name",
+            "set_backend",
+            "recharge",
+            "recharge_off",
+            "clear_card_cache",
+            "push_card_states",
+            "next_fare",
+            "force_clear_trip",
+            "clear_trip",
+            "register_on",
+            "register_off",
+            "maintenance_on",
+            "maintenance_off",
+            "set_card_balance",
+        ];
+        for action_type in handled {
+            assert!(
+                ACTION_SPECS
+                    .iter()
+                    .any(|spec| spec.action_type == action_type),
+                "{}",
+                action_type
+            );
+        }
+        assert_eq!(ACTION_SPECS.len(), handled.len());
+        assert_eq!(action_parse_error("type=bogus"), "未知操作");
+        assert_eq!(action_parse_error("type=set_route"), "操作参数缺失或无效");
+    }
+
+    fn trip_state(card_ids: &[&str]) -> GatewayState {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.mask_card_ids = true;
+        let clock = std::sync::Arc::new(MockClock::new(1_704_067_200_000));
+        let mut state = GatewayState::bootstrap(settings).with_clock(clock.clone());
+        for (seq, card_id) in card_ids.iter().enumerate() {
+            let event = TapEvent::new(
+                format!("gw-test-1704067200-{}", seq),
+                card_id.to_string(),
+                7,
+                2,
+                "二站".to_string(),
+                TapType::TapIn,
+                1_704_067_200,
+                "gw-test".to_string(),
+            );
+            state.active_trips.insert(event, 1_704_067_200, None);
+        }
+        clock.advance(90_000);
+        state
+    }
+
+    #[test]
+    fn trips_payload_lists_masked_cards_with_record_ids() {
+        let mut state = trip_state(&["A1B2C3D4"]);
+        let payload = trips_payload(&mut state);
+        assert_eq!(payload["count"], 1);
+        let trip = &payload["trips"][0];
+        assert_eq!(trip["card_id"], "****C3D4");
+        assert_eq!(trip["record_id"], "gw-test-1704067200-0");
+        assert_eq!(trip["route_id"], 7);
+        assert_eq!(trip["station_id"], 2);
+        assert_eq!(trip["station_name"], "二站");
+        assert_eq!(trip["age_secs"], 90);
+    }
+
+    #[test]
+    fn trips_payload_is_empty_without_open_trips() {
+        let mut state = trip_state(&[]);
+        let payload = trips_payload(&mut state);
+        assert_eq!(payload["count"], 0);
+        assert_eq!(payload["trips"].as_array().map(Vec::len), Some(0));
+    }
+
+    #[test]
+    fn clear_trip_accepts_record_id_or_card_id() {
+        assert!(matches!(
+            parse_action("type=clear_trip&record_id=gw-1-2"),
+            Some(DriverAction::ClearTrip { trip }) if trip == "gw-1-2"
+        ));
+        assert!(matches!(
+            parse_action("type=clear_trip&card_id=****C3D4"),
+            Some(DriverAction::ClearTrip { trip }) if trip == "****C3D4"
+        ));
+        assert!(matches!(
+            parse_action("type=clear_trip&record_id=&card_id=A1B2C3D4"),
+            Some(DriverAction::ClearTrip { trip }) if trip == "A1B2C3D4"
+        ));
+        assert!(parse_action("type=clear_trip").is_none());
+    }
+
+    #[test]
+    fn maintenance_actions_require_pin() {
+        assert!(matches!(
+            parse_action("type=set_card_balance&amount=0&pin=1"),
+            Some(DriverAction::SetNextCardBalance { cents: 0 })
+        ));
+        let action = parse_action("type=set_card_balance&amount=12.5").unwrap();
+        assert!(matches!(
+            action,
+            DriverAction::SetNextCardBalance { cents: 1250 }
+        ));
+        assert!(action.requires_pin());
+        assert!(parse_action("type=maintenance_on").unwrap().requires_pin());
+        assert!(!parse_action("type=next").unwrap().requires_pin());
+        assert!(parse_action("type=set_card_balance&amount=abc").is_none());
+    }
+
+    #[test]
+    fn web_server_params_use_configured_values() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.web_stack_size = 12 * 1024;
+        settings.web_port = 8080;
+        let params = WebServerParams::from_settings(&settings);
+        assert_eq!(
+            params,
+            WebServerParams {
+                stack_size: 12 * 1024,
+                port: 8080
+            }
+        );
+    }
+
+    #[test]
+    fn web_server_params_are_bounded() {
+        let mut settings = GatewaySettings::with_gateway_id("gw-test");
+        settings.web_stack_size = 1024;
+        settings.web_port = 0;
+        let params = WebServerParams::from_settings(&settings);
+        assert_eq!(params.stack_size, WEB_STACK_SIZE_MIN);
+        assert_eq!(params.port, WEB_PORT_DEFAULT);
+        settings.web_stack_size = usize::MAX;
+        assert_eq!(
+            WebServerParams::from_settings(&settings).stack_size,
+            WEB_STACK_SIZE_MAX
+        );
+    }
+
+    #[test]
+    fn default_web_server_params() {
+        let params = WebServerParams::from_settings(&GatewaySettings::with_gateway_id("gw-test"));
+        assert_eq!(
+            params,
+            WebServerParams {
+                stack_size: 8192,
+                port: 80
+            }
+        );
+    }
+
+    #[test]
+    fn action_parse_error_distinguishes_failures() {
+        assert_eq!(action_parse_error(""), "缺少操作类型");
+        assert_eq!(action_parse_error("type="), "缺少操作类型");
+        assert_eq!(action_parse_error("type=launch"), "未知操作");
+        assert_eq!(action_parse_error("type=set_station"), "操作参数缺失或无效");
+        assert_eq!(
+            action_parse_error("type=recharge&amount=-1"),
+            "操作参数缺失或无效"
+        );
+        assert!(parse_action("type=set_station&station_id=2").is_some());
+    }
+
+    #[test]
+    fn backend_url_is_normalized() {
+        let norm = |s: &str| normalize_backend_url(s.to_string());
+        assert_eq!(norm("").as_deref(), Some(""));
+        assert_eq!(norm("172.20.1.5").as_deref(), Some("http://172.20.1.5"));
+        assert_eq!(
+            norm(" 172.20.1.5 8080 ").as_deref(),
+            Some("http://172.20.1.5:8080")
+        );
+        assert_eq!(
+            norm("https://api.example.com:8443/v1/").as_deref(),
+            Some("https://api.example.com:8443/v1")
+        );
+    }
+
+    #[test]
+    fn malformed_backend_url_is_rejected() {
+        let norm = |s: &str| normalize_backend_url(s.to_string());
+        assert_eq!(norm("172.20.1.5:80:"), None);
+        assert_eq!(norm("8080"), None);
+        assert_eq!(norm(":8080"), None);
+        assert_eq!(norm("host:0"), None);
+        assert_eq!(norm("host:65536"), None);
+        assert_eq!(norm("host 80 81"), None);
+        assert_eq!(norm("host/a b"), None);
+    }
+
+    #[test]
+    fn upload_queue_shows_count_and_oldest_minutes() {
+        assert_eq!(format_upload_queue(0, Some(600)), "0 条");
+        assert_eq!(format_upload_queue(3, Some(59)), "3 条, 最早 0 分钟前");
+        assert_eq!(format_upload_queue(12, Some(185)), "12 条, 最早 3 分钟前");
+        assert_eq!(format_upload_queue(1, None), "1 条, 最早 0 分钟前");
+        let mut status = sample_status();
+        status.upload_queue_len = 4;
+        status.upload_oldest_age_secs = Some(120);
+        let html = render_index(&status, PageView::Full);
+        assert!(html.contains("<div id=\"upload-queue\">4 条, 最早 2 分钟前</div>"));
+    }
+
+    #[test]
+    fn transfer_hint_follows_status() {
+        let mut status = sample_status();
+        let html = render_index(&status, PageView::Passenger);
+        assert!(html.contains("<div class=\"sub\" id=\"station-transfer\" hidden>"));
+        status.station_is_transfer = true;
+        let html = render_index(&status, PageView::Passenger);
+        assert!(html.contains("<div class=\"sub\" id=\"station-transfer\">换乘站"));
+    }
+
+    #[test]
+    fn debug_state_masks_card_ids_regardless_of_setting() {
+        let mut state = trip_state(&[]);
+        state.settings.mask_card_ids = false;
+        state.settings.admin_pin = "2468".to_string();
+        state.settings.staff_card_ids = vec!["0102030405060708".to_string()];
+        state.stuck_trip_card = Some("A1B2C3D4".to_string());
+        state.cleared_trip_cards = vec!["11223344".to_string()];
+        // 面板按关闭的脱敏开关给出完整卡号
+        let mut status = sample_status();
+        status.last_card_id = "55667788".to_string();
+        status.stuck_trip_card = Some("A1B2C3D4".to_string());
+        let payload = debug_state_payload(&state, &status);
+        assert_eq!(payload["stuck_trip_card"], "****C3D4");
+        assert_eq!(
+            payload["cleared_trip_cards"],
+            serde_json::json!(["****3344"])
+        );
+        assert_eq!(payload["status"]["last_card_id"], "****7788");
+        assert_eq!(payload["status"]["stuck_trip_card"], "****C3D4");
+        assert_eq!(payload["status"]["station_name"], "二站");
+        assert_eq!(payload["caches"]["active_trips"], 0);
+        // 设置按结构化 JSON 导出
+        assert_eq!(payload["settings"]["mask_card_ids"], false);
+        assert_eq!(payload["settings"]["admin_pin"], "***");
+        assert_eq!(payload["settings"]["serial_mode"], "framed");
+        let text = payload.to_string();
+        assert!(text.contains("****0708"));
+        for secret in [
+            "0102030405060708",
+            "A1B2C3D4",
+            "55667788",
+            "11223344",
+            "2468",
+        ] {
+            assert!(!text.contains(secret), "{} leaked", secret);
+        }
+    }
+
+    #[test]
+    fn station_name_is_trimmed_and_bounded() {
+        let parse = |name: &str| match parse_action(&format!("type=station_name&name={}", name)) {
+            Some(DriverAction::SetStationName { name }) => Some(name),
+            _ => None,
+        };
+        assert_eq!(parse("%20临时站%20").as_deref(), Some("临时站"));
+        assert_eq!(
+            parse(&"站".repeat(STATION_NAME_MAX_CHARS)).map(|n| n.chars().count()),
+            Some(32)
+        );
+        assert_eq!(parse(&"站".repeat(STATION_NAME_MAX_CHARS + 1)), None);
+        assert_eq!(parse("%20%20"), None);
+    }
+
+    #[test]
+    fn render_index_escapes_station_route_and_backend() {
+        let mut status = sample_status();
+        status.station_name = "<script>alert(1)</script>".to_string();
+        status.route_name = "7路<b>".to_string();
+        status.backend_base_url = "http://a\"><img src=x onerror=alert(1)>".to_string();
+        let html = render_index(&status, PageView::Full);
+        assert!(!html.contains("<script>alert(1)</script>"));
+        assert!(!html.contains("<img src=x"));
+        assert!(!html.contains("7路<b>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(html.contains("7路&lt;b&gt;"));
+        assert!(html.contains("value=\"http://a&quot;&gt;&lt;img src=x onerror=alert(1)&gt;\""));
+        assert_eq!(escape_html("a&'b"), "a&amp;&#39;b");
+    }
+}
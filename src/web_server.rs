@@ -1,5 +1,7 @@
-use std::sync::{mpsc::Sender, Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Read as _, Write as _};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{mpsc, mpsc::Sender, Arc, Mutex};
+use std::time::Duration;
 
 use embedded_svc::http::Method;
 use embedded_svc::io::Write as _;
@@ -7,27 +9,95 @@ use esp_idf_svc::http::server::{Configuration, EspHttpServer};
 use esp_idf_svc::io::EspIOError;
 use serde_json::json;
 
+use crate::api::{
+    peer_blacklist_payload, peer_config_payload, PEER_BLACKLIST_PATH, PEER_CONFIG_PATH,
+};
+use crate::blacklist_store::{parse_blacklist_text, BlacklistStore, LOCAL_BLACKLIST_MAX_BYTES};
+use crate::card_data::normalize_uid;
+use crate::factory_reset::{self, FACTORY_RESET_CONFIRM};
+use crate::lock::LockRecover;
+use crate::log_buffer::{parse_level, recent_logs};
+use crate::metrics;
 use crate::net::NetCommand;
 use crate::model::{FareType, TapMode};
 use crate::state::GatewayState;
-use crate::web::{parse_action, render_index, DriverAction, StatusPanel};
+use crate::web::{
+    action_parse_error, debug_state_payload, normalize_backend_url, parse_action, query_value,
+    render_index, status_payload, trips_payload, ActionResult, DriverAction, PageView,
+    StatusPanel, WebServerParams, ACTION_SPECS,
+};
 
-/// 启动内置 HTTP 服务（司机操作页）。
+// 等待网络任务回报结果（全量同步、卡片状态推送）的最长时间（秒）。
+const FULL_RESYNC_TIMEOUT_SECS: u64 = 30;
+// HTTP 服务自检：本机回环请求超时（毫秒）。
+const SELF_PROBE_TIMEOUT_MS: u64 = 2000;
+
+/// 按校正后的参数构建 HTTP 服务配置。
+fn server_config(params: WebServerParams) -> Configuration {
+    log::info!(
+        "Web server config: port={} stack_size={}",
+        params.port,
+        params.stack_size
+    );
+    Configuration {
+        stack_size: params.stack_size,
+        http_port: params.port,
+        ..Default::default()
+    }
+}
+
+/// 自检：经本机回环请求 /ping，判断服务仍能接受连接并响应（套接字耗尽时连接或读取超时）。
+pub fn probe_server(port: u16) -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let timeout = Duration::from_millis(SELF_PROBE_TIMEOUT_MS);
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, timeout) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    if stream.write_all(b"GET /ping HTTP/1.0\r\n\r\n").is_err() {
+        return false;
+    }
+    let mut head = [0u8; 12];
+    matches!(stream.read(&mut head), Ok(n) if head[..n].starts_with(b"HTTP/1."))
+}
+
+/// 启动内置 HTTP 服务（司机操作页）；服务卡死重建时复用同一黑名单存储。
 pub fn start_server(
     state: Arc<Mutex<GatewayState>>,
     net_cmd_tx: Sender<NetCommand>,
+    blacklist_store: Arc<Mutex<Option<BlacklistStore>>>,
 ) -> Result<EspHttpServer<'static>, EspIOError> {
-    let mut server = EspHttpServer::new(&Configuration {
-        stack_size: 8192,
-        ..Default::default()
+    let params = WebServerParams::from_settings(&state.lock_recover().settings);
+    let mut server = EspHttpServer::new(&server_config(params))?;
+
+    // 自检接口：不访问网关状态，仅用于判断服务是否卡死
+    server.fn_handler("/ping", Method::Get, move |req| {
+        req.into_response(200, Some("OK"), &[("content-type", "text/plain")])?
+            .write_all(b"ok")
+            .map(|_| ())
     })?;
 
     // 首页：渲染 HTML
     let state_root = state.clone();
     server.fn_handler("/", Method::Get, move |req| {
+        let view = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| PageView::from_query(q))
+            .unwrap_or(PageView::Full);
         let status = status_from_state(&state_root);
         req.into_response(200, Some("OK"), &[("content-type", "text/html; charset=utf-8")])?
-            .write_all(render_index(&status).as_bytes())
+            .write_all(render_index(&status, view).as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 乘客屏：仅渲染乘客区域（公共显示屏使用，不含司机操作）
+    let state_display = state.clone();
+    server.fn_handler("/display", Method::Get, move |req| {
+        let status = status_from_state(&state_display);
+        req.into_response(200, Some("OK"), &[("content-type", "text/html; charset=utf-8")])?
+            .write_all(render_index(&status, PageView::Passenger).as_bytes())
             .map(|_| ())
     })?;
 
@@ -35,42 +105,21 @@ pub fn start_server(
     let state_status = state.clone();
     server.fn_handler("/status", Method::Get, move |req| {
         let status = status_from_state(&state_status);
-        let direction_label = match status.direction {
-            crate::model::Direction::Up => "上行",
-            crate::model::Direction::Down => "下行",
-        };
-        let tone_class = status.passenger_tone.css_class();
-        let tone_label = status.passenger_tone.label();
+        let payload = status_payload(&status);
+        let body = payload.to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 接口描述：动作类型与参数、/status 字段类型、固件版本（供第三方看板对接）
+    let state_api = state.clone();
+    server.fn_handler("/api", Method::Get, move |req| {
+        let status = status_from_state(&state_api);
         let payload = json!({
-            "route_id": status.route_id,
-            "route_name": status.route_name,
-            "station_id": status.station_id,
-            "station_name": status.station_name,
-            "direction": direction_label,
-            "tap_mode_label": status.tap_mode_label,
-            "fare_type_label": status.fare_type_label,
-            "cache_count": status.cache_count,
-            "wifi_connected": status.wifi_connected,
-            "backend_reachable": status.backend_reachable,
-            "backend_base_url": status.backend_base_url,
-            "last_card_id": status.last_card_id,
-            "last_balance_cents": status.last_balance_cents,
-            "last_card_data_len": status.last_card_data_len,
-            "last_card_data_prefix_hex": status.last_card_data_prefix_hex,
-            "last_card_data_error": status.last_card_data_error,
-            "passenger": {
-                "tone_class": tone_class,
-                "tone_label": tone_label,
-                "message": status.passenger_message,
-            },
-            "fare": {
-                "standard": status.standard_fare,
-                "actual": status.last_fare,
-                "label": status.last_fare_label,
-            },
-            "recharge_active": status.recharge_active,
-            "recharge_amount_cents": status.recharge_amount_cents,
-            "register_active": status.register_active,
+            "firmware_version": env!("CARGO_PKG_VERSION"),
+            "actions": ACTION_SPECS,
+            "status_schema": describe_schema(&status_payload(&status)),
         });
         let body = payload.to_string();
         req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
@@ -78,204 +127,598 @@ pub fn start_server(
             .map(|_| ())
     })?;
 
+    // 未完成行程列表：排查卡住的行程（卡号按脱敏设置展示）
+    let state_trips = state.clone();
+    server.fn_handler("/trips", Method::Get, move |req| {
+        let body = trips_payload(&mut state_trips.lock_recover());
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.to_string().as_bytes())
+            .map(|_| ())
+    })?;
+
     // 操作接口：通过 query 参数触发动作
     let state_action = state.clone();
     let net_cmd_action = net_cmd_tx.clone();
     server.fn_handler("/action", Method::Get, move |req| {
-        if let Some(query) = req.uri().splitn(2, '?').nth(1) {
-            if let Some(action) = parse_action(query) {
-                apply_action(&state_action, &net_cmd_action, action);
+        let query = req.uri().splitn(2, '?').nth(1).unwrap_or("").to_string();
+        let result = match parse_action(&query) {
+            Some(action) if action.requires_pin() && !pin_authorized(&state_action, &query) => {
+                Some(ActionResult {
+                    success: false,
+                    message: "口令错误".to_string(),
+                })
             }
+            Some(action) => apply_action(&state_action, &net_cmd_action, action),
+            // 无法解析的动作明确报错，避免重定向后看似成功
+            None => Some(ActionResult {
+                success: false,
+                message: action_parse_error(&query).to_string(),
+            }),
+        };
+        // 有执行结果的动作直接返回 JSON，其余重定向回首页
+        if let Some(result) = result {
+            let body = serde_json::to_string(&result).unwrap_or_default();
+            return req
+                .into_response(200, Some("OK"), &[("content-type", "application/json")])?
+                .write_all(body.as_bytes())
+                .map(|_| ());
         }
         req.into_response(303, Some("See Other"), &[("Location", "/")])?
             .write_all(b"")
             .map(|_| ())
     })?;
 
+    // 日志接口：返回内存环形缓冲中的近期日志（需口令）
+    let state_logs = state.clone();
+    server.fn_handler("/logs", Method::Get, move |req| {
+        let query = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| q.to_string())
+            .unwrap_or_default();
+        if !pin_authorized(&state_logs, &query) {
+            return req
+                .into_response(403, Some("Forbidden"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"forbidden"}"#)
+                .map(|_| ());
+        }
+        let level = query_value(&query, "level");
+        let entries = recent_logs(parse_level(level.as_deref()));
+        let body = json!({
+            "count": entries.len(),
+            "entries": entries,
+        })
+        .to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 指标接口：Prometheus 文本格式
+    let state_metrics = state.clone();
+    server.fn_handler("/metrics", Method::Get, move |req| {
+        let body = metrics::render(&state_metrics.lock_recover());
+        req.into_response(
+            200,
+            Some("OK"),
+            &[("content-type", "text/plain; version=0.0.4")],
+        )?
+        .write_all(body.as_bytes())
+        .map(|_| ())
+    })?;
+
+    // 配置诊断接口：返回内存中已同步的原始线路配置与缓存状态
+    let state_config = state.clone();
+    server.fn_handler("/config/raw", Method::Get, move |req| {
+        let body = {
+            let state = state_config.lock_recover();
+            let now = state.now_secs();
+            state.config_cache.raw_json(now).to_string()
+        };
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 支持包：一次性导出设置、线路、缓存与最近错误等完整诊断快照（需口令，卡号按脱敏设置展示）
+    let state_debug = state.clone();
+    server.fn_handler("/debug/state", Method::Get, move |req| {
+        let query = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| q.to_string())
+            .unwrap_or_default();
+        if !pin_authorized(&state_debug, &query) {
+            return req
+                .into_response(403, Some("Forbidden"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"forbidden"}"#)
+                .map(|_| ());
+        }
+        let status = status_from_state(&state_debug);
+        let body = debug_state_payload(&state_debug.lock_recover(), &status).to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 对端同步：以后端接口格式提供当前线路配置与黑名单（同车另一网关无 Wi-Fi 上行时拉取）
+    let state_peer_config = state.clone();
+    server.fn_handler(PEER_CONFIG_PATH, Method::Get, move |req| {
+        let body = state_peer_config
+            .lock_recover()
+            .config_cache
+            .route
+            .as_ref()
+            .map(peer_config_payload);
+        let Some(body) = body else {
+            return req
+                .into_response(404, Some("Not Found"), &[("content-type", "application/json")])?
+                .write_all(br#"{"success":false,"message":"config not synced"}"#)
+                .map(|_| ());
+        };
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.to_string().as_bytes())
+            .map(|_| ())
+    })?;
+
+    let state_peer_blacklist = state.clone();
+    server.fn_handler(PEER_BLACKLIST_PATH, Method::Get, move |req| {
+        let cards: Vec<String> = {
+            let state = state_peer_blacklist.lock_recover();
+            let cache = &state.blacklist_cache;
+            cache.cards.iter().chain(cache.local_cards.iter()).cloned().collect()
+        };
+        let body = peer_blacklist_payload(&cards).to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 本地黑名单导入：POST 换行分隔的卡号列表，替换本地名单并写入 NVS（需口令）
+    let state_blacklist = state.clone();
+    server.fn_handler("/blacklist", Method::Post, move |mut req| {
+        let query = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| q.to_string())
+            .unwrap_or_default();
+        if !pin_authorized(&state_blacklist, &query) {
+            return req
+                .into_response(403, Some("Forbidden"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"forbidden"}"#)
+                .map(|_| ());
+        }
+        let mut body = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            let read = req.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..read]);
+            if body.len() > LOCAL_BLACKLIST_MAX_BYTES {
+                return req
+                    .into_response(413, Some("Payload Too Large"), &[("content-type", "application/json")])?
+                    .write_all(br#"{"error":"too_large"}"#)
+                    .map(|_| ());
+            }
+        }
+        let cards = parse_blacklist_text(&String::from_utf8_lossy(&body));
+        let persisted = match blacklist_store.lock_recover().as_mut() {
+            Some(store) => match store.save(&cards) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("Local blacklist save failed: {:?}", err);
+                    false
+                }
+            },
+            None => false,
+        };
+        let count = cards.len();
+        state_blacklist.lock_recover().update_local_blacklist(cards);
+        log::info!("Local blacklist imported: {} cards (persisted={})", count, persisted);
+        let body = json!({
+            "count": count,
+            "persisted": persisted,
+        })
+        .to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 卡片缓存失效：后端在卡类型/状态变更后推送，下次刷卡重新查询（需口令）
+    let state_invalidate = state.clone();
+    server.fn_handler("/cards/invalidate", Method::Post, move |req| {
+        let query = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| q.to_string())
+            .unwrap_or_default();
+        if !pin_authorized(&state_invalidate, &query) {
+            return req
+                .into_response(403, Some("Forbidden"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"forbidden"}"#)
+                .map(|_| ());
+        }
+        let Some(card_id) = query_value(&query, "card_id")
+            .map(|id| normalize_uid(&id))
+            .filter(|id| !id.is_empty())
+        else {
+            return req
+                .into_response(400, Some("Bad Request"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"missing_card_id"}"#)
+                .map(|_| ());
+        };
+        let removed = state_invalidate.lock_recover().invalidate_card_profile(&card_id);
+        let body = json!({
+            "card_id": card_id,
+            "removed": removed,
+        })
+        .to_string();
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())
+            .map(|_| ())
+    })?;
+
+    // 恢复出厂：擦除 NVS 并重启（需口令，且须带 confirm=RESET 防误触）
+    let state_reset = state.clone();
+    server.fn_handler("/factory-reset", Method::Post, move |req| {
+        let query = req
+            .uri()
+            .split_once('?')
+            .map(|(_, q)| q.to_string())
+            .unwrap_or_default();
+        if !pin_authorized(&state_reset, &query) {
+            return req
+                .into_response(403, Some("Forbidden"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"forbidden"}"#)
+                .map(|_| ());
+        }
+        if query_value(&query, "confirm").as_deref() != Some(FACTORY_RESET_CONFIRM) {
+            return req
+                .into_response(400, Some("Bad Request"), &[("content-type", "application/json")])?
+                .write_all(br#"{"error":"confirm_required"}"#)
+                .map(|_| ());
+        }
+        {
+            let mut state = state_reset.lock_recover();
+            factory_reset::reset_in_memory(&mut state);
+        }
+        if !factory_reset::erase_nvs_and_restart() {
+            return req
+                .into_response(
+                    500,
+                    Some("Internal Server Error"),
+                    &[("content-type", "application/json")],
+                )?
+                .write_all(br#"{"error":"erase_failed"}"#)
+                .map(|_| ());
+        }
+        req.into_response(200, Some("OK"), &[("content-type", "application/json")])?
+            .write_all(br#"{"reset":true,"restarting":true}"#)
+            .map(|_| ())
+    })?;
+
     Ok(server)
 }
 
-/// 执行司机操作指令，并触发必要的同步/上传。
-fn apply_action(state: &Arc<Mutex<GatewayState>>, net_cmd_tx: &Sender<NetCommand>, action: DriverAction) {
+/// 校验维护口令（未配置口令时放行）。
+fn pin_authorized(state: &Arc<Mutex<GatewayState>>, query: &str) -> bool {
+    let admin_pin = state.lock_recover().settings.admin_pin.clone();
+    admin_pin.is_empty() || query_value(query, "pin").as_deref() == Some(admin_pin.as_str())
+}
+
+/// 执行司机操作指令，并触发必要的同步/上传；需要回报的动作返回执行结果。
+fn apply_action(
+    state: &Arc<Mutex<GatewayState>>,
+    net_cmd_tx: &Sender<NetCommand>,
+    action: DriverAction,
+) -> Option<ActionResult> {
+    {
+        let mut state = state.lock_recover();
+        let now_ms = state.now_millis();
+        state.touch_activity(now_ms);
+    }
     match action {
         DriverAction::SetRoute { route_id } => {
-            if let Ok(mut state) = state.lock() {
+            {
+                let mut state = state.lock_recover();
                 let direction = state.route_state.direction;
                 state.update_route(route_id, 0, "未设置".to_string(), direction);
             }
             let _ = net_cmd_tx.send(NetCommand::SyncConfig { route_id });
         }
         DriverAction::SetDirection { direction } => {
-            if let Ok(mut state) = state.lock() {
-                state.set_direction(direction);
-            }
+            let mut state = state.lock_recover();
+            let now_ms = state.now_millis();
+            state.set_direction(direction);
+            state.pause_auto_advance(now_ms);
         }
         DriverAction::SetStation { station_id } => {
-            if let Ok(mut state) = state.lock() {
+            {
+                let mut state = state.lock_recover();
+                let now_ms = state.now_millis();
                 let _ = state.set_station_by_id(station_id);
+                state.pause_auto_advance(now_ms);
             }
             let _ = net_cmd_tx.send(NetCommand::UploadNow);
         }
+        DriverAction::SetStationName { name } => {
+            return Some(if state.lock_recover().set_station_name_override(name) {
+                ActionResult {
+                    success: true,
+                    message: "站名已修改（同步配置后恢复）".to_string(),
+                }
+            } else {
+                ActionResult {
+                    success: false,
+                    message: "当前未设置站点".to_string(),
+                }
+            });
+        }
         DriverAction::NextStation => {
-            if let Ok(mut state) = state.lock() {
+            {
+                let mut state = state.lock_recover();
+                let now_ms = state.now_millis();
                 let _ = state.step_station(true);
+                state.pause_auto_advance(now_ms);
             }
             let _ = net_cmd_tx.send(NetCommand::UploadNow);
         }
         DriverAction::PrevStation => {
-            if let Ok(mut state) = state.lock() {
+            {
+                let mut state = state.lock_recover();
+                let now_ms = state.now_millis();
                 let _ = state.step_station(false);
+                state.pause_auto_advance(now_ms);
             }
             let _ = net_cmd_tx.send(NetCommand::UploadNow);
         }
         DriverAction::SyncConfig => {
-            let route_id = state
-                .lock()
-                .map(|s| s.route_state.route_id)
-                .unwrap_or(0);
+            let route_id = state.lock_recover().route_state.route_id;
             let _ = net_cmd_tx.send(NetCommand::SyncConfig { route_id });
         }
+        DriverAction::FullResync => {
+            let route_id = state.lock_recover().route_state.route_id;
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let sent = net_cmd_tx.send(NetCommand::FullResync {
+                route_id,
+                reply: Some(reply_tx),
+            });
+            let result = match sent {
+                Ok(()) => reply_rx
+                    .recv_timeout(Duration::from_secs(FULL_RESYNC_TIMEOUT_SECS))
+                    .unwrap_or_else(|_| ActionResult {
+                        success: false,
+                        message: "同步超时".to_string(),
+                    }),
+                Err(_) => ActionResult {
+                    success: false,
+                    message: "网络任务不可用".to_string(),
+                },
+            };
+            return Some(result);
+        }
         DriverAction::UploadNow => {
             let _ = net_cmd_tx.send(NetCommand::UploadNow);
         }
         DriverAction::SetBackend { base_url } => {
-            let normalized = normalize_backend_url(base_url);
-            if let Ok(mut state) = state.lock() {
-                state.update_backend_base_url(normalized.clone());
-            }
+            let Some(normalized) = normalize_backend_url(base_url) else {
+                return Some(ActionResult {
+                    success: false,
+                    message: "后端地址无效（应为 主机 或 主机:端口）".to_string(),
+                });
+            };
+            state.lock_recover().update_backend_base_url(normalized.clone());
             let _ = net_cmd_tx.send(NetCommand::SetBackend { base_url: normalized });
         }
         DriverAction::StartRecharge { amount_cents } => {
-            let now_ms = current_epoch_millis();
-            if let Ok(mut state) = state.lock() {
-                state.set_recharge_mode(amount_cents, now_ms);
-            }
+            let mut state = state.lock_recover();
+            let now_ms = state.now_millis();
+            state.set_recharge_mode(amount_cents, now_ms);
         }
         DriverAction::CancelRecharge => {
-            if let Ok(mut state) = state.lock() {
-                state.clear_recharge_mode();
+            state.lock_recover().clear_recharge_mode();
+        }
+        DriverAction::PushCardStates => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let sent = net_cmd_tx.send(NetCommand::PushCardStates {
+                reply: Some(reply_tx),
+            });
+            let result = match sent {
+                Ok(()) => reply_rx
+                    .recv_timeout(Duration::from_secs(FULL_RESYNC_TIMEOUT_SECS))
+                    .unwrap_or_else(|_| ActionResult {
+                        success: false,
+                        message: "推送超时".to_string(),
+                    }),
+                Err(_) => ActionResult {
+                    success: false,
+                    message: "网络任务不可用".to_string(),
+                },
+            };
+            return Some(result);
+        }
+        DriverAction::ClearCardCache => {
+            let mut state = state.lock_recover();
+            let count = state.clear_card_cache();
+            log::info!("Card profile cache cleared: {} entries", count);
+        }
+        DriverAction::SetNextFare { cents } => {
+            state.lock_recover().next_fare_override_cents = Some(cents);
+        }
+        DriverAction::ForceClearTrip { card_id, seq } => {
+            let mut state = state.lock_recover();
+            if !state.arm_force_clear_trip(&card_id, seq) {
+                log::warn!(
+                    "Force clear ignored: card {} not pending",
+                    state.display_card_id(&card_id)
+                );
             }
         }
+        DriverAction::ClearTrip { trip } => {
+            let cleared = state.lock_recover().clear_active_trip(&trip);
+            let result = match cleared {
+                Some(_) => ActionResult {
+                    success: true,
+                    message: "行程已清除".to_string(),
+                },
+                None => ActionResult {
+                    success: false,
+                    message: "未找到行程（脱敏卡号重复时请按记录号清除）".to_string(),
+                },
+            };
+            return Some(result);
+        }
         DriverAction::StartRegister => {
-            let now_ms = current_epoch_millis();
-            if let Ok(mut state) = state.lock() {
-                state.set_register_mode(now_ms);
-            }
+            let mut state = state.lock_recover();
+            let now_ms = state.now_millis();
+            state.set_register_mode(now_ms);
         }
         DriverAction::CancelRegister => {
-            if let Ok(mut state) = state.lock() {
-                state.clear_register_mode();
-            }
+            state.lock_recover().clear_register_mode();
+        }
+        DriverAction::StartMaintenance => {
+            let mut state = state.lock_recover();
+            let now_ms = state.now_millis();
+            state.set_maintenance_mode(now_ms);
+        }
+        DriverAction::CancelMaintenance => {
+            state.lock_recover().clear_maintenance_mode();
+        }
+        DriverAction::SetNextCardBalance { cents } => {
+            let armed = {
+                let mut state = state.lock_recover();
+                let now_ms = state.now_millis();
+                state.set_balance_override(cents, now_ms)
+            };
+            let message = if armed {
+                "请刷卡写入余额"
+            } else {
+                "需先进入维护模式（或超出余额上限）"
+            };
+            return Some(ActionResult {
+                success: armed,
+                message: message.to_string(),
+            });
         }
     }
+    None
 }
 
 /// 从全局状态构建前端面板展示数据。
 fn status_from_state(state: &Arc<Mutex<GatewayState>>) -> StatusPanel {
-    if let Ok(mut state) = state.lock() {
-        // 清理过期提示
-        let now_ms = current_epoch_millis();
-        if state.last_message_deadline_ms > 0 && now_ms >= state.last_message_deadline_ms {
-            state.last_message_deadline_ms = 0;
-            state.last_passenger_tone = crate::model::PassengerTone::Normal;
-            state.last_passenger_message = "等待刷卡".to_string();
-            state.last_fare_base = None;
-            state.last_fare = None;
-            state.last_fare_label = "应付".to_string();
-            state.last_tap_type = None;
-        }
-        let mut route_name = String::new();
-        let mut tap_mode_label = "未同步".to_string();
-        let mut fare_type_label = "未同步".to_string();
-        // 若已同步配置，使用更友好的标签
-        if let Some(cfg) = state.config_cache.route.as_ref() {
-            route_name = cfg.route_name.clone();
-            tap_mode_label = match cfg.tap_mode {
-                TapMode::SingleTap => "单次刷卡",
-                TapMode::TapInOut => "上下车刷卡",
-            }
-            .to_string();
-            fare_type_label = match cfg.fare_type {
-                FareType::Uniform => "统一票价",
-                FareType::Segment => "分段计价",
-                FareType::Distance => "距离计价",
-            }
-            .to_string();
-        }
-        StatusPanel {
-            route_id: state.route_state.route_id,
-            route_name,
-            station_id: state.route_state.station_id,
-            station_name: state.route_state.station_name.clone(),
-            direction: state.route_state.direction,
-            tap_mode_label,
-            fare_type_label,
-            cache_count: state.tap_cache.len(),
-            wifi_connected: state.wifi_connected,
-            backend_reachable: state.backend_reachable,
-            backend_base_url: state.backend_base_url.clone(),
-            passenger_tone: state.last_passenger_tone,
-            passenger_message: state.last_passenger_message.clone(),
-            standard_fare: state.standard_fare(),
-            last_fare: state.last_fare,
-            last_fare_label: state.last_fare_label.clone(),
-            recharge_active: state.recharge_mode.is_some(),
-            recharge_amount_cents: state.recharge_mode.as_ref().map(|mode| mode.amount_cents),
-            register_active: state.register_mode.is_some(),
-            last_card_id: state.last_card_id.clone(),
-            last_balance_cents: state.last_balance_cents,
-            last_card_data_len: state.last_card_data_len,
-            last_card_data_prefix_hex: state.last_card_data_prefix_hex.clone(),
-            last_card_data_error: state.last_card_data_error.clone(),
-        }
-    } else {
-        // 无法获取锁时返回默认状态
-        StatusPanel {
-            route_id: 0,
-            route_name: String::new(),
-            station_id: 0,
-            station_name: "未设置".to_string(),
-            direction: crate::model::Direction::Up,
-            tap_mode_label: "未同步".to_string(),
-            fare_type_label: "未同步".to_string(),
-            cache_count: 0,
-            wifi_connected: false,
-            backend_reachable: false,
-            backend_base_url: String::new(),
-            passenger_tone: crate::model::PassengerTone::Normal,
-            passenger_message: "等待刷卡".to_string(),
-            standard_fare: None,
-            last_fare: None,
-            last_fare_label: "应付".to_string(),
-            recharge_active: false,
-            recharge_amount_cents: None,
-            register_active: false,
-            last_card_id: String::new(),
-            last_balance_cents: None,
-            last_card_data_len: 0,
-            last_card_data_prefix_hex: None,
-            last_card_data_error: None,
-        }
+    let mut state = state.lock_recover();
+    // 清理过期提示
+    let now_ms = state.now_millis();
+    if state.last_message_deadline_ms > 0 && now_ms >= state.last_message_deadline_ms {
+        state.last_message_deadline_ms = 0;
+        state.last_passenger_tone = crate::model::PassengerTone::Normal;
+        state.last_passenger_message = "等待刷卡".to_string();
+        state.last_fare_base_cents = None;
+        state.last_fare_cents = None;
+        state.last_fare_label = "应付".to_string();
+        state.last_tap_type = None;
+        state.last_board_station = None;
+        state.last_trip_elapsed_secs = None;
     }
-}
-
-/// 规范化后端地址（自动补齐协议/去尾斜杠）。
-fn normalize_backend_url(input: String) -> String {
-    let trimmed = input.trim();
-    if trimmed.is_empty() {
-        return String::new();
+    let mut route_name = String::new();
+    let mut tap_mode_label = "未同步".to_string();
+    let mut fare_type_label = "未同步".to_string();
+    // 若已同步配置，使用更友好的标签
+    if let Some(cfg) = state.config_cache.route.as_ref() {
+        route_name = cfg.route_name.clone();
+        tap_mode_label = match cfg.tap_mode {
+            TapMode::SingleTap => "单次刷卡",
+            TapMode::TapInOut => "上下车刷卡",
+        }
+        .to_string();
+        fare_type_label = match cfg.fare_type {
+            FareType::Uniform => "统一票价",
+            FareType::Segment => "分段计价",
+            FareType::Distance => "距离计价",
+        }
+        .to_string();
     }
-    let mut url = trimmed.to_string();
-    if !url.starts_with("http://") && !url.starts_with("https://") {
-        url = format!("http://{}", url);
+    StatusPanel {
+        route_id: state.route_state.route_id,
+        route_name,
+        station_id: state.route_state.station_id,
+        station_name: state.route_state.station_name.clone(),
+        station_is_transfer: state.is_transfer_station(state.route_state.station_id),
+        direction: state.route_state.direction,
+        tap_mode_label,
+        fare_type_label,
+        cache_count: state.tap_cache.len(),
+        wifi_connected: state.wifi_connected,
+        wifi_connect_attempts: state.wifi_connect_attempts,
+        backend_reachable: state.backend_reachable,
+        local_time: state.local_time_label(),
+        backend_base_url: state.backend_base_url.clone(),
+        backend_error: state.last_backend_error.as_ref().map(|err| err.summary()),
+        backend_error_age_secs: state
+            .last_backend_error
+            .as_ref()
+            .map(|err| now_ms.saturating_sub(err.at_ms) / 1000),
+        last_upload_age_secs: state.last_upload_age_secs(now_ms),
+        passenger_tone: state.last_passenger_tone,
+        passenger_message: if state.is_idle(now_ms) {
+            "停运".to_string()
+        } else if state.warming_up() && state.last_card_id.is_empty() {
+            "系统启动中".to_string()
+        } else {
+            state.last_passenger_message.clone()
+        },
+        standard_fare_cents: state.standard_fare_cents(),
+        last_fare_cents: state.last_fare_cents,
+        last_fare_label: state.last_fare_label.clone(),
+        last_board_station: state.last_board_station.clone(),
+        last_trip_elapsed_secs: state.last_trip_elapsed_secs,
+        next_fare_override_cents: state.next_fare_override_cents,
+        recharge_active: state.recharge_mode.is_some(),
+        recharge_amount_cents: state.recharge_mode.as_ref().map(|mode| mode.amount_cents),
+        stuck_trip_card: state
+            .stuck_trip_card
+            .as_deref()
+            .map(|id| state.display_card_id(id)),
+        stuck_trip_seq: state.stuck_trip_seq,
+        force_clear_armed: state.force_clear_card.is_some(),
+        register_active: state.register_mode.is_some(),
+        last_card_id: state.display_card_id(&state.last_card_id),
+        last_balance_cents: state.last_balance_cents,
+        last_card_trip: state.last_card_trip.clone(),
+        last_card_data_len: state.last_card_data_len,
+        last_card_data_prefix_hex: state.last_card_data_prefix_hex.clone(),
+        last_card_data_error: state.last_card_data_error.clone(),
+        pending_writes: state.pending_write_count(),
+        upload_dropped: state.upload_dropped,
+        upload_queue_len: state.upload_queue_len,
+        upload_oldest_age_secs: state.upload_oldest_age_secs,
+        write_unconfirmed: state.write_unconfirmed(),
+        reader_firmware: state
+            .reader_hello
+            .as_ref()
+            .map(|hello| hello.firmware_version.clone()),
+        reader_capabilities: state.reader_capabilities,
+        reader_offline: state.reader_offline,
+        idle: state.is_idle(now_ms),
     }
-    url.trim_end_matches('/').to_string()
 }
 
-/// 当前时间戳（毫秒）。
-fn current_epoch_millis() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_millis() as u64)
-        .unwrap_or(0)
+/// 将 JSON 值转换为字段类型描述（对象逐字段展开，可为空的字段当前值为 null 时记为 nullable）。
+fn describe_schema(value: &serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), describe_schema(value)))
+                .collect(),
+        ),
+        Value::Null => json!("nullable"),
+        Value::Bool(_) => json!("boolean"),
+        Value::Number(_) => json!("number"),
+        Value::String(_) => json!("string"),
+        Value::Array(_) => json!("array"),
+    }
 }